@@ -0,0 +1,26 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use naked_rust_api::http::{Limits, parse};
+
+// The parser must never panic, and whatever it accepts must respect the limits
+// and parse the same way again from exactly the bytes it consumed.
+fuzz_target!(|data: &[u8]| {
+    let limits = Limits {
+        max_request_line: 256,
+        max_head: 1024,
+        max_headers: 16,
+        max_body: 4096,
+    };
+    if let Ok(Some((request, consumed))) = parse(data, &limits) {
+        assert!(consumed <= data.len());
+        assert!(request.body.len() <= limits.max_body);
+        assert!(request.headers.len() <= limits.max_headers);
+
+        let (again, consumed_again) = parse(&data[..consumed], &limits)
+            .expect("Accepted request failed to re-parse")
+            .expect("Accepted request was incomplete on re-parse");
+        assert_eq!(consumed_again, consumed);
+        assert_eq!(again.body, request.body);
+    }
+});
@@ -0,0 +1,165 @@
+// Who changed which todo, when, and how. Events are kept with the store, so
+// they are persisted, snapshotted and rolled back together with the todos.
+use crate::Todo;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Created,
+    Updated,
+    Deleted,
+    Restored,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct FieldChange {
+    pub before: Value,
+    pub after: Value,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct AuditEvent {
+    // Position in the log, starting at 1
+    pub seq: u64,
+    pub at: DateTime<Utc>,
+    pub actor: String,
+    pub action: Action,
+    pub owner: String,
+    pub id: usize,
+    // Only the fields that changed; a field that didn't exist is null
+    pub changes: BTreeMap<String, FieldChange>,
+}
+
+impl AuditEvent {
+    // `before` is None for a new todo. The sequence number is assigned when
+    // the event is logged.
+    pub fn new(actor: &str, action: Action, before: Option<&Todo>, after: &Todo) -> AuditEvent {
+        AuditEvent {
+            seq: 0,
+            at: Utc::now(),
+            actor: actor.to_string(),
+            action,
+            owner: after.owner.clone(),
+            id: after.id,
+            changes: diff(before, after),
+        }
+    }
+}
+
+fn diff(before: Option<&Todo>, after: &Todo) -> BTreeMap<String, FieldChange> {
+    let fields = |todo: Option<&Todo>| match todo.map(serde_json::to_value) {
+        Some(Ok(Value::Object(fields))) => fields,
+        _ => serde_json::Map::new(),
+    };
+    let (before, after) = (fields(before), fields(Some(after)));
+    let mut names: Vec<&String> = before.keys().chain(after.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let before = before.get(name).cloned().unwrap_or(Value::Null);
+            let after = after.get(name).cloned().unwrap_or(Value::Null);
+            (before != after).then(|| (name.clone(), FieldChange { before, after }))
+        })
+        .collect()
+}
+
+// Events kept in memory; older ones are dropped as new ones arrive
+pub const CAPACITY: usize = 100_000;
+
+// Filters for the global log; every one left out matches everything
+#[derive(Default)]
+pub struct AuditQuery {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub actor: Option<String>,
+    // Only events after this sequence number, to page through the log
+    pub after: u64,
+}
+
+impl AuditQuery {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        self.since.is_none_or(|since| event.at >= since)
+            && self.until.is_none_or(|until| event.at <= until)
+            && self
+                .actor
+                .as_ref()
+                .is_none_or(|actor| event.actor == *actor)
+    }
+}
+
+#[derive(Clone)]
+pub struct AuditLog {
+    events: VecDeque<AuditEvent>,
+    // Kept apart from the events, which may all have been dropped
+    next_seq: u64,
+}
+
+impl Default for AuditLog {
+    fn default() -> AuditLog {
+        AuditLog {
+            events: VecDeque::new(),
+            next_seq: 1,
+        }
+    }
+}
+
+impl AuditLog {
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    // Returns the oldest event if it had to go to make room
+    pub fn push(&mut self, event: AuditEvent) -> Option<AuditEvent> {
+        self.next_seq = event.seq + 1;
+        self.events.push_back(event);
+        if self.events.len() > CAPACITY {
+            return self.events.pop_front();
+        }
+        None
+    }
+
+    // Puts back events `push` dropped, oldest first, after newer ones were
+    // undone
+    pub fn restore(&mut self, dropped: Vec<AuditEvent>) {
+        for event in dropped.into_iter().rev() {
+            self.events.push_front(event);
+        }
+    }
+
+    // Drops the events after `seq`, for changes that were undone
+    pub fn truncate_after(&mut self, seq: u64) {
+        let keep = self.events.partition_point(|event| event.seq <= seq);
+        self.events.truncate(keep);
+        self.next_seq = self.next_seq.min(seq + 1);
+    }
+
+    // Oldest first
+    pub fn events(&self) -> &VecDeque<AuditEvent> {
+        &self.events
+    }
+
+    pub fn for_todo(&self, owner: &str, id: usize) -> Vec<&AuditEvent> {
+        self.events
+            .iter()
+            .filter(|event| event.owner == owner && event.id == id)
+            .collect()
+    }
+
+    // At most `limit` matching events, oldest first
+    pub fn query(&self, query: &AuditQuery, limit: usize) -> Vec<&AuditEvent> {
+        let start = self
+            .events
+            .partition_point(|event| event.seq <= query.after);
+        self.events
+            .range(start..)
+            .filter(|event| query.matches(event))
+            .take(limit)
+            .collect()
+    }
+}
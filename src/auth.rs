@@ -0,0 +1,463 @@
+use crate::config::AuthConfig;
+use crate::crypto::{
+    base64_decode, base64_encode, base64url_decode, base64url_encode, constant_time_eq,
+    hmac_sha256, pbkdf2_hmac_sha256, random_key, random_salt,
+};
+use crate::http::Headers;
+use crate::log_error;
+use chrono::Utc;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// Who a request is acting for, as established by an authenticator
+#[derive(Clone)]
+pub struct Principal {
+    pub user: String,
+    pub scheme: &'static str,
+}
+
+impl Principal {
+    // False for users merely claimed through X-User-Id, or the default one
+    pub fn is_authenticated(&self) -> bool {
+        self.scheme != "anonymous" && self.scheme != "header"
+    }
+}
+
+pub enum AuthOutcome {
+    Authenticated(Principal),
+    // The request carries no credentials this authenticator understands
+    Missing,
+    Invalid(&'static str),
+}
+
+// One way of proving identity. The server tries each configured
+// authenticator in turn, so new schemes only need to implement this trait.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, headers: &Headers) -> AuthOutcome;
+
+    // Value for the WWW-Authenticate header sent with a 401
+    fn challenge(&self) -> String;
+
+    // Re-reads any external credential source, e.g. on SIGHUP
+    fn reload(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+pub struct AuthError {
+    pub message: &'static str,
+    pub challenges: Vec<String>,
+}
+
+pub struct Authentication {
+    authenticators: Vec<Box<dyn Authenticator>>,
+}
+
+impl Authentication {
+    pub fn from_config(config: &AuthConfig) -> Authentication {
+        let mut authenticators: Vec<Box<dyn Authenticator>> = Vec::new();
+        if !config.api_keys.is_empty() {
+            authenticators.push(Box::new(ApiKeyAuthenticator {
+                keys: config.api_keys.clone(),
+            }));
+        }
+        if let Some(ref secret) = config.jwt_secret {
+            authenticators.push(Box::new(JwtAuthenticator {
+                secret: secret.as_bytes().to_vec(),
+                issuer: config.jwt_issuer.clone(),
+                audience: config.jwt_audience.clone(),
+                leeway_secs: config.jwt_leeway_secs as i64,
+            }));
+        }
+        if let Some(ref path) = config.htpasswd_file {
+            let basic = BasicAuthenticator::new(path);
+            // Start with no accounts rather than no authentication if the file
+            // is broken; a later reload can still fix it
+            if let Err(e) = basic.reload() {
+                eprintln!("{}", e);
+                log_error(&e);
+            }
+            authenticators.push(Box::new(basic));
+        }
+        Authentication { authenticators }
+    }
+
+    pub fn reload(&self) -> Result<(), String> {
+        self.authenticators
+            .iter()
+            .try_for_each(|authenticator| authenticator.reload())
+    }
+
+    pub fn add(&mut self, authenticator: Box<dyn Authenticator>) {
+        self.authenticators.push(authenticator);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.authenticators.is_empty()
+    }
+
+    // Ok(None) means authentication is switched off
+    pub fn authenticate(&self, headers: &Headers) -> Result<Option<Principal>, AuthError> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+
+        let mut message = "Authentication required.";
+        for authenticator in &self.authenticators {
+            match authenticator.authenticate(headers) {
+                AuthOutcome::Authenticated(principal) => return Ok(Some(principal)),
+                AuthOutcome::Invalid(error) => message = error,
+                AuthOutcome::Missing => {}
+            }
+        }
+        Err(self.error(message))
+    }
+
+    pub fn error(&self, message: &'static str) -> AuthError {
+        let mut challenges: Vec<String> = Vec::new();
+        for authenticator in &self.authenticators {
+            let challenge = authenticator.challenge();
+            if !challenges.contains(&challenge) {
+                challenges.push(challenge);
+            }
+        }
+        AuthError {
+            message,
+            challenges,
+        }
+    }
+}
+
+fn bearer_token(headers: &Headers) -> Option<&str> {
+    let value = headers.get("Authorization")?;
+    let (scheme, token) = value.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("Bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+// Static keys from the config, sent as `X-API-Key` or as a bearer token
+pub struct ApiKeyAuthenticator {
+    keys: HashMap<String, String>,
+}
+
+impl Authenticator for ApiKeyAuthenticator {
+    fn authenticate(&self, headers: &Headers) -> AuthOutcome {
+        let (key, from_header) = match headers.get("X-API-Key") {
+            Some(key) => (key.trim(), true),
+            None => match bearer_token(headers) {
+                Some(token) => (token, false),
+                None => return AuthOutcome::Missing,
+            },
+        };
+
+        let user = self
+            .keys
+            .iter()
+            .find(|(candidate, _)| constant_time_eq(candidate.as_bytes(), key.as_bytes()));
+        match user {
+            Some((_, user)) => AuthOutcome::Authenticated(Principal {
+                user: user.clone(),
+                scheme: "api-key",
+            }),
+            // Bearer tokens that look like a JWT are left for the JWT authenticator
+            None if !from_header && key.contains('.') => AuthOutcome::Missing,
+            None => AuthOutcome::Invalid("Invalid API key."),
+        }
+    }
+
+    fn challenge(&self) -> String {
+        "Bearer realm=\"todos\"".to_string()
+    }
+}
+
+// HS256-signed JSON Web Tokens; the `sub` claim names the user
+pub struct JwtAuthenticator {
+    secret: Vec<u8>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway_secs: i64,
+}
+
+impl JwtAuthenticator {
+    fn verify(&self, token: &str) -> Result<Principal, &'static str> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("Malformed token.");
+        };
+
+        let header: Value = base64url_decode(header)
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or("Malformed token.")?;
+        // Never let the token pick its own algorithm, in particular not "none"
+        if header.get("alg").and_then(Value::as_str) != Some("HS256") {
+            return Err("Unsupported token algorithm.");
+        }
+
+        let signature = base64url_decode(signature).ok_or("Malformed token.")?;
+        let signing_input = &token[..header_and_payload_len(token)];
+        let expected = hmac_sha256(&self.secret, signing_input.as_bytes());
+        if !constant_time_eq(&expected, &signature) {
+            return Err("Invalid token signature.");
+        }
+
+        let claims: Value = base64url_decode(payload)
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or("Malformed token.")?;
+        let now = Utc::now().timestamp();
+        if let Some(exp) = claims.get("exp") {
+            let exp = exp.as_i64().ok_or("Malformed token.")?;
+            if now > exp + self.leeway_secs {
+                return Err("Token has expired.");
+            }
+        }
+        if let Some(nbf) = claims.get("nbf") {
+            let nbf = nbf.as_i64().ok_or("Malformed token.")?;
+            if now + self.leeway_secs < nbf {
+                return Err("Token is not valid yet.");
+            }
+        }
+        if let Some(ref issuer) = self.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+                return Err("Invalid token issuer.");
+            }
+        }
+        if let Some(ref audience) = self.audience {
+            let matches = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !matches {
+                return Err("Invalid token audience.");
+            }
+        }
+
+        match claims.get("sub").and_then(Value::as_str) {
+            Some(sub) if !sub.is_empty() => Ok(Principal {
+                user: sub.to_string(),
+                scheme: "bearer",
+            }),
+            _ => Err("Token has no subject."),
+        }
+    }
+}
+
+fn header_and_payload_len(token: &str) -> usize {
+    token.rfind('.').unwrap_or(token.len())
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn authenticate(&self, headers: &Headers) -> AuthOutcome {
+        match bearer_token(headers) {
+            Some(token) => match self.verify(token) {
+                Ok(principal) => AuthOutcome::Authenticated(principal),
+                Err(error) => AuthOutcome::Invalid(error),
+            },
+            None => AuthOutcome::Missing,
+        }
+    }
+
+    fn challenge(&self) -> String {
+        "Bearer realm=\"todos\"".to_string()
+    }
+}
+
+pub const PASSWORD_ITERATIONS: u32 = 10_000;
+const PASSWORD_SCHEME: &str = "pbkdf2-sha256";
+
+// Hashes a password for the credentials file as
+// `pbkdf2-sha256$<iterations>$<base64 salt>$<base64 hash>`
+pub fn hash_password(password: &str) -> String {
+    let salt = random_salt();
+    let hash = pbkdf2_hmac_sha256(password.as_bytes(), &salt, PASSWORD_ITERATIONS);
+    format!(
+        "{}${}${}${}",
+        PASSWORD_SCHEME,
+        PASSWORD_ITERATIONS,
+        base64_encode(&salt),
+        base64_encode(&hash)
+    )
+}
+
+struct PasswordHash {
+    iterations: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    fn parse(encoded: &str) -> Option<PasswordHash> {
+        let mut parts = encoded.split('$');
+        if parts.next()? != PASSWORD_SCHEME {
+            return None;
+        }
+        let iterations = parts.next()?.parse().ok().filter(|&n| n > 0)?;
+        let salt = base64_decode(parts.next()?)?;
+        let hash = base64_decode(parts.next()?)?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(PasswordHash {
+            iterations,
+            salt,
+            hash,
+        })
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        let candidate = pbkdf2_hmac_sha256(password.as_bytes(), &self.salt, self.iterations);
+        constant_time_eq(&candidate, &self.hash)
+    }
+}
+
+// How long a verified Authorization header is trusted without hashing the
+// password again, and how many are remembered
+const VERIFIED_TTL: Duration = Duration::from_secs(60);
+const MAX_VERIFIED: usize = 10_000;
+
+// `Authorization: Basic` checked against an htpasswd-style file of
+// `user:hash` lines, as produced by `hash_password`
+pub struct BasicAuthenticator {
+    path: String,
+    credentials: RwLock<HashMap<String, PasswordHash>>,
+    // Checked for users that don't exist, so they take as long as those that do
+    dummy: PasswordHash,
+    // Headers that verified recently, by their HMAC under `verified_key` so
+    // no password is kept in memory, with the user and when they expire
+    verified: Mutex<HashMap<[u8; 32], (String, Instant)>>,
+    verified_key: [u8; 32],
+}
+
+impl BasicAuthenticator {
+    pub fn new(path: &str) -> BasicAuthenticator {
+        let dummy = PasswordHash {
+            iterations: PASSWORD_ITERATIONS,
+            salt: random_salt().to_vec(),
+            hash: vec![0; 32],
+        };
+        BasicAuthenticator {
+            path: path.to_string(),
+            credentials: RwLock::new(HashMap::new()),
+            dummy,
+            verified: Mutex::new(HashMap::new()),
+            verified_key: random_key(),
+        }
+    }
+
+    fn remember(&self, key: [u8; 32], user: &str) {
+        let now = Instant::now();
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() >= MAX_VERIFIED {
+            verified.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+        if verified.len() < MAX_VERIFIED {
+            verified.insert(key, (user.to_string(), now + VERIFIED_TTL));
+        }
+    }
+
+    fn read_credentials(&self) -> Result<HashMap<String, PasswordHash>, String> {
+        let contents = fs::read_to_string(&self.path)
+            .map_err(|e| format!("Failed to read credentials file {}: {}", self.path, e))?;
+        let mut credentials = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = line
+                .split_once(':')
+                .and_then(|(user, hash)| Some((user, PasswordHash::parse(hash)?)));
+            match parsed {
+                Some((user, hash)) if !user.is_empty() => {
+                    credentials.insert(user.to_string(), hash);
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid entry on line {} of credentials file {}",
+                        number + 1,
+                        self.path
+                    ));
+                }
+            }
+        }
+        Ok(credentials)
+    }
+}
+
+impl Authenticator for BasicAuthenticator {
+    fn authenticate(&self, headers: &Headers) -> AuthOutcome {
+        let Some((scheme, encoded)) = headers
+            .get("Authorization")
+            .and_then(|value| value.split_once(' '))
+        else {
+            return AuthOutcome::Missing;
+        };
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return AuthOutcome::Missing;
+        }
+
+        let encoded = encoded.trim();
+        let key = hmac_sha256(&self.verified_key, encoded.as_bytes());
+        let remembered = self.verified.lock().unwrap().get(&key).cloned();
+        if let Some((user, expires_at)) = remembered {
+            if expires_at > Instant::now() {
+                return AuthOutcome::Authenticated(Principal {
+                    user,
+                    scheme: "basic",
+                });
+            }
+        }
+
+        let decoded = base64_decode(encoded).and_then(|bytes| String::from_utf8(bytes).ok());
+        let Some((user, password)) = decoded.as_deref().and_then(|d| d.split_once(':')) else {
+            return AuthOutcome::Invalid("Malformed Basic credentials.");
+        };
+
+        let credentials = self.credentials.read().unwrap();
+        let verified = match credentials.get(user) {
+            Some(hash) => hash.verify(password),
+            None => {
+                self.dummy.verify(password);
+                false
+            }
+        };
+        if !verified {
+            return AuthOutcome::Invalid("Invalid username or password.");
+        }
+        self.remember(key, user);
+        AuthOutcome::Authenticated(Principal {
+            user: user.to_string(),
+            scheme: "basic",
+        })
+    }
+
+    fn challenge(&self) -> String {
+        "Basic realm=\"todos\", charset=\"UTF-8\"".to_string()
+    }
+
+    fn reload(&self) -> Result<(), String> {
+        let credentials = self.read_credentials()?;
+        let mut current = self.credentials.write().unwrap();
+        *current = credentials;
+        // Passwords may have changed
+        self.verified.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+// Issues an HS256 token for `claims`, e.g. for tooling and tests
+pub fn encode_hs256_token(claims: &Value, secret: &[u8]) -> String {
+    let header = base64url_encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+    let payload = base64url_encode(claims.to_string().as_bytes());
+    let signing_input = format!("{}.{}", header, payload);
+    let signature = base64url_encode(&hmac_sha256(secret, signing_input.as_bytes()));
+    format!("{}.{}", signing_input, signature)
+}
@@ -0,0 +1,158 @@
+// GET /todos/changes?since=<seq>&wait=<secs>: long polling for clients that
+// can't keep a stream open. The request is held until one of the caller's
+// todos changes after `since` or `wait` runs out, and is answered with those
+// changes and the sequence number to ask from next time.
+use crate::auth::Principal;
+use crate::feed::{self, ChangeFeed, Missed, TodoEvent};
+use crate::http::Headers;
+use crate::policy::Operation;
+use crate::{AppState, Response, Takeover, compression, forbidden, format, log_error, query_param};
+use serde::Serialize;
+use std::sync::{Arc, mpsc};
+use std::time::{Duration, Instant};
+
+pub const PATH: &str = "/todos/changes";
+
+// In seconds
+const DEFAULT_WAIT: u64 = 30;
+const MAX_WAIT: u64 = 60;
+
+#[derive(Serialize)]
+struct Changes {
+    changes: Vec<TodoEvent>,
+    // The `since` for the next request
+    seq: u64,
+}
+
+// A request waiting for changes, on a thread of its own
+pub struct Poll {
+    feed: Arc<ChangeFeed>,
+    user: String,
+    // The last change the client has been told about
+    after: u64,
+    deadline: Instant,
+    headers: Headers,
+}
+
+pub fn open(query: &str, headers: &Headers, principal: &Principal, state: &AppState) -> Response {
+    if !state.permits(principal, Operation::Read) {
+        return forbidden(principal, Operation::Read, state).into();
+    }
+    let number = |name: &str, expected: &str| -> Result<Option<u64>, String> {
+        query_param(query, name)
+            .map(|value| {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| format!("Invalid {}: expected {}.", name, expected))
+            })
+            .transpose()
+    };
+    let (since, wait) = match (
+        number("since", "a sequence number"),
+        number("wait", "a number of seconds"),
+    ) {
+        (Ok(since), Ok(wait)) => (since, wait.unwrap_or(DEFAULT_WAIT).min(MAX_WAIT)),
+        (Err(e), _) | (_, Err(e)) => {
+            log_error(&e);
+            return ("400 Bad Request", e).into();
+        }
+    };
+
+    let db = state.db.lock().unwrap();
+    let feed = Arc::clone(db.feed());
+    let resumed = feed::resume(&db, since);
+    drop(db);
+    let after = match resumed {
+        Ok(after) => after,
+        Err(missed) => return gone(&missed),
+    };
+    let mut poll = Poll {
+        feed,
+        user: principal.user.clone(),
+        after,
+        deadline: Instant::now(),
+        headers: headers.clone(),
+    };
+    // Answered right away when there is something to report or no time to
+    // wait; otherwise the worker is let go while the request waits
+    match poll.collect() {
+        Err(missed) => gone(&missed),
+        Ok(changes) if !changes.is_empty() || wait == 0 => poll.reply(changes),
+        Ok(_) => {
+            poll.deadline = Instant::now() + Duration::from_secs(wait);
+            Response {
+                status: "200 OK",
+                headers: Vec::new(),
+                content_type: format::JSON_CONTENT_TYPE,
+                body: Vec::new(),
+                takeover: Some(Takeover::Changes(Box::new(poll))),
+            }
+        }
+    }
+}
+
+impl Poll {
+    // Completes `response`, which already has the headers the request was
+    // given, once there are changes or the wait is over
+    pub fn wait(mut self, response: Response, state: &AppState) -> Response {
+        let reply = match self.collect() {
+            Ok(changes) => self.reply(changes),
+            Err(missed) => gone(&missed),
+        };
+        let response = Response {
+            status: reply.status,
+            body: reply.body,
+            ..response
+        };
+        let format = format::negotiate(&self.headers, &format::JSON).unwrap_or(&format::JSON);
+        let response = format::encode_response(response, format);
+        compression::apply(response, &self.headers, &state.config.compression)
+    }
+
+    // The caller's changes after `after`, waiting until the deadline for
+    // some. Only changes to the caller's todos wake the wait; the cursor still
+    // moves past everyone else's.
+    fn collect(&mut self) -> Result<Vec<TodoEvent>, Missed> {
+        let mut watching = None;
+        loop {
+            let (own, latest) = self.feed.owned_after(&self.user, self.after)?;
+            self.after = latest;
+            let timeout = self.deadline.saturating_duration_since(Instant::now());
+            if !own.is_empty() || timeout.is_zero() {
+                return Ok(own);
+            }
+            match &watching {
+                // Checked again once watching, so nothing published in
+                // between is missed
+                None => {
+                    let (wake, woken) = mpsc::channel();
+                    let watch = self.feed.watch(&self.user, move || {
+                        let _ = wake.send(());
+                    });
+                    watching = Some((watch, woken));
+                }
+                Some((_, woken)) => {
+                    let _ = woken.recv_timeout(timeout);
+                }
+            }
+        }
+    }
+
+    fn reply(&self, changes: Vec<TodoEvent>) -> Response {
+        let body = Changes {
+            changes,
+            seq: self.after,
+        };
+        ("200 OK", serde_json::to_string(&body).unwrap()).into()
+    }
+}
+
+// Some of the changes asked for are gone; the client has to reload its todos
+// and carry on from `seq`
+fn gone(missed: &Missed) -> Response {
+    let error =
+        "Changes since the given sequence number are no longer available; reload the todos.";
+    log_error(error);
+    let body = serde_json::json!({ "error": error, "seq": missed.latest });
+    ("410 Gone", body.to_string()).into()
+}
@@ -0,0 +1,193 @@
+use crate::Response;
+use crate::config::CompressionConfig;
+use crate::http::Headers;
+use flate2::Compression;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::borrow::Cow;
+use std::io::{Read, Write};
+
+// Content codings we produce and accept, most preferred first
+const SUPPORTED: &str = "br, gzip, deflate";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+    fn from_token(token: &str) -> Option<Encoding> {
+        match token.to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None,
+        }
+    }
+
+    fn token(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    Unsupported,
+    Malformed,
+    TooLarge,
+}
+
+impl DecodeError {
+    pub fn status(&self) -> &'static str {
+        match self {
+            DecodeError::Unsupported => "415 Unsupported Media Type",
+            DecodeError::Malformed => "400 Bad Request",
+            DecodeError::TooLarge => "413 Content Too Large",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            DecodeError::Unsupported => "Unsupported Content-Encoding.",
+            DecodeError::Malformed => "Request body could not be decompressed.",
+            DecodeError::TooLarge => "Decompressed request body is too large.",
+        }
+    }
+}
+
+// Compresses the body with the coding the client likes best. Bodies under the
+// threshold are left alone and don't depend on Accept-Encoding at all.
+pub fn apply(response: Response, headers: &Headers, config: &CompressionConfig) -> Response {
+    if !config.enabled || response.body.len() < config.min_bytes {
+        return response;
+    }
+    let already_encoded = response
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Content-Encoding"));
+    let response = response.with_header("Vary", "Accept-Encoding");
+    if already_encoded {
+        return response;
+    }
+    let Some(encoding) = negotiate(headers) else {
+        return response;
+    };
+
+    match compress(encoding, &response.body) {
+        // Not worth it if nothing was saved
+        Ok(body) if body.len() < response.body.len() => {
+            Response { body, ..response }.with_header("Content-Encoding", encoding.token())
+        }
+        _ => response,
+    }
+}
+
+// Undoes a request's Content-Encoding, refusing to inflate past `max_len`
+pub fn decode_body<'a>(
+    headers: &Headers,
+    body: &'a [u8],
+    max_len: usize,
+) -> Result<Cow<'a, [u8]>, DecodeError> {
+    // Codings are listed in the order they were applied
+    let mut codings = Vec::new();
+    for token in headers
+        .get_all("Content-Encoding")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|token| !token.is_empty() && !token.eq_ignore_ascii_case("identity"))
+    {
+        codings.push(Encoding::from_token(token).ok_or(DecodeError::Unsupported)?);
+    }
+
+    let mut body = Cow::Borrowed(body);
+    for encoding in codings.into_iter().rev() {
+        body = Cow::Owned(decompress(encoding, &body, max_len)?);
+    }
+    Ok(body)
+}
+
+// The `Accept-Encoding` header to send along with a 415
+pub fn accept_encoding() -> &'static str {
+    SUPPORTED
+}
+
+// Picks the coding with the highest q-value; ties go to the order of
+// SUPPORTED. None means identity, either by choice or because nothing else
+// is acceptable.
+fn negotiate(headers: &Headers) -> Option<Encoding> {
+    let mut wildcard = None;
+    let mut identity = None;
+    let mut weights = [None; 3];
+    for (token, q) in headers.weighted("Accept-Encoding") {
+        if token == "*" {
+            wildcard = Some(q);
+        } else if token.eq_ignore_ascii_case("identity") {
+            identity = Some(q);
+        } else if let Some(encoding) = Encoding::from_token(token) {
+            let index = Encoding::ALL.iter().position(|e| *e == encoding).unwrap();
+            weights[index] = Some(q);
+        }
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for (encoding, weight) in Encoding::ALL.into_iter().zip(weights) {
+        let q = weight.or(wildcard).unwrap_or(0.0);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((encoding, q));
+        }
+    }
+    let (encoding, q) = best?;
+    // Identity is acceptable unless excluded, and wins when preferred
+    if identity.is_some_and(|identity_q| identity_q > q) {
+        return None;
+    }
+    Some(encoding)
+}
+
+fn compress(encoding: Encoding, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Brotli => {
+            // Quality 5 keeps most of the gain at a fraction of the CPU of 11
+            let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+            encoder.write_all(data)?;
+            Ok(encoder.into_inner())
+        }
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        // HTTP's "deflate" is the zlib format, not a raw deflate stream
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+    }
+}
+
+fn decompress(encoding: Encoding, data: &[u8], max_len: usize) -> Result<Vec<u8>, DecodeError> {
+    let decoder: Box<dyn Read + '_> = match encoding {
+        Encoding::Brotli => Box::new(brotli::Decompressor::new(data, 4096)),
+        Encoding::Gzip => Box::new(GzDecoder::new(data)),
+        Encoding::Deflate => Box::new(ZlibDecoder::new(data)),
+    };
+    // Read one byte past the limit to tell "exactly max" from "too much"
+    let mut output = Vec::new();
+    decoder
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut output)
+        .map_err(|_| DecodeError::Malformed)?;
+    if output.len() > max_len {
+        return Err(DecodeError::TooLarge);
+    }
+    Ok(output)
+}
@@ -0,0 +1,370 @@
+use crate::http::Limits;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Server settings. Every field has a default so a config file only needs to
+// list the values it wants to change.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub address: String,
+    pub threads: usize,
+    // How connections wait for their requests; the event loop needs the
+    // `event-loop` feature
+    pub server_mode: ServerMode,
+    // Connections the event loop holds while their requests arrive; more are
+    // turned away
+    pub max_connections: usize,
+    pub idempotency_ttl_secs: u64,
+    // Keys remembered at once; the oldest make way for new ones
+    pub idempotency_max_keys: usize,
+    pub trash_retention_secs: u64,
+    pub trash_purge_interval_secs: u64,
+    // Directory holding the journal; without one todos live only in memory
+    pub data_dir: Option<String>,
+    // How often the store in data_dir is snapshotted; 0 turns snapshots off
+    pub snapshot_interval_secs: u64,
+    // Snapshots kept; restores can go back as far as the oldest one
+    pub snapshot_retention: usize,
+    // Keep todos in SQLite instead; needs the `sqlite` feature and excludes
+    // data_dir
+    pub sqlite: Option<SqliteConfig>,
+    // Longest wait for any single read from a client
+    pub read_timeout_secs: u64,
+    // Longest wait for any single write to a client
+    pub write_timeout_secs: u64,
+    // Time a client gets to send the complete request line and headers
+    pub header_timeout_secs: u64,
+    // Time a client gets to send the whole request, body included
+    pub request_deadline_secs: u64,
+    // Longest an HTTP/2 connection stays open, however busy it is
+    pub http2_max_connection_secs: u64,
+    // HTTP/2 connections served at once. Each holds a worker for as long as
+    // it is open; None leaves half the workers for other requests.
+    pub http2_max_connections: Option<usize>,
+    // Connections served at once for one client address; None means no limit
+    pub max_connections_per_ip: Option<usize>,
+    // Connections served on threads of their own at once: event streams,
+    // WebSockets and waiting long polls. More are answered with 503.
+    pub max_takeovers: usize,
+    // Sizes of request heads and bodies, the same for every server mode
+    pub limits: Limits,
+    // Known user ids. Empty means any X-User-Id is accepted.
+    pub users: Vec<String>,
+    pub auth: AuthConfig,
+    // JSON file assigning roles to users; without one everyone may do anything
+    pub policy_file: Option<String>,
+    pub rate_limit: RateLimitConfig,
+    // Serve HTTPS instead of plain HTTP; needs the `tls` feature
+    pub tls: Option<TlsConfig>,
+    // Lets browser front-ends on other origins call the API
+    pub cors: Option<CorsConfig>,
+    pub compression: CompressionConfig,
+    pub webhooks: WebhookConfig,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerMode {
+    // A worker from the pool reads, handles and answers each connection
+    Threads,
+    // One thread waits on every connection at once and passes complete
+    // requests to the pool
+    EventLoop,
+}
+
+// Authentication is off unless at least one scheme is configured here
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    // API key -> user id
+    pub api_keys: HashMap<String, String>,
+    pub jwt_secret: Option<String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    // Clock skew tolerated when checking `exp` and `nbf`
+    pub jwt_leeway_secs: u64,
+    // htpasswd-style `user:hash` file for HTTP Basic authentication
+    pub htpasswd_file: Option<String>,
+}
+
+impl AuthConfig {
+    // Whether any authentication scheme is configured
+    pub fn is_enabled(&self) -> bool {
+        !self.api_keys.is_empty() || self.jwt_secret.is_some() || self.htpasswd_file.is_some()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            address: "127.0.0.1:8080".to_string(),
+            threads: 4,
+            server_mode: ServerMode::Threads,
+            max_connections: 10_000,
+            idempotency_ttl_secs: 24 * 60 * 60,
+            idempotency_max_keys: 100_000,
+            trash_retention_secs: 30 * 24 * 60 * 60,
+            trash_purge_interval_secs: 60 * 60,
+            data_dir: None,
+            snapshot_interval_secs: 60 * 60,
+            snapshot_retention: 24,
+            sqlite: None,
+            read_timeout_secs: 10,
+            write_timeout_secs: 10,
+            header_timeout_secs: 10,
+            request_deadline_secs: 30,
+            http2_max_connection_secs: 10 * 60,
+            http2_max_connections: None,
+            max_connections_per_ip: None,
+            max_takeovers: 1000,
+            limits: Limits::default(),
+            users: Vec::new(),
+            auth: AuthConfig::default(),
+            policy_file: None,
+            rate_limit: RateLimitConfig::default(),
+            tls: None,
+            cors: None,
+            compression: CompressionConfig::default(),
+            webhooks: WebhookConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TlsConfig {
+    // PEM certificate chain, leaf first
+    pub cert_file: String,
+    // PEM private key for the leaf certificate
+    pub key_file: String,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct CorsConfig {
+    // Exact origins such as "https://app.example.com", or "*" for any
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    // Response headers scripts may read besides the CORS-safelisted ones
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    // How long browsers may cache a preflight
+    pub max_age_secs: Option<u64>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PUT", "DELETE"].map(String::from).to_vec(),
+            allowed_headers: [
+                "Content-Type",
+                "Authorization",
+                "X-API-Key",
+                "X-User-Id",
+                "Idempotency-Key",
+            ]
+            .map(String::from)
+            .to_vec(),
+            exposed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age_secs: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SqliteConfig {
+    // Database file, created on first use
+    pub path: String,
+}
+
+// Responses are compressed for clients that send Accept-Encoding
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    // Smaller bodies are sent as they are; compressing them rarely pays off
+    pub min_bytes: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> CompressionConfig {
+        CompressionConfig {
+            enabled: true,
+            min_bytes: 1024,
+        }
+    }
+}
+
+// How change notifications are delivered to the URLs users register
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct WebhookConfig {
+    // JSON file holding the webhooks, queued deliveries and delivery log.
+    // Defaults to webhooks.json in data_dir; without either they live only in
+    // memory.
+    pub state_file: Option<String>,
+    // Attempts per delivery before it is given up
+    pub max_attempts: u32,
+    // Wait before the first retry, doubled after every further failure
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    // Longest wait for a receiver to accept the connection, and for each read
+    // and write after that
+    pub timeout_secs: u64,
+    // Webhooks sent to at the same time
+    pub max_parallel: usize,
+    // Hosts webhooks may point at even though they aren't public, such as a
+    // receiver on the internal network. Matched against the URL's host as
+    // written.
+    pub allowed_hosts: Vec<String>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> WebhookConfig {
+        WebhookConfig {
+            state_file: None,
+            max_attempts: 8,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 60 * 60 * 1000,
+            timeout_secs: 10,
+            max_parallel: 8,
+            allowed_hosts: Vec::new(),
+        }
+    }
+}
+
+impl WebhookConfig {
+    // Wait before the attempt after `failures` failed ones
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let factor = 2u64.saturating_pow(failures.saturating_sub(1));
+        let millis = self.initial_backoff_ms.saturating_mul(factor);
+        Duration::from_millis(millis.min(self.max_backoff_ms))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+// Requests are only limited by routes listed here, or by `default` if set
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub default: Option<RateLimitRule>,
+    pub routes: Vec<RateLimitRule>,
+    // Buckets kept at once; past it the least recently used are dropped
+    pub max_clients: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        RateLimitConfig {
+            default: None,
+            routes: Vec::new(),
+            max_clients: 100_000,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RateLimitRule {
+    // Matches any method when absent
+    pub method: Option<String>,
+    // Exact path, or a prefix when it ends in `*`; matches any path when absent
+    pub path: Option<String>,
+    // Burst size: how many requests a client may make in a row
+    pub capacity: u32,
+    // Sustained rate: tokens added back per second
+    pub refill_per_sec: f64,
+}
+
+impl RateLimitRule {
+    pub fn matches(&self, method: &str, path: &str) -> bool {
+        let method_matches = self
+            .method
+            .as_deref()
+            .is_none_or(|m| m.eq_ignore_ascii_case(method));
+        let path_matches = match self.path.as_deref() {
+            None => true,
+            Some(pattern) => match pattern.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == pattern,
+            },
+        };
+        method_matches && path_matches
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path, e))?;
+        let config: Config = serde_json::from_str(&contents)
+            .map_err(|e| format!("Failed to parse config file {}: {}", path, e))?;
+        // That would let any website make credentialed requests
+        if let Some(cors) = &config.cors {
+            if cors.allow_credentials && cors.allowed_origins.iter().any(|origin| origin == "*") {
+                return Err(format!(
+                    "Invalid config file {}: cors.allowed_origins can't contain \"*\" with allow_credentials.",
+                    path
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn idempotency_ttl(&self) -> Duration {
+        Duration::from_secs(self.idempotency_ttl_secs)
+    }
+
+    pub fn trash_retention(&self) -> Duration {
+        Duration::from_secs(self.trash_retention_secs)
+    }
+
+    pub fn trash_purge_interval(&self) -> Duration {
+        Duration::from_secs(self.trash_purge_interval_secs)
+    }
+
+    pub fn snapshot_interval(&self) -> Duration {
+        Duration::from_secs(self.snapshot_interval_secs)
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout_secs)
+    }
+
+    pub fn write_timeout(&self) -> Duration {
+        Duration::from_secs(self.write_timeout_secs)
+    }
+
+    pub fn header_timeout(&self) -> Duration {
+        Duration::from_secs(self.header_timeout_secs)
+    }
+
+    pub fn request_deadline(&self) -> Duration {
+        Duration::from_secs(self.request_deadline_secs)
+    }
+
+    pub fn http2_max_connection(&self) -> Duration {
+        Duration::from_secs(self.http2_max_connection_secs)
+    }
+
+    pub fn http2_max_connections(&self) -> usize {
+        self.http2_max_connections
+            .unwrap_or((self.threads / 2).max(1))
+    }
+
+    pub fn webhook_state_file(&self) -> Option<PathBuf> {
+        match (&self.webhooks.state_file, &self.data_dir) {
+            (Some(path), _) => Some(PathBuf::from(path)),
+            (None, Some(data_dir)) => Some(Path::new(data_dir).join("webhooks.json")),
+            (None, None) => None,
+        }
+    }
+}
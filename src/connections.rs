@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Counts connections being served per client address, so one client can't
+// occupy every worker by holding connections open
+pub struct ConnectionTracker {
+    max_per_ip: Option<usize>,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+// Holds one of a client's connection slots until dropped. It doesn't borrow
+// the tracker, so it can move along with a connection handed to its own
+// thread.
+pub struct Slot {
+    active: Option<Arc<Mutex<HashMap<IpAddr, usize>>>>,
+    ip: Option<IpAddr>,
+}
+
+impl ConnectionTracker {
+    pub fn new(max_per_ip: Option<usize>) -> ConnectionTracker {
+        ConnectionTracker {
+            max_per_ip,
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // None when the client already has as many connections as it may. Streams
+    // without an address are never limited.
+    pub fn admit(&self, ip: Option<IpAddr>) -> Option<Slot> {
+        let (Some(max), Some(addr)) = (self.max_per_ip, ip) else {
+            return Some(Slot {
+                active: None,
+                ip: None,
+            });
+        };
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(addr).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(Slot {
+            active: Some(Arc::clone(&self.active)),
+            ip,
+        })
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let (Some(active), Some(ip)) = (&self.active, self.ip) else {
+            return;
+        };
+        let mut active = active.lock().unwrap();
+        if let Some(count) = active.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&ip);
+            }
+        }
+    }
+}
+
+// Counts connections of one kind that are held for a long time, such as those
+// taken over by a long-lived protocol, so there can't be more than `max`
+pub struct Capacity {
+    max: usize,
+    active: Arc<AtomicUsize>,
+}
+
+// Holds one place until dropped
+pub struct Place {
+    active: Arc<AtomicUsize>,
+}
+
+impl Capacity {
+    pub fn new(max: usize) -> Capacity {
+        Capacity {
+            max,
+            active: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // None when every place is taken
+    pub fn admit(&self) -> Option<Place> {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < self.max).then_some(active + 1)
+            })
+            .ok()?;
+        Some(Place {
+            active: Arc::clone(&self.active),
+        })
+    }
+}
+
+impl Drop for Place {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
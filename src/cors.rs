@@ -0,0 +1,116 @@
+use crate::Response;
+use crate::config::CorsConfig;
+use crate::http::Headers;
+
+// Methods the router knows about, advertised to plain OPTIONS requests
+const ALLOWED: &str = "GET, POST, PUT, DELETE, OPTIONS";
+
+// Answers an OPTIONS request. A CORS preflight from an allowed origin asking
+// for allowed methods and headers gets the grant; any other OPTIONS request
+// just learns which methods exist.
+pub fn preflight(headers: &Headers, config: Option<&CorsConfig>) -> Response {
+    let response = Response::from(("204 No Content", String::new())).with_header("Allow", ALLOWED);
+    let (Some(config), Some(origin), Some(method)) = (
+        config,
+        headers.get("Origin"),
+        headers.get("Access-Control-Request-Method"),
+    ) else {
+        return response;
+    };
+
+    // The list may be split over several fields
+    let headers_allowed = headers
+        .get_all("Access-Control-Request-Headers")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .all(|name| contains(&config.allowed_headers, name));
+    if !origin_allowed(config, origin)
+        || !contains(&config.allowed_methods, method)
+        || !headers_allowed
+    {
+        // Without the grant headers the browser blocks the actual request
+        return response.with_header("Vary", "Origin");
+    }
+
+    let mut response = allow_origin(response, config, origin)
+        .with_header(
+            "Access-Control-Allow-Methods",
+            &config.allowed_methods.join(", "),
+        )
+        .with_header(
+            "Access-Control-Allow-Headers",
+            &config.allowed_headers.join(", "),
+        );
+    if let Some(max_age) = config.max_age_secs {
+        response = response.with_header("Access-Control-Max-Age", &max_age.to_string());
+    }
+    response
+}
+
+// Adds CORS headers to the response of an actual cross-origin request
+pub fn apply(response: Response, headers: &Headers, config: Option<&CorsConfig>) -> Response {
+    let (Some(config), Some(origin)) = (config, headers.get("Origin")) else {
+        return response;
+    };
+    if !origin_allowed(config, origin) {
+        return response.with_header("Vary", "Origin");
+    }
+    let response = allow_origin(response, config, origin);
+    if config.exposed_headers.is_empty() {
+        return response;
+    }
+    response.with_header(
+        "Access-Control-Expose-Headers",
+        &config.exposed_headers.join(", "),
+    )
+}
+
+// Whether a page from the request's origin may open a WebSocket. Browsers
+// send cookies and cached credentials with the upgrade whatever page asked
+// for it, and the response doesn't stop the page from using the socket. So
+// only the server's own origin and origins listed by name pass; a wildcard
+// doesn't. Clients that send no Origin aren't browsers.
+pub fn websocket_allowed(headers: &Headers, config: Option<&CorsConfig>) -> bool {
+    let Some(origin) = headers.get("Origin") else {
+        return true;
+    };
+    let same_origin = match (origin.split_once("://"), headers.get("Host")) {
+        (Some((_, authority)), Some(host)) => authority.eq_ignore_ascii_case(host),
+        _ => false,
+    };
+    same_origin
+        || config.is_some_and(|config| {
+            config
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed == origin)
+        })
+}
+
+fn origin_allowed(config: &CorsConfig, origin: &str) -> bool {
+    config
+        .allowed_origins
+        .iter()
+        .any(|allowed| allowed == "*" || allowed == origin)
+}
+
+// A wildcard is never combined with credentials: `Config::load` refuses
+// that, and one built in code still gets no credentials header with it
+fn allow_origin(response: Response, config: &CorsConfig, origin: &str) -> Response {
+    if config.allowed_origins.iter().any(|allowed| allowed == "*") {
+        return response.with_header("Access-Control-Allow-Origin", "*");
+    }
+    let response = response
+        .with_header("Access-Control-Allow-Origin", origin)
+        .with_header("Vary", "Origin");
+    if config.allow_credentials {
+        response.with_header("Access-Control-Allow-Credentials", "true")
+    } else {
+        response
+    }
+}
+
+fn contains(list: &[String], value: &str) -> bool {
+    list.iter().any(|item| item.eq_ignore_ascii_case(value))
+}
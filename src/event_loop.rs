@@ -0,0 +1,257 @@
+// The event loop server mode. One thread waits for readiness on every
+// connection at once (epoll on Linux, through mio) and buffers each request
+// as it arrives. Only once it is complete does the connection go to the pool,
+// so idle and slow clients don't hold on to a worker. Connections that stay
+// open past their request leave the loop for good: HTTP/2 keeps its worker,
+// capped by `http2_max_connections`, and takeovers move to threads of their
+// own, capped by `max_takeovers`.
+use crate::http::{self, Progress};
+use crate::{
+    AppState, Connection, ThreadPool, handle_connection_with_state, http2, log_error,
+    reject_timed_out,
+};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const LISTENER: Token = Token(0);
+// How often connections are checked against their deadlines
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+// A connection whose request is still arriving
+struct Pending {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    // How much of the request has been checked
+    progress: Progress,
+    accepted_at: Instant,
+}
+
+// Serves connections from `listener` until polling fails
+pub fn serve(
+    listener: std::net::TcpListener,
+    pool: &ThreadPool,
+    state: &Arc<AppState>,
+) -> std::io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener);
+    let mut poll = Poll::new()?;
+    poll.registry()
+        .register(&mut listener, LISTENER, Interest::READABLE)?;
+
+    let mut events = Events::with_capacity(1024);
+    let mut pending: HashMap<Token, Pending> = HashMap::new();
+    let mut next_token = 1;
+    let mut last_sweep = Instant::now();
+    loop {
+        match poll.poll(&mut events, Some(SWEEP_INTERVAL)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+
+        for event in &events {
+            if event.token() == LISTENER {
+                accept(&listener, &poll, &mut pending, &mut next_token, state);
+                continue;
+            }
+            let Some(connection) = pending.get_mut(&event.token()) else {
+                continue;
+            };
+            match fill(connection) {
+                Ok(false) if !is_complete(connection, state) => {}
+                Ok(_) => {
+                    let connection = pending.remove(&event.token()).unwrap();
+                    hand_over(connection, &poll, pool, state, false);
+                }
+                Err(e) => {
+                    log_error(&format!("Failed to read from stream. Details: {}", e));
+                    let mut connection = pending.remove(&event.token()).unwrap();
+                    let _ = poll.registry().deregister(&mut connection.stream);
+                }
+            }
+        }
+
+        if last_sweep.elapsed() >= SWEEP_INTERVAL {
+            last_sweep = Instant::now();
+            let expired: Vec<Token> = pending
+                .iter()
+                .filter(|(_, connection)| is_expired(connection, state))
+                .map(|(token, _)| *token)
+                .collect();
+            for token in expired {
+                let connection = pending.remove(&token).unwrap();
+                hand_over(connection, &poll, pool, state, true);
+            }
+        }
+    }
+}
+
+fn accept(
+    listener: &TcpListener,
+    poll: &Poll,
+    pending: &mut HashMap<Token, Pending>,
+    next_token: &mut usize,
+    state: &AppState,
+) {
+    loop {
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                eprintln!("Failed to connect: {}", e);
+                return;
+            }
+        };
+        if pending.len() >= state.config.max_connections {
+            log_error("Too many connections.");
+            continue;
+        }
+        let token = Token(*next_token);
+        *next_token += 1;
+        if let Err(e) = poll
+            .registry()
+            .register(&mut stream, token, Interest::READABLE)
+        {
+            log_error(&format!("Failed to watch connection: {}", e));
+            continue;
+        }
+        pending.insert(
+            token,
+            Pending {
+                stream,
+                buffer: Vec::new(),
+                progress: Progress::default(),
+                accepted_at: Instant::now(),
+            },
+        );
+    }
+}
+
+// Reads everything that has arrived. True if the client has stopped sending,
+// so there is nothing more to wait for.
+fn fill(connection: &mut Pending) -> std::io::Result<bool> {
+    let mut buffer = [0; 8192];
+    loop {
+        match connection.stream.read(&mut buffer) {
+            Ok(0) => return Ok(true),
+            Ok(bytes_read) => connection.buffer.extend_from_slice(&buffer[..bytes_read]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Whether a worker can take over without waiting on the client: the request
+// is complete or already known to be bad, by the limits the worker enforces,
+// or an HTTP/2 connection has begun
+fn is_complete(connection: &mut Pending, state: &AppState) -> bool {
+    let buffer = &connection.buffer;
+    if buffer.starts_with(http2::PREFACE) {
+        return true;
+    }
+    if buffer.is_empty() || http2::PREFACE.starts_with(buffer) {
+        return false;
+    }
+    connection.progress.advance(buffer, &state.config.limits)
+}
+
+// The same deadlines a worker applies while reading a request
+fn is_expired(connection: &Pending, state: &AppState) -> bool {
+    let config = &state.config;
+    let limit = match http::head_end(&connection.buffer) {
+        None => config.header_timeout().min(config.request_deadline()),
+        Some(_) => config.request_deadline(),
+    };
+    connection.accepted_at.elapsed() >= limit
+}
+
+fn hand_over(
+    mut connection: Pending,
+    poll: &Poll,
+    pool: &ThreadPool,
+    state: &Arc<AppState>,
+    timed_out: bool,
+) {
+    let _ = poll.registry().deregister(&mut connection.stream);
+    let stream = std::net::TcpStream::from(connection.stream);
+    if let Err(e) = stream.set_nonblocking(false) {
+        log_error(&format!("Failed to hand over connection: {}", e));
+        return;
+    }
+    let mut stream = Buffered {
+        stream,
+        buffer: connection.buffer,
+        position: 0,
+    };
+    let state = Arc::clone(state);
+    pool.execute(move || {
+        if !timed_out {
+            handle_connection_with_state(stream, &state);
+            return;
+        }
+        let write_timeout = state.config.write_timeout().max(Duration::from_millis(1));
+        if let Err(e) = stream.set_write_timeout(Some(write_timeout)) {
+            log_error(&format!("Failed to set write timeout: {}", e));
+        }
+        reject_timed_out(&mut stream);
+    });
+}
+
+// A socket that first gives back what the event loop already read from it
+struct Buffered {
+    stream: std::net::TcpStream,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl Read for Buffered {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.buffer.len() {
+            return self.stream.read(out);
+        }
+        let rest = &self.buffer[self.position..];
+        let length = rest.len().min(out.len());
+        out[..length].copy_from_slice(&rest[..length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+impl Write for Buffered {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(data)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Connection for Buffered {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.stream.peer_ip()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        Connection::set_read_timeout(&self.stream, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        Connection::set_write_timeout(&self.stream, timeout)
+    }
+
+    fn socket(&self) -> Option<&std::net::TcpStream> {
+        Some(&self.stream)
+    }
+
+    fn has_buffered_input(&mut self) -> bool {
+        self.position < self.buffer.len()
+    }
+}
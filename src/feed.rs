@@ -0,0 +1,169 @@
+// Changes to todos as they are committed, for clients that follow them live.
+// The most recent ones are kept so a client that reconnects can pick up where
+// it left off instead of reloading everything.
+use crate::Todo;
+use crate::audit::AuditEvent;
+use crate::store::Store;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// How many events are kept for replay
+pub const REPLAY_CAPACITY: usize = 1000;
+
+// An audit event together with the todo as it was left by the change
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TodoEvent {
+    #[serde(flatten)]
+    pub change: AuditEvent,
+    pub todo: Todo,
+}
+
+impl TodoEvent {
+    pub fn seq(&self) -> u64 {
+        self.change.seq
+    }
+}
+
+// Some events after the requested one are no longer kept. `latest` is the
+// newest event that is, to resume from once the client has caught up.
+pub struct Missed {
+    pub latest: u64,
+}
+
+#[derive(Default)]
+pub struct ChangeFeed {
+    recent: Mutex<VecDeque<TodoEvent>>,
+    published: Condvar,
+    watchers: Mutex<HashMap<u64, Watcher>>,
+    next_watcher: AtomicU64,
+}
+
+// Called whenever changes to one user's todos are published
+struct Watcher {
+    owner: String,
+    wake: Box<dyn Fn() + Send>,
+}
+
+// Keeps a watcher registered until dropped
+pub struct Watch {
+    feed: Arc<ChangeFeed>,
+    id: u64,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.feed.watchers.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl ChangeFeed {
+    pub fn publish(&self, events: Vec<TodoEvent>) {
+        if events.is_empty() {
+            return;
+        }
+        let count = events.len().min(REPLAY_CAPACITY);
+        let mut recent = self.recent.lock().unwrap();
+        recent.extend(events);
+        while recent.len() > REPLAY_CAPACITY {
+            recent.pop_front();
+        }
+        self.published.notify_all();
+        let published = recent.range(recent.len() - count..);
+        let owners: Vec<&str> = published.map(|event| event.change.owner.as_str()).collect();
+        for watcher in self.watchers.lock().unwrap().values() {
+            if owners.contains(&watcher.owner.as_str()) {
+                (watcher.wake)();
+            }
+        }
+    }
+
+    // Calls `wake` each time changes to `owner`'s todos are published, for as
+    // long as the returned Watch is kept. It shouldn't block: it is called
+    // while publishing.
+    pub fn watch(self: &Arc<Self>, owner: &str, wake: impl Fn() + Send + 'static) -> Watch {
+        let id = self.next_watcher.fetch_add(1, Ordering::Relaxed);
+        let watcher = Watcher {
+            owner: owner.to_string(),
+            wake: Box::new(wake),
+        };
+        self.watchers.lock().unwrap().insert(id, watcher);
+        Watch {
+            feed: Arc::clone(self),
+            id,
+        }
+    }
+
+    // Whether every event after `seq`, up to and including `latest`, can
+    // still be replayed
+    pub fn covers(&self, seq: u64, latest: u64) -> bool {
+        seq >= latest
+            || self
+                .recent
+                .lock()
+                .unwrap()
+                .front()
+                .is_some_and(|first| first.seq() <= seq + 1)
+    }
+
+    // The events after `seq` on `owner`'s todos, and the newest event on
+    // anyone's, which is where to carry on from. Only the owner's are copied.
+    pub fn owned_after(&self, owner: &str, seq: u64) -> Result<(Vec<TodoEvent>, u64), Missed> {
+        let recent = self.recent.lock().unwrap();
+        if let Some(first) = recent.front().filter(|first| first.seq() > seq + 1) {
+            let latest = recent.back().map_or(first.seq(), TodoEvent::seq);
+            return Err(Missed { latest });
+        }
+        let start = recent.partition_point(|event| event.seq() <= seq);
+        let events = recent
+            .range(start..)
+            .filter(|event| event.change.owner == owner)
+            .cloned()
+            .collect();
+        let latest = recent.back().map_or(seq, |last| last.seq().max(seq));
+        Ok((events, latest))
+    }
+
+    // The events after `seq`, waiting up to `timeout` for the first one to be
+    // published. Empty if none was.
+    pub fn wait_after(&self, seq: u64, timeout: Duration) -> Result<Vec<TodoEvent>, Missed> {
+        let deadline = Instant::now() + timeout;
+        let mut recent = self.recent.lock().unwrap();
+        loop {
+            if let Some(first) = recent.front().filter(|first| first.seq() > seq + 1) {
+                let latest = recent.back().map_or(first.seq(), TodoEvent::seq);
+                return Err(Missed { latest });
+            }
+            let events: Vec<TodoEvent> = recent
+                .iter()
+                .filter(|event| event.seq() > seq)
+                .cloned()
+                .collect();
+            let now = Instant::now();
+            if !events.is_empty() || now >= deadline {
+                return Ok(events);
+            }
+            recent = self
+                .published
+                .wait_timeout(recent, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+// Where a client that last saw event `last_seen` picks up: the event to follow
+// on from, or Missed if some of what came since is gone. Without a last event
+// it starts from now. The caller holds the store's lock, so nothing is
+// published in between.
+pub fn resume(store: &Store, last_seen: Option<u64>) -> Result<u64, Missed> {
+    let latest = store.change_seq();
+    // An id from before a restore may be ahead of the log
+    let after = last_seen.map_or(latest, |seq| seq.min(latest));
+    match store.feed().covers(after, latest) {
+        true => Ok(after),
+        false => Err(Missed { latest }),
+    }
+}
@@ -0,0 +1,391 @@
+// Representations the API can produce and consume. Handlers work in JSON;
+// other formats are converted at the edge through serde_json::Value, so a
+// new format only needs a Format value listed in FORMATS.
+use crate::http::Headers;
+use crate::{Response, Todo};
+use chrono::Utc;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+pub struct Format {
+    pub media_type: &'static str,
+    // Sent as Content-Type, parameters included
+    pub content_type: &'static str,
+    // Other media types clients use for the same format
+    aliases: &'static [&'static str],
+    // Used in error messages
+    name: &'static str,
+    encode: fn(&Value) -> Result<Vec<u8>, String>,
+    decode: fn(&[u8]) -> Result<Value, String>,
+}
+
+pub const JSON_CONTENT_TYPE: &str = "application/json; charset=UTF-8";
+
+pub static JSON: Format = Format {
+    media_type: "application/json",
+    content_type: JSON_CONTENT_TYPE,
+    aliases: &[],
+    name: "JSON",
+    encode: |value| serde_json::to_vec(value).map_err(|e| e.to_string()),
+    decode: |body| serde_json::from_slice(body).map_err(|e| e.to_string()),
+};
+
+// One JSON value per line; a list becomes one line per element
+pub static JSON_LINES: Format = Format {
+    media_type: "application/x-ndjson",
+    content_type: "application/x-ndjson",
+    aliases: &["application/jsonl", "application/x-jsonlines"],
+    name: "JSON lines",
+    encode: |value| Ok(json_lines_encode(value).into_bytes()),
+    decode: json_lines_decode,
+};
+
+pub static CSV: Format = Format {
+    media_type: "text/csv",
+    content_type: "text/csv; charset=UTF-8",
+    aliases: &[],
+    name: "CSV",
+    encode: |value| Ok(csv_encode(value).into_bytes()),
+    decode: csv_decode,
+};
+
+pub static YAML: Format = Format {
+    media_type: "application/yaml",
+    content_type: "application/yaml",
+    aliases: &["application/x-yaml", "text/yaml"],
+    name: "YAML",
+    encode: |value| {
+        serde_yaml::to_string(value)
+            .map(String::into_bytes)
+            .map_err(|e| e.to_string())
+    },
+    decode: |body| serde_yaml::from_slice(body).map_err(|e| e.to_string()),
+};
+
+pub static MESSAGE_PACK: Format = Format {
+    media_type: "application/msgpack",
+    content_type: "application/msgpack",
+    aliases: &["application/x-msgpack", "application/vnd.msgpack"],
+    name: "MessagePack",
+    encode: |value| rmp_serde::to_vec(value).map_err(|e| e.to_string()),
+    decode: |body| rmp_serde::from_slice(body).map_err(|e| e.to_string()),
+};
+
+// JSON comes first: it is the usual default and wins ties
+pub static FORMATS: [&Format; 5] = [&JSON, &CSV, &YAML, &MESSAGE_PACK, &JSON_LINES];
+
+impl Format {
+    fn is_json(&self) -> bool {
+        std::ptr::eq(self, &JSON)
+    }
+
+    fn matches(&self, media_type: &str) -> bool {
+        self.media_type.eq_ignore_ascii_case(media_type)
+            || self
+                .aliases
+                .iter()
+                .any(|alias| alias.eq_ignore_ascii_case(media_type))
+    }
+
+    // 2 for an exact match, 1 for `type/*`, 0 for `*/*`
+    fn specificity(&self, range: &str) -> Option<u8> {
+        if self.matches(range) {
+            return Some(2);
+        }
+        if range == "*/*" {
+            return Some(0);
+        }
+        let kind = range.strip_suffix("/*")?;
+        let (own_kind, _) = self.media_type.split_once('/')?;
+        own_kind.eq_ignore_ascii_case(kind).then_some(1)
+    }
+}
+
+// Picks the format for the response from the Accept header. A format's
+// q-value comes from the most specific range that matches it; ties go to
+// `preferred`, then to the order of FORMATS. None means nothing we can
+// produce is acceptable.
+pub fn negotiate(headers: &Headers, preferred: &'static Format) -> Option<&'static Format> {
+    let ranges: Vec<(&str, f32)> = headers.weighted("Accept").collect();
+    if ranges.is_empty() {
+        return Some(preferred);
+    }
+
+    let mut best: Option<(&'static Format, f32)> = None;
+    for format in std::iter::once(preferred).chain(FORMATS) {
+        let q = ranges
+            .iter()
+            .filter_map(|(range, q)| Some((format.specificity(range)?, *q)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0.0, |(_, q)| q);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((format, q));
+        }
+    }
+    best.map(|(format, _)| format)
+}
+
+pub fn not_acceptable() -> Response {
+    let supported: Vec<&str> = FORMATS.iter().map(|format| format.media_type).collect();
+    let error = format!(
+        "No acceptable media type. Supported types: {}.",
+        supported.join(", ")
+    );
+    Response::from(("406 Not Acceptable", error))
+}
+
+// Turns a request body into the JSON the handlers expect. Bodies without a
+// Content-Type are taken to be in the `preferred` format.
+pub fn decode_request<'a>(
+    headers: &Headers,
+    body: &'a [u8],
+    preferred: &'static Format,
+) -> Result<Cow<'a, [u8]>, Response> {
+    if body.is_empty() {
+        return Ok(Cow::Borrowed(body));
+    }
+    let format = match headers.get("Content-Type") {
+        None => preferred,
+        Some(content_type) => {
+            let media_type = content_type.split(';').next().unwrap_or("").trim();
+            let Some(format) = FORMATS
+                .into_iter()
+                .find(|format| format.matches(media_type))
+            else {
+                let error = format!("Unsupported Content-Type: {}.", media_type);
+                return Err(Response::from(("415 Unsupported Media Type", error)));
+            };
+            format
+        }
+    };
+    if format.is_json() {
+        return Ok(Cow::Borrowed(body));
+    }
+
+    (format.decode)(body)
+        .and_then(|value| serde_json::to_vec(&value).map_err(|e| e.to_string()))
+        .map(Cow::Owned)
+        .map_err(|e| {
+            let error = format!("Request body is not valid {}: {}", format.name, e);
+            Response::from(("400 Bad Request", error))
+        })
+}
+
+// Re-encodes a successful JSON response in the negotiated format. Error
+// messages and other plain-text bodies are left as they are.
+pub fn encode_response(response: Response, format: &Format) -> Response {
+    if !response.status.starts_with('2') || response.content_type != JSON_CONTENT_TYPE {
+        return response;
+    }
+    let response = response.with_header("Vary", "Accept");
+    // Already in the format asked for, so there's nothing to parse
+    if format.is_json() {
+        return response;
+    }
+    let Ok(value) = serde_json::from_slice::<Value>(&response.body) else {
+        return response;
+    };
+
+    match (format.encode)(&value) {
+        Ok(body) => Response {
+            body,
+            content_type: format.content_type,
+            ..response
+        },
+        Err(e) => Response::from((
+            "500 Internal Server Error",
+            format!("Failed to encode response as {}: {}", format.name, e),
+        )),
+    }
+}
+
+fn json_lines_encode(value: &Value) -> String {
+    let items: Vec<&Value> = match value {
+        Value::Array(items) => items.iter().collect(),
+        other => vec![other],
+    };
+    items
+        .into_iter()
+        .map(|item| format!("{}\n", item))
+        .collect()
+}
+
+// Always a list, even for a single line; blank lines are skipped
+fn json_lines_decode(body: &[u8]) -> Result<Value, String> {
+    let text = std::str::from_utf8(body).map_err(|_| "not UTF-8".to_string())?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line).map_err(|e| format!("line {}: {}", index + 1, e))
+        })
+        .collect::<Result<Vec<Value>, String>>()
+        .map(Value::Array)
+}
+
+// One row per array element, with a column for every key seen. A single
+// object becomes a single row; nested values are written as JSON.
+fn csv_encode(value: &Value) -> String {
+    let rows: Vec<&Value> = match value {
+        Value::Array(items) => items.iter().collect(),
+        other => vec![other],
+    };
+    let mut columns: Vec<&str> = Vec::new();
+    for row in &rows {
+        let keys: Vec<&str> = match row {
+            Value::Object(map) => map.keys().map(String::as_str).collect(),
+            _ => vec!["value"],
+        };
+        for key in keys {
+            if !columns.contains(&key) {
+                columns.push(key);
+            }
+        }
+    }
+    if columns.is_empty() {
+        return String::new();
+    }
+
+    let mut out = String::new();
+    let header: Vec<String> = columns.iter().map(|column| csv_field(column)).collect();
+    out.push_str(&header.join(","));
+    out.push_str("\r\n");
+    for row in rows {
+        let fields: Vec<String> = columns
+            .iter()
+            .map(|column| {
+                let cell = match row {
+                    Value::Object(map) => map.get(*column),
+                    scalar => (*column == "value").then_some(scalar),
+                };
+                match cell {
+                    None | Some(Value::Null) => String::new(),
+                    Some(Value::String(s)) => csv_field(s),
+                    Some(other) => csv_field(&other.to_string()),
+                }
+            })
+            .collect();
+        out.push_str(&fields.join(","));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+// The first record names the fields. One data row becomes an object, more
+// become an array. Empty cells are null. A column named like a todo field
+// takes that field's type, so ids stay numbers and titles stay strings;
+// in other columns `true`/`false` are booleans and the rest are strings.
+fn csv_decode(body: &[u8]) -> Result<Value, String> {
+    let text = std::str::from_utf8(body).map_err(|_| "not UTF-8".to_string())?;
+    let mut records = csv_records(text)?.into_iter();
+    let header = records.next().ok_or("missing header row")?;
+
+    let mut rows = Vec::new();
+    for (index, record) in records.enumerate() {
+        if record.len() != header.len() {
+            return Err(format!(
+                "row {} has {} fields, expected {}",
+                index + 1,
+                record.len(),
+                header.len()
+            ));
+        }
+        let row = header
+            .iter()
+            .zip(record)
+            .map(|(name, cell)| (name.clone(), csv_value(name, cell)))
+            .collect();
+        rows.push(Value::Object(row));
+    }
+    match rows.len() {
+        1 => Ok(rows.remove(0)),
+        _ => Ok(Value::Array(rows)),
+    }
+}
+
+fn csv_value(name: &str, cell: String) -> Value {
+    let typed = match (TODO_FIELDS.get(name), cell.as_str()) {
+        (_, "") => Some(Value::Null),
+        (Some(Value::Number(_)), number) => number.parse::<u64>().ok().map(Value::from),
+        (Some(Value::Bool(_)) | None, "true") => Some(Value::Bool(true)),
+        (Some(Value::Bool(_)) | None, "false") => Some(Value::Bool(false)),
+        _ => None,
+    };
+    // Anything that doesn't fit is left for the handler to reject
+    typed.unwrap_or(Value::String(cell))
+}
+
+// The fields of a todo, each with a value of its type
+static TODO_FIELDS: LazyLock<Map<String, Value>> = LazyLock::new(|| {
+    let todo = Todo {
+        id: 1,
+        owner: String::new(),
+        title: String::new(),
+        completed: false,
+        deleted_at: Some(Utc::now()),
+    };
+    match serde_json::to_value(todo) {
+        Ok(Value::Object(fields)) => fields,
+        _ => Map::new(),
+    }
+});
+
+// RFC 4180 records. Quoted fields may hold commas, line breaks and doubled
+// quotes; bare LF line endings and blank lines are tolerated.
+fn csv_records(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => {
+                    quoted = false;
+                    if !matches!(chars.peek(), None | Some(',' | '\r' | '\n')) {
+                        return Err("unexpected text after a closing quote".to_string());
+                    }
+                }
+                other => field.push(other),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            '"' => return Err("quote inside an unquoted field".to_string()),
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' | '\r' => {
+                record.push(std::mem::take(&mut field));
+                if record != [""] {
+                    records.push(std::mem::take(&mut record));
+                }
+                record.clear();
+            }
+            other => field.push(other),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field".to_string());
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
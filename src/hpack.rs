@@ -0,0 +1,399 @@
+// HPACK header compression for HTTP/2 (RFC 7541). The decoder understands the
+// full format, including Huffman-coded strings and the dynamic table. The
+// encoder keeps things simple and never indexes, so it needs no table state.
+
+use crate::http::Limits;
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+// Table size both sides start with (RFC 7541 4.2)
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    // Not valid HPACK; the table can't be trusted any more
+    Malformed,
+    // Valid, but the fields add up to more than the limits allow. The rest of
+    // the block was still decoded, so the table stays in step with the peer's.
+    TooLarge,
+}
+
+pub struct Decoder {
+    dynamic: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    // Upper bound the peer may raise max_size to, from our SETTINGS
+    limit: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            dynamic: VecDeque::new(),
+            size: 0,
+            max_size: DEFAULT_TABLE_SIZE,
+            limit: DEFAULT_TABLE_SIZE,
+        }
+    }
+
+    // Decodes one complete header block into (name, value) pairs. Fields are
+    // counted as they would be in an HTTP/1.1 head, and once they pass the
+    // limits no more are kept, so a block of short references to large table
+    // entries can't expand into a huge header list.
+    pub fn decode(
+        &mut self,
+        mut block: &[u8],
+        limits: &Limits,
+    ) -> Result<Vec<(String, String)>, DecodeError> {
+        let mut fields = Vec::new();
+        let mut size = 0;
+        let mut too_large = false;
+        let mut keep = |fields: &mut Vec<(String, String)>, name: &str, value: &str| {
+            size += name.len() + value.len() + 4;
+            too_large |= size > limits.max_head || fields.len() >= limits.max_headers;
+            if !too_large {
+                fields.push((name.to_string(), value.to_string()));
+            }
+        };
+        let mut seen_field = false;
+        while let Some(&first) = block.first() {
+            if first & 0x80 != 0 {
+                // Indexed field
+                let index = decode_integer(&mut block, 7)?;
+                let (name, value) = self.entry(index)?;
+                keep(&mut fields, name, value);
+                seen_field = true;
+            } else if first & 0xc0 == 0x40 {
+                // Literal with incremental indexing
+                let (name, value) = self.decode_literal(&mut block, 6)?;
+                keep(&mut fields, &name, &value);
+                self.insert((name, value));
+                seen_field = true;
+            } else if first & 0xe0 == 0x20 {
+                // Table size update, only allowed ahead of the first field
+                if seen_field {
+                    return Err(DecodeError::Malformed);
+                }
+                let size = decode_integer(&mut block, 5)?;
+                if size > self.limit {
+                    return Err(DecodeError::Malformed);
+                }
+                self.max_size = size;
+                self.evict();
+            } else {
+                // Literal without indexing, or never indexed
+                let (name, value) = self.decode_literal(&mut block, 4)?;
+                keep(&mut fields, &name, &value);
+                seen_field = true;
+            }
+        }
+        match too_large {
+            true => Err(DecodeError::TooLarge),
+            false => Ok(fields),
+        }
+    }
+
+    fn decode_literal(
+        &self,
+        block: &mut &[u8],
+        prefix: u8,
+    ) -> Result<(String, String), DecodeError> {
+        let index = decode_integer(block, prefix)?;
+        let name = if index == 0 {
+            decode_string(block)?
+        } else {
+            self.entry(index)?.0.to_string()
+        };
+        let value = decode_string(block)?;
+        Ok((name, value))
+    }
+
+    fn entry(&self, index: usize) -> Result<(&str, &str), DecodeError> {
+        if index == 0 {
+            return Err(DecodeError::Malformed);
+        }
+        if let Some((name, value)) = STATIC_TABLE.get(index - 1) {
+            return Ok((name, value));
+        }
+        self.dynamic
+            .get(index - 1 - STATIC_TABLE.len())
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .ok_or(DecodeError::Malformed)
+    }
+
+    fn insert(&mut self, field: (String, String)) {
+        self.size += entry_size(&field);
+        self.dynamic.push_front(field);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            match self.dynamic.pop_back() {
+                Some(field) => self.size -= entry_size(&field),
+                None => break,
+            }
+        }
+    }
+}
+
+// Encodes a header block. Names found in the static table are referenced by
+// index; everything else is sent as a plain literal that is never indexed.
+pub fn encode(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        if let Some(index) = STATIC_TABLE
+            .iter()
+            .position(|(n, v)| n == name && v == value)
+        {
+            encode_integer(&mut block, 0x80, 7, index + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|(n, _)| n == name) {
+            Some(index) => encode_integer(&mut block, 0x00, 4, index + 1),
+            None => {
+                block.push(0x00);
+                encode_string(&mut block, name);
+            }
+        }
+        encode_string(&mut block, value);
+    }
+    block
+}
+
+// Each entry counts its name and value plus 32 bytes of overhead (RFC 7541 4.1)
+fn entry_size((name, value): &(String, String)) -> usize {
+    name.len() + value.len() + 32
+}
+
+fn decode_integer(block: &mut &[u8], prefix: u8) -> Result<usize, DecodeError> {
+    let (&first, mut rest) = block.split_first().ok_or(DecodeError::Malformed)?;
+    let mask = (1u16 << prefix) as u8 - 1;
+    let mut value = (first & mask) as usize;
+    if value == mask as usize {
+        let mut shift = 0;
+        loop {
+            let (&byte, tail) = rest.split_first().ok_or(DecodeError::Malformed)?;
+            rest = tail;
+            // Anything past 28 bits is far beyond any sane header
+            if shift > 28 {
+                return Err(DecodeError::Malformed);
+            }
+            value += ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+    *block = rest;
+    Ok(value)
+}
+
+fn encode_integer(block: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let mask = ((1u16 << prefix) - 1) as usize;
+    if value < mask {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | mask as u8);
+    value -= mask;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn decode_string(block: &mut &[u8]) -> Result<String, DecodeError> {
+    let huffman = block.first().ok_or(DecodeError::Malformed)? & 0x80 != 0;
+    let len = decode_integer(block, 7)?;
+    if block.len() < len {
+        return Err(DecodeError::Malformed);
+    }
+    let (raw, rest) = block.split_at(len);
+    *block = rest;
+    let bytes = if huffman {
+        huffman_decode(raw)?
+    } else {
+        raw.to_vec()
+    };
+    String::from_utf8(bytes).map_err(|_| DecodeError::Malformed)
+}
+
+fn encode_string(block: &mut Vec<u8>, value: &str) {
+    encode_integer(block, 0x00, 7, value.len());
+    block.extend_from_slice(value.as_bytes());
+}
+
+// Binary tree over the Huffman codes; leaves hold the decoded byte
+struct HuffmanTree {
+    // children[node] = [zero, one]; values at or above LEAF are symbols
+    children: Vec<[u16; 2]>,
+}
+
+const LEAF: u16 = 0x8000;
+
+fn huffman_tree() -> &'static HuffmanTree {
+    static TREE: OnceLock<HuffmanTree> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut children = vec![[0u16; 2]];
+        for symbol in 0..256 {
+            let (code, len) = (ENCODE_CODES[symbol], ENCODE_CODE_LENGTHS[symbol]);
+            let mut node = 0;
+            for bit in (0..len).rev() {
+                let branch = ((code >> bit) & 1) as usize;
+                if bit == 0 {
+                    children[node][branch] = LEAF | symbol as u16;
+                } else {
+                    if children[node][branch] == 0 {
+                        children.push([0, 0]);
+                        children[node][branch] = (children.len() - 1) as u16;
+                    }
+                    node = children[node][branch] as usize;
+                }
+            }
+        }
+        HuffmanTree { children }
+    })
+}
+
+fn huffman_decode(raw: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let tree = huffman_tree();
+    let mut decoded = Vec::with_capacity(raw.len() * 8 / 5);
+    let mut node = 0;
+    // Bits read since the last complete symbol, and whether all were ones
+    let mut pending = 0;
+    let mut all_ones = true;
+    for byte in raw {
+        for bit in (0..8).rev() {
+            let branch = ((byte >> bit) & 1) as usize;
+            let next = tree.children[node][branch];
+            pending += 1;
+            all_ones &= branch == 1;
+            if next & LEAF != 0 {
+                decoded.push((next & 0xff) as u8);
+                node = 0;
+                pending = 0;
+                all_ones = true;
+            } else if next == 0 {
+                // Only the 30-bit EOS code leads here, which must not appear
+                return Err(DecodeError::Malformed);
+            } else {
+                node = next as usize;
+            }
+        }
+    }
+    // Padding is the most significant bits of EOS: at most 7 bits, all ones
+    if pending > 7 || !all_ones {
+        return Err(DecodeError::Malformed);
+    }
+    Ok(decoded)
+}
+
+// RFC 7541 Appendix A
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// Huffman code for each byte value (RFC 7541 Appendix B)
+const ENCODE_CODES: [u32; 256] = [
+    0x1ff8, 0x7fffd8, 0xfffffe2, 0xfffffe3, 0xfffffe4, 0xfffffe5, 0xfffffe6, 0xfffffe7, 0xfffffe8,
+    0xffffea, 0x3ffffffc, 0xfffffe9, 0xfffffea, 0x3ffffffd, 0xfffffeb, 0xfffffec, 0xfffffed,
+    0xfffffee, 0xfffffef, 0xffffff0, 0xffffff1, 0xffffff2, 0x3ffffffe, 0xffffff3, 0xffffff4,
+    0xffffff5, 0xffffff6, 0xffffff7, 0xffffff8, 0xffffff9, 0xffffffa, 0xffffffb, 0x14, 0x3f8,
+    0x3f9, 0xffa, 0x1ff9, 0x15, 0xf8, 0x7fa, 0x3fa, 0x3fb, 0xf9, 0x7fb, 0xfa, 0x16, 0x17, 0x18,
+    0x0, 0x1, 0x2, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f, 0x5c, 0xfb, 0x7ffc, 0x20, 0xffb,
+    0x3fc, 0x1ffa, 0x21, 0x5d, 0x5e, 0x5f, 0x60, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f, 0x70, 0x71, 0x72, 0xfc, 0x73, 0xfd, 0x1ffb, 0x7fff0,
+    0x1ffc, 0x3ffc, 0x22, 0x7ffd, 0x3, 0x23, 0x4, 0x24, 0x5, 0x25, 0x26, 0x27, 0x6, 0x74, 0x75,
+    0x28, 0x29, 0x2a, 0x7, 0x2b, 0x76, 0x2c, 0x8, 0x9, 0x2d, 0x77, 0x78, 0x79, 0x7a, 0x7b, 0x7ffe,
+    0x7fc, 0x3ffd, 0x1ffd, 0xffffffc, 0xfffe6, 0x3fffd2, 0xfffe7, 0xfffe8, 0x3fffd3, 0x3fffd4,
+    0x3fffd5, 0x7fffd9, 0x3fffd6, 0x7fffda, 0x7fffdb, 0x7fffdc, 0x7fffdd, 0x7fffde, 0xffffeb,
+    0x7fffdf, 0xffffec, 0xffffed, 0x3fffd7, 0x7fffe0, 0xffffee, 0x7fffe1, 0x7fffe2, 0x7fffe3,
+    0x7fffe4, 0x1fffdc, 0x3fffd8, 0x7fffe5, 0x3fffd9, 0x7fffe6, 0x7fffe7, 0xffffef, 0x3fffda,
+    0x1fffdd, 0xfffe9, 0x3fffdb, 0x3fffdc, 0x7fffe8, 0x7fffe9, 0x1fffde, 0x7fffea, 0x3fffdd,
+    0x3fffde, 0xfffff0, 0x1fffdf, 0x3fffdf, 0x7fffeb, 0x7fffec, 0x1fffe0, 0x1fffe1, 0x3fffe0,
+    0x1fffe2, 0x7fffed, 0x3fffe1, 0x7fffee, 0x7fffef, 0xfffea, 0x3fffe2, 0x3fffe3, 0x3fffe4,
+    0x7ffff0, 0x3fffe5, 0x3fffe6, 0x7ffff1, 0x3ffffe0, 0x3ffffe1, 0xfffeb, 0x7fff1, 0x3fffe7,
+    0x7ffff2, 0x3fffe8, 0x1ffffec, 0x3ffffe2, 0x3ffffe3, 0x3ffffe4, 0x7ffffde, 0x7ffffdf,
+    0x3ffffe5, 0xfffff1, 0x1ffffed, 0x7fff2, 0x1fffe3, 0x3ffffe6, 0x7ffffe0, 0x7ffffe1, 0x3ffffe7,
+    0x7ffffe2, 0xfffff2, 0x1fffe4, 0x1fffe5, 0x3ffffe8, 0x3ffffe9, 0xffffffd, 0x7ffffe3, 0x7ffffe4,
+    0x7ffffe5, 0xfffec, 0xfffff3, 0xfffed, 0x1fffe6, 0x3fffe9, 0x1fffe7, 0x1fffe8, 0x7ffff3,
+    0x3fffea, 0x3fffeb, 0x1ffffee, 0x1ffffef, 0xfffff4, 0xfffff5, 0x3ffffea, 0x7ffff4, 0x3ffffeb,
+    0x7ffffe6, 0x3ffffec, 0x3ffffed, 0x7ffffe7, 0x7ffffe8, 0x7ffffe9, 0x7ffffea, 0x7ffffeb,
+    0xffffffe, 0x7ffffec, 0x7ffffed, 0x7ffffee, 0x7ffffef, 0x7fffff0, 0x3ffffee,
+];
+
+const ENCODE_CODE_LENGTHS: [u8; 256] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28,
+    28, 28, 28, 28, 28, 28, 28, 28, 6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5,
+    5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10, 13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6, 15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6,
+    6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, 20, 22, 20, 20, 22, 22, 22, 23, 22,
+    23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24, 22,
+    21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20,
+    22, 22, 22, 23, 22, 22, 23, 26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19,
+    21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27, 20, 24, 20, 21, 22, 21, 21, 23, 22,
+    22, 25, 25, 24, 24, 26, 23, 26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26,
+];
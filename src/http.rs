@@ -0,0 +1,648 @@
+// HTTP/1.1 request parsing following RFC 9112. The parser works on a byte
+// buffer that may hold only part of a request, so the caller can keep reading
+// until it reports a complete request or an error.
+
+use serde::Deserialize;
+use std::fmt;
+
+// Upper bounds enforced while parsing. Anything larger is rejected before it
+// is buffered in full.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct Limits {
+    pub max_request_line: usize,
+    // Size of the whole header section, request line included
+    pub max_head: usize,
+    pub max_headers: usize,
+    pub max_body: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_request_line: 8 * 1024,
+            max_head: 64 * 1024,
+            max_headers: 100,
+            max_body: 8 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+    // Requests that arrived as HTTP/2 streams rather than text
+    Http2,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    BadRequestLine,
+    RequestLineTooLong,
+    UnsupportedVersion,
+    BadHeader,
+    MissingHost,
+    DuplicateHost,
+    HeadersTooLarge,
+    BadContentLength,
+    DuplicateContentLength,
+    BadTransferEncoding,
+    UnsupportedTransferEncoding,
+    BadChunk,
+    BodyTooLarge,
+}
+
+impl ParseError {
+    pub fn status(&self) -> &'static str {
+        match self {
+            ParseError::RequestLineTooLong => "414 URI Too Long",
+            ParseError::UnsupportedVersion => "505 HTTP Version Not Supported",
+            ParseError::HeadersTooLarge => "431 Request Header Fields Too Large",
+            ParseError::UnsupportedTransferEncoding => "501 Not Implemented",
+            ParseError::BodyTooLarge => "413 Content Too Large",
+            _ => "400 Bad Request",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ParseError::BadRequestLine => "Invalid request line.",
+            ParseError::RequestLineTooLong => "Request line is too long.",
+            ParseError::UnsupportedVersion => "HTTP version is not supported.",
+            ParseError::BadHeader => "Invalid header format.",
+            ParseError::MissingHost => "Missing Host header.",
+            ParseError::DuplicateHost => "Duplicate Host header.",
+            ParseError::HeadersTooLarge => "Request headers are too large.",
+            ParseError::BadContentLength => "Invalid Content-Length.",
+            ParseError::DuplicateContentLength => "Duplicate Content-Length.",
+            ParseError::BadTransferEncoding => "Invalid Transfer-Encoding.",
+            ParseError::UnsupportedTransferEncoding => "Transfer-Encoding is not supported.",
+            ParseError::BadChunk => "Invalid chunked body.",
+            ParseError::BodyTooLarge => "Request is too large.",
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+// Header fields in arrival order. Names compare case-insensitively and a name
+// may appear more than once.
+#[derive(Clone, Default, Debug)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    // First value of the named field
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Every value of the named field, in arrival order
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Items of a comma-separated list field such as Accept, each with its
+    // q-value (1 when absent). Other parameters are dropped.
+    pub fn weighted<'a>(&'a self, name: &'a str) -> impl Iterator<Item = (&'a str, f32)> + 'a {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let token = parts.next()?.trim();
+                if token.is_empty() {
+                    return None;
+                }
+                let q = parts
+                    .filter_map(|param| {
+                        let (name, value) = param.split_once('=')?;
+                        name.trim()
+                            .eq_ignore_ascii_case("q")
+                            .then(|| value.trim().parse::<f32>().ok())?
+                    })
+                    .next()
+                    .unwrap_or(1.0);
+                Some((token, q))
+            })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+pub struct Request {
+    pub method: String,
+    // The request-target as sent, query included
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn path(&self) -> &str {
+        self.target
+            .split_once('?')
+            .map_or(self.target.as_str(), |(path, _)| path)
+    }
+
+    pub fn query(&self) -> &str {
+        self.target.split_once('?').map_or("", |(_, query)| query)
+    }
+}
+
+// Parses one request from the start of `buf`. Returns Ok(None) while the
+// request is still incomplete, otherwise the request and the number of bytes
+// it took up.
+pub fn parse(buf: &[u8], limits: &Limits) -> Result<Option<(Request, usize)>, ParseError> {
+    let Some(head) = parse_head(buf, limits)? else {
+        return Ok(None);
+    };
+    let pos = head.end;
+    let body = match body_framing(&head.headers, head.version)? {
+        Framing::Chunked => parse_chunked(buf, pos, limits)?,
+        Framing::Length(length) => {
+            if length > limits.max_body {
+                return Err(ParseError::BodyTooLarge);
+            }
+            buf.get(pos..pos + length)
+                .map(|body| (body.to_vec(), pos + length))
+        }
+    };
+    Ok(body.map(|(body, end)| {
+        let request = Request {
+            method: head.method.to_string(),
+            target: head.target.to_string(),
+            version: head.version,
+            headers: head.headers,
+            body,
+        };
+        (request, end)
+    }))
+}
+
+// The request line and header fields, up to where the body starts
+struct Head<'a> {
+    method: &'a str,
+    target: &'a str,
+    version: Version,
+    headers: Headers,
+    end: usize,
+}
+
+fn parse_head<'a>(buf: &'a [u8], limits: &Limits) -> Result<Option<Head<'a>>, ParseError> {
+    // Servers should ignore empty lines ahead of the request line (RFC 9112 2.2)
+    let start = skip_empty_lines(buf);
+
+    let Some((line, mut pos)) = next_line(buf, start) else {
+        if buf.len() - start > limits.max_request_line {
+            return Err(ParseError::RequestLineTooLong);
+        }
+        return Ok(None);
+    };
+    if line.len() > limits.max_request_line {
+        return Err(ParseError::RequestLineTooLong);
+    }
+    let (method, target, version) = parse_request_line(line)?;
+
+    let mut headers = Headers::new();
+    loop {
+        let Some((line, next)) = next_line(buf, pos) else {
+            if buf.len() - start > limits.max_head {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            return Ok(None);
+        };
+        pos = next;
+        if pos - start > limits.max_head {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() >= limits.max_headers {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        let (name, value) = parse_field(line)?;
+        headers.append(name, &value);
+    }
+    // HTTP/1.1 requests need exactly one Host, HTTP/1.0 ones at most one
+    // (RFC 9112 3.2)
+    match headers.get_all("Host").count() {
+        0 if version == Version::Http11 => return Err(ParseError::MissingHost),
+        0 | 1 => {}
+        _ => return Err(ParseError::DuplicateHost),
+    }
+    Ok(Some(Head {
+        method,
+        target,
+        version,
+        headers,
+        end: pos,
+    }))
+}
+
+// How far a request arriving piece by piece has been checked. Each call only
+// looks at what arrived since the last, where parsing the whole buffer after
+// every read would take time quadratic in the request's size.
+#[derive(Default)]
+pub struct Progress {
+    // Start of the next line to check
+    pos: usize,
+    // Where the search for that line's end left off
+    scanned: usize,
+    stage: Stage,
+}
+
+#[derive(Default, Clone, Copy)]
+enum Stage {
+    // Empty lines ahead of it are skipped
+    #[default]
+    RequestLine,
+    Fields {
+        start: usize,
+        count: usize,
+    },
+    Body {
+        end: usize,
+    },
+    Chunks {
+        total: usize,
+    },
+    Trailers {
+        start: usize,
+    },
+    Done,
+}
+
+impl Progress {
+    // True once `buf`, which only ever grows, holds a complete request or
+    // enough of one to tell it is bad. `parse` then has the answer; this
+    // applies the same limits and checks, so the two agree.
+    pub fn advance(&mut self, buf: &[u8], limits: &Limits) -> bool {
+        let done = self.check(buf, limits).unwrap_or(true);
+        if done {
+            self.stage = Stage::Done;
+        }
+        done
+    }
+
+    fn check(&mut self, buf: &[u8], limits: &Limits) -> Result<bool, ParseError> {
+        loop {
+            match self.stage {
+                Stage::Done => return Ok(true),
+                Stage::Body { end } => return Ok(buf.len() >= end),
+                Stage::RequestLine => {
+                    let Some((line, next)) = self.next_line(buf) else {
+                        return Ok(buf.len() - self.pos > limits.max_request_line);
+                    };
+                    if !line.is_empty() {
+                        if line.len() > limits.max_request_line {
+                            return Ok(true);
+                        }
+                        parse_request_line(line)?;
+                        self.stage = Stage::Fields {
+                            start: self.pos,
+                            count: 0,
+                        };
+                    }
+                    self.move_to(next);
+                }
+                Stage::Fields { start, count } => {
+                    let Some((line, next)) = self.next_line(buf) else {
+                        return Ok(buf.len() - start > limits.max_head);
+                    };
+                    if next - start > limits.max_head {
+                        return Ok(true);
+                    }
+                    self.move_to(next);
+                    if !line.is_empty() {
+                        if count >= limits.max_headers {
+                            return Ok(true);
+                        }
+                        parse_field(line)?;
+                        let count = count + 1;
+                        self.stage = Stage::Fields { start, count };
+                        continue;
+                    }
+                    // The head is parsed once more, now whole, for its framing
+                    let Some(head) = parse_head(buf, limits)? else {
+                        return Ok(true);
+                    };
+                    self.stage = match body_framing(&head.headers, head.version)? {
+                        Framing::Length(length) if length > limits.max_body => return Ok(true),
+                        Framing::Length(length) => Stage::Body { end: next + length },
+                        Framing::Chunked => Stage::Chunks { total: 0 },
+                    };
+                }
+                Stage::Chunks { total } => {
+                    let Some((line, next)) = self.next_line(buf) else {
+                        return Ok(buf.len() - self.pos > limits.max_request_line);
+                    };
+                    let size = chunk_size(line)?;
+                    let total = total.saturating_add(size);
+                    if total > limits.max_body {
+                        return Ok(true);
+                    }
+                    if size == 0 {
+                        self.move_to(next);
+                        self.stage = Stage::Trailers { start: next };
+                        continue;
+                    }
+                    // The size line is checked again once the data is in
+                    let data_end = next + size;
+                    match buf.get(data_end..) {
+                        None | Some([] | [b'\r']) => return Ok(false),
+                        Some([b'\r', b'\n', ..]) => self.move_to(data_end + 2),
+                        Some([b'\n', ..]) => self.move_to(data_end + 1),
+                        Some(_) => return Ok(true),
+                    }
+                    self.stage = Stage::Chunks { total };
+                }
+                Stage::Trailers { start } => {
+                    let Some((line, next)) = self.next_line(buf) else {
+                        return Ok(false);
+                    };
+                    if next - start > limits.max_head || line.is_empty() {
+                        return Ok(true);
+                    }
+                    parse_field(line)?;
+                    self.move_to(next);
+                }
+            }
+        }
+    }
+
+    // The line starting at `pos`, searching only what wasn't searched yet
+    fn next_line<'b>(&mut self, buf: &'b [u8]) -> Option<(&'b [u8], usize)> {
+        let from = self.scanned.max(self.pos);
+        let Some(newline) = buf[from..].iter().position(|b| *b == b'\n') else {
+            self.scanned = buf.len();
+            return None;
+        };
+        let line = &buf[self.pos..from + newline];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        Some((line, from + newline + 1))
+    }
+
+    fn move_to(&mut self, pos: usize) {
+        self.pos = pos;
+        self.scanned = pos;
+    }
+}
+
+// Offset just past the header section, if `buf` holds all of it. Lets readers
+// tell when a client has finished sending headers.
+pub fn head_end(buf: &[u8]) -> Option<usize> {
+    let (_, mut pos) = next_line(buf, skip_empty_lines(buf))?;
+    loop {
+        let (line, next) = next_line(buf, pos)?;
+        if line.is_empty() {
+            return Some(next);
+        }
+        pos = next;
+    }
+}
+
+enum Framing {
+    Length(usize),
+    Chunked,
+}
+
+// Works out how the body is delimited (RFC 9112 6.3). Requests that carry
+// both Transfer-Encoding and Content-Length, or conflicting lengths, are
+// rejected outright since front-ends might frame them differently.
+fn body_framing(headers: &Headers, version: Version) -> Result<Framing, ParseError> {
+    let codings: Vec<String> = headers
+        .get_all("Transfer-Encoding")
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .collect();
+    let lengths: Vec<&str> = headers
+        .get_all("Content-Length")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    if !codings.is_empty() {
+        if !lengths.is_empty() || version == Version::Http10 {
+            return Err(ParseError::BadTransferEncoding);
+        }
+        if codings.iter().any(String::is_empty) || codings.last().unwrap() != "chunked" {
+            return Err(ParseError::BadTransferEncoding);
+        }
+        if codings.len() > 1 {
+            return Err(ParseError::UnsupportedTransferEncoding);
+        }
+        return Ok(Framing::Chunked);
+    }
+
+    match lengths.as_slice() {
+        [] => Ok(Framing::Length(0)),
+        [length] => {
+            if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::BadContentLength);
+            }
+            length
+                .parse()
+                .map(Framing::Length)
+                .map_err(|_| ParseError::BadContentLength)
+        }
+        _ => Err(ParseError::DuplicateContentLength),
+    }
+}
+
+fn parse_request_line(line: &[u8]) -> Result<(&str, &str, Version), ParseError> {
+    if !line.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+        return Err(ParseError::BadRequestLine);
+    }
+    // Only ASCII is left, so this can't fail
+    let line = std::str::from_utf8(line).map_err(|_| ParseError::BadRequestLine)?;
+    let parts: Vec<&str> = line.split(' ').filter(|part| !part.is_empty()).collect();
+    let [method, target, version] = parts.as_slice() else {
+        return Err(ParseError::BadRequestLine);
+    };
+    if !method.bytes().all(is_token_byte) {
+        return Err(ParseError::BadRequestLine);
+    }
+    let version = match *version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        other => {
+            let digits = other.strip_prefix("HTTP/").map(str::as_bytes);
+            return match digits {
+                Some([major, b'.', minor]) if major.is_ascii_digit() && minor.is_ascii_digit() => {
+                    Err(ParseError::UnsupportedVersion)
+                }
+                _ => Err(ParseError::BadRequestLine),
+            };
+        }
+    };
+    Ok((method, target, version))
+}
+
+// field-line = field-name ":" OWS field-value OWS (RFC 9112 5)
+fn parse_field(line: &[u8]) -> Result<(&str, String), ParseError> {
+    // A line starting with whitespace is obsolete line folding, which servers
+    // may reject; so is whitespace between the name and the colon
+    let colon = line
+        .iter()
+        .position(|b| *b == b':')
+        .ok_or(ParseError::BadHeader)?;
+    let (name, value) = (&line[..colon], &line[colon + 1..]);
+    if name.is_empty() || !name.iter().copied().all(is_token_byte) {
+        return Err(ParseError::BadHeader);
+    }
+    let value = trim_whitespace(value);
+    if value
+        .iter()
+        .any(|b| (b.is_ascii_control() && *b != b'\t') || *b == 0x7f)
+    {
+        return Err(ParseError::BadHeader);
+    }
+    // Names are tokens, so always ASCII
+    let name = std::str::from_utf8(name).map_err(|_| ParseError::BadHeader)?;
+    Ok((name, String::from_utf8_lossy(value).into_owned()))
+}
+
+fn parse_chunked(
+    buf: &[u8],
+    mut pos: usize,
+    limits: &Limits,
+) -> Result<Option<(Vec<u8>, usize)>, ParseError> {
+    // Chunks are only located here and copied once the body is complete, so
+    // re-parsing a growing buffer stays cheap
+    let mut chunks = Vec::new();
+    let mut total: usize = 0;
+    loop {
+        let Some((line, next)) = next_line(buf, pos) else {
+            if buf.len() - pos > limits.max_request_line {
+                return Err(ParseError::BadChunk);
+            }
+            return Ok(None);
+        };
+        let size = chunk_size(line)?;
+        total = total.saturating_add(size);
+        if total > limits.max_body {
+            return Err(ParseError::BodyTooLarge);
+        }
+        pos = next;
+
+        if size == 0 {
+            // Trailer fields are read and dropped
+            let trailer_start = pos;
+            loop {
+                let Some((line, next)) = next_line(buf, pos) else {
+                    return Ok(None);
+                };
+                pos = next;
+                if pos - trailer_start > limits.max_head {
+                    return Err(ParseError::HeadersTooLarge);
+                }
+                if line.is_empty() {
+                    let body = chunks
+                        .iter()
+                        .flat_map(|(start, end)| &buf[*start..*end])
+                        .copied()
+                        .collect();
+                    return Ok(Some((body, pos)));
+                }
+                parse_field(line)?;
+            }
+        }
+
+        if buf.len() < pos + size {
+            return Ok(None);
+        }
+        chunks.push((pos, pos + size));
+        pos += size;
+        match &buf[pos..] {
+            [b'\r', b'\n', ..] => pos += 2,
+            [b'\n', ..] => pos += 1,
+            [] | [b'\r'] => return Ok(None),
+            _ => return Err(ParseError::BadChunk),
+        }
+    }
+}
+
+// chunk-size [ chunk-ext ] CRLF; extensions carry nothing we use
+fn chunk_size(line: &[u8]) -> Result<usize, ParseError> {
+    let size = line.split(|b| *b == b';').next().unwrap_or_default();
+    let size = trim_whitespace(size);
+    if size.is_empty() || size.len() > 16 || !size.iter().all(u8::is_ascii_hexdigit) {
+        return Err(ParseError::BadChunk);
+    }
+    std::str::from_utf8(size)
+        .ok()
+        .and_then(|size| usize::from_str_radix(size, 16).ok())
+        .ok_or(ParseError::BadChunk)
+}
+
+// Next line starting at `pos` without its terminator, and the offset after
+// it. Lines end in CRLF, though a bare LF is accepted too (RFC 9112 2.2).
+fn next_line(buf: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let rest = buf.get(pos..)?;
+    let newline = rest.iter().position(|b| *b == b'\n')?;
+    let line = &rest[..newline];
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    Some((line, pos + newline + 1))
+}
+
+fn skip_empty_lines(buf: &[u8]) -> usize {
+    let mut pos = 0;
+    loop {
+        match &buf[pos..] {
+            [b'\r', b'\n', ..] => pos += 2,
+            [b'\n', ..] => pos += 1,
+            _ => return pos,
+        }
+    }
+}
+
+fn trim_whitespace(bytes: &[u8]) -> &[u8] {
+    let start = bytes
+        .iter()
+        .position(|b| *b != b' ' && *b != b'\t')
+        .unwrap_or(bytes.len());
+    let end = bytes
+        .iter()
+        .rposition(|b| *b != b' ' && *b != b'\t')
+        .map_or(start, |end| end + 1);
+    &bytes[start..end]
+}
+
+// tchar from RFC 9110 5.6.2
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
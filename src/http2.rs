@@ -0,0 +1,858 @@
+// HTTP/2 over cleartext TCP (RFC 9113), entered either with the connection
+// preface ("prior knowledge") or through an HTTP/1.1 `Upgrade: h2c` request.
+// Streams are multiplexed over the one connection: frames of different
+// requests may interleave, each request is routed through `process_request`
+// once complete, and response bodies share the flow-control windows.
+// Request bodies are buffered whole, so the connection window is only handed
+// back as bodies are dealt with, and a stream's only up to the body limit.
+
+use crate::crypto::base64url_decode;
+use crate::hpack::{self, Decoder};
+use crate::http::{Headers, Limits, ParseError, Request, Version};
+use crate::{AppState, Connection, Response, Takeover, http_date, log_error, process_request};
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+
+// Settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+// What we accept from the peer; both are the protocol defaults
+const MAX_FRAME_SIZE: usize = 16_384;
+const MAX_CONCURRENT_STREAMS: usize = 100;
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+enum Error {
+    // Ends the whole connection with GOAWAY
+    Connection(u32),
+    // The peer went away or stopped talking
+    Closed,
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+// A request whose headers have arrived but whose body may still be coming
+struct IncomingStream {
+    fields: Vec<(String, String)>,
+    body: Vec<u8>,
+    // What the peer may still send on the stream
+    window: i64,
+}
+
+// A response body waiting for flow-control credit
+struct OutgoingData {
+    stream_id: u32,
+    data: Vec<u8>,
+    sent: usize,
+    // Reset the stream once sent, for requests answered before they finished
+    reset_after: bool,
+}
+
+// A header block split over HEADERS and CONTINUATION frames
+struct PendingHeaders {
+    stream_id: u32,
+    end_stream: bool,
+    block: Vec<u8>,
+}
+
+struct Http2<'a, S: Connection> {
+    stream: &'a mut S,
+    state: &'a AppState,
+    peer: Option<IpAddr>,
+    input: Vec<u8>,
+    decoder: Decoder,
+    limits: Limits,
+    incoming: HashMap<u32, IncomingStream>,
+    continuation: Option<PendingHeaders>,
+    outgoing: VecDeque<OutgoingData>,
+    send_window: i64,
+    // What the peer may still send across all streams
+    recv_window: i64,
+    stream_windows: HashMap<u32, i64>,
+    peer_initial_window: i64,
+    peer_max_frame: usize,
+    last_stream_id: u32,
+    goaway_received: bool,
+    // When each open stream began
+    started: HashMap<u32, Instant>,
+    // When a stream last began or finished
+    active_at: Instant,
+    closes_at: Instant,
+}
+
+// Serves a connection that opened with the HTTP/2 preface. `rest` holds
+// whatever arrived after the preface in the same read.
+pub fn serve<S: Connection>(stream: &mut S, rest: &[u8], peer: Option<IpAddr>, state: &AppState) {
+    let mut connection = Http2::new(stream, rest, peer, state);
+    connection.run(None);
+}
+
+// Turns away a connection that opened with the preface when too many are
+// open: the server's SETTINGS, then GOAWAY before any stream was processed
+pub fn refuse<S: Connection>(stream: &mut S, state: &AppState) {
+    log_error("Too many HTTP/2 connections.");
+    let mut connection = Http2::new(stream, &[], None, state);
+    let _ = connection
+        .write_frame(SETTINGS, 0, 0, &[])
+        .and_then(|()| connection.go_away(REFUSED_STREAM));
+    connection.stream.close();
+}
+
+// True for an HTTP/1.1 request asking to switch to h2c (RFC 7540 3.2)
+pub fn wants_upgrade(request: &Request) -> bool {
+    let has_token = |name: &str, token: &str| {
+        request
+            .headers
+            .get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    request.version == Version::Http11
+        && has_token("Upgrade", "h2c")
+        && has_token("Connection", "Upgrade")
+        && has_token("Connection", "HTTP2-Settings")
+        && request.headers.get_all("HTTP2-Settings").count() == 1
+        && request
+            .headers
+            .get("HTTP2-Settings")
+            .and_then(base64url_decode)
+            .is_some_and(|settings| settings.len().is_multiple_of(6))
+}
+
+// Switches an upgradable HTTP/1.1 request to HTTP/2 and answers it on stream 1
+pub fn serve_upgrade<S: Connection>(
+    stream: &mut S,
+    request: Request,
+    peer: Option<IpAddr>,
+    state: &AppState,
+) {
+    let switching =
+        "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+    if let Err(e) = stream.write_all(switching.as_bytes()) {
+        log_error(&format!("Stream write error: {}", e));
+        return;
+    }
+    let mut connection = Http2::new(stream, &[], peer, state);
+    // The settings travel in the upgrade request and are never acknowledged
+    let settings = request
+        .headers
+        .get("HTTP2-Settings")
+        .and_then(base64url_decode)
+        .unwrap_or_default();
+    if connection.apply_settings(&settings).is_err() {
+        return;
+    }
+    connection.run(Some(request));
+}
+
+impl<'a, S: Connection> Http2<'a, S> {
+    fn new(stream: &'a mut S, rest: &[u8], peer: Option<IpAddr>, state: &'a AppState) -> Self {
+        Http2 {
+            stream,
+            state,
+            peer,
+            input: rest.to_vec(),
+            decoder: Decoder::new(),
+            limits: state.config.limits,
+            incoming: HashMap::new(),
+            continuation: None,
+            outgoing: VecDeque::new(),
+            send_window: DEFAULT_WINDOW,
+            recv_window: DEFAULT_WINDOW,
+            stream_windows: HashMap::new(),
+            peer_initial_window: DEFAULT_WINDOW,
+            peer_max_frame: MAX_FRAME_SIZE,
+            last_stream_id: 0,
+            goaway_received: false,
+            started: HashMap::new(),
+            active_at: Instant::now(),
+            closes_at: Instant::now() + state.config.http2_max_connection(),
+        }
+    }
+
+    fn run(&mut self, upgraded: Option<Request>) {
+        if let Err(Error::Connection(code)) = self.serve_frames(upgraded) {
+            log_error(&format!("HTTP/2 connection error {:#x}.", code));
+            let _ = self.go_away(code);
+        }
+        self.stream.close();
+    }
+
+    fn serve_frames(&mut self, upgraded: Option<Request>) -> Result<(), Error> {
+        let mut settings = Vec::new();
+        settings.extend_from_slice(&SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes());
+        settings.extend_from_slice(&(MAX_CONCURRENT_STREAMS as u32).to_be_bytes());
+        self.write_frame(SETTINGS, 0, 0, &settings)?;
+        // Room for one whole body; it is shared by all streams, so that's
+        // also the most the peer can have buffered at once
+        let window = (self.limits.max_body as i64 + 1).clamp(DEFAULT_WINDOW, MAX_WINDOW);
+        self.credit(0, (window - DEFAULT_WINDOW) as usize)?;
+
+        if let Some(request) = upgraded {
+            // The upgrade request becomes stream 1, already half-closed by the
+            // client; the preface follows the 101 response
+            self.last_stream_id = 1;
+            self.stream_windows.insert(1, self.peer_initial_window);
+            self.started.insert(1, Instant::now());
+            self.respond(1, request)?;
+            self.fill(PREFACE.len())?;
+            if !self.input.starts_with(PREFACE) {
+                return Err(Error::Connection(PROTOCOL_ERROR));
+            }
+            self.input.drain(..PREFACE.len());
+        }
+
+        // The first frame from the client must be its SETTINGS
+        let first = self.read_frame()?;
+        if first.kind != SETTINGS || first.flags & ACK != 0 {
+            return Err(Error::Connection(PROTOCOL_ERROR));
+        }
+        self.handle_frame(first)?;
+
+        loop {
+            self.flush()?;
+            if self.goaway_received && self.incoming.is_empty() && self.outgoing.is_empty() {
+                return self.go_away(NO_ERROR);
+            }
+            let frame = match self.read_frame() {
+                Ok(frame) => frame,
+                Err(Error::Closed) if self.outgoing.is_empty() => {
+                    // Past a timeout or deadline, or the client hung up
+                    return self.go_away(NO_ERROR);
+                }
+                Err(error) => return Err(error),
+            };
+            self.handle_frame(frame)?;
+        }
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), Error> {
+        if let Some(pending) = &self.continuation {
+            if frame.kind != CONTINUATION || frame.stream_id != pending.stream_id {
+                return Err(Error::Connection(PROTOCOL_ERROR));
+            }
+        }
+
+        match frame.kind {
+            DATA => self.on_data(frame),
+            HEADERS => self.on_headers(frame),
+            CONTINUATION => self.on_continuation(frame),
+            PRIORITY => Ok(()),
+            RST_STREAM => {
+                if frame.stream_id == 0 {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                if frame.payload.len() != 4 {
+                    return Err(Error::Connection(FRAME_SIZE_ERROR));
+                }
+                self.take_incoming(frame.stream_id)?;
+                self.stream_windows.remove(&frame.stream_id);
+                self.outgoing
+                    .retain(|outgoing| outgoing.stream_id != frame.stream_id);
+                Ok(())
+            }
+            SETTINGS => {
+                if frame.stream_id != 0 {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                if frame.flags & ACK != 0 {
+                    return match frame.payload.is_empty() {
+                        true => Ok(()),
+                        false => Err(Error::Connection(FRAME_SIZE_ERROR)),
+                    };
+                }
+                self.apply_settings(&frame.payload)?;
+                self.write_frame(SETTINGS, ACK, 0, &[])
+            }
+            PING => {
+                if frame.stream_id != 0 {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                if frame.payload.len() != 8 {
+                    return Err(Error::Connection(FRAME_SIZE_ERROR));
+                }
+                if frame.flags & ACK != 0 {
+                    return Ok(());
+                }
+                self.write_frame(PING, ACK, 0, &frame.payload)
+            }
+            GOAWAY => {
+                self.goaway_received = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.on_window_update(frame),
+            // Clients can't push, and the setting says so
+            PUSH_PROMISE => Err(Error::Connection(PROTOCOL_ERROR)),
+            // Unknown frame types must be ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn on_headers(&mut self, frame: Frame) -> Result<(), Error> {
+        let id = frame.stream_id;
+        if id == 0 || id.is_multiple_of(2) {
+            return Err(Error::Connection(PROTOCOL_ERROR));
+        }
+        let mut payload = strip_padding(&frame)?;
+        if frame.flags & PRIORITY_FLAG != 0 {
+            payload = payload.get(5..).ok_or(Error::Connection(PROTOCOL_ERROR))?;
+        }
+        let pending = PendingHeaders {
+            stream_id: id,
+            end_stream: frame.flags & END_STREAM != 0,
+            block: payload.to_vec(),
+        };
+        if frame.flags & END_HEADERS != 0 {
+            self.on_header_block(pending)
+        } else {
+            self.continuation = Some(pending);
+            Ok(())
+        }
+    }
+
+    fn on_continuation(&mut self, frame: Frame) -> Result<(), Error> {
+        let Some(mut pending) = self.continuation.take() else {
+            return Err(Error::Connection(PROTOCOL_ERROR));
+        };
+        pending.block.extend_from_slice(&frame.payload);
+        if pending.block.len() > self.limits.max_head {
+            return Err(Error::Connection(COMPRESSION_ERROR));
+        }
+        if frame.flags & END_HEADERS != 0 {
+            self.on_header_block(pending)
+        } else {
+            self.continuation = Some(pending);
+            Ok(())
+        }
+    }
+
+    fn on_header_block(&mut self, pending: PendingHeaders) -> Result<(), Error> {
+        // Decode even when the stream gets refused, to keep the table in sync
+        let decoded = self.decoder.decode(&pending.block, &self.limits);
+        let id = pending.stream_id;
+        let fields = match decoded {
+            Ok(fields) => fields,
+            Err(hpack::DecodeError::Malformed) => return Err(Error::Connection(COMPRESSION_ERROR)),
+            Err(hpack::DecodeError::TooLarge) => return self.refuse_headers(pending),
+        };
+
+        if self.incoming.contains_key(&id) {
+            // Trailers, which must end the stream; their fields are dropped
+            if !pending.end_stream {
+                return Err(Error::Connection(PROTOCOL_ERROR));
+            }
+            return self.complete(id);
+        }
+        if id <= self.last_stream_id {
+            return Err(Error::Connection(STREAM_CLOSED));
+        }
+        self.last_stream_id = id;
+        if self.goaway_received || self.incoming.len() >= MAX_CONCURRENT_STREAMS {
+            return self.reset(id, REFUSED_STREAM);
+        }
+
+        self.incoming.insert(
+            id,
+            IncomingStream {
+                fields,
+                body: Vec::new(),
+                window: DEFAULT_WINDOW,
+            },
+        );
+        self.stream_windows.insert(id, self.peer_initial_window);
+        self.started.insert(id, Instant::now());
+        if pending.end_stream {
+            return self.complete(id);
+        }
+        Ok(())
+    }
+
+    // Answers a request whose headers expand past the limits with 431, as an
+    // HTTP/1.1 one would be, and drops anything else sent on its stream
+    fn refuse_headers(&mut self, pending: PendingHeaders) -> Result<(), Error> {
+        let id = pending.stream_id;
+        let error = ParseError::HeadersTooLarge;
+        log_error(error.message());
+        if self.take_incoming(id)?.is_some() {
+            // Trailers
+            return self.reset(id, PROTOCOL_ERROR);
+        }
+        if id <= self.last_stream_id {
+            return Err(Error::Connection(STREAM_CLOSED));
+        }
+        self.last_stream_id = id;
+        self.stream_windows.insert(id, self.peer_initial_window);
+        let response = Response::from((error.status(), error.message().to_string()));
+        self.send_response(id, response, !pending.end_stream)
+    }
+
+    fn on_data(&mut self, frame: Frame) -> Result<(), Error> {
+        let id = frame.stream_id;
+        if id == 0 {
+            return Err(Error::Connection(PROTOCOL_ERROR));
+        }
+        // The whole frame counts against flow control, padding included
+        let size = frame.payload.len();
+        if size as i64 > self.recv_window {
+            return Err(Error::Connection(FLOW_CONTROL_ERROR));
+        }
+        self.recv_window -= size as i64;
+        let data = strip_padding(&frame)?;
+        // Padding isn't kept, so its share is handed straight back
+        self.credit(0, size - data.len())?;
+
+        let Some(incoming) = self.incoming.get_mut(&id) else {
+            self.credit(0, data.len())?;
+            if id > self.last_stream_id {
+                return Err(Error::Connection(PROTOCOL_ERROR));
+            }
+            return self.reset(id, STREAM_CLOSED);
+        };
+        incoming.body.extend_from_slice(data);
+        if size as i64 > incoming.window {
+            self.take_incoming(id)?;
+            return self.reset(id, FLOW_CONTROL_ERROR);
+        }
+        incoming.window -= size as i64;
+        let received = incoming.body.len();
+
+        if received > self.limits.max_body {
+            self.take_incoming(id)?;
+            let response =
+                Response::from(("413 Content Too Large", "Request is too large.".to_string()));
+            return self.send_response(id, response, true);
+        }
+        if frame.flags & END_STREAM != 0 {
+            return self.complete(id);
+        }
+        // Enough for the stream to go one byte past the limit, which gets it a
+        // 413, but no further
+        let allowance = (self.limits.max_body + 1 - received) as i64 - incoming.window;
+        let credit = (size as i64).min(allowance);
+        if credit > 0 {
+            incoming.window += credit;
+            self.credit(id, credit as usize)?;
+        }
+        Ok(())
+    }
+
+    // Lets the peer send `size` more bytes on the stream, or on the connection
+    // for stream 0
+    fn credit(&mut self, id: u32, size: usize) -> Result<(), Error> {
+        if size == 0 {
+            return Ok(());
+        }
+        if id == 0 {
+            self.recv_window += size as i64;
+        }
+        self.write_frame(WINDOW_UPDATE, 0, id, &(size as u32).to_be_bytes())
+    }
+
+    // Takes a stream's request off the connection. Its body is dealt with from
+    // here on, so the connection window gets that room back.
+    fn take_incoming(&mut self, id: u32) -> Result<Option<IncomingStream>, Error> {
+        let incoming = self.incoming.remove(&id);
+        if let Some(incoming) = &incoming {
+            self.credit(0, incoming.body.len())?;
+        }
+        Ok(incoming)
+    }
+
+    fn on_window_update(&mut self, frame: Frame) -> Result<(), Error> {
+        let bytes: [u8; 4] = frame
+            .payload
+            .as_slice()
+            .try_into()
+            .map_err(|_| Error::Connection(FRAME_SIZE_ERROR))?;
+        let increment = (u32::from_be_bytes(bytes) & 0x7fff_ffff) as i64;
+        if frame.stream_id == 0 {
+            if increment == 0 {
+                return Err(Error::Connection(PROTOCOL_ERROR));
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW {
+                return Err(Error::Connection(FLOW_CONTROL_ERROR));
+            }
+            return Ok(());
+        }
+        let Some(window) = self.stream_windows.get_mut(&frame.stream_id) else {
+            // Closed streams may still see updates in flight
+            return Ok(());
+        };
+        *window += increment;
+        if increment == 0 || *window > MAX_WINDOW {
+            return self.reset(frame.stream_id, FLOW_CONTROL_ERROR);
+        }
+        Ok(())
+    }
+
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<(), Error> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(Error::Connection(FRAME_SIZE_ERROR));
+        }
+        for setting in payload.chunks_exact(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW {
+                        return Err(Error::Connection(FLOW_CONTROL_ERROR));
+                    }
+                    // Applies retroactively to every open stream
+                    let delta = value - self.peer_initial_window;
+                    for window in self.stream_windows.values_mut() {
+                        *window += delta;
+                    }
+                    self.peer_initial_window = value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(16_384..=16_777_215).contains(&value) {
+                        return Err(Error::Connection(PROTOCOL_ERROR));
+                    }
+                    self.peer_max_frame = value as usize;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // The request on `id` is complete: route it and queue the response
+    fn complete(&mut self, id: u32) -> Result<(), Error> {
+        let Some(incoming) = self.take_incoming(id)? else {
+            return Ok(());
+        };
+        match build_request(incoming) {
+            Some(request) => self.respond(id, request),
+            None => self.reset(id, PROTOCOL_ERROR),
+        }
+    }
+
+    fn respond(&mut self, id: u32, request: Request) -> Result<(), Error> {
+        let mut response = process_request(&request, self.peer, self.state);
+        match response.takeover.take() {
+            // Queued whole, to go out as the stream's window allows
+            Some(Takeover::Export(export)) => {
+                if let Err(e) = export.write_to(&mut response.body) {
+                    log_error(&format!("Failed to export todos: {}", e));
+                    response = ("500 Internal Server Error", e.to_string()).into();
+                }
+            }
+            Some(_) => {
+                let error = "Long-lived responses are only served over HTTP/1.1.";
+                log_error(error);
+                response = ("501 Not Implemented", error.to_string()).into();
+            }
+            None => {}
+        }
+        self.send_response(id, response, false)
+    }
+
+    fn send_response(
+        &mut self,
+        id: u32,
+        response: Response,
+        reset_after: bool,
+    ) -> Result<(), Error> {
+        let status = response.status.split(' ').next().unwrap_or("500");
+        let content_length = response.body.len().to_string();
+        let date = http_date();
+        let names: Vec<String> = response
+            .headers
+            .iter()
+            .map(|(name, _)| name.to_ascii_lowercase())
+            .collect();
+        let mut fields = vec![(":status", status)];
+        if !response.content_type.is_empty() {
+            fields.push(("content-type", response.content_type));
+        }
+        fields.push(("content-length", content_length.as_str()));
+        fields.push(("date", date.as_str()));
+        for (name, (_, value)) in names.iter().zip(&response.headers) {
+            // Connection-specific fields are HTTP/1 only (RFC 9113 8.2.2)
+            if !is_connection_specific(name) {
+                fields.push((name.as_str(), value.as_str()));
+            }
+        }
+        let block = hpack::encode(&fields);
+
+        // Header blocks aren't flow controlled but may need several frames
+        let end_stream = response.body.is_empty();
+        let mut chunks = block.chunks(self.peer_max_frame).peekable();
+        let mut kind = HEADERS;
+        while let Some(chunk) = chunks.next() {
+            let mut flags = if kind == HEADERS && end_stream {
+                END_STREAM
+            } else {
+                0
+            };
+            if chunks.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            self.write_frame(kind, flags, id, chunk)?;
+            kind = CONTINUATION;
+        }
+
+        if end_stream {
+            self.stream_windows.remove(&id);
+            if reset_after {
+                self.reset(id, NO_ERROR)?;
+            }
+        } else {
+            self.outgoing.push_back(OutgoingData {
+                stream_id: id,
+                data: response.body,
+                sent: 0,
+                reset_after,
+            });
+        }
+        Ok(())
+    }
+
+    // Sends as much queued response data as the windows allow, taking turns
+    // between streams so one large body can't hold up the others
+    fn flush(&mut self) -> Result<(), Error> {
+        let mut progressed = true;
+        while progressed && self.send_window > 0 {
+            progressed = false;
+            for _ in 0..self.outgoing.len() {
+                let Some(mut outgoing) = self.outgoing.pop_front() else {
+                    break;
+                };
+                let id = outgoing.stream_id;
+                let stream_window = self.stream_windows.get(&id).copied().unwrap_or(0);
+                let allowed = stream_window.min(self.send_window).max(0) as usize;
+                let size = allowed
+                    .min(self.peer_max_frame)
+                    .min(outgoing.data.len() - outgoing.sent);
+                if size == 0 {
+                    self.outgoing.push_back(outgoing);
+                    continue;
+                }
+
+                let end = outgoing.sent + size;
+                let done = end == outgoing.data.len();
+                let flags = if done { END_STREAM } else { 0 };
+                self.write_frame(DATA, flags, id, &outgoing.data[outgoing.sent..end])?;
+                self.send_window -= size as i64;
+                if let Some(window) = self.stream_windows.get_mut(&id) {
+                    *window -= size as i64;
+                }
+                outgoing.sent = end;
+                progressed = true;
+
+                if done {
+                    self.stream_windows.remove(&id);
+                    if outgoing.reset_after {
+                        self.reset(id, NO_ERROR)?;
+                    }
+                } else {
+                    self.outgoing.push_back(outgoing);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self, id: u32, code: u32) -> Result<(), Error> {
+        self.stream_windows.remove(&id);
+        self.write_frame(RST_STREAM, 0, id, &code.to_be_bytes())
+    }
+
+    fn go_away(&mut self, code: u32) -> Result<(), Error> {
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        self.write_frame(GOAWAY, 0, 0, &payload)
+    }
+
+    fn read_frame(&mut self) -> Result<Frame, Error> {
+        self.fill(9)?;
+        let length = u32::from_be_bytes([0, self.input[0], self.input[1], self.input[2]]) as usize;
+        if length > MAX_FRAME_SIZE {
+            return Err(Error::Connection(FRAME_SIZE_ERROR));
+        }
+        self.fill(9 + length)?;
+        let header: Vec<u8> = self.input.drain(..9).collect();
+        let payload = self.input.drain(..length).collect();
+        Ok(Frame {
+            kind: header[3],
+            flags: header[4],
+            stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]])
+                & 0x7fff_ffff,
+            payload,
+        })
+    }
+
+    // The same deadlines an HTTP/1.1 connection gets, so frames that keep
+    // trickling in can't hold on to a worker: each open stream has the request
+    // deadline to finish, and an idle connection the header timeout to begin
+    // another. PINGs and other connection-level frames don't count. No
+    // connection outlives `http2_max_connection_secs`.
+    fn deadline(&mut self) -> Instant {
+        let open = self.started.len();
+        let (incoming, windows) = (&self.incoming, &self.stream_windows);
+        self.started
+            .retain(|id, _| incoming.contains_key(id) || windows.contains_key(id));
+        if self.started.len() < open {
+            self.active_at = Instant::now();
+        }
+        let config = &self.state.config;
+        let deadline = match self.started.values().min() {
+            Some(oldest) => *oldest + config.request_deadline(),
+            None => self.active_at + config.header_timeout(),
+        };
+        deadline.min(self.closes_at)
+    }
+
+    // Reads until at least `len` bytes are buffered or a deadline passes
+    fn fill(&mut self, len: usize) -> Result<(), Error> {
+        let mut buffer = [0; 16_384];
+        while self.input.len() < len {
+            let remaining = self.deadline().saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(Error::Closed);
+            }
+            let read_timeout = self
+                .state
+                .config
+                .read_timeout()
+                .min(remaining)
+                .max(Duration::from_millis(1));
+            if self.stream.set_read_timeout(Some(read_timeout)).is_err() {
+                return Err(Error::Closed);
+            }
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Err(Error::Closed),
+                Ok(read) => self.input.extend_from_slice(&buffer[..read]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return Err(Error::Closed),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        stream_id: u32,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        let length = (payload.len() as u32).to_be_bytes();
+        let mut frame = Vec::with_capacity(9 + payload.len());
+        frame.extend_from_slice(&length[1..]);
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame).map_err(|e| {
+            log_error(&format!("Stream write error: {}", e));
+            Error::Closed
+        })
+    }
+}
+
+fn strip_padding(frame: &Frame) -> Result<&[u8], Error> {
+    if frame.flags & PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    let (&padding, rest) = frame
+        .payload
+        .split_first()
+        .ok_or(Error::Connection(PROTOCOL_ERROR))?;
+    rest.len()
+        .checked_sub(padding as usize)
+        .map(|len| &rest[..len])
+        .ok_or(Error::Connection(PROTOCOL_ERROR))
+}
+
+// Turns a stream's fields into the request the router expects. None for
+// malformed requests (RFC 9113 8.1.1), which get their stream reset.
+fn build_request(incoming: IncomingStream) -> Option<Request> {
+    let mut method = None;
+    let mut path = None;
+    let mut authority = None;
+    let mut headers = Headers::new();
+    for (name, value) in incoming.fields {
+        match name.as_str() {
+            ":method" if headers.is_empty() => method = Some(value),
+            ":path" if headers.is_empty() && !value.is_empty() => path = Some(value),
+            ":authority" if headers.is_empty() => authority = Some(value),
+            ":scheme" if headers.is_empty() => {}
+            _ if name.starts_with(':') => return None,
+            // Field names must be lowercase, and connection-specific fields
+            // have no meaning in HTTP/2
+            _ if name.bytes().any(|b| b.is_ascii_uppercase()) => return None,
+            _ if is_connection_specific(&name) => return None,
+            _ => headers.append(&name, &value),
+        }
+    }
+    if headers.get("host").is_none() {
+        if let Some(authority) = authority {
+            headers.append("host", &authority);
+        }
+    }
+    if let Some(length) = headers.get("content-length") {
+        if length.parse::<usize>().ok() != Some(incoming.body.len()) {
+            return None;
+        }
+    }
+    Some(Request {
+        method: method?,
+        target: path?,
+        version: Version::Http2,
+        headers,
+        body: incoming.body,
+    })
+}
+
+fn is_connection_specific(name: &str) -> bool {
+    matches!(
+        name,
+        "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade"
+    )
+}
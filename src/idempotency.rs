@@ -0,0 +1,128 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const MAX_KEY_LEN: usize = 255;
+
+struct CachedResponse {
+    fingerprint: u64,
+    status: &'static str,
+    body: String,
+    created: Instant,
+}
+
+pub enum Outcome {
+    Fresh(&'static str, String),
+    Replayed(&'static str, String),
+    Mismatch,
+}
+
+// Remembers the response produced for each Idempotency-Key so that a retried
+// request gets the original answer instead of creating a second todo.
+pub struct IdempotencyCache {
+    ttl: Duration,
+    max_keys: usize,
+    slots: Mutex<Slots>,
+}
+
+struct Slots {
+    by_key: HashMap<String, Arc<Slot>>,
+    // Keys in the order they were first seen, oldest first
+    order: VecDeque<String>,
+    // When expired slots were last dropped
+    swept: Instant,
+}
+
+// One key's response. Its lock is held while the request that produces the
+// response runs, so requests with other keys aren't held up.
+#[derive(Default)]
+struct Slot {
+    response: Mutex<Option<CachedResponse>>,
+}
+
+impl IdempotencyCache {
+    pub fn new(ttl: Duration, max_keys: usize) -> IdempotencyCache {
+        IdempotencyCache {
+            ttl,
+            max_keys: max_keys.max(1),
+            slots: Mutex::new(Slots {
+                by_key: HashMap::new(),
+                order: VecDeque::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    // Runs `handler` at most once per key within the TTL. Two concurrent
+    // retries wait for each other, and the second gets the first's response.
+    // Server errors aren't kept, so a retry gets to try again.
+    pub fn run<F>(&self, user: &str, key: &str, body: &str, handler: F) -> Outcome
+    where
+        F: FnOnce() -> (&'static str, String),
+    {
+        // Keys are only unique per user, so two users may pick the same one
+        let slot = self.slot(format!("{}\0{}", user, key));
+        let fingerprint = fingerprint(body);
+        let mut response = slot.response.lock().unwrap();
+        if let Some(cached) = response
+            .as_ref()
+            .filter(|cached| cached.created.elapsed() < self.ttl)
+        {
+            if cached.fingerprint != fingerprint {
+                return Outcome::Mismatch;
+            }
+            return Outcome::Replayed(cached.status, cached.body.clone());
+        }
+
+        let (status, response_body) = handler();
+        *response = (!status.starts_with('5')).then(|| CachedResponse {
+            fingerprint,
+            status,
+            body: response_body.clone(),
+            created: Instant::now(),
+        });
+        Outcome::Fresh(status, response_body)
+    }
+
+    fn slot(&self, key: String) -> Arc<Slot> {
+        let mut slots = self.slots.lock().unwrap();
+        // At most once per TTL, drop the slots nobody is using that hold no
+        // live response. Nobody else can pick one up while the map is locked.
+        if slots.swept.elapsed() >= self.ttl {
+            let ttl = self.ttl;
+            slots.by_key.retain(|_, slot| {
+                Arc::strong_count(slot) > 1
+                    || slot
+                        .response
+                        .lock()
+                        .unwrap()
+                        .as_ref()
+                        .is_some_and(|cached| cached.created.elapsed() < ttl)
+            });
+            let Slots { by_key, order, .. } = &mut *slots;
+            order.retain(|key| by_key.contains_key(key));
+            slots.swept = Instant::now();
+        }
+        if let Some(slot) = slots.by_key.get(&key) {
+            return Arc::clone(slot);
+        }
+        // A burst of new keys can't outgrow the cap before the next sweep. An
+        // evicted key whose request is still running just isn't kept after.
+        while slots.by_key.len() >= self.max_keys {
+            let Some(oldest) = slots.order.pop_front() else {
+                break;
+            };
+            slots.by_key.remove(&oldest);
+        }
+        slots.order.push_back(key.clone());
+        Arc::clone(slots.by_key.entry(key).or_default())
+    }
+}
+
+fn fingerprint(body: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    hasher.finish()
+}
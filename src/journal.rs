@@ -0,0 +1,155 @@
+// Durable storage: every change to the store is appended to a journal file,
+// one JSON object per line, and replaying the journal rebuilds the store.
+use crate::audit::AuditEvent;
+use crate::snapshot;
+use crate::store::Store;
+use crate::{Todo, log_error};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Change {
+    // The todo as it now is, whether new or updated
+    Put { todo: Todo },
+    Remove { owner: String, id: usize },
+    Clear,
+    Audit { event: AuditEvent },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> Result<Journal, String> {
+        Ok(Journal {
+            path: path.to_path_buf(),
+            file: Mutex::new(open_append(path)?),
+        })
+    }
+
+    // Moves the entries written so far to `archive` and starts an empty journal
+    pub fn rotate(&self, archive: &Path) -> Result<(), String> {
+        let mut file = self.file.lock().unwrap();
+        fs::rename(&self.path, archive)
+            .map_err(|e| format!("Failed to archive journal {}: {}", self.path.display(), e))?;
+        *file = open_append(&self.path)?;
+        Ok(())
+    }
+
+    // Changes are written with a single write so a batch is never interleaved
+    // with other writers. A write that fails part way is cut off again, so the
+    // journal never holds part of a batch.
+    pub fn append(&self, changes: &[Change]) -> Result<(), String> {
+        let at = Utc::now();
+        let mut lines = String::new();
+        for change in changes {
+            let entry = Entry {
+                at,
+                change: change.clone(),
+            };
+            lines.push_str(&serde_json::to_string(&entry).unwrap());
+            lines.push('\n');
+        }
+        let mut file = self.file.lock().unwrap();
+        let error = |e: std::io::Error| format!("Failed to write to journal: {}", e);
+        let length = file.metadata().map_err(error)?.len();
+        if let Err(e) = file.write_all(lines.as_bytes()) {
+            if let Err(e) = file.set_len(length) {
+                log_error(&format!("Failed to cut off a partial journal write: {}", e));
+            }
+            return Err(error(e));
+        }
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open journal {}: {}", path.display(), e))
+}
+
+pub fn journal_path(data_dir: &str) -> PathBuf {
+    Path::new(data_dir).join("journal.jsonl")
+}
+
+// Journal entries in order. A last line without its newline was torn by a
+// crash mid-write and is ignored; damage anywhere else is an error.
+pub fn read_entries(path: &Path) -> Result<Vec<Entry>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read journal {}: {}", path.display(), e)),
+    };
+    let complete = &text[..text.rfind('\n').map_or(0, |end| end + 1)];
+    complete
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| format!("Journal {} line {}: {}", path.display(), index + 1, e))
+        })
+        .collect()
+}
+
+// Cuts off a torn last line so new entries start on a line of their own
+fn truncate_torn_line(path: &Path) -> Result<(), String> {
+    let Ok(text) = fs::read(path) else {
+        return Ok(());
+    };
+    if text.is_empty() || text.ends_with(b"\n") {
+        return Ok(());
+    }
+    let end = text
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |end| end + 1);
+    OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(end as u64))
+        .map_err(|e| format!("Failed to repair journal {}: {}", path.display(), e))
+}
+
+// Rebuilds the store kept in `data_dir` from the latest snapshot and the
+// journal, then attaches the journal so further changes are persisted.
+// Entries from before the snapshot may still be in the journal if a crash cut
+// the rotation short; replaying them again ends in the same state.
+pub fn open_store(data_dir: &str) -> Result<Store, String> {
+    fs::create_dir_all(data_dir)
+        .map_err(|e| format!("Failed to create data directory {}: {}", data_dir, e))?;
+    let path = journal_path(data_dir);
+    let mut store = match snapshot::list(data_dir)?.last() {
+        Some(latest) => snapshot::load(&latest.path)?,
+        None => Store::new(),
+    };
+    for entry in read_entries(&path)? {
+        store.apply(entry.change);
+    }
+    attach(store, data_dir)
+}
+
+// Persists further changes to `store` in the journal kept in `data_dir`
+pub fn attach(store: Store, data_dir: &str) -> Result<Store, String> {
+    let path = journal_path(data_dir);
+    truncate_torn_line(&path)?;
+    let journal = Journal::open(&path)?;
+    Ok(store.with_journal(Arc::new(journal)))
+}
@@ -1,21 +1,236 @@
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
 use std::fs::OpenOptions;
 use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::net::{IpAddr, TcpStream};
 use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
+use std::time::{Duration, Instant};
+
+pub mod audit;
+pub mod auth;
+mod changes;
+mod compression;
+pub mod config;
+mod connections;
+mod cors;
+mod crypto;
+#[cfg(feature = "event-loop")]
+pub mod event_loop;
+pub mod feed;
+mod format;
+mod hpack;
+pub mod http;
+mod http2;
+mod idempotency;
+pub mod journal;
+pub mod policy;
+mod ratelimit;
+mod search;
+mod signals;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+mod sse;
+mod store;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transfer;
+pub mod webhooks;
+mod websocket;
+
+use audit::{Action, AuditEvent, AuditQuery};
+use auth::{AuthError, Authentication, Principal};
+use compression::DecodeError;
+pub use config::Config;
+use connections::{Capacity, ConnectionTracker};
+use feed::TodoEvent;
+use format::Format;
+use http::{Headers, ParseError, Progress, Request};
+use idempotency::{IdempotencyCache, Outcome};
+use policy::{Operation, Policy};
+use ratelimit::{Decision, RateLimiter};
+pub use store::Store;
+use transfer::{Conflict, ImportMode};
+use webhooks::{NewWebhook, Webhooks};
+
+// Reloads credentials whenever the process receives SIGHUP. A no-op on
+// platforms without signals.
+pub fn spawn_sighup_reloader(state: Arc<AppState>) {
+    if !signals::install_hangup_handler() {
+        return;
+    }
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            if signals::take_hangup() {
+                match state.auth.reload() {
+                    Ok(()) => println!("Reloaded credentials."),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        log_error(&e);
+                    }
+                }
+            }
+        }
+    });
+}
+
+// Owner of todos created by requests that don't name a user
+pub const DEFAULT_USER: &str = "default";
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Todo {
     pub id: usize,
+    pub owner: String,
     pub title: String,
     pub completed: bool,
+    // Set when the todo is moved to the trash; cleared again on restore
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+impl Todo {
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+}
+
+pub type Db = Arc<Mutex<Store>>;
+
+// Everything a request handler needs besides the request itself. One instance
+// is shared by all connections of a server.
+pub struct AppState {
+    pub db: Db,
+    pub config: Config,
+    pub auth: Authentication,
+    // None means no policy is configured and every caller may do anything
+    pub policy: Option<Policy>,
+    pub webhooks: Arc<Webhooks>,
+    idempotency: IdempotencyCache,
+    rate_limiter: RateLimiter,
+    connections: ConnectionTracker,
+    // Connections on threads of their own
+    takeovers: Capacity,
+    // HTTP/2 connections, each holding a worker
+    http2: Capacity,
+}
+
+impl AppState {
+    pub fn new(db: Db, config: Config) -> AppState {
+        let idempotency =
+            IdempotencyCache::new(config.idempotency_ttl(), config.idempotency_max_keys);
+        let auth = Authentication::from_config(&config.auth);
+        let policy = config.policy_file.as_ref().map(|path| {
+            Policy::load(path).unwrap_or_else(|e| {
+                // Deny everything rather than silently dropping authorization
+                eprintln!("{}", e);
+                log_error(&e);
+                Policy::default()
+            })
+        });
+        let webhooks = Webhooks::open(config.webhooks.clone(), config.webhook_state_file())
+            .unwrap_or_else(|e| {
+                // Kept in memory, so the damaged file isn't overwritten
+                eprintln!("{}", e);
+                log_error(&e);
+                Webhooks::in_memory(config.webhooks.clone())
+            });
+        let rate_limiter = RateLimiter::new(config.rate_limit.clone());
+        let connections = ConnectionTracker::new(config.max_connections_per_ip);
+        let takeovers = Capacity::new(config.max_takeovers);
+        let http2 = Capacity::new(config.http2_max_connections());
+        AppState {
+            db,
+            config,
+            auth,
+            policy,
+            webhooks: Arc::new(webhooks),
+            idempotency,
+            rate_limiter,
+            connections,
+            takeovers,
+            http2,
+        }
+    }
+
+    // Anyone can claim a user through X-User-Id, so only authenticated
+    // principals get the roles a policy assigns
+    pub fn permits(&self, principal: &Principal, operation: Operation) -> bool {
+        self.policy.as_ref().is_none_or(|policy| {
+            principal.is_authenticated() && policy.permits(&principal.user, operation)
+        })
+    }
+}
+
+pub struct Response {
+    pub status: &'static str,
+    pub headers: Vec<(String, String)>,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+    // Set when the connection is handed over once the head is written
+    pub(crate) takeover: Option<Takeover>,
+}
+
+// Responses that hold on to a connection: long-lived protocols that take it
+// over after the response head, and requests waiting for something to answer
+pub(crate) enum Takeover {
+    Events(Box<sse::EventStream>),
+    WebSocket(Box<websocket::Session>),
+    Changes(Box<changes::Poll>),
+    Export(Box<transfer::Export>),
+}
+
+impl Takeover {
+    // Writes `response` and serves the connection until it is done
+    fn run<S: Connection>(self, stream: &mut S, response: Response, state: &AppState) {
+        match self {
+            Takeover::Events(events) => {
+                if write_head(stream, &response) {
+                    events.run(stream);
+                }
+                stream.close();
+            }
+            Takeover::WebSocket(session) => {
+                if write_head(stream, &response) {
+                    session.run(stream, state);
+                }
+                stream.close();
+            }
+            Takeover::Changes(poll) => write_response(stream, poll.wait(response, state)),
+            // The body runs until the connection closes
+            Takeover::Export(export) => {
+                if write_head(stream, &response) {
+                    if let Err(e) = export.write_to(stream) {
+                        log_error(&format!("Stream write error: {}", e));
+                    }
+                }
+                stream.close();
+            }
+        }
+    }
 }
 
-pub type Db = Arc<Mutex<HashMap<String, Todo>>>;
+impl Response {
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+// Handlers return a bare status and body; headers are added on the way out
+impl From<(&'static str, String)> for Response {
+    fn from((status, body): (&'static str, String)) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            content_type: format::JSON_CONTENT_TYPE,
+            body: body.into_bytes(),
+            takeover: None,
+        }
+    }
+}
 
 #[derive(Deserialize)]
 struct UpdateTodoRequest {
@@ -23,9 +238,73 @@ struct UpdateTodoRequest {
     completed: Option<bool>,
 }
 
+const MAX_BULK_OPERATIONS: usize = 10_000;
+
+#[derive(Deserialize)]
+struct BulkRequest {
+    #[serde(default)]
+    atomic: bool,
+    // Kept as raw values so one malformed item fails on its own instead of
+    // rejecting the whole batch
+    operations: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BulkOperation {
+    Create {
+        title: String,
+    },
+    Update {
+        id: usize,
+        title: Option<String>,
+        completed: Option<bool>,
+    },
+    Delete {
+        id: usize,
+    },
+}
+
+#[derive(Serialize)]
+struct BulkResult {
+    index: usize,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    todo: Option<Todo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl BulkResult {
+    fn success(index: usize, status: u16, todo: Todo) -> BulkResult {
+        BulkResult {
+            index,
+            status,
+            todo: Some(todo),
+            error: None,
+        }
+    }
+
+    fn failure(index: usize, status: u16, error: &str) -> BulkResult {
+        BulkResult {
+            index,
+            status,
+            todo: None,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BulkResponse {
+    atomic: bool,
+    committed: bool,
+    results: Vec<BulkResult>,
+}
+
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
+    sender: Option<mpsc::Sender<Job>>,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
@@ -43,7 +322,10 @@ impl ThreadPool {
             workers.push(Worker::new(id, Arc::clone(&receiver)));
         }
 
-        ThreadPool { workers, sender }
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+        }
     }
 
     pub fn execute<F>(&self, f: F)
@@ -52,7 +334,21 @@ impl ThreadPool {
     {
         println!("Sending job to thread pool.");
         let job = Box::new(f);
-        self.sender.send(job).unwrap();
+        self.sender.as_ref().unwrap().send(job).unwrap();
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Closing the channel makes every worker's recv() fail and exit its loop
+        drop(self.sender.take());
+
+        for worker in &mut self.workers {
+            println!("Shutting down worker {}", worker.id);
+            if let Some(thread) = worker.thread.take() {
+                thread.join().unwrap();
+            }
+        }
     }
 }
 
@@ -108,8 +404,35 @@ fn validate_todo_completed(completed: &Option<bool>) -> Result<(), &'static str>
     }
 }
 
+// Works out which user a request acts for. With authentication configured the
+// credentials decide; otherwise requests name their user with X-User-Id and
+// those without it belong to DEFAULT_USER. When accounts are configured, only
+// those user ids are accepted either way.
+fn authenticate(headers: &Headers, state: &AppState) -> Result<Principal, AuthError> {
+    let principal = match state.auth.authenticate(headers)? {
+        Some(principal) => principal,
+        None => match headers.get("X-User-Id").map(str::trim) {
+            None => Principal {
+                user: DEFAULT_USER.to_string(),
+                scheme: "anonymous",
+            },
+            Some("") => return Err(state.auth.error("Invalid user id.")),
+            Some(user) => Principal {
+                user: user.to_string(),
+                scheme: "header",
+            },
+        },
+    };
+
+    let users = &state.config.users;
+    if !users.is_empty() && !users.contains(&principal.user) {
+        return Err(state.auth.error("Unknown user."));
+    }
+    Ok(principal)
+}
+
 // Function to log errors to a file
-fn log_error(message: &str) {
+pub(crate) fn log_error(message: &str) {
     let mut file = OpenOptions::new()
         .create(true)
         .append(true)
@@ -119,166 +442,495 @@ fn log_error(message: &str) {
     writeln!(file, "[{}] {}", timestamp, message).expect("Failed to write to error log.");
 }
 
-pub fn process_request(request: &str, db: Db) -> (&'static str, String) {
-    let mut lines = request.lines();
-    if let Some(first_line) = lines.next() {
-        let parts: Vec<&str> = first_line.split_whitespace().collect();
-        if parts.len() != 3 {
-            let error = "Invalid request line.";
-            log_error(error);
-            return ("400 Bad Request", error.to_string());
+// `peer` is the client address, used to rate limit callers that have not
+// authenticated
+pub fn process_request(request: &Request, peer: Option<IpAddr>, state: &AppState) -> Response {
+    let headers = &request.headers;
+    let body = match compression::decode_body(headers, &request.body, state.config.limits.max_body)
+    {
+        Ok(body) => body,
+        Err(e) => {
+            log_error(e.message());
+            let response = Response::from((e.status(), e.message().to_string()));
+            return match e {
+                DecodeError::Unsupported => {
+                    response.with_header("Accept-Encoding", compression::accept_encoding())
+                }
+                _ => response,
+            };
+        }
+    };
+    let method = request.method.as_str();
+    // Preflights come without credentials, so they are answered before
+    // authentication and rate limiting
+    if method == "OPTIONS" {
+        return cors::preflight(headers, state.config.cors.as_ref());
+    }
+    // Whole-database transfers speak JSON lines unless told otherwise
+    let path = request.path();
+    let (response_default, request_default) = match path {
+        "/admin/export" => (&format::JSON_LINES, &format::JSON),
+        "/admin/import" => (&format::JSON, &format::JSON_LINES),
+        _ => (&format::JSON, &format::JSON),
+    };
+    // Event streams and WebSockets have formats of their own
+    let negotiated = match path {
+        sse::PATH | websocket::PATH => Some(&format::JSON),
+        _ => format::negotiate(headers, response_default),
+    };
+    // Refused before routing so nothing is changed for a response the
+    // client can't read
+    let Some(format) = negotiated else {
+        log_error("No acceptable media type.");
+        return format::not_acceptable();
+    };
+    let body = match format::decode_request(headers, &body, request_default) {
+        Ok(body) => body,
+        Err(response) => {
+            log_error("Request body could not be decoded.");
+            return response;
         }
-        let method = parts[0];
-        let path = parts[1];
-        let version = parts[2];
+    };
+    let Ok(body) = std::str::from_utf8(&body) else {
+        let error = "Request body is not valid UTF-8.";
+        log_error(error);
+        return ("400 Bad Request", error.to_string()).into();
+    };
 
-        if version != "HTTP/1.1" && version != "HTTP/1.0" && version != "HTTP/2.0" {
-            let error = "HTTP version is not supported.";
-            log_error(error);
-            return ("505 HTTP Version Not Supported", error.to_string());
+    let response = dispatch(request, body, format, peer, state);
+    let response = format::encode_response(response, format);
+    let response = cors::apply(response, headers, state.config.cors.as_ref());
+    compression::apply(response, headers, &state.config.compression)
+}
+
+// `body` is the request's, already decoded
+fn dispatch(
+    request: &Request,
+    body: &str,
+    format: &'static Format,
+    peer: Option<IpAddr>,
+    state: &AppState,
+) -> Response {
+    let (method, path, query) = (request.method.as_str(), request.path(), request.query());
+    let headers = &request.headers;
+    let authenticated = authenticate(headers, state);
+    let decision = state
+        .rate_limiter
+        .check(method, path, &rate_limit_key(&authenticated, peer));
+    if let Some(decision) = decision.as_ref().filter(|decision| !decision.allowed) {
+        log_error("Rate limit exceeded.");
+        let response = Response::from(("429 Too Many Requests", "Too many requests.".to_string()))
+            .with_header("Retry-After", &decision.retry_after_secs.to_string());
+        return with_rate_limit_headers(response, decision);
+    }
+
+    let response = match authenticated {
+        Ok(principal) if method == "GET" && path == sse::PATH => {
+            sse::open(headers, &principal, state)
+        }
+        Ok(principal) if method == "GET" && path == changes::PATH => {
+            changes::open(query, headers, &principal, state)
+        }
+        Ok(principal) if method == "GET" && path == websocket::PATH => {
+            websocket::open(headers, &principal, peer, state)
+        }
+        // Refused by `route` when not permitted
+        Ok(principal)
+            if method == "GET"
+                && path == "/admin/export"
+                && state.permits(&principal, Operation::Admin) =>
+        {
+            export_todos(format, state)
+        }
+        Ok(principal) => route(method, path, query, headers, body, &principal, state).into(),
+        Err(e) => {
+            log_error(e.message);
+            let mut response = Response::from(("401 Unauthorized", e.message.to_string()));
+            for challenge in e.challenges {
+                response = response.with_header("WWW-Authenticate", &challenge);
+            }
+            response
         }
+    };
+    match decision {
+        Some(decision) => with_rate_limit_headers(response, &decision),
+        None => response,
+    }
+}
+
+// Authenticated callers are limited per user, so clients behind a shared
+// address don't starve each other. Everyone else, including callers who merely
+// claim a user through X-User-Id, is limited per address.
+fn rate_limit_key(authenticated: &Result<Principal, AuthError>, peer: Option<IpAddr>) -> String {
+    match authenticated {
+        Ok(principal) if principal.is_authenticated() => {
+            format!("user:{}", principal.user)
+        }
+        _ => peer.map_or_else(|| "unknown".to_string(), |ip| format!("ip:{}", ip)),
+    }
+}
+
+fn with_rate_limit_headers(response: Response, decision: &Decision) -> Response {
+    response
+        .with_header("RateLimit-Limit", &decision.limit.to_string())
+        .with_header("RateLimit-Remaining", &decision.remaining.to_string())
+        .with_header("RateLimit-Reset", &decision.reset_secs.to_string())
+}
 
-        let mut headers = HashMap::new();
-        let mut content_length = None;
-        for line in lines.by_ref() {
-            if line.is_empty() {
-                break;
+fn route(
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &Headers,
+    body: &str,
+    principal: &Principal,
+    state: &AppState,
+) -> (&'static str, String) {
+    let db = Arc::clone(&state.db);
+    let user = &principal.user;
+    // Who changes are recorded against in the audit log. Todo routes only
+    // reach the principal's own todos; an admin import reaches anyone's.
+    let actor = &principal.user;
+    if let Some(operation) = required_operation(method, path) {
+        if !state.permits(principal, operation) {
+            return forbidden(principal, operation, state);
+        }
+    }
+    match method {
+        "GET" => {
+            if path == "/todos/search" {
+                let q = query_param(query, "q").unwrap_or_default();
+                return search_todos(user, &q, db);
+            }
+            if path == "/audit" {
+                return audit_log(query, db);
             }
-            if let Some((key, value)) = line.split_once(": ") {
-                if key.eq_ignore_ascii_case("Content-Length") {
-                    content_length = Some(value.parse().unwrap_or(0));
+            if path == "/webhooks" {
+                return list_webhooks(user, state);
+            }
+            if let Some(id_str) = path
+                .strip_prefix("/webhooks/")
+                .and_then(|rest| rest.strip_suffix("/deliveries"))
+            {
+                if let Ok(id) = id_str.parse::<usize>() {
+                    return webhook_deliveries(user, id, state);
                 }
-                headers.insert(key.to_string(), value.to_string());
-            } else {
-                let error = "Invalid header format.";
+                let error = "Invalid ID.";
                 log_error(error);
                 return ("400 Bad Request", error.to_string());
             }
-        }
-
-        let body: String = lines.collect::<Vec<&str>>().join("\n");
-        let body = &body[..content_length.unwrap_or(0)];
-
-        match method {
-            "GET" => {
-                if path == "/todos" {
-                    return process_request_get_todos(db);
-                } else if path.starts_with("/todos/") {
-                    if let Some(id_str) = path.strip_prefix("/todos/") {
-                        if let Ok(id) = id_str.parse::<usize>() {
-                            return get_todo(id, db);
-                        }
-                    }
-                    let error = "Invalid ID.";
-                    log_error(error);
-                    return ("400 Bad Request", error.to_string());
+            if let Some(id_str) = path
+                .strip_prefix("/todos/")
+                .and_then(|rest| rest.strip_suffix("/history"))
+            {
+                if let Ok(id) = id_str.parse::<usize>() {
+                    return todo_history(user, id, db);
                 }
-                let error = "Endpoint not found.";
+                let error = "Invalid ID.";
                 log_error(error);
-                return ("404 Not Found", error.to_string());
-            }
-            "POST" => {
-                if path == "/todos" {
-                    match serde_json::from_str::<Value>(body) {
-                        Ok(json) => {
-                            if let Some(title) = json.get("title").and_then(|v| v.as_str()) {
-                                if let Err(e) = validate_todo_title(title) {
-                                    log_error(e);
-                                    return ("400 Bad Request", e.to_string());
-                                }
-                                let title = title.to_string();
-                                return create_todo(title, db);
-                            } else {
-                                let error = "Title is required.";
-                                log_error(error);
-                                return ("400 Bad Request", error.to_string());
-                            }
-                        }
-                        Err(_) => {
-                            let error = "Invalid JSON format.";
-                            log_error(error);
-                            return ("400 Bad Request", error.to_string());
-                        }
+                return ("400 Bad Request", error.to_string());
+            }
+            if path == "/todos" {
+                let deleted = query_param(query, "deleted").as_deref() == Some("true");
+                return process_request_get_todos(user, deleted, db);
+            } else if path.starts_with("/todos/") {
+                if let Some(id_str) = path.strip_prefix("/todos/") {
+                    if let Ok(id) = id_str.parse::<usize>() {
+                        return get_todo(user, id, db);
                     }
                 }
-                let error = "Endpoint not found.";
+                let error = "Invalid ID.";
                 log_error(error);
-                return ("404 Not Found", error.to_string());
-            }
-            "PUT" => {
-                if path.starts_with("/todos/") {
-                    if let Some(id_str) = path.strip_prefix("/todos/") {
-                        if let Ok(id) = id_str.parse::<usize>() {
-                            match serde_json::from_str::<UpdateTodoRequest>(body) {
-                                Ok(update_req) => {
-                                    if let Some(ref title) = update_req.title {
-                                        if let Err(e) = validate_todo_title(title) {
-                                            log_error(e);
-                                            return ("400 Bad Request", e.to_string());
-                                        }
-                                    }
-                                    if let Err(e) = validate_todo_completed(&update_req.completed) {
+                return ("400 Bad Request", error.to_string());
+            }
+            let error = "Endpoint not found.";
+            log_error(error);
+            ("404 Not Found", error.to_string())
+        }
+        "POST" => {
+            if path == "/todos/bulk" {
+                return bulk_todos(user, actor, body, db, |operation| {
+                    state.permits(principal, operation)
+                });
+            }
+            if path == "/admin/import" {
+                return import_todos(actor, query, body, db);
+            }
+            if path == "/webhooks" {
+                return create_webhook(user, body, state);
+            }
+            if path == "/admin/purge" {
+                let retention = query_param(query, "retention_secs")
+                    .and_then(|secs| secs.parse().ok())
+                    .map_or(Duration::ZERO, Duration::from_secs);
+                let purged = purge_deleted_todos(&db, retention);
+                return (
+                    "200 OK",
+                    serde_json::json!({ "purged": purged }).to_string(),
+                );
+            }
+            if let Some(id_str) = path
+                .strip_prefix("/todos/")
+                .and_then(|rest| rest.strip_suffix("/restore"))
+            {
+                if let Ok(id) = id_str.parse::<usize>() {
+                    return restore_todo(user, actor, id, db);
+                }
+                let error = "Invalid ID.";
+                log_error(error);
+                return ("400 Bad Request", error.to_string());
+            }
+            if path == "/todos" {
+                let idempotency_key = headers.get("Idempotency-Key").map(str::trim);
+                return match idempotency_key {
+                    Some(key) => {
+                        process_request_post_todos_idempotent(user, actor, key, body, state)
+                    }
+                    None => process_request_post_todos(user, actor, body, db),
+                };
+            }
+            let error = "Endpoint not found.";
+            log_error(error);
+            ("404 Not Found", error.to_string())
+        }
+        "PUT" => {
+            if path.starts_with("/todos/") {
+                if let Some(id_str) = path.strip_prefix("/todos/") {
+                    if let Ok(id) = id_str.parse::<usize>() {
+                        match serde_json::from_str::<UpdateTodoRequest>(body) {
+                            Ok(update_req) => {
+                                if let Some(ref title) = update_req.title {
+                                    if let Err(e) = validate_todo_title(title) {
                                         log_error(e);
                                         return ("400 Bad Request", e.to_string());
                                     }
-                                    return update_todo(
-                                        id,
-                                        update_req.title,
-                                        update_req.completed,
-                                        db,
-                                    );
                                 }
-                                Err(e) => {
-                                    let error = "Invalid JSON format.";
-                                    log_error(&format!("Error details: {}", e));
-                                    return ("400 Bad Request", error.to_string());
+                                if let Err(e) = validate_todo_completed(&update_req.completed) {
+                                    log_error(e);
+                                    return ("400 Bad Request", e.to_string());
                                 }
+                                return update_todo(
+                                    user,
+                                    actor,
+                                    id,
+                                    update_req.title,
+                                    update_req.completed,
+                                    db,
+                                );
+                            }
+                            Err(e) => {
+                                let error = "Invalid JSON format.";
+                                log_error(&format!("Error details: {}", e));
+                                return ("400 Bad Request", error.to_string());
                             }
                         }
                     }
-                    let error = "Invalid ID.";
-                    log_error(error);
-                    return ("400 Bad Request", error.to_string());
                 }
-                let error = "Endpoint not found.";
+                let error = "Invalid ID.";
                 log_error(error);
-                return ("404 Not Found", error.to_string());
+                return ("400 Bad Request", error.to_string());
             }
-            "DELETE" => {
-                if path.starts_with("/todos/") {
-                    if let Some(id_str) = path.strip_prefix("/todos/") {
-                        if let Ok(id) = id_str.parse::<usize>() {
-                            return delete_todo(id, db);
-                        }
+            let error = "Endpoint not found.";
+            log_error(error);
+            ("404 Not Found", error.to_string())
+        }
+        "DELETE" => {
+            if let Some(id_str) = path.strip_prefix("/webhooks/") {
+                if let Ok(id) = id_str.parse::<usize>() {
+                    return delete_webhook(user, id, state);
+                }
+                let error = "Invalid ID.";
+                log_error(error);
+                return ("400 Bad Request", error.to_string());
+            }
+            if path.starts_with("/todos/") {
+                if let Some(id_str) = path.strip_prefix("/todos/") {
+                    if let Ok(id) = id_str.parse::<usize>() {
+                        return delete_todo(user, actor, id, db);
                     }
-                    let error = "Invalid ID.";
-                    log_error(error);
-                    return ("400 Bad Request", error.to_string());
                 }
-                let error = "Endpoint not found.";
+                let error = "Invalid ID.";
                 log_error(error);
-                return ("404 Not Found", error.to_string());
+                return ("400 Bad Request", error.to_string());
             }
-            _ => {
-                let error = "Method is not allowed.";
+            let error = "Endpoint not found.";
+            log_error(error);
+            ("404 Not Found", error.to_string())
+        }
+        _ => {
+            let error = "Method is not allowed.";
+            log_error(error);
+            ("405 Method Not Allowed", error.to_string())
+        }
+    }
+}
+
+// The permission a route needs. Bulk requests are checked item by item, and
+// unknown routes fall through to their usual 404/405.
+fn required_operation(method: &str, path: &str) -> Option<Operation> {
+    match method {
+        _ if path.starts_with("/admin/") || path == "/audit" => Some(Operation::Admin),
+        // A webhook is sent the user's todos, so managing one is reading them
+        _ if path == "/webhooks" || path.starts_with("/webhooks/") => Some(Operation::Read),
+        "GET" => Some(Operation::Read),
+        "POST" if path == "/todos" => Some(Operation::Create),
+        // Restoring undoes a delete, so it needs the same permission
+        "POST" if path.ends_with("/restore") => Some(Operation::Delete),
+        "PUT" => Some(Operation::Update),
+        "DELETE" => Some(Operation::Delete),
+        _ => None,
+    }
+}
+
+fn forbidden(
+    principal: &Principal,
+    operation: Operation,
+    state: &AppState,
+) -> (&'static str, String) {
+    let role = state
+        .policy
+        .as_ref()
+        .filter(|_| principal.is_authenticated())
+        .and_then(|policy| policy.role_of(&principal.user));
+    log_error(&format!(
+        "User {} may not {} todos.",
+        principal.user,
+        operation.as_str()
+    ));
+    let body = serde_json::json!({
+        "error": "Forbidden.",
+        "user": principal.user,
+        "role": role,
+        "operation": operation.as_str(),
+    });
+    ("403 Forbidden", body.to_string())
+}
+
+fn process_request_post_todos(
+    user: &str,
+    actor: &str,
+    body: &str,
+    db: Db,
+) -> (&'static str, String) {
+    match serde_json::from_str::<Value>(body) {
+        Ok(json) => {
+            if let Some(title) = json.get("title").and_then(|v| v.as_str()) {
+                if let Err(e) = validate_todo_title(title) {
+                    log_error(e);
+                    return ("400 Bad Request", e.to_string());
+                }
+                let title = title.to_string();
+                create_todo(user, actor, title, db)
+            } else {
+                let error = "Title is required.";
                 log_error(error);
-                return ("405 Method Not Allowed", error.to_string());
+                ("400 Bad Request", error.to_string())
+            }
+        }
+        Err(_) => {
+            let error = "Invalid JSON format.";
+            log_error(error);
+            ("400 Bad Request", error.to_string())
+        }
+    }
+}
+
+fn process_request_post_todos_idempotent(
+    user: &str,
+    actor: &str,
+    key: &str,
+    body: &str,
+    state: &AppState,
+) -> (&'static str, String) {
+    if key.is_empty() || key.len() > idempotency::MAX_KEY_LEN {
+        let error = "Invalid Idempotency-Key.";
+        log_error(error);
+        return ("400 Bad Request", error.to_string());
+    }
+    let db = Arc::clone(&state.db);
+    match state.idempotency.run(user, key, body, || {
+        process_request_post_todos(user, actor, body, db)
+    }) {
+        Outcome::Fresh(status, body) | Outcome::Replayed(status, body) => (status, body),
+        Outcome::Mismatch => {
+            let error = "Idempotency-Key was already used with a different request body.";
+            log_error(error);
+            ("422 Unprocessable Entity", error.to_string())
+        }
+    }
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+// Decodes `%XX` escapes and `+` as used in query strings. Malformed escapes
+// are kept as they are.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
             }
+            byte => decoded.push(byte),
         }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[derive(Serialize)]
+struct SearchHit<'a> {
+    #[serde(flatten)]
+    todo: &'a Todo,
+    score: f64,
+}
+
+pub fn search_todos(user: &str, q: &str, db: Db) -> (&'static str, String) {
+    if q.trim().is_empty() {
+        let error = "Search query is required.";
+        log_error(error);
+        return ("400 Bad Request", error.to_string());
     }
-    ("400 Bad Request", "Invalid request.".to_string())
+    let db = db.lock().unwrap();
+    let hits: Vec<SearchHit> = db
+        .search(user, q)
+        .into_iter()
+        .map(|(todo, score)| SearchHit { todo, score })
+        .collect();
+    let body = serde_json::to_string(&hits).unwrap();
+    ("200 OK", body)
 }
 
-fn process_request_get_todos(db: Db) -> (&'static str, String) {
+// Lists live todos, or only the ones in the trash when `deleted` is set
+fn process_request_get_todos(user: &str, deleted: bool, db: Db) -> (&'static str, String) {
     let db = db.lock().unwrap();
-    let todos: Vec<&Todo> = db.values().collect();
+    let todos: Vec<&Todo> = db
+        .values_for(user)
+        .filter(|todo| todo.is_deleted() == deleted)
+        .collect();
     let body = serde_json::to_string(&todos).unwrap();
     ("200 OK", body)
 }
 
-pub fn get_todo(id: usize, db: Db) -> (&'static str, String) {
+pub fn get_todo(user: &str, id: usize, db: Db) -> (&'static str, String) {
     let db = db.lock().unwrap();
-    if let Some(todo) = db.get(&id.to_string()) {
+    if let Some(todo) = db.get(user, id).filter(|todo| !todo.is_deleted()) {
         let body = serde_json::to_string(todo).unwrap();
         ("200 OK", body)
     } else {
@@ -288,82 +940,879 @@ pub fn get_todo(id: usize, db: Db) -> (&'static str, String) {
     }
 }
 
-pub fn create_todo(title: String, db: Db) -> (&'static str, String) {
+pub fn create_todo(user: &str, actor: &str, title: String, db: Db) -> (&'static str, String) {
     let mut db = db.lock().unwrap();
-    let id = db.len() + 1;
-    let todo = Todo {
-        id,
-        title,
-        completed: false,
-    };
-    db.insert(id.to_string(), todo.clone());
-    let body = serde_json::to_string(&todo).unwrap();
-    ("201 Created", body)
+    match db.transaction(|db| insert_todo(db, user, actor, title)) {
+        Ok(todo) => {
+            let body = serde_json::to_string(&todo).unwrap();
+            ("201 Created", body)
+        }
+        Err(e) => storage_failure(&e),
+    }
 }
 
 pub fn update_todo(
+    user: &str,
+    actor: &str,
     id: usize,
     title: Option<String>,
     completed: Option<bool>,
     db: Db,
 ) -> (&'static str, String) {
     let mut db = db.lock().unwrap();
-    if let Some(todo) = db.get_mut(&id.to_string()) {
-        if let Some(t) = title {
-            todo.title = t;
+    match db.transaction(|db| modify_todo(db, user, actor, id, title, completed)) {
+        Ok(Ok(todo)) => {
+            let body = serde_json::to_string(&todo).unwrap();
+            ("200 OK", body)
+        }
+        Ok(Err(error)) => {
+            log_error(error);
+            ("404 Not Found", error.to_string())
         }
-        if let Some(c) = completed {
-            todo.completed = c;
+        Err(e) => storage_failure(&e),
+    }
+}
+
+pub fn delete_todo(user: &str, actor: &str, id: usize, db: Db) -> (&'static str, String) {
+    let mut db = db.lock().unwrap();
+    match db.transaction(|db| remove_todo(db, user, actor, id)) {
+        Ok(Ok(_)) => ("200 OK", "Todo has been deleted.".to_string()),
+        Ok(Err(error)) => {
+            log_error(error);
+            ("404 Not Found", error.to_string())
         }
-        let body = serde_json::to_string(todo).unwrap();
-        ("200 OK", body)
-    } else {
+        Err(e) => storage_failure(&e),
+    }
+}
+
+pub fn restore_todo(user: &str, actor: &str, id: usize, db: Db) -> (&'static str, String) {
+    let mut db = db.lock().unwrap();
+    match db.get(user, id).cloned() {
+        Some(before) if before.is_deleted() => {
+            let restored = db.transaction(|db| {
+                let todo = db
+                    .update(user, id, |todo| todo.deleted_at = None)
+                    .unwrap()
+                    .clone();
+                db.audit(AuditEvent::new(
+                    actor,
+                    Action::Restored,
+                    Some(&before),
+                    &todo,
+                ));
+                todo
+            });
+            match restored {
+                Ok(todo) => ("200 OK", serde_json::to_string(&todo).unwrap()),
+                Err(e) => storage_failure(&e),
+            }
+        }
+        Some(_) => {
+            let error = "Todo is not deleted.";
+            log_error(error);
+            ("409 Conflict", error.to_string())
+        }
+        None => {
+            let error = "Todo not found.";
+            log_error(error);
+            ("404 Not Found", error.to_string())
+        }
+    }
+}
+
+// Every recorded change to one of the user's todos, oldest first. History
+// outlives the todo, so it is still there after a purge.
+pub fn todo_history(user: &str, id: usize, db: Db) -> (&'static str, String) {
+    let db = db.lock().unwrap();
+    let events = db.audit_log().for_todo(user, id);
+    if events.is_empty() && db.get(user, id).is_none() {
         let error = "Todo not found.";
         log_error(error);
-        ("404 Not Found", error.to_string())
+        return ("404 Not Found", error.to_string());
     }
+    ("200 OK", serde_json::to_string(&events).unwrap())
+}
+
+const DEFAULT_AUDIT_PAGE: usize = 100;
+const MAX_AUDIT_PAGE: usize = 1000;
+
+// The whole audit log, filtered by `since` and `until` (RFC 3339, both
+// inclusive) and `actor`, a page of `limit` events after the `after` cursor
+fn audit_log(query: &str, db: Db) -> (&'static str, String) {
+    let time = |name: &str| -> Result<Option<DateTime<Utc>>, String> {
+        query_param(query, name)
+            .map(|value| {
+                DateTime::parse_from_rfc3339(&value)
+                    .map(|at| at.with_timezone(&Utc))
+                    .map_err(|_| format!("Invalid {}: expected an RFC 3339 time.", name))
+            })
+            .transpose()
+    };
+    let number = |name: &str, default: u64| -> Result<u64, String> {
+        query_param(query, name).map_or(Ok(default), |value| {
+            value
+                .parse()
+                .map_err(|_| format!("Invalid {}: expected a number.", name))
+        })
+    };
+    let page = (
+        time("since"),
+        time("until"),
+        number("after", 0),
+        number("limit", DEFAULT_AUDIT_PAGE as u64),
+    );
+    let (filter, limit) = match page {
+        (Ok(since), Ok(until), Ok(after), Ok(limit)) => (
+            AuditQuery {
+                since,
+                until,
+                actor: query_param(query, "actor"),
+                after,
+            },
+            (limit as usize).clamp(1, MAX_AUDIT_PAGE),
+        ),
+        (Err(e), ..) | (_, Err(e), ..) | (_, _, Err(e), _) | (.., Err(e)) => {
+            log_error(&e);
+            return ("400 Bad Request", e);
+        }
+    };
+    let db = db.lock().unwrap();
+    let events = db.audit_log().query(&filter, limit);
+    ("200 OK", serde_json::to_string(&events).unwrap())
 }
 
-pub fn delete_todo(id: usize, db: Db) -> (&'static str, String) {
+// Permanently removes todos that have been in the trash for longer than
+// `retention`. Returns how many were purged.
+pub fn purge_deleted_todos(db: &Db, retention: Duration) -> usize {
+    let now = Utc::now();
     let mut db = db.lock().unwrap();
-    if db.remove(&id.to_string()).is_some() {
-        ("200 OK", "Todo has been deleted.".to_string())
-    } else {
-        let error = "Todo not found.";
+    let before = db.len();
+    let purged = db.transaction(|db| {
+        db.retain(|todo| match todo.deleted_at {
+            // A negative age (clock went backwards) fails to_std and keeps the todo
+            Some(deleted_at) => now
+                .signed_duration_since(deleted_at)
+                .to_std()
+                .map_or(true, |age| age < retention),
+            None => true,
+        })
+    });
+    if let Err(e) = purged {
+        log_error(&format!("Purge was undone: {}", e));
+    }
+    before - db.len()
+}
+
+// JSON and JSON lines are written to the connection a batch at a time, so
+// neither the whole export nor the lock is held while it goes out. Other
+// formats are converted from the whole list, which is copied under the lock
+// and serialized after.
+fn export_todos(format: &'static Format, state: &AppState) -> Response {
+    let lines = std::ptr::eq(format, &format::JSON_LINES);
+    if lines || std::ptr::eq(format, &format::JSON) {
+        let export = transfer::Export::new(Arc::clone(&state.db), lines);
+        return Response {
+            status: "200 OK",
+            headers: Vec::new(),
+            content_type: format.content_type,
+            body: Vec::new(),
+            takeover: Some(Takeover::Export(Box::new(export))),
+        }
+        .with_header("Vary", "Accept");
+    }
+    let mut todos: Vec<Todo> = state.db.lock().unwrap().values().cloned().collect();
+    transfer::sort(&mut todos);
+    ("200 OK", serde_json::to_string(&todos).unwrap()).into()
+}
+
+fn import_todos(actor: &str, query: &str, body: &str, db: Db) -> (&'static str, String) {
+    let options = (
+        query_param(query, "mode").map_or(Ok(ImportMode::Merge), |mode| mode.parse()),
+        query_param(query, "on_conflict").map_or(Ok(Conflict::Fail), |policy| policy.parse()),
+    );
+    let (mode, conflict) = match options {
+        (Ok(mode), Ok(conflict)) => (mode, conflict),
+        (Err(e), _) | (_, Err(e)) => {
+            log_error(&e);
+            return ("400 Bad Request", e);
+        }
+    };
+    // A single record may arrive as a bare object
+    let records = match serde_json::from_str::<Value>(body) {
+        Ok(Value::Array(records)) => records,
+        Ok(record @ Value::Object(_)) => vec![record],
+        Ok(_) | Err(_) => {
+            let error = "Expected a list of todos.";
+            log_error(error);
+            return ("400 Bad Request", error.to_string());
+        }
+    };
+    let todos: Result<Vec<Todo>, String> = records
+        .into_iter()
+        .enumerate()
+        .map(|(index, record)| {
+            serde_json::from_value(record).map_err(|e| format!("Record {}: {}", index + 1, e))
+        })
+        .collect();
+    let todos = match todos {
+        Ok(todos) => todos,
+        Err(e) => {
+            log_error(&e);
+            return ("400 Bad Request", e);
+        }
+    };
+
+    let mut db = db.lock().unwrap();
+    match transfer::import(&mut db, actor, todos, mode, conflict) {
+        Ok(summary) => ("200 OK", serde_json::to_string(&summary).unwrap()),
+        Err(e) => {
+            log_error(e.message());
+            (e.status(), e.message().to_string())
+        }
+    }
+}
+
+pub fn spawn_trash_purger(
+    db: Db,
+    retention: Duration,
+    interval: Duration,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            let purged = purge_deleted_todos(&db, retention);
+            if purged > 0 {
+                println!("Purged {} deleted todos.", purged);
+            }
+        }
+    })
+}
+
+// Snapshots the store every `interval`, keeping the newest `keep`. The store
+// stays locked while the snapshot is written so it matches the journal cut.
+pub fn spawn_snapshotter(
+    db: Db,
+    data_dir: String,
+    interval: Duration,
+    keep: usize,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            let db = db.lock().unwrap();
+            if let Err(e) = snapshot::take(&db, &data_dir, keep) {
+                log_error(&e);
+            }
+        }
+    })
+}
+
+// Queues the changes committed from now on for the webhooks that want them,
+// and attempts each delivery when it is due
+pub fn spawn_webhook_dispatcher(state: Arc<AppState>) -> thread::JoinHandle<()> {
+    // Taken before returning, so no change made afterwards is missed
+    let (feed, mut after) = {
+        let db = state.db.lock().unwrap();
+        let after = feed::resume(&db, None).unwrap_or_else(|missed| missed.latest);
+        (Arc::clone(db.feed()), after)
+    };
+    thread::spawn(move || {
+        loop {
+            let wait = state
+                .webhooks
+                .until_next_due()
+                .map_or(WEBHOOK_POLL_INTERVAL, |due| due.min(WEBHOOK_POLL_INTERVAL));
+            match feed.wait_after(after, wait) {
+                Ok(events) => {
+                    after = events.last().map_or(after, TodoEvent::seq);
+                    state.webhooks.enqueue(&events);
+                }
+                Err(missed) => {
+                    log_error(&format!(
+                        "Webhooks missed changes up to {}; they won't be delivered.",
+                        missed.latest
+                    ));
+                    after = missed.latest;
+                }
+            }
+            state.webhooks.deliver_due();
+        }
+    })
+}
+
+// Longest the webhook dispatcher sleeps when there is nothing to do
+const WEBHOOK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+fn list_webhooks(user: &str, state: &AppState) -> (&'static str, String) {
+    let webhooks = state.webhooks.list(user);
+    ("200 OK", serde_json::to_string(&webhooks).unwrap())
+}
+
+fn create_webhook(user: &str, body: &str, state: &AppState) -> (&'static str, String) {
+    let new = match serde_json::from_str::<NewWebhook>(body) {
+        Ok(new) => new,
+        Err(e) => {
+            let error = format!("Invalid webhook: {}", e);
+            log_error(&error);
+            return ("400 Bad Request", error);
+        }
+    };
+    match state.webhooks.create(user, new) {
+        Ok(webhook) => (
+            "201 Created",
+            serde_json::to_string(&webhook.public()).unwrap(),
+        ),
+        Err(error) => {
+            log_error(&error);
+            ("400 Bad Request", error)
+        }
+    }
+}
+
+fn delete_webhook(user: &str, id: usize, state: &AppState) -> (&'static str, String) {
+    match state.webhooks.remove(user, id) {
+        Some(_) => ("200 OK", "Webhook has been deleted.".to_string()),
+        None => {
+            let error = "Webhook not found.";
+            log_error(error);
+            ("404 Not Found", error.to_string())
+        }
+    }
+}
+
+fn webhook_deliveries(user: &str, id: usize, state: &AppState) -> (&'static str, String) {
+    match state.webhooks.deliveries(user, id) {
+        Some(records) => ("200 OK", serde_json::to_string(&records).unwrap()),
+        None => {
+            let error = "Webhook not found.";
+            log_error(error);
+            ("404 Not Found", error.to_string())
+        }
+    }
+}
+
+const STORAGE_FAILURE: &str = "Failed to save the changes.";
+
+// The change couldn't be persisted, and was undone
+fn storage_failure(error: &str) -> (&'static str, String) {
+    log_error(error);
+    ("500 Internal Server Error", STORAGE_FAILURE.to_string())
+}
+
+// Store operations shared by the single-item handlers and the bulk endpoint.
+// They work on an already locked store so a whole batch can run under one lock.
+fn insert_todo(todos: &mut Store, user: &str, actor: &str, title: String) -> Todo {
+    let todo = Todo {
+        id: todos.next_id(user),
+        owner: user.to_string(),
+        title,
+        completed: false,
+        deleted_at: None,
+    };
+    todos.insert(todo.clone());
+    todos.audit(AuditEvent::new(actor, Action::Created, None, &todo));
+    todo
+}
+
+fn modify_todo(
+    todos: &mut Store,
+    user: &str,
+    actor: &str,
+    id: usize,
+    title: Option<String>,
+    completed: Option<bool>,
+) -> Result<Todo, &'static str> {
+    let Some(before) = todos
+        .get(user, id)
+        .filter(|todo| !todo.is_deleted())
+        .cloned()
+    else {
+        return Err("Todo not found.");
+    };
+    let todo = todos
+        .update(user, id, |todo| {
+            if let Some(t) = title {
+                todo.title = t;
+            }
+            if let Some(c) = completed {
+                todo.completed = c;
+            }
+        })
+        .unwrap()
+        .clone();
+    todos.audit(AuditEvent::new(
+        actor,
+        Action::Updated,
+        Some(&before),
+        &todo,
+    ));
+    Ok(todo)
+}
+
+// Deleting only moves the todo to the trash; the purger removes it for good
+fn remove_todo(
+    todos: &mut Store,
+    user: &str,
+    actor: &str,
+    id: usize,
+) -> Result<Todo, &'static str> {
+    let Some(before) = todos
+        .get(user, id)
+        .filter(|todo| !todo.is_deleted())
+        .cloned()
+    else {
+        return Err("Todo not found.");
+    };
+    let todo = todos
+        .update(user, id, |todo| todo.deleted_at = Some(Utc::now()))
+        .unwrap()
+        .clone();
+    todos.audit(AuditEvent::new(
+        actor,
+        Action::Deleted,
+        Some(&before),
+        &todo,
+    ));
+    Ok(todo)
+}
+
+pub fn bulk_todos<P>(
+    user: &str,
+    actor: &str,
+    body: &str,
+    db: Db,
+    permits: P,
+) -> (&'static str, String)
+where
+    P: Fn(Operation) -> bool,
+{
+    let bulk_req = match serde_json::from_str::<BulkRequest>(body) {
+        Ok(bulk_req) => bulk_req,
+        Err(e) => {
+            let error = "Invalid JSON format.";
+            log_error(&format!("Error details: {}", e));
+            return ("400 Bad Request", error.to_string());
+        }
+    };
+    if bulk_req.operations.len() > MAX_BULK_OPERATIONS {
+        let error = "Too many operations in a single bulk request.";
         log_error(error);
-        ("404 Not Found", error.to_string())
+        return ("413 Payload Too Large", error.to_string());
+    }
+
+    let mut db = db.lock().unwrap();
+    if bulk_req.atomic {
+        // Nothing reaches the journal unless the whole batch commits, and the
+        // batch keeps what it changed so it can be rolled back
+        db.begin_batch();
+    }
+
+    let results: Vec<BulkResult> = bulk_req
+        .operations
+        .into_iter()
+        .enumerate()
+        .map(|(index, operation)| {
+            apply_bulk_operation(&mut db, user, actor, &permits, index, operation)
+        })
+        .collect();
+
+    let failed = results.iter().any(|result| result.error.is_some());
+    let committed = !(failed && bulk_req.atomic);
+    if committed {
+        // The batch is undone if it can't be persisted
+        if let Err(e) = db.commit_batch() {
+            return storage_failure(&e);
+        }
+    } else {
+        db.rollback_batch();
+        log_error("Bulk request rolled back because an operation failed.");
+    }
+
+    let body = serde_json::to_string(&BulkResponse {
+        atomic: bulk_req.atomic,
+        committed,
+        results,
+    })
+    .unwrap();
+    if committed {
+        ("200 OK", body)
+    } else {
+        ("400 Bad Request", body)
     }
 }
 
-pub fn handle_connection(mut stream: TcpStream, db: Db) {
-    let mut buffer = [0; 1024];
-    match stream.read(&mut buffer) {
-        Ok(bytes_read) => {
-            let request = String::from_utf8_lossy(&buffer[..bytes_read]);
-            let (status, body) = process_request(&request, db);
-
-            let date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
-            let content_length = body.len();
-            let response = format!(
-                "HTTP/1.1 {}\r\n\
-                Date: {}\r\n\
-                Content-Type: application/json; charset=UTF-8\r\n\
-                Content-Length: {}\r\n\
-                Connection: close\r\n\
-                \r\n\
-                {}",
-                status, date, content_length, body
+fn apply_bulk_operation(
+    todos: &mut Store,
+    user: &str,
+    actor: &str,
+    permits: &dyn Fn(Operation) -> bool,
+    index: usize,
+    operation: Value,
+) -> BulkResult {
+    let operation = match serde_json::from_value::<BulkOperation>(operation) {
+        Ok(operation) => operation,
+        Err(e) => {
+            log_error(&format!("Invalid bulk operation {}: {}", index, e));
+            return BulkResult::failure(index, 400, "Invalid operation.");
+        }
+    };
+
+    let required = match operation {
+        BulkOperation::Create { .. } => Operation::Create,
+        BulkOperation::Update { .. } => Operation::Update,
+        BulkOperation::Delete { .. } => Operation::Delete,
+    };
+    if !permits(required) {
+        return BulkResult::failure(index, 403, "Forbidden.");
+    }
+
+    match operation {
+        BulkOperation::Create { title } => {
+            if let Err(e) = validate_todo_title(&title) {
+                return BulkResult::failure(index, 400, e);
+            }
+            match todos.transaction(|todos| insert_todo(todos, user, actor, title)) {
+                Ok(todo) => BulkResult::success(index, 201, todo),
+                Err(e) => bulk_storage_failure(index, &e),
+            }
+        }
+        BulkOperation::Update {
+            id,
+            title,
+            completed,
+        } => {
+            if let Some(ref title) = title {
+                if let Err(e) = validate_todo_title(title) {
+                    return BulkResult::failure(index, 400, e);
+                }
+            }
+            if let Err(e) = validate_todo_completed(&completed) {
+                return BulkResult::failure(index, 400, e);
+            }
+            match todos.transaction(|todos| modify_todo(todos, user, actor, id, title, completed)) {
+                Ok(Ok(todo)) => BulkResult::success(index, 200, todo),
+                Ok(Err(e)) => BulkResult::failure(index, 404, e),
+                Err(e) => bulk_storage_failure(index, &e),
+            }
+        }
+        BulkOperation::Delete { id } => {
+            match todos.transaction(|todos| remove_todo(todos, user, actor, id)) {
+                Ok(Ok(todo)) => BulkResult::success(index, 200, todo),
+                Ok(Err(e)) => BulkResult::failure(index, 404, e),
+                Err(e) => bulk_storage_failure(index, &e),
+            }
+        }
+    }
+}
+
+// Inside an atomic batch nothing is written until the end, so this only
+// happens to operations of their own
+fn bulk_storage_failure(index: usize, error: &str) -> BulkResult {
+    log_error(error);
+    BulkResult::failure(index, 500, STORAGE_FAILURE)
+}
+
+// What a connection opened with
+enum Incoming {
+    Http1(Request),
+    // The HTTP/2 preface, followed by these bytes
+    Http2(Vec<u8>),
+}
+
+enum ReadError {
+    Io(std::io::Error),
+    Parse(ParseError),
+}
+
+impl From<std::io::Error> for ReadError {
+    fn from(error: std::io::Error) -> ReadError {
+        ReadError::Io(error)
+    }
+}
+
+// Keeps reading until the buffered bytes parse as a complete request, so
+// bodies larger than one read still work.
+fn read_request<S: Connection>(stream: &mut S, config: &Config) -> Result<Incoming, ReadError> {
+    let limits = config.limits;
+    let mut progress = Progress::default();
+    let mut request = Vec::new();
+    let mut buffer = [0; 8192];
+    let start = Instant::now();
+    let header_deadline = start + config.header_timeout();
+    let deadline = start + config.request_deadline();
+
+    loop {
+        if request.starts_with(http2::PREFACE) {
+            return Ok(Incoming::Http2(request.split_off(http2::PREFACE.len())));
+        }
+        // A partial preface would otherwise parse as an HTTP/2.0 request line
+        let partial_preface = !request.is_empty() && http2::PREFACE.starts_with(&request);
+        if !partial_preface && progress.advance(&request, &limits) {
+            if let Some((request, _)) = http::parse(&request, &limits).map_err(ReadError::Parse)? {
+                return Ok(Incoming::Http1(request));
+            }
+        }
+
+        // Every read is bounded by the per-read timeout and by whatever is
+        // left of the deadlines, so trickling bytes can't keep a worker busy
+        let limit = match http::head_end(&request) {
+            None => header_deadline.min(deadline),
+            Some(_) => deadline,
+        };
+        let remaining = limit.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "Request timed out.").into(),
             );
+        }
+        let timeout = remaining
+            .min(config.read_timeout())
+            .max(Duration::from_millis(1));
+        stream.set_read_timeout(Some(timeout))?;
+
+        let bytes_read = stream.read(&mut buffer)?;
+        if bytes_read == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Connection closed before the request was complete.",
+            )
+            .into());
+        }
+        request.extend_from_slice(&buffer[..bytes_read]);
+    }
+}
+
+// Serves one connection with the default configuration. Servers that need
+// state shared across connections should use `handle_connection_with_state`.
+pub fn handle_connection<S: Connection + Send + 'static>(stream: S, db: Db) {
+    handle_connection_with_state(stream, &Arc::new(AppState::new(db, Config::default())));
+}
+
+// A byte stream the server can answer requests on: a plain socket, or a TLS
+// session wrapping one
+pub trait Connection: Read + Write {
+    // Client address, if the transport has one
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
 
-            if let Err(e) = stream.write_all(response.as_bytes()) {
-                eprintln!("Failed to write to stream: {}", e);
-                log_error(&format!("Stream write error: {}", e));
+    // Bounds each following read; None waits forever
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    // Bounds each following write; None waits forever
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    // True for transports that encrypt, where h2c upgrades don't apply
+    fn is_encrypted(&self) -> bool {
+        false
+    }
+
+    // Called once the response has been written
+    fn close(&mut self) {}
+
+    // The underlying socket, to wait on for input without reading it
+    fn socket(&self) -> Option<&TcpStream> {
+        None
+    }
+
+    // Whether input already received can be read without touching the socket
+    fn has_buffered_input(&mut self) -> bool {
+        false
+    }
+}
+
+impl Connection for TcpStream {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|addr| addr.ip())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn socket(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+// Connections taken over by a long-lived protocol, or held by a request
+// waiting for changes, move to a thread of their own so they don't hold on to
+// a worker. At most `max_takeovers` of these threads run at once.
+pub fn handle_connection_with_state<S: Connection + Send + 'static>(
+    mut stream: S,
+    state: &Arc<AppState>,
+) {
+    let write_timeout = state.config.write_timeout().max(Duration::from_millis(1));
+    if let Err(e) = stream.set_write_timeout(Some(write_timeout)) {
+        log_error(&format!("Failed to set write timeout: {}", e));
+    }
+
+    let Some(slot) = state.connections.admit(stream.peer_ip()) else {
+        let error = "Too many connections.";
+        log_error(error);
+        let response = Response::from(("503 Service Unavailable", error.to_string()))
+            .with_header("Retry-After", "1");
+        discard_pending(&mut stream);
+        write_response(&mut stream, response);
+        return;
+    };
+
+    let peer = stream.peer_ip();
+    match read_request(&mut stream, &state.config) {
+        Ok(Incoming::Http1(request)) => {
+            // h2c is only defined for cleartext connections. The upgrade is
+            // optional, so without room for another HTTP/2 connection the
+            // request is answered over HTTP/1.1 instead.
+            if !stream.is_encrypted() && http2::wants_upgrade(&request) {
+                if let Some(_place) = state.http2.admit() {
+                    http2::serve_upgrade(&mut stream, request, peer, state);
+                    return;
+                }
             }
+            let mut response = process_request(&request, peer, state);
+            let Some(takeover) = response.takeover.take() else {
+                write_response(&mut stream, response);
+                return;
+            };
+            let Some(place) = state.takeovers.admit() else {
+                let error = "Too many long-lived connections.";
+                log_error(error);
+                let response = Response::from(("503 Service Unavailable", error.to_string()))
+                    .with_header("Retry-After", "1");
+                write_response(&mut stream, response);
+                return;
+            };
+            let state = Arc::clone(state);
+            thread::spawn(move || {
+                let _held = (slot, place);
+                takeover.run(&mut stream, response, &state);
+            });
         }
-        Err(e) => {
+        Ok(Incoming::Http2(rest)) => match state.http2.admit() {
+            Some(_place) => http2::serve(&mut stream, &rest, peer, state),
+            None => {
+                discard_pending(&mut stream);
+                http2::refuse(&mut stream, state);
+            }
+        },
+        Err(ReadError::Parse(e)) => {
+            log_error(e.message());
+            discard_pending(&mut stream);
+            write_response(&mut stream, (e.status(), e.message().to_string()).into());
+        }
+        Err(ReadError::Io(e))
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+            ) =>
+        {
+            reject_timed_out(&mut stream);
+        }
+        Err(ReadError::Io(e)) => {
             let error = "Failed to read from stream.";
-            log_error(&format!("Read error details: {}", e));
+            log_error(&format!("{} Details: {}", error, e));
             eprintln!("Failed to read from stream: {}", e);
         }
     }
 }
+
+// Answers a client that took too long to send its request
+fn reject_timed_out<S: Connection>(stream: &mut S) {
+    let error = "Request timed out.";
+    log_error(error);
+    discard_pending(stream);
+    write_response(stream, ("408 Request Timeout", error.to_string()).into());
+}
+
+// Closing a socket with unread input makes the kernel reset the connection,
+// and the client never sees the response. Reads what has already arrived,
+// briefly, so a rejection still gets through.
+fn discard_pending<S: Connection>(stream: &mut S) {
+    if stream
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .is_err()
+    {
+        return;
+    }
+    let mut buffer = [0; 1024];
+    for _ in 0..64 {
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+}
+
+// Current time in the IMF-fixdate format of the Date header
+pub(crate) fn http_date() -> String {
+    Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+// Without a Content-Length the body runs until the connection closes
+fn response_head(response: &Response, content_length: Option<usize>) -> String {
+    let date = http_date();
+    let content_length = content_length
+        .map(|length| format!("Content-Length: {}\r\n", length))
+        .unwrap_or_default();
+    // Upgrades have no content, and say which protocol follows instead
+    let content_type = match response.content_type {
+        "" => String::new(),
+        content_type => format!("Content-Type: {}\r\n", content_type),
+    };
+    let has_connection = response
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Connection"));
+    let connection = if has_connection {
+        ""
+    } else {
+        "Connection: close\r\n"
+    };
+    let extra_headers: String = response
+        .headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    format!(
+        "HTTP/1.1 {}\r\n\
+        Date: {}\r\n\
+        {}\
+        {}\
+        {}\
+        {}\
+        \r\n",
+        response.status, date, content_type, content_length, extra_headers, connection
+    )
+}
+
+// For bodies that follow as the connection goes on
+fn write_head<S: Connection>(stream: &mut S, response: &Response) -> bool {
+    match stream.write_all(response_head(response, None).as_bytes()) {
+        Ok(()) => true,
+        Err(e) => {
+            log_error(&format!("Stream write error: {}", e));
+            false
+        }
+    }
+}
+
+fn write_response<S: Connection>(stream: &mut S, response: Response) {
+    let head = response_head(&response, Some(response.body.len()));
+
+    // One write, so the head and body don't go out as separate segments
+    let mut message = head.into_bytes();
+    message.extend_from_slice(&response.body);
+    if let Err(e) = stream.write_all(&message) {
+        eprintln!("Failed to write to stream: {}", e);
+        log_error(&format!("Stream write error: {}", e));
+    }
+    stream.close();
+}
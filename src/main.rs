@@ -1,21 +1,118 @@
-use naked_rust_api::{Db, ThreadPool, handle_connection};
-use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use naked_rust_api::config::{ServerMode, SqliteConfig};
+use naked_rust_api::transfer::{self, Conflict, ImportMode};
+use naked_rust_api::{
+    AppState, Config, Db, Store, ThreadPool, auth, handle_connection_with_state, journal, snapshot,
+    spawn_sighup_reloader, spawn_snapshotter, spawn_trash_purger, spawn_webhook_dispatcher,
+};
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
 use std::net::TcpListener;
+use std::process;
 use std::sync::{Arc, Mutex};
 
 fn main() {
-    let db: Db = Arc::new(Mutex::new(HashMap::new()));
-    let listener = TcpListener::bind("127.0.0.1:8080").expect("Failed to bind to port 8080");
-    let pool = ThreadPool::new(4); // Specify the size of the thread pool
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("hash-password") {
+        match args.get(1) {
+            Some(password) => println!("{}", auth::hash_password(password)),
+            None => {
+                eprintln!("Usage: naked-rust-api hash-password <password>");
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let config = match option("--config") {
+        Some(path) => Config::load(&path).unwrap_or_else(|e| exit_with(&e)),
+        None => Config::default(),
+    };
+    match args.first().map(String::as_str) {
+        Some("export") => return export(&config),
+        Some("import") => return import(&config),
+        Some("restore") => return restore(&config),
+        _ => {}
+    }
+
+    let store = open_store(&config).unwrap_or_default();
+    let db: Db = Arc::new(Mutex::new(store));
+    let listener = TcpListener::bind(&config.address)
+        .unwrap_or_else(|_| panic!("Failed to bind to {}", config.address));
+    let pool = ThreadPool::new(config.threads);
+
+    #[cfg(feature = "tls")]
+    let tls = config.tls.as_ref().map(|tls| {
+        naked_rust_api::tls::load_server_config(&tls.cert_file, &tls.key_file).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        })
+    });
+    #[cfg(not(feature = "tls"))]
+    if config.tls.is_some() {
+        eprintln!("TLS is configured but this build lacks the `tls` feature.");
+        process::exit(1);
+    }
+    if config.policy_file.is_some() && !config.auth.is_enabled() {
+        exit_with("A policy file needs authentication configured under `auth`.");
+    }
+    let event_loop = config.server_mode == ServerMode::EventLoop;
+    #[cfg(not(feature = "event-loop"))]
+    if event_loop {
+        exit_with("The event loop server mode needs the `event-loop` feature.");
+    }
+    if event_loop && config.tls.is_some() {
+        exit_with("TLS is not supported in the event loop server mode.");
+    }
 
-    println!("Server is running at http://127.0.0.1:8080");
+    let scheme = if config.tls.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    println!("Server is running at {}://{}", scheme, config.address);
 
+    spawn_trash_purger(
+        Arc::clone(&db),
+        config.trash_retention(),
+        config.trash_purge_interval(),
+    );
+    if let (Some(data_dir), true) = (&config.data_dir, config.snapshot_interval_secs > 0) {
+        spawn_snapshotter(
+            Arc::clone(&db),
+            data_dir.clone(),
+            config.snapshot_interval(),
+            config.snapshot_retention,
+        );
+    }
+    let state = Arc::new(AppState::new(db, config));
+    spawn_sighup_reloader(Arc::clone(&state));
+    spawn_webhook_dispatcher(Arc::clone(&state));
+
+    #[cfg(feature = "event-loop")]
+    if event_loop {
+        if let Err(e) = naked_rust_api::event_loop::serve(listener, &pool, &state) {
+            exit_with(&format!("Event loop failed: {}", e));
+        }
+        return;
+    }
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                let db = Arc::clone(&db);
+                let state = Arc::clone(&state);
+                #[cfg(feature = "tls")]
+                if let Some(tls) = &tls {
+                    match naked_rust_api::tls::accept(stream, tls) {
+                        Ok(stream) => pool.execute(move || {
+                            handle_connection_with_state(stream, &state);
+                        }),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                    continue;
+                }
                 pool.execute(move || {
-                    handle_connection(stream, db);
+                    handle_connection_with_state(stream, &state);
                 });
             }
             Err(e) => {
@@ -24,3 +121,112 @@ fn main() {
         }
     }
 }
+
+// Writes every todo in the data directory as JSON lines to `--output`, or to
+// standard output
+fn export(config: &Config) {
+    let store = open_data_dir(config);
+    let result = match option("--output") {
+        Some(path) => {
+            fs::File::create(&path).and_then(|mut file| transfer::write_lines(&store, &mut file))
+        }
+        None => transfer::write_lines(&store, &mut io::stdout().lock()),
+    };
+    if let Err(e) = result {
+        exit_with(&format!("Export failed: {}", e));
+    }
+}
+
+// Reads JSON lines from `--input`, or from standard input, into the data
+// directory. `--mode` and `--on-conflict` work as on /admin/import.
+fn import(config: &Config) {
+    let mode: ImportMode = option("--mode")
+        .map_or(Ok(ImportMode::Merge), |mode| mode.parse())
+        .unwrap_or_else(|e| exit_with(&e));
+    let conflict: Conflict = option("--on-conflict")
+        .map_or(Ok(Conflict::Fail), |policy| policy.parse())
+        .unwrap_or_else(|e| exit_with(&e));
+    let mut text = String::new();
+    let read = match option("--input") {
+        Some(path) => fs::File::open(&path).and_then(|mut file| file.read_to_string(&mut text)),
+        None => io::stdin().read_to_string(&mut text),
+    };
+    if let Err(e) = read {
+        exit_with(&format!("Failed to read the import: {}", e));
+    }
+
+    let todos = transfer::parse_lines(&text).unwrap_or_else(|e| exit_with(e.message()));
+    let mut store = open_data_dir(config);
+    // Audited as the command rather than any user
+    match transfer::import(&mut store, "import", todos, mode, conflict) {
+        Ok(summary) => {
+            let mut stdout = io::stdout().lock();
+            let _ = writeln!(stdout, "{}", serde_json::to_string(&summary).unwrap());
+        }
+        Err(e) => exit_with(e.message()),
+    }
+}
+
+// Rolls the data directory back to how it was at `--at`, an RFC 3339 time.
+// The server must not be running meanwhile.
+fn restore(config: &Config) {
+    let Some(at) = option("--at") else {
+        exit_with("Usage: naked-rust-api restore --config <file> --at <RFC 3339 time>");
+    };
+    let at: DateTime<Utc> = DateTime::parse_from_rfc3339(&at)
+        .map(|at| at.with_timezone(&Utc))
+        .unwrap_or_else(|e| exit_with(&format!("Invalid time '{}': {}", at, e)));
+    let store = snapshot::restore(data_dir(config), at, config.snapshot_retention)
+        .unwrap_or_else(|e| exit_with(&e));
+    println!("Restored {} todos as of {}.", store.len(), at.to_rfc3339());
+}
+
+fn open_data_dir(config: &Config) -> Store {
+    open_store(config).unwrap_or_else(|| {
+        exit_with("This command needs `data_dir` or `sqlite` in the configuration.")
+    })
+}
+
+// The persistent store the configuration names, if any
+fn open_store(config: &Config) -> Option<Store> {
+    let store = match (&config.data_dir, &config.sqlite) {
+        (Some(_), Some(_)) => exit_with("Configure either `data_dir` or `sqlite`, not both."),
+        (Some(data_dir), None) => journal::open_store(data_dir),
+        (None, Some(sqlite)) => open_sqlite(sqlite),
+        (None, None) => return None,
+    };
+    Some(store.unwrap_or_else(|e| exit_with(&e)))
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite(config: &SqliteConfig) -> Result<Store, String> {
+    naked_rust_api::sqlite::Database::open(&config.path).and_then(|database| database.open_store())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite(_: &SqliteConfig) -> Result<Store, String> {
+    Err("SQLite is configured but this build lacks the `sqlite` feature.".to_string())
+}
+
+fn data_dir(config: &Config) -> &str {
+    match &config.data_dir {
+        Some(data_dir) => data_dir,
+        None => exit_with("This command needs `data_dir` in the configuration."),
+    }
+}
+
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+// The value following `name` on the command line, e.g. `--config <path>`
+fn option(name: &str) -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args.next();
+        }
+    }
+    None
+}
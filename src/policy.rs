@@ -0,0 +1,94 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Read,
+    Create,
+    Update,
+    Delete,
+    Admin,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Read => "read",
+            Operation::Create => "create",
+            Operation::Update => "update",
+            Operation::Delete => "delete",
+            Operation::Admin => "admin",
+        }
+    }
+}
+
+// Maps users to roles and roles to the operations they may perform. The
+// built-in reader/writer/admin roles can be redefined or extended by the file.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    roles: HashMap<String, HashSet<Operation>>,
+    users: HashMap<String, String>,
+    default_role: Option<String>,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            roles: builtin_roles(),
+            users: HashMap::new(),
+            default_role: None,
+        }
+    }
+}
+
+fn builtin_roles() -> HashMap<String, HashSet<Operation>> {
+    use Operation::*;
+    let mut roles = HashMap::new();
+    roles.insert("reader".to_string(), HashSet::from([Read]));
+    roles.insert(
+        "writer".to_string(),
+        HashSet::from([Read, Create, Update, Delete]),
+    );
+    roles.insert(
+        "admin".to_string(),
+        HashSet::from([Read, Create, Update, Delete, Admin]),
+    );
+    roles
+}
+
+impl Policy {
+    pub fn load(path: &str) -> Result<Policy, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read policy file {}: {}", path, e))?;
+        let mut policy: Policy = serde_json::from_str(&contents)
+            .map_err(|e| format!("Failed to parse policy file {}: {}", path, e))?;
+
+        let mut roles = builtin_roles();
+        roles.extend(policy.roles.drain());
+        policy.roles = roles;
+
+        let assigned = policy.users.values().chain(policy.default_role.iter());
+        for role in assigned {
+            if !policy.roles.contains_key(role) {
+                return Err(format!("Unknown role '{}' in policy file {}", role, path));
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn role_of(&self, user: &str) -> Option<&str> {
+        self.users
+            .get(user)
+            .or(self.default_role.as_ref())
+            .map(String::as_str)
+    }
+
+    pub fn permits(&self, user: &str, operation: Operation) -> bool {
+        self.role_of(user)
+            .and_then(|role| self.roles.get(role))
+            .is_some_and(|operations| operations.contains(&operation))
+    }
+}
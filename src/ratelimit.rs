@@ -0,0 +1,143 @@
+use crate::config::{RateLimitConfig, RateLimitRule};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Buckets that are back to full carry no information. They are swept out
+// once the map has doubled since the last sweep, but not before it reaches
+// this size, so each sweep is paid for by the inserts leading up to it.
+const MIN_SWEEP_AT: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    // Seconds until the bucket is full again
+    pub reset_secs: u64,
+    // Seconds until the next token, only meaningful when not allowed
+    pub retry_after_secs: u64,
+}
+
+// Token-bucket limiter. Every client gets its own bucket per rule, so a noisy
+// client only ever exhausts its own budget.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    by_client: HashMap<(usize, String), Bucket>,
+    // Size at which the next sweep runs
+    sweep_at: usize,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            config,
+            buckets: Mutex::new(Buckets {
+                by_client: HashMap::new(),
+                sweep_at: MIN_SWEEP_AT,
+            }),
+        }
+    }
+
+    // Index and rule for a request: the most specific matching route rule,
+    // else the default. Index usize::MAX stands for the default rule.
+    fn rule_for(&self, method: &str, path: &str) -> Option<(usize, &RateLimitRule)> {
+        self.config
+            .routes
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| rule.matches(method, path))
+            .max_by_key(|(_, rule)| rule.path.as_deref().map_or(0, str::len))
+            .or_else(|| self.config.default.as_ref().map(|rule| (usize::MAX, rule)))
+    }
+
+    // Takes a token for `client`; None when no rule covers the route
+    pub fn check(&self, method: &str, path: &str, client: &str) -> Option<Decision> {
+        let (rule_index, rule) = self.rule_for(method, path)?;
+        let capacity = rule.capacity.max(1) as f64;
+        let refill = rule.refill_per_sec.max(f64::MIN_POSITIVE);
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let key = (rule_index, client.to_string());
+        // Only new clients grow the map
+        if !buckets.by_client.contains_key(&key) {
+            self.make_room(&mut buckets, now);
+        }
+
+        let bucket = buckets.by_client.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill).min(capacity);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        Some(Decision {
+            allowed,
+            limit: rule.capacity.max(1),
+            remaining: bucket.tokens.floor() as u32,
+            reset_secs: ((capacity - bucket.tokens) / refill).ceil() as u64,
+            retry_after_secs: ((1.0 - bucket.tokens).max(0.0) / refill).ceil() as u64,
+        })
+    }
+
+    // Sweeps out full buckets when due. If the map is still at the cap, the
+    // least recently used tenth goes too; those clients only get a fresh
+    // bucket sooner than they would have.
+    fn make_room(&self, buckets: &mut Buckets, now: Instant) {
+        let max_clients = self.config.max_clients.max(1);
+        if buckets.by_client.len() < buckets.sweep_at.min(max_clients) {
+            return;
+        }
+        buckets.by_client.retain(|(index, _), bucket| {
+            let refill = self.refill_of(*index);
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * refill
+                < self.capacity_of(*index)
+        });
+        if buckets.by_client.len() >= max_clients {
+            let mut updated: Vec<Instant> = buckets
+                .by_client
+                .values()
+                .map(|bucket| bucket.updated)
+                .collect();
+            let evicted = (max_clients / 10).max(1);
+            let (_, cutoff, _) = updated.select_nth_unstable(evicted - 1);
+            let cutoff = *cutoff;
+            buckets
+                .by_client
+                .retain(|_, bucket| bucket.updated > cutoff);
+        }
+        buckets.sweep_at = (buckets.by_client.len() * 2).max(MIN_SWEEP_AT);
+    }
+
+    fn rule_at(&self, index: usize) -> Option<&RateLimitRule> {
+        if index == usize::MAX {
+            self.config.default.as_ref()
+        } else {
+            self.config.routes.get(index)
+        }
+    }
+
+    fn capacity_of(&self, index: usize) -> f64 {
+        self.rule_at(index)
+            .map_or(1.0, |rule| rule.capacity.max(1) as f64)
+    }
+
+    fn refill_of(&self, index: usize) -> f64 {
+        self.rule_at(index)
+            .map_or(1.0, |rule| rule.refill_per_sec.max(f64::MIN_POSITIVE))
+    }
+}
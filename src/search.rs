@@ -0,0 +1,87 @@
+use std::collections::{BTreeMap, HashMap};
+
+// Inverted index from lowercase title terms to the todos containing them.
+// Terms live in a BTreeMap so prefix queries are a simple range scan.
+#[derive(Clone, Default)]
+pub struct SearchIndex {
+    postings: BTreeMap<String, HashMap<usize, u32>>,
+    // Todos indexed, for the idf
+    docs: usize,
+}
+
+// Matches on a whole term count for more than matches on a prefix of one
+const PREFIX_WEIGHT: f64 = 0.5;
+
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+        .collect()
+}
+
+impl SearchIndex {
+    pub fn add(&mut self, id: usize, title: &str) {
+        self.docs += 1;
+        for term in tokenize(title) {
+            *self
+                .postings
+                .entry(term)
+                .or_default()
+                .entry(id)
+                .or_insert(0) += 1;
+        }
+    }
+
+    pub fn remove(&mut self, id: usize, title: &str) {
+        self.docs = self.docs.saturating_sub(1);
+        for term in tokenize(title) {
+            if let Some(docs) = self.postings.get_mut(&term) {
+                docs.remove(&id);
+                if docs.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    // Returns (id, score) pairs for todos matching every query term, either
+    // exactly or as a prefix, best match first. Scores are tf-idf based.
+    pub fn search(&self, query: &str) -> Vec<(usize, f64)> {
+        let terms = tokenize(query);
+        if terms.is_empty() {
+            return Vec::new();
+        }
+
+        let mut scores: HashMap<usize, (usize, f64)> = HashMap::new();
+        for (position, term) in terms.iter().enumerate() {
+            let mut term_scores: HashMap<usize, f64> = HashMap::new();
+            for (indexed, docs) in self.postings.range(term.clone()..) {
+                if !indexed.starts_with(term.as_str()) {
+                    break;
+                }
+                let weight = if indexed == term { 1.0 } else { PREFIX_WEIGHT };
+                let idf = ((self.docs as f64 + 1.0) / (docs.len() as f64 + 0.5)).ln() + 1.0;
+                for (&id, &count) in docs {
+                    let score = term_scores.entry(id).or_insert(0.0);
+                    *score = score.max(weight * count as f64 * idf);
+                }
+            }
+            for (id, score) in term_scores {
+                let entry = scores.entry(id).or_insert((0, 0.0));
+                // Only documents that matched all earlier terms can stay in the running
+                if entry.0 == position {
+                    entry.0 += 1;
+                    entry.1 += score;
+                }
+            }
+        }
+
+        let mut results: Vec<(usize, f64)> = scores
+            .into_iter()
+            .filter(|(_, (matched, _))| *matched == terms.len())
+            .map(|(id, (_, score))| (id, score))
+            .collect();
+        results.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        results
+    }
+}
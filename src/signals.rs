@@ -0,0 +1,42 @@
+// Minimal SIGHUP handling without pulling in libc: the handler only flips an
+// atomic flag, and a regular thread polls it to do the actual work.
+
+#[cfg(unix)]
+mod imp {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    const SIGHUP: i32 = 1;
+    const SIG_ERR: usize = usize::MAX;
+
+    static HANGUP: AtomicBool = AtomicBool::new(false);
+
+    unsafe extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+
+    extern "C" fn on_hangup(_: i32) {
+        HANGUP.store(true, Ordering::SeqCst);
+    }
+
+    pub fn install_hangup_handler() -> bool {
+        // SAFETY: the handler is async-signal-safe, it only stores to an atomic
+        unsafe { signal(SIGHUP, on_hangup) != SIG_ERR }
+    }
+
+    pub fn take_hangup() -> bool {
+        HANGUP.swap(false, Ordering::SeqCst)
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    pub fn install_hangup_handler() -> bool {
+        false
+    }
+
+    pub fn take_hangup() -> bool {
+        false
+    }
+}
+
+pub use imp::{install_hangup_handler, take_hangup};
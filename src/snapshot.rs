@@ -0,0 +1,214 @@
+// Point-in-time copies of the store. Taking a snapshot also archives the
+// journal written so far and starts a new one, so the live journal stays
+// short. The kept snapshots and the archived journals between them let the
+// store be rebuilt as it was at any moment since the oldest kept snapshot.
+use crate::Todo;
+use crate::journal::{self, Change};
+use crate::store::Store;
+use crate::transfer;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// Sorts in time order, and is safe in file names
+const STAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.6fZ";
+
+// First line of a snapshot file. One todo per line follows, then one audit
+// event per line.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    at: DateTime<Utc>,
+    next_ids: HashMap<String, usize>,
+    // Todo lines; when missing, every line is a todo
+    #[serde(default)]
+    todos: Option<usize>,
+}
+
+pub struct Snapshot {
+    pub at: DateTime<Utc>,
+    pub path: PathBuf,
+}
+
+fn snapshot_dir(data_dir: &str) -> PathBuf {
+    Path::new(data_dir).join("snapshots")
+}
+
+fn stamp(at: DateTime<Utc>) -> String {
+    at.format(STAMP_FORMAT).to_string()
+}
+
+fn parse_stamp(name: &str, prefix: &str, suffix: &str) -> Option<DateTime<Utc>> {
+    let stamp = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+    NaiveDateTime::parse_from_str(stamp, STAMP_FORMAT)
+        .ok()
+        .map(|at| at.and_utc())
+}
+
+// Files in `dir` named `{prefix}{stamp}{suffix}`, oldest first
+fn stamped_files(dir: &Path, prefix: &str, suffix: &str) -> Result<Vec<Snapshot>, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", dir.display(), e)),
+    };
+    let mut files: Vec<Snapshot> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let at = parse_stamp(entry.file_name().to_str()?, prefix, suffix)?;
+            Some(Snapshot {
+                at,
+                path: entry.path(),
+            })
+        })
+        .collect();
+    files.sort_by_key(|file| file.at);
+    Ok(files)
+}
+
+pub fn list(data_dir: &str) -> Result<Vec<Snapshot>, String> {
+    stamped_files(&snapshot_dir(data_dir), "snapshot-", ".jsonl")
+}
+
+// Archived journals, each named after the snapshot that closed it
+fn archived_journals(data_dir: &str) -> Result<Vec<Snapshot>, String> {
+    stamped_files(Path::new(data_dir), "journal-", ".jsonl")
+}
+
+// Writes the store to a new snapshot, archives the journal and prunes old
+// snapshots down to `keep`. The caller must hold the store's lock throughout,
+// so no change can land between the snapshot and the journal rotation.
+pub fn take(store: &Store, data_dir: &str, keep: usize) -> Result<Snapshot, String> {
+    let dir = snapshot_dir(data_dir);
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let at = Utc::now();
+    let path = dir.join(format!("snapshot-{}.jsonl", stamp(at)));
+    // Written beside the target and renamed into place, so a crash never
+    // leaves a partial snapshot behind under the real name
+    let temp = dir.join(format!(".snapshot-{}.tmp", stamp(at)));
+    write(store, at, &temp)
+        .and_then(|()| fs::rename(&temp, &path))
+        .and_then(|()| File::open(&dir)?.sync_all())
+        .map_err(|e| format!("Failed to write snapshot {}: {}", path.display(), e))?;
+
+    if let Some(journal) = store.journal() {
+        let archive = Path::new(data_dir).join(format!("journal-{}.jsonl", stamp(at)));
+        journal.rotate(&archive)?;
+    }
+    prune(data_dir, keep)?;
+    Ok(Snapshot { at, path })
+}
+
+fn write(store: &Store, at: DateTime<Utc>, path: &Path) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let header = Header {
+        at,
+        next_ids: store.id_sequences().clone(),
+        todos: Some(store.len()),
+    };
+    serde_json::to_writer(&mut out, &header)?;
+    out.write_all(b"\n")?;
+    transfer::write_lines(store, &mut out)?;
+    for event in store.audit_log().events() {
+        serde_json::to_writer(&mut out, event)?;
+        out.write_all(b"\n")?;
+    }
+    out.into_inner()?.sync_all()
+}
+
+// Keeps the newest `keep` snapshots, at least one, and drops the archived
+// journals that only cover time before the oldest of them
+fn prune(data_dir: &str, keep: usize) -> Result<(), String> {
+    let snapshots = list(data_dir)?;
+    let keep = keep.max(1);
+    if snapshots.len() <= keep {
+        return Ok(());
+    }
+    let (dropped, kept) = snapshots.split_at(snapshots.len() - keep);
+    let oldest = kept[0].at;
+    let journals = archived_journals(data_dir)?
+        .into_iter()
+        .filter(|journal| journal.at <= oldest);
+    for file in dropped.iter().chain(&journals.collect::<Vec<_>>()) {
+        fs::remove_file(&file.path)
+            .map_err(|e| format!("Failed to remove {}: {}", file.path.display(), e))?;
+    }
+    Ok(())
+}
+
+pub fn load(path: &Path) -> Result<Store, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read snapshot {}: {}", path.display(), e))?;
+    let mut lines = text.lines();
+    let header: Header = lines
+        .next()
+        .ok_or_else(|| "missing header".to_string())
+        .and_then(|line| serde_json::from_str(line).map_err(|e| e.to_string()))
+        .map_err(|e| format!("Snapshot {}: {}", path.display(), e))?;
+
+    let mut store = Store::new();
+    let todos = header.todos.unwrap_or(usize::MAX);
+    for (index, line) in lines.enumerate() {
+        let error =
+            |e: serde_json::Error| format!("Snapshot {} line {}: {}", path.display(), index + 2, e);
+        if index < todos {
+            store.insert(serde_json::from_str::<Todo>(line).map_err(error)?);
+        } else {
+            let event = serde_json::from_str(line).map_err(error)?;
+            store.apply(Change::Audit { event });
+        }
+    }
+    for (owner, next_id) in &header.next_ids {
+        store.reserve_ids(owner, *next_id);
+    }
+    Ok(store)
+}
+
+// The store as it was at `at`: the newest snapshot taken by then, plus the
+// journal entries that followed it up to `at`
+pub fn rebuild(data_dir: &str, at: DateTime<Utc>) -> Result<Store, String> {
+    let snapshots = list(data_dir)?;
+    let journals = archived_journals(data_dir)?;
+    let base = snapshots.iter().rev().find(|snapshot| snapshot.at <= at);
+    // Without a snapshot to start from, replay has to begin with the very
+    // first journal, which pruning removes along with the first snapshot
+    if let (None, Some(oldest)) = (base, snapshots.first()) {
+        if journals.first().map(|journal| journal.at) != Some(oldest.at) {
+            return Err(format!(
+                "No snapshot at or before {}; the oldest is from {}.",
+                at.to_rfc3339(),
+                oldest.at.to_rfc3339()
+            ));
+        }
+    }
+
+    let (mut store, since) = match base {
+        Some(snapshot) => (load(&snapshot.path)?, Some(snapshot.at)),
+        None => (Store::new(), None),
+    };
+    let mut paths: Vec<PathBuf> = journals
+        .into_iter()
+        .filter(|journal| since.is_none_or(|since| journal.at > since))
+        .map(|journal| journal.path)
+        .collect();
+    paths.push(journal::journal_path(data_dir));
+    for path in paths {
+        for entry in journal::read_entries(&path)? {
+            if since.is_none_or(|since| entry.at > since) && entry.at <= at {
+                store.apply(entry.change);
+            }
+        }
+    }
+    Ok(store)
+}
+
+// Makes the store as it was at `at` the current state. It is recorded as a
+// fresh snapshot, so the history it replaces stays available for another
+// restore.
+pub fn restore(data_dir: &str, at: DateTime<Utc>, keep: usize) -> Result<Store, String> {
+    let store = journal::attach(rebuild(data_dir, at)?, data_dir)?;
+    take(&store, data_dir, keep)?;
+    Ok(store)
+}
@@ -0,0 +1,223 @@
+// SQLite as the system of record, behind the `sqlite` feature. The store is
+// loaded from it at startup and every batch of changes is written through in
+// one transaction before it is published; a write that fails is undone in
+// memory too, and the request fails with it. SQLite is bundled, so there is
+// no server to run. Reads are not served from here, so the todos still have
+// to fit in memory.
+use crate::Todo;
+use crate::audit;
+use crate::journal::Change;
+use crate::store::Store;
+use rusqlite::types::Type;
+use rusqlite::{Connection, Transaction, params};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Each entry moves the schema up one version, counted in `user_version`.
+// Released entries must never change; add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: todos, and the id sequences that outlive them
+    "CREATE TABLE todos (
+        owner TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        completed INTEGER NOT NULL,
+        deleted_at TEXT,
+        PRIMARY KEY (owner, id)
+    );
+    CREATE TABLE id_sequences (
+        owner TEXT PRIMARY KEY,
+        next_id INTEGER NOT NULL
+    );",
+    // 2: the audit log
+    "CREATE TABLE audit_events (
+        seq INTEGER PRIMARY KEY,
+        event TEXT NOT NULL
+    );",
+];
+
+// How long the connection waits on a lock held by another process
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Reads are served from the store's copy in memory and writes already queue
+// on the store's lock, so a single connection is all the server needs
+pub struct Database {
+    connection: Mutex<Connection>,
+}
+
+impl Database {
+    // Opens the database file at `path`, creating it if needed, and brings its
+    // schema up to date
+    pub fn open(path: &str) -> Result<Database, String> {
+        Database::connect(|| {
+            let connection = Connection::open(path)?;
+            // Other processes, such as the `export` subcommand, can read
+            // while the server writes
+            connection.pragma_update(None, "journal_mode", "WAL")?;
+            connection.pragma_update(None, "synchronous", "NORMAL")?;
+            Ok(connection)
+        })
+        .map_err(|e| format!("Failed to open SQLite database {}: {}", path, e))
+    }
+
+    // A database that lives only as long as this value
+    pub fn in_memory() -> Result<Database, String> {
+        Database::connect(Connection::open_in_memory)
+            .map_err(|e| format!("Failed to open in-memory SQLite database: {}", e))
+    }
+
+    fn connect<F>(open: F) -> Result<Database, String>
+    where
+        F: FnOnce() -> rusqlite::Result<Connection>,
+    {
+        let mut connection = open().map_err(|e| e.to_string())?;
+        connection
+            .busy_timeout(BUSY_TIMEOUT)
+            .map_err(|e| e.to_string())?;
+        migrate(&mut connection)?;
+        Ok(Database {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    // The schema version, which is the number of migrations applied
+    pub fn schema_version(&self) -> Result<usize, String> {
+        schema_version(&self.connection.lock().unwrap()).map_err(|e| e.to_string())
+    }
+
+    // Everything stored, as a store that doesn't write back
+    pub fn load(&self) -> Result<Store, String> {
+        let connection = self.connection.lock().unwrap();
+        let read = || -> rusqlite::Result<Store> {
+            let mut store = Store::new();
+            let mut todos =
+                connection.prepare("SELECT owner, id, title, completed, deleted_at FROM todos")?;
+            let rows = todos.query_map([], |row| {
+                Ok(Todo {
+                    owner: row.get(0)?,
+                    id: row.get(1)?,
+                    title: row.get(2)?,
+                    completed: row.get(3)?,
+                    deleted_at: row.get(4)?,
+                })
+            })?;
+            for todo in rows {
+                store.insert(todo?);
+            }
+            let mut sequences = connection.prepare("SELECT owner, next_id FROM id_sequences")?;
+            let rows = sequences.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, usize>(1)?))
+            })?;
+            for sequence in rows {
+                let (owner, next_id) = sequence?;
+                store.reserve_ids(&owner, next_id);
+            }
+            // Only as many events as the in-memory log keeps
+            let mut events = connection.prepare(
+                "SELECT event FROM
+                 (SELECT seq, event FROM audit_events ORDER BY seq DESC LIMIT ?1)
+                 ORDER BY seq",
+            )?;
+            let rows = events.query_map([audit::CAPACITY], |row| row.get::<_, String>(0))?;
+            for json in rows {
+                let event = serde_json::from_str(&json?).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e))
+                })?;
+                store.apply(Change::Audit { event });
+            }
+            Ok(store)
+        };
+        read().map_err(|e| format!("Failed to load todos from SQLite: {}", e))
+    }
+
+    // Loads the store and writes its further changes back here
+    pub fn open_store(self) -> Result<Store, String> {
+        let store = self.load()?;
+        Ok(store.with_database(Arc::new(self)))
+    }
+
+    // Applies `changes` in a single transaction, so either all of them are
+    // stored or none
+    pub fn write(&self, changes: &[Change]) -> Result<(), String> {
+        let mut connection = self.connection.lock().unwrap();
+        connection
+            .transaction()
+            .and_then(|tx| {
+                for change in changes {
+                    apply(&tx, change)?;
+                }
+                tx.commit()
+            })
+            .map_err(|e| format!("Failed to write to SQLite: {}", e))
+    }
+}
+
+fn apply(tx: &Transaction, change: &Change) -> rusqlite::Result<()> {
+    match change {
+        Change::Put { todo } => {
+            tx.execute(
+                "INSERT OR REPLACE INTO todos (owner, id, title, completed, deleted_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    todo.owner,
+                    todo.id,
+                    todo.title,
+                    todo.completed,
+                    todo.deleted_at
+                ],
+            )?;
+            // Ids stay reserved after their todo is gone
+            tx.execute(
+                "INSERT INTO id_sequences (owner, next_id) VALUES (?1, ?2)
+                 ON CONFLICT (owner) DO UPDATE SET next_id = max(next_id, excluded.next_id)",
+                params![todo.owner, todo.id + 1],
+            )?;
+        }
+        Change::Remove { owner, id } => {
+            tx.execute(
+                "DELETE FROM todos WHERE owner = ?1 AND id = ?2",
+                params![owner, id],
+            )?;
+        }
+        Change::Clear => {
+            tx.execute("DELETE FROM todos", [])?;
+        }
+        Change::Audit { event } => {
+            let json = serde_json::to_string(event).unwrap();
+            tx.execute(
+                "INSERT OR IGNORE INTO audit_events (seq, event) VALUES (?1, ?2)",
+                params![event.seq, json],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn schema_version(connection: &Connection) -> rusqlite::Result<usize> {
+    connection.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+// Applies the migrations the database hasn't seen yet, each in its own
+// transaction so a failed one leaves the schema at the previous version
+fn migrate(connection: &mut Connection) -> Result<(), String> {
+    let version = schema_version(connection).map_err(|e| e.to_string())?;
+    if version > MIGRATIONS.len() {
+        return Err(format!(
+            "schema version {} is newer than this build supports ({})",
+            version,
+            MIGRATIONS.len()
+        ));
+    }
+    for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let version = applied + 1;
+        connection
+            .transaction()
+            .and_then(|tx| {
+                tx.execute_batch(migration)?;
+                tx.pragma_update(None, "user_version", version)?;
+                tx.commit()
+            })
+            .map_err(|e| format!("migration {} failed: {}", version, e))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,118 @@
+// GET /todos/events: a Server-Sent Events stream of the caller's todo changes.
+// Every event carries its audit sequence number as its id, so a client that
+// reconnects with Last-Event-ID gets what it missed from the change feed.
+use crate::auth::Principal;
+use crate::feed::{self, ChangeFeed, Missed, TodoEvent};
+use crate::http::Headers;
+use crate::policy::Operation;
+use crate::{AppState, Response, Takeover, forbidden, log_error};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const PATH: &str = "/todos/events";
+pub const CONTENT_TYPE: &str = "text/event-stream";
+
+// A comment is sent when nothing else was, so proxies keep the connection
+// open and a client that went away is noticed
+const HEARTBEAT: Duration = Duration::from_secs(15);
+// How long clients wait before reconnecting
+const RETRY_MILLIS: u64 = 3000;
+
+pub struct EventStream {
+    feed: Arc<ChangeFeed>,
+    user: String,
+    // The last event the client has seen
+    after: u64,
+    // Events the client asked for are gone; it has to reload
+    missed: Option<Missed>,
+}
+
+pub fn open(headers: &Headers, principal: &Principal, state: &AppState) -> Response {
+    if !state.permits(principal, Operation::Read) {
+        return forbidden(principal, Operation::Read, state).into();
+    }
+    let last_event_id = match headers
+        .get("Last-Event-ID")
+        .map(|id| id.trim().parse::<u64>())
+    {
+        None => None,
+        Some(Ok(id)) => Some(id),
+        Some(Err(_)) => {
+            let error = "Invalid Last-Event-ID.";
+            log_error(error);
+            return ("400 Bad Request", error.to_string()).into();
+        }
+    };
+
+    let db = state.db.lock().unwrap();
+    let feed = Arc::clone(db.feed());
+    let (after, missed) = match feed::resume(&db, last_event_id) {
+        Ok(after) => (after, None),
+        Err(missed) => (missed.latest, Some(missed)),
+    };
+    drop(db);
+    let stream = EventStream {
+        feed,
+        user: principal.user.clone(),
+        after,
+        missed,
+    };
+    Response {
+        status: "200 OK",
+        headers: vec![("Cache-Control".to_string(), "no-cache".to_string())],
+        content_type: CONTENT_TYPE,
+        body: Vec::new(),
+        takeover: Some(Takeover::Events(Box::new(stream))),
+    }
+}
+
+impl EventStream {
+    // Sends events until the client goes away
+    pub fn run<W: Write>(mut self, out: &mut W) {
+        let mut message = format!("retry: {}\n\n", RETRY_MILLIS);
+        loop {
+            if let Some(missed) = self.missed.take() {
+                message.push_str(&reset(&missed));
+                self.after = missed.latest;
+            }
+            if let Err(e) = out.write_all(message.as_bytes()).and_then(|()| out.flush()) {
+                log_error(&format!("Event stream closed: {}", e));
+                return;
+            }
+            message = match self.feed.wait_after(self.after, HEARTBEAT) {
+                Ok(events) if events.is_empty() => ": keep-alive\n\n".to_string(),
+                Ok(events) => {
+                    self.after = events.last().map_or(self.after, TodoEvent::seq);
+                    events
+                        .iter()
+                        .filter(|event| event.change.owner == self.user)
+                        .map(frame)
+                        .collect()
+                }
+                Err(missed) => {
+                    self.missed = Some(missed);
+                    String::new()
+                }
+            };
+        }
+    }
+}
+
+fn frame(event: &TodoEvent) -> String {
+    let action = serde_json::to_value(event.change.action).unwrap_or_default();
+    format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        event.seq(),
+        action.as_str().unwrap_or("change"),
+        serde_json::to_string(event).unwrap()
+    )
+}
+
+// Tells the client to reload its todos, and where the stream resumes
+fn reset(missed: &Missed) -> String {
+    let data = serde_json::json!({
+        "error": "Changes since the last event are no longer available; reload the todos.",
+    });
+    format!("id: {}\nevent: reset\ndata: {}\n\n", missed.latest, data)
+}
@@ -0,0 +1,414 @@
+use crate::audit::{AuditEvent, AuditLog};
+use crate::feed::{ChangeFeed, TodoEvent};
+use crate::journal::{Change, Journal};
+use crate::search::SearchIndex;
+#[cfg(feature = "sqlite")]
+use crate::sqlite::Database;
+use crate::{Todo, log_error};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::Arc;
+
+pub type TodoKey = (String, usize);
+
+// The todo map plus everything derived from it. All writes go through these
+// methods so the derived data can never drift from the todos themselves.
+// Todos are keyed by (owner, id): every user has their own id sequence and
+// search index, and lookups never cross into another user's todos. The keys
+// are ordered, so one user's todos are a range rather than a scan of all.
+// With a backend attached every change is also persisted there.
+#[derive(Clone, Default)]
+pub struct Store {
+    todos: BTreeMap<TodoKey, Todo>,
+    next_ids: HashMap<String, usize>,
+    indexes: HashMap<String, SearchIndex>,
+    audit: AuditLog,
+    backend: Option<Backend>,
+    batch: Option<Batch>,
+    feed: Arc<ChangeFeed>,
+    // Events held back from the feed until the batch commits
+    unpublished: Vec<TodoEvent>,
+}
+
+// Where a store persists its changes
+#[derive(Clone)]
+enum Backend {
+    Journal(Arc<Journal>),
+    #[cfg(feature = "sqlite")]
+    Sqlite(Arc<Database>),
+}
+
+impl Backend {
+    fn write(&self, changes: &[Change]) -> Result<(), String> {
+        match self {
+            Backend::Journal(journal) => journal.append(changes),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(database) => database.write(changes),
+        }
+    }
+}
+
+// Changes made since `begin_batch`: what to persist once it commits, and what
+// to put back in memory if that fails
+#[derive(Clone, Default)]
+struct Batch {
+    changes: Vec<Change>,
+    // Todos as they were before the batch first touched them
+    previous: HashMap<TodoKey, Option<Todo>>,
+    // Each touched user's next id before the batch, None if they had none
+    next_ids: HashMap<String, Option<usize>>,
+    // The last audit event from before the batch
+    audit_seq: u64,
+    // Older events the audit log let go of to make room during the batch
+    dropped_events: Vec<AuditEvent>,
+}
+
+impl Store {
+    pub fn new() -> Store {
+        Store::default()
+    }
+
+    pub fn with_journal(mut self, journal: Arc<Journal>) -> Store {
+        self.backend = Some(Backend::Journal(journal));
+        self
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn with_database(mut self, database: Arc<Database>) -> Store {
+        self.backend = Some(Backend::Sqlite(database));
+        self
+    }
+
+    // Until `commit_batch`, changes are held in memory instead of persisted or
+    // published. Each todo's state before the batch is kept to undo it with.
+    pub fn begin_batch(&mut self) {
+        if self.batch.is_none() {
+            self.batch = Some(Batch {
+                audit_seq: self.change_seq(),
+                ..Batch::default()
+            });
+        }
+    }
+
+    pub fn journal(&self) -> Option<&Arc<Journal>> {
+        match &self.backend {
+            Some(Backend::Journal(journal)) => Some(journal),
+            _ => None,
+        }
+    }
+
+    // The next id of every user, including users whose todos are all gone
+    pub fn id_sequences(&self) -> &HashMap<String, usize> {
+        &self.next_ids
+    }
+
+    // Makes sure `owner` is never given an id below `next_id`
+    pub fn reserve_ids(&mut self, owner: &str, next_id: usize) {
+        self.remember_next_id(owner);
+        let current = self.next_ids.entry(owner.to_string()).or_insert(1);
+        *current = (*current).max(next_id);
+    }
+
+    // Persists the batch in one write. If that fails, its changes are undone
+    // in memory as well and nothing is published.
+    pub fn commit_batch(&mut self) -> Result<(), String> {
+        let Some(batch) = self.batch.take() else {
+            return Ok(());
+        };
+        if let (false, Some(backend)) = (batch.changes.is_empty(), &self.backend) {
+            if let Err(e) = backend.write(&batch.changes) {
+                self.undo(batch);
+                return Err(e);
+            }
+        }
+        self.feed.publish(std::mem::take(&mut self.unpublished));
+        Ok(())
+    }
+
+    // Drops the batch and undoes everything it changed
+    pub fn rollback_batch(&mut self) {
+        if let Some(batch) = self.batch.take() {
+            self.undo(batch);
+        }
+    }
+
+    // Runs `f` as a batch of its own, or as part of the one already open
+    pub fn transaction<T, F>(&mut self, f: F) -> Result<T, String>
+    where
+        F: FnOnce(&mut Store) -> T,
+    {
+        if self.batch.is_some() {
+            return Ok(f(self));
+        }
+        self.begin_batch();
+        let value = f(self);
+        self.commit_batch().map(|()| value)
+    }
+
+    fn undo(&mut self, batch: Batch) {
+        for ((owner, id), previous) in batch.previous {
+            self.detach(&owner, id);
+            if let Some(todo) = previous {
+                self.put(todo);
+            }
+        }
+        // After the todos, as putting them back moves the sequences too
+        for (owner, next_id) in batch.next_ids {
+            match next_id {
+                Some(next_id) => self.next_ids.insert(owner, next_id),
+                None => self.next_ids.remove(&owner),
+            };
+        }
+        self.audit.truncate_after(batch.audit_seq);
+        self.audit.restore(batch.dropped_events);
+        self.unpublished.clear();
+    }
+
+    // Where committed changes are published
+    pub fn feed(&self) -> &Arc<ChangeFeed> {
+        &self.feed
+    }
+
+    pub fn len(&self) -> usize {
+        self.todos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.todos.is_empty()
+    }
+
+    pub fn get(&self, owner: &str, id: usize) -> Option<&Todo> {
+        self.todos.get(&(owner.to_string(), id))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Todo> {
+        self.todos.values()
+    }
+
+    // The owner's todos in id order
+    pub fn values_for(&self, owner: &str) -> impl Iterator<Item = &Todo> {
+        let range = (owner.to_string(), 0)..=(owner.to_string(), usize::MAX);
+        self.todos.range(range).map(|(_, todo)| todo)
+    }
+
+    // Up to `limit` todos of any user in key order, starting after `after`
+    pub fn values_after(
+        &self,
+        after: Option<&TodoKey>,
+        limit: usize,
+    ) -> impl Iterator<Item = &Todo> {
+        let start = after.map_or(Bound::Unbounded, |key| Bound::Excluded(key.clone()));
+        self.todos
+            .range((start, Bound::Unbounded))
+            .map(|(_, todo)| todo)
+            .take(limit)
+    }
+
+    // Ids are never reused, even after the todo holding one has been purged
+    pub fn next_id(&mut self, owner: &str) -> usize {
+        self.remember_next_id(owner);
+        let next_id = self.next_ids.entry(owner.to_string()).or_insert(1);
+        let id = *next_id;
+        *next_id += 1;
+        id
+    }
+
+    pub fn insert(&mut self, todo: Todo) -> Option<Todo> {
+        self.remember(&todo.owner, todo.id);
+        self.record(|| Change::Put { todo: todo.clone() });
+        self.put(todo)
+    }
+
+    // Applies `change` to a todo and re-indexes it afterwards. The owner and id
+    // are part of the key and must not be changed.
+    pub fn update<F>(&mut self, owner: &str, id: usize, change: F) -> Option<&Todo>
+    where
+        F: FnOnce(&mut Todo),
+    {
+        let key = (owner.to_string(), id);
+        self.remember(owner, id);
+        let mut todo = self.todos.remove(&key)?;
+        self.index_remove(&todo);
+        change(&mut todo);
+        self.index_add(&todo);
+        self.record(|| Change::Put { todo: todo.clone() });
+        Some(self.todos.entry(key).or_insert(todo))
+    }
+
+    pub fn remove(&mut self, owner: &str, id: usize) -> Option<Todo> {
+        self.remember(owner, id);
+        let todo = self.detach(owner, id)?;
+        self.record(|| Change::Remove {
+            owner: owner.to_string(),
+            id,
+        });
+        Some(todo)
+    }
+
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    // Sequence number of the latest change, or 0 before the first. It only
+    // ever grows, across all users' todos.
+    pub fn change_seq(&self) -> u64 {
+        self.audit.next_seq() - 1
+    }
+
+    // Numbers the event, appends it to the audit log and publishes it along
+    // with the todo it is about
+    pub fn audit(&mut self, mut event: AuditEvent) {
+        event.seq = self.audit.next_seq();
+        self.record(|| Change::Audit {
+            event: event.clone(),
+        });
+        if let Some(todo) = self.get(&event.owner, event.id).cloned() {
+            let published = TodoEvent {
+                change: event.clone(),
+                todo,
+            };
+            match self.batch {
+                Some(_) => self.unpublished.push(published),
+                None => self.feed.publish(vec![published]),
+            }
+        }
+        if let (Some(batch), Some(dropped)) = (&mut self.batch, self.audit.push(event)) {
+            batch.dropped_events.push(dropped);
+        }
+    }
+
+    // Drops every todo. Id sequences are kept, so ids are still not reused,
+    // and so is the audit log.
+    pub fn clear(&mut self) {
+        let keys: Vec<TodoKey> = self.todos.keys().cloned().collect();
+        for (owner, id) in keys {
+            self.remember(&owner, id);
+        }
+        self.record(|| Change::Clear);
+        self.todos.clear();
+        self.indexes.clear();
+    }
+
+    // Applies a journaled change without journaling it again
+    pub fn apply(&mut self, change: Change) {
+        match change {
+            Change::Put { todo } => {
+                self.put(todo);
+            }
+            Change::Remove { owner, id } => {
+                self.detach(&owner, id);
+            }
+            Change::Clear => {
+                self.todos.clear();
+                self.indexes.clear();
+            }
+            // Events already logged may be replayed again after a crash
+            Change::Audit { event } => {
+                if event.seq >= self.audit.next_seq() {
+                    self.audit.push(event);
+                }
+            }
+        }
+    }
+
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&Todo) -> bool,
+    {
+        let dropped: Vec<TodoKey> = self
+            .todos
+            .iter()
+            .filter(|(_, todo)| !keep(todo))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for (owner, id) in dropped {
+            self.remove(&owner, id);
+        }
+    }
+
+    // The owner's live todos whose titles match `query`, most relevant first
+    pub fn search(&self, owner: &str, query: &str) -> Vec<(&Todo, f64)> {
+        let Some(index) = self.indexes.get(owner) else {
+            return Vec::new();
+        };
+        index
+            .search(query)
+            .into_iter()
+            .filter_map(|(id, score)| self.get(owner, id).map(|todo| (todo, score)))
+            .collect()
+    }
+
+    fn put(&mut self, todo: Todo) -> Option<Todo> {
+        let next_id = self.next_ids.entry(todo.owner.clone()).or_insert(1);
+        *next_id = (*next_id).max(todo.id + 1);
+        let previous = self.detach(&todo.owner, todo.id);
+        self.index_add(&todo);
+        self.todos.insert((todo.owner.clone(), todo.id), todo);
+        previous
+    }
+
+    fn detach(&mut self, owner: &str, id: usize) -> Option<Todo> {
+        let todo = self.todos.remove(&(owner.to_string(), id))?;
+        self.index_remove(&todo);
+        Some(todo)
+    }
+
+    // Takes a closure so stores without a backend don't pay for the change.
+    // Requests change the store in batches, so a failed write can be undone and
+    // reported; anything else can only log it.
+    fn record<F>(&mut self, change: F)
+    where
+        F: FnOnce() -> Change,
+    {
+        match (&mut self.batch, &self.backend) {
+            (Some(batch), Some(_)) => batch.changes.push(change()),
+            (None, Some(backend)) => {
+                if let Err(e) = backend.write(&[change()]) {
+                    log_error(&e);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Notes how a todo was before the batch changed it, in case the batch
+    // can't be persisted or is rolled back
+    fn remember(&mut self, owner: &str, id: usize) {
+        self.remember_next_id(owner);
+        if let Some(batch) = &mut self.batch {
+            let key = (owner.to_string(), id);
+            let todos = &self.todos;
+            batch
+                .previous
+                .entry(key)
+                .or_insert_with_key(|key| todos.get(key).cloned());
+        }
+    }
+
+    fn remember_next_id(&mut self, owner: &str) {
+        if let Some(batch) = &mut self.batch {
+            let next_ids = &self.next_ids;
+            batch
+                .next_ids
+                .entry(owner.to_string())
+                .or_insert_with(|| next_ids.get(owner).copied());
+        }
+    }
+
+    fn index_add(&mut self, todo: &Todo) {
+        if !todo.is_deleted() {
+            self.indexes
+                .entry(todo.owner.clone())
+                .or_default()
+                .add(todo.id, &todo.title);
+        }
+    }
+
+    fn index_remove(&mut self, todo: &Todo) {
+        if !todo.is_deleted() {
+            if let Some(index) = self.indexes.get_mut(&todo.owner) {
+                index.remove(todo.id, &todo.title);
+            }
+        }
+    }
+}
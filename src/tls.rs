@@ -0,0 +1,73 @@
+use crate::Connection;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::net::{IpAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+// Builds a server configuration from a PEM certificate chain and a PEM private
+// key (PKCS#8, PKCS#1 or SEC1)
+pub fn load_server_config(cert_file: &str, key_file: &str) -> Result<Arc<ServerConfig>, String> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Failed to read certificates from {}: {}", cert_file, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}.", cert_file));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .map_err(|e| format!("Failed to read private key from {}: {}", key_file, e))?;
+
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(|e| format!("Invalid TLS configuration: {}", e))?;
+    Ok(Arc::new(config))
+}
+
+// Wraps an accepted socket. The handshake itself runs on first read, inside
+// the worker thread rather than the accept loop.
+pub fn accept(stream: TcpStream, config: &Arc<ServerConfig>) -> Result<TlsStream, String> {
+    let connection = ServerConnection::new(Arc::clone(config))
+        .map_err(|e| format!("Failed to start TLS session: {}", e))?;
+    Ok(StreamOwned::new(connection, stream))
+}
+
+impl Connection for TlsStream {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.sock.peer_addr().ok().map(|addr| addr.ip())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.sock.set_write_timeout(timeout)
+    }
+
+    fn is_encrypted(&self) -> bool {
+        true
+    }
+
+    // Clients treat a TLS stream that ends without close_notify as truncated
+    fn close(&mut self) {
+        self.conn.send_close_notify();
+        let _ = self.conn.complete_io(&mut self.sock);
+    }
+
+    fn socket(&self) -> Option<&TcpStream> {
+        Some(&self.sock)
+    }
+
+    // Records already received may hold more than the last read took. An
+    // error counts too, so the next read reports it.
+    fn has_buffered_input(&mut self) -> bool {
+        self.conn
+            .process_new_packets()
+            .map_or(true, |state| state.plaintext_bytes_to_read() > 0)
+    }
+}
@@ -0,0 +1,270 @@
+// Moving the whole database in and out, shared by the admin endpoints and the
+// `export` and `import` subcommands. The interchange format is JSON lines:
+// one todo per line, owner and trash state included.
+use crate::audit::{Action, AuditEvent};
+use crate::store::Store;
+use crate::{Db, Todo};
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    // Imported todos are added to the ones already stored
+    Merge,
+    // Stored todos are dropped first
+    Replace,
+}
+
+impl FromStr for ImportMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ImportMode, String> {
+        match s {
+            "merge" => Ok(ImportMode::Merge),
+            "replace" => Ok(ImportMode::Replace),
+            _ => Err(format!(
+                "Unknown import mode '{}'; use merge or replace.",
+                s
+            )),
+        }
+    }
+}
+
+// What to do with an imported todo whose owner already has a todo with its id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conflict {
+    // Import nothing at all
+    Fail,
+    // Keep the stored todo
+    Skip,
+    // Replace the stored todo
+    Overwrite,
+    // Import the todo under the owner's next free id
+    Renumber,
+}
+
+impl FromStr for Conflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Conflict, String> {
+        match s {
+            "fail" => Ok(Conflict::Fail),
+            "skip" => Ok(Conflict::Skip),
+            "overwrite" => Ok(Conflict::Overwrite),
+            "renumber" => Ok(Conflict::Renumber),
+            _ => Err(format!(
+                "Unknown conflict policy '{}'; use fail, skip, overwrite or renumber.",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Default, Debug, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub overwritten: usize,
+    pub skipped: usize,
+    pub renumbered: usize,
+    // Todos dropped by a replace
+    pub removed: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImportError {
+    Invalid(String),
+    Conflict(String),
+    // Persisting failed, and the import was undone
+    Storage(String),
+}
+
+impl ImportError {
+    pub fn status(&self) -> &'static str {
+        match self {
+            ImportError::Invalid(_) => "400 Bad Request",
+            ImportError::Conflict(_) => "409 Conflict",
+            ImportError::Storage(_) => "500 Internal Server Error",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ImportError::Invalid(message)
+            | ImportError::Conflict(message)
+            | ImportError::Storage(message) => message,
+        }
+    }
+}
+
+// Every todo of every user, trash included, in a stable order
+pub fn export(store: &Store) -> Vec<&Todo> {
+    let mut todos: Vec<&Todo> = store.values().collect();
+    todos.sort_by(|a, b| order(a, b));
+    todos
+}
+
+// Puts copies of the todos in export order, so they can be serialized after
+// the store's lock has been released
+pub fn sort(todos: &mut [Todo]) {
+    todos.sort_by(order);
+}
+
+fn order(a: &Todo, b: &Todo) -> Ordering {
+    (&a.owner, a.id).cmp(&(&b.owner, b.id))
+}
+
+// Todos copied out of the store per turn of its lock
+const EXPORT_BATCH: usize = 500;
+
+// An export written out as it is read, a batch of todos per turn of the
+// store's lock, in the same order as `export`. Todos changed while it runs
+// show up as they are when their batch is read.
+pub struct Export {
+    db: Db,
+    // JSON lines rather than a JSON array
+    lines: bool,
+}
+
+impl Export {
+    pub fn new(db: Db, lines: bool) -> Export {
+        Export { db, lines }
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        if !self.lines {
+            out.write_all(b"[")?;
+        }
+        let mut after = None;
+        loop {
+            let batch: Vec<Todo> = self
+                .db
+                .lock()
+                .unwrap()
+                .values_after(after.as_ref(), EXPORT_BATCH)
+                .cloned()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            let mut chunk = Vec::new();
+            for todo in &batch {
+                if !self.lines && after.is_some() {
+                    chunk.push(b',');
+                }
+                serde_json::to_writer(&mut chunk, todo)?;
+                if self.lines {
+                    chunk.push(b'\n');
+                }
+                after = Some((todo.owner.clone(), todo.id));
+            }
+            out.write_all(&chunk)?;
+        }
+        if !self.lines {
+            out.write_all(b"]")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn write_lines<W: Write>(store: &Store, out: &mut W) -> io::Result<()> {
+    for todo in export(store) {
+        serde_json::to_writer(&mut *out, todo)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+pub fn parse_lines(text: &str) -> Result<Vec<Todo>, ImportError> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| ImportError::Invalid(format!("Line {}: {}", index + 1, e)))
+        })
+        .collect()
+}
+
+// Checks everything before touching the store, so an import either applies
+// completely or not at all. The journal sees it as one batch. Every todo
+// stored is audited as created or updated by `actor`.
+pub fn import(
+    store: &mut Store,
+    actor: &str,
+    todos: Vec<Todo>,
+    mode: ImportMode,
+    conflict: Conflict,
+) -> Result<ImportSummary, ImportError> {
+    let mut taken: HashSet<(String, usize)> = match mode {
+        ImportMode::Merge => store
+            .values()
+            .map(|todo| (todo.owner.clone(), todo.id))
+            .collect(),
+        ImportMode::Replace => HashSet::new(),
+    };
+    for (index, todo) in todos.iter().enumerate() {
+        let record = index + 1;
+        if todo.owner.is_empty() {
+            return Err(ImportError::Invalid(format!(
+                "Record {}: owner is empty.",
+                record
+            )));
+        }
+        if todo.id == 0 {
+            return Err(ImportError::Invalid(format!(
+                "Record {}: id must be at least 1.",
+                record
+            )));
+        }
+        if todo.title.trim().is_empty() {
+            return Err(ImportError::Invalid(format!(
+                "Record {}: title is empty.",
+                record
+            )));
+        }
+        let key = (todo.owner.clone(), todo.id);
+        if !taken.insert(key) && conflict == Conflict::Fail {
+            return Err(ImportError::Conflict(format!(
+                "Record {}: todo {} of user '{}' already exists.",
+                record, todo.id, todo.owner
+            )));
+        }
+    }
+
+    let mut summary = ImportSummary::default();
+    store.begin_batch();
+    if mode == ImportMode::Replace {
+        summary.removed = store.len();
+        store.clear();
+    }
+    for mut todo in todos {
+        let mut before = store.get(&todo.owner, todo.id).cloned();
+        if before.is_some() {
+            match conflict {
+                // Fail was ruled out above
+                Conflict::Fail | Conflict::Overwrite => summary.overwritten += 1,
+                Conflict::Skip => {
+                    summary.skipped += 1;
+                    continue;
+                }
+                Conflict::Renumber => {
+                    todo.id = store.next_id(&todo.owner);
+                    summary.renumbered += 1;
+                    before = None;
+                }
+            }
+        }
+        let action = match before {
+            Some(_) => Action::Updated,
+            None => Action::Created,
+        };
+        store.insert(todo.clone());
+        store.audit(AuditEvent::new(actor, action, before.as_ref(), &todo));
+        summary.imported += 1;
+    }
+    store.commit_batch().map_err(ImportError::Storage)?;
+    Ok(summary)
+}
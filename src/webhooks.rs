@@ -0,0 +1,740 @@
+// Outgoing webhooks: users register URLs that are sent their todo changes.
+// Each change becomes a queued delivery, POSTed with an HMAC-SHA256 signature
+// and retried with exponential backoff until it is accepted or runs out of
+// attempts. Every change to the webhooks, the queue and the delivery log is
+// appended to a journal beside the state file, and the two are merged into
+// the state file now and then, so nothing queued is lost to a restart.
+use crate::audit::Action;
+use crate::config::WebhookConfig;
+use crate::crypto::hmac_sha256;
+use crate::feed::TodoEvent;
+use crate::log_error;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Delivery log entries kept per webhook
+const LOG_LIMIT: usize = 100;
+// Journal entries written before they are merged into the state file
+const COMPACT_AFTER: usize = 1000;
+// Shorter secrets are too easy to guess
+const MIN_SECRET_LEN: usize = 16;
+// The most of a receiver's answer read to find its status line
+const STATUS_LINE_LIMIT: u64 = 1024;
+const ALL_ACTIONS: [Action; 4] = [
+    Action::Created,
+    Action::Updated,
+    Action::Deleted,
+    Action::Restored,
+];
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Webhook {
+    pub id: usize,
+    pub owner: String,
+    pub url: String,
+    // The actions the webhook is sent
+    pub events: Vec<Action>,
+    // Blanked by `public`, and then left out
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub secret: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Webhook {
+    // The webhook as its owner may see it
+    pub fn public(&self) -> Webhook {
+        Webhook {
+            secret: String::new(),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewWebhook {
+    pub url: String,
+    // Every action when left out
+    pub events: Option<Vec<Action>>,
+    // Key for the signature; the receiver needs the same one to check it
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Delivery {
+    id: u64,
+    webhook: usize,
+    event: TodoEvent,
+    // Failed attempts so far
+    attempts: u32,
+    next_attempt_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Delivered,
+    // Failed, and tried again later
+    Retrying,
+    // Failed for the last time
+    Failed,
+}
+
+// One delivery attempt
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeliveryRecord {
+    pub delivery: u64,
+    pub webhook: usize,
+    pub seq: u64,
+    pub action: Action,
+    pub attempt: u32,
+    pub at: DateTime<Utc>,
+    pub outcome: Outcome,
+    // The receiver's status code, if it answered
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    next_webhook: usize,
+    next_delivery: u64,
+    webhooks: Vec<Webhook>,
+    queue: Vec<Delivery>,
+    log: Vec<DeliveryRecord>,
+}
+
+impl Default for State {
+    fn default() -> State {
+        State {
+            next_webhook: 1,
+            next_delivery: 1,
+            webhooks: Vec::new(),
+            queue: Vec::new(),
+            log: Vec::new(),
+        }
+    }
+}
+
+// A change to the state, as written to the journal
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Entry {
+    Created {
+        webhook: Webhook,
+    },
+    Removed {
+        id: usize,
+    },
+    Queued {
+        delivery: Delivery,
+    },
+    // None when the delivery leaves the queue
+    Attempted {
+        record: DeliveryRecord,
+        next_attempt_at: Option<DateTime<Utc>>,
+    },
+}
+
+impl State {
+    // Entries already reflected in the state are skipped, so replaying a
+    // journal the state file has caught up with changes nothing
+    fn apply(&mut self, entry: Entry) {
+        match entry {
+            Entry::Created { webhook } => {
+                if webhook.id >= self.next_webhook {
+                    self.next_webhook = webhook.id + 1;
+                    self.webhooks.push(webhook);
+                }
+            }
+            Entry::Removed { id } => {
+                self.webhooks.retain(|webhook| webhook.id != id);
+                self.queue.retain(|delivery| delivery.webhook != id);
+                self.log.retain(|record| record.webhook != id);
+            }
+            Entry::Queued { delivery } => {
+                if delivery.id >= self.next_delivery {
+                    self.next_delivery = delivery.id + 1;
+                    self.queue.push(delivery);
+                }
+            }
+            Entry::Attempted {
+                record,
+                next_attempt_at,
+            } => {
+                let Some(index) = self.queue.iter().position(|delivery| {
+                    delivery.id == record.delivery && delivery.attempts + 1 == record.attempt
+                }) else {
+                    return;
+                };
+                match next_attempt_at {
+                    Some(at) => {
+                        self.queue[index].attempts = record.attempt;
+                        self.queue[index].next_attempt_at = at;
+                    }
+                    None => {
+                        self.queue.remove(index);
+                    }
+                }
+                let webhook = record.webhook;
+                self.log.push(record);
+                let logged = self.log.iter().filter(|r| r.webhook == webhook).count();
+                if logged > LOG_LIMIT {
+                    if let Some(oldest) = self.log.iter().position(|r| r.webhook == webhook) {
+                        self.log.remove(oldest);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The state file and the journal of changes made since it was written
+struct Storage {
+    path: PathBuf,
+    journal: File,
+    entries: usize,
+}
+
+pub struct Webhooks {
+    state: Mutex<State>,
+    // None keeps the state in memory. Only locked while `state` is.
+    storage: Option<Mutex<Storage>>,
+    // Webhooks with deliveries being sent right now
+    sending: Mutex<HashSet<usize>>,
+    config: WebhookConfig,
+}
+
+impl Webhooks {
+    // Picks up the webhooks and queue saved in `path`, if there are any
+    pub fn open(config: WebhookConfig, path: Option<PathBuf>) -> Result<Webhooks, String> {
+        let Some(path) = path else {
+            return Ok(Webhooks::in_memory(config));
+        };
+        let mut state = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text)
+                .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        let journal_path = journal_path(&path);
+        let entries = read_journal(&journal_path)?;
+        let count = entries.len();
+        for entry in entries {
+            state.apply(entry);
+        }
+        let journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal_path)
+            .map_err(|e| format!("Failed to open {}: {}", journal_path.display(), e))?;
+        Ok(Webhooks {
+            state: Mutex::new(state),
+            storage: Some(Mutex::new(Storage {
+                path,
+                journal,
+                entries: count,
+            })),
+            sending: Mutex::new(HashSet::new()),
+            config,
+        })
+    }
+
+    pub fn in_memory(config: WebhookConfig) -> Webhooks {
+        Webhooks {
+            state: Mutex::new(State::default()),
+            storage: None,
+            sending: Mutex::new(HashSet::new()),
+            config,
+        }
+    }
+
+    pub fn create(&self, owner: &str, new: NewWebhook) -> Result<Webhook, String> {
+        Target::parse(&new.url)?.resolve(&self.config.allowed_hosts)?;
+        if new.secret.len() < MIN_SECRET_LEN {
+            return Err(format!(
+                "The secret must be at least {} characters long.",
+                MIN_SECRET_LEN
+            ));
+        }
+        let events = new.events.unwrap_or_else(|| ALL_ACTIONS.to_vec());
+        if events.is_empty() {
+            return Err("A webhook needs at least one event.".to_string());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let webhook = Webhook {
+            id: state.next_webhook,
+            owner: owner.to_string(),
+            url: new.url,
+            events,
+            secret: new.secret,
+            created_at: Utc::now(),
+        };
+        self.commit(
+            &mut state,
+            Entry::Created {
+                webhook: webhook.clone(),
+            },
+        );
+        Ok(webhook)
+    }
+
+    pub fn list(&self, owner: &str) -> Vec<Webhook> {
+        let state = self.state.lock().unwrap();
+        state
+            .webhooks
+            .iter()
+            .filter(|webhook| webhook.owner == owner)
+            .map(Webhook::public)
+            .collect()
+    }
+
+    // Drops the webhook along with its queued deliveries and log
+    pub fn remove(&self, owner: &str, id: usize) -> Option<Webhook> {
+        let mut state = self.state.lock().unwrap();
+        let webhook = state
+            .webhooks
+            .iter()
+            .find(|webhook| webhook.owner == owner && webhook.id == id)?
+            .public();
+        self.commit(&mut state, Entry::Removed { id });
+        Some(webhook)
+    }
+
+    // Attempts for one of the owner's webhooks, oldest first
+    pub fn deliveries(&self, owner: &str, id: usize) -> Option<Vec<DeliveryRecord>> {
+        let state = self.state.lock().unwrap();
+        state
+            .webhooks
+            .iter()
+            .find(|webhook| webhook.owner == owner && webhook.id == id)?;
+        let records = state.log.iter().filter(|record| record.webhook == id);
+        Some(records.cloned().collect())
+    }
+
+    // Deliveries waiting for their next attempt
+    pub fn pending(&self) -> usize {
+        self.state.lock().unwrap().queue.len()
+    }
+
+    // Queues a delivery of each event to every webhook that wants it
+    pub fn enqueue(&self, events: &[TodoEvent]) {
+        let mut state = self.state.lock().unwrap();
+        let now = Utc::now();
+        for event in events {
+            let targets: Vec<usize> = state
+                .webhooks
+                .iter()
+                .filter(|webhook| {
+                    webhook.owner == event.change.owner
+                        && webhook.events.contains(&event.change.action)
+                })
+                .map(|webhook| webhook.id)
+                .collect();
+            for webhook in targets {
+                let delivery = Delivery {
+                    id: state.next_delivery,
+                    webhook,
+                    event: event.clone(),
+                    attempts: 0,
+                    next_attempt_at: now,
+                };
+                self.commit(&mut state, Entry::Queued { delivery });
+            }
+        }
+    }
+
+    // Time until the next delivery is due; None when nothing is queued
+    pub fn until_next_due(&self) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        let next = state
+            .queue
+            .iter()
+            .map(|delivery| delivery.next_attempt_at)
+            .min()?;
+        Some((next - Utc::now()).to_std().unwrap_or(Duration::ZERO))
+    }
+
+    // Starts sending the deliveries that are due. Each webhook's are sent in
+    // order on a thread of their own, so a slow receiver only holds up its
+    // own deliveries, and at most `max_parallel` webhooks are sent to at once.
+    // The lock is not held while receivers are contacted.
+    pub fn deliver_due(self: &Arc<Self>) {
+        let now = Utc::now();
+        let mut sending = self.sending.lock().unwrap();
+        let mut due: BTreeMap<usize, (Webhook, Vec<Delivery>)> = BTreeMap::new();
+        {
+            let state = self.state.lock().unwrap();
+            let waiting = state.queue.iter().filter(|delivery| {
+                delivery.next_attempt_at <= now && !sending.contains(&delivery.webhook)
+            });
+            for delivery in waiting {
+                let Some(webhook) = state.webhooks.iter().find(|w| w.id == delivery.webhook) else {
+                    continue;
+                };
+                due.entry(webhook.id)
+                    .or_insert_with(|| (webhook.clone(), Vec::new()))
+                    .1
+                    .push(delivery.clone());
+            }
+        }
+        for (id, (webhook, deliveries)) in due {
+            if sending.len() >= self.config.max_parallel.max(1) {
+                break;
+            }
+            sending.insert(id);
+            let webhooks = Arc::clone(self);
+            thread::spawn(move || {
+                for delivery in deliveries {
+                    let result = webhooks.send(&webhook, &delivery);
+                    let failed = result.is_err();
+                    webhooks.record(&delivery, result);
+                    // The rest would most likely fail too; they are tried
+                    // again on the next round
+                    if failed {
+                        break;
+                    }
+                }
+                webhooks.sending.lock().unwrap().remove(&id);
+            });
+        }
+    }
+
+    fn send(&self, webhook: &Webhook, delivery: &Delivery) -> Result<u16, (Option<u16>, String)> {
+        let body = serde_json::to_string(&delivery.event).unwrap();
+        let timestamp = Utc::now().timestamp().to_string();
+        let headers = [
+            ("X-Webhook-Id", webhook.id.to_string()),
+            ("X-Webhook-Delivery", delivery.id.to_string()),
+            ("X-Webhook-Event", action_name(delivery.event.change.action)),
+            ("X-Webhook-Timestamp", timestamp.clone()),
+            (
+                "X-Webhook-Signature",
+                signature(&webhook.secret, &timestamp, &body),
+            ),
+        ];
+        let status = post(&webhook.url, &headers, &body, &self.config).map_err(|e| (None, e))?;
+        match status {
+            200..=299 => Ok(status),
+            _ => Err((Some(status), format!("Receiver answered {}.", status))),
+        }
+    }
+
+    // Logs an attempt and reschedules or drops the delivery. A delivery gone
+    // from the queue meanwhile belonged to a removed webhook.
+    fn record(&self, delivery: &Delivery, result: Result<u16, (Option<u16>, String)>) {
+        let mut state = self.state.lock().unwrap();
+        if !state.queue.iter().any(|queued| queued.id == delivery.id) {
+            return;
+        }
+        let attempt = delivery.attempts + 1;
+        let (outcome, status, error) = match result {
+            Ok(status) => (Outcome::Delivered, Some(status), None),
+            Err((status, error)) if attempt >= self.config.max_attempts => {
+                (Outcome::Failed, status, Some(error))
+            }
+            Err((status, error)) => (Outcome::Retrying, status, Some(error)),
+        };
+        if let Some(error) = &error {
+            log_error(&format!(
+                "Webhook {} delivery {} attempt {} failed: {}",
+                delivery.webhook, delivery.id, attempt, error
+            ));
+        }
+        let next_attempt_at = match outcome {
+            Outcome::Retrying => {
+                let backoff = self.config.backoff(attempt).as_millis() as i64;
+                Some(Utc::now() + chrono::Duration::milliseconds(backoff))
+            }
+            Outcome::Delivered | Outcome::Failed => None,
+        };
+        let record = DeliveryRecord {
+            delivery: delivery.id,
+            webhook: delivery.webhook,
+            seq: delivery.event.seq(),
+            action: delivery.event.change.action,
+            attempt,
+            at: Utc::now(),
+            outcome,
+            status,
+            error,
+        };
+        self.commit(
+            &mut state,
+            Entry::Attempted {
+                record,
+                next_attempt_at,
+            },
+        );
+    }
+
+    // Applies `entry` and appends it to the journal, merging the journal into
+    // the state file once it has grown long enough
+    fn commit(&self, state: &mut State, entry: Entry) {
+        let Some(storage) = &self.storage else {
+            state.apply(entry);
+            return;
+        };
+        let mut storage = storage.lock().unwrap();
+        let mut line = serde_json::to_string(&entry).unwrap();
+        line.push('\n');
+        state.apply(entry);
+        if let Err(e) = storage.journal.write_all(line.as_bytes()) {
+            log_error(&format!(
+                "Failed to write to {}: {}",
+                journal_path(&storage.path).display(),
+                e
+            ));
+        }
+        storage.entries += 1;
+        if storage.entries >= COMPACT_AFTER {
+            match compact(&mut storage, state) {
+                Ok(()) => storage.entries = 0,
+                Err(e) => log_error(&e),
+            }
+        }
+    }
+}
+
+// Beside the state file: webhooks.json is journalled in webhooks.jsonl
+fn journal_path(path: &Path) -> PathBuf {
+    path.with_extension("jsonl")
+}
+
+// The entries in the journal at `path`. A crash can leave the last one cut
+// short; it is dropped.
+fn read_journal(path: &Path) -> Result<Vec<Entry>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    let mut entries = Vec::new();
+    let mut lines = text.lines().peekable();
+    while let Some(line) = lines.next() {
+        match serde_json::from_str(line) {
+            Ok(entry) => entries.push(entry),
+            Err(_) if lines.peek().is_none() && !text.ends_with('\n') => break,
+            Err(e) => return Err(format!("Failed to parse {}: {}", path.display(), e)),
+        }
+    }
+    Ok(entries)
+}
+
+// Writes the whole state beside the state file and renames it into place, so
+// a crash leaves either the old file or the new one, then empties the
+// journal. Should a crash come in between, the journal is replayed onto a
+// state that already has its entries, which skips them.
+fn compact(storage: &mut Storage, state: &State) -> Result<(), String> {
+    let path = &storage.path;
+    let temp = path.with_extension("tmp");
+    File::create(&temp)
+        .and_then(|mut file| {
+            serde_json::to_writer(&mut file, state)?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&temp, path))
+        .and_then(|()| storage.journal.set_len(0))
+        .map_err(|e| format!("Failed to save {}: {}", path.display(), e))
+}
+
+fn action_name(action: Action) -> String {
+    serde_json::to_value(action)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+// The X-Webhook-Signature of a delivery: HMAC-SHA256 over the timestamp, a
+// dot and the body, keyed with the webhook's secret. Covering the timestamp
+// lets receivers refuse old deliveries replayed at them.
+pub fn signature(secret: &str, timestamp: &str, body: &str) -> String {
+    let message = format!("{}.{}", timestamp, body);
+    let digest = hmac_sha256(secret.as_bytes(), message.as_bytes());
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256={}", hex)
+}
+
+// Where a webhook URL points
+struct Target {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Target {
+    // Only plain http:// URLs are supported
+    fn parse(url: &str) -> Result<Target, String> {
+        let invalid = || {
+            format!(
+                "Invalid webhook URL {}; expected http://host[:port]/path.",
+                url
+            )
+        };
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let parse_port = |port: &str| port.parse().map_err(|_| invalid());
+        // IPv6 literals come in brackets, so their colons aren't taken for the port
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => match bracketed.split_once(']').ok_or_else(invalid)? {
+                (host, "") => (host, 80),
+                (host, rest) => (
+                    host,
+                    parse_port(rest.strip_prefix(':').ok_or_else(invalid)?)?,
+                ),
+            },
+            None => match authority.rsplit_once(':') {
+                Some((host, rest)) => (host, parse_port(rest)?),
+                None => (authority, 80),
+            },
+        };
+        // Both end up in the request line and Host header, so anything that
+        // could break out of them is refused
+        let unsafe_char = |c: char| c.is_control() || c.is_whitespace();
+        if host.is_empty()
+            || host.contains('@')
+            || host.contains(unsafe_char)
+            || path.contains(unsafe_char)
+        {
+            return Err(invalid());
+        }
+        Ok(Target {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    // What goes in the Host header
+    fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    // The address to send to. Unless the host is one of `allowed_hosts`,
+    // every address it resolves to has to be public, so webhooks can't be
+    // aimed at the server itself or the network behind it. Checked again on
+    // every attempt, as the host may resolve differently by then.
+    fn resolve(&self, allowed_hosts: &[String]) -> Result<SocketAddr, String> {
+        let addresses: Vec<SocketAddr> = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(|e| format!("Failed to resolve {}: {}", self.host, e))?
+            .collect();
+        let allowed = allowed_hosts
+            .iter()
+            .any(|host| host.eq_ignore_ascii_case(&self.host));
+        if !allowed {
+            if let Some(address) = addresses.iter().find(|address| !is_public(address.ip())) {
+                return Err(format!(
+                    "Webhook host {} resolves to {}, which is not a public address.",
+                    self.host,
+                    address.ip()
+                ));
+            }
+        }
+        addresses
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("Failed to resolve {}.", self.host))
+    }
+}
+
+// Not loopback, private, link-local (which includes cloud metadata services),
+// shared, multicast or otherwise reserved. IPv6 ranges that can lead to an
+// IPv4 address (6to4, NAT64) are refused along with deprecated site-local.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                || a >= 240
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let segments = ip.segments();
+                !(ip.is_loopback()
+                    || segments[0] == 0x2002
+                    || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+                    || segments[0] & 0xffc0 == 0xfec0
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+// Sends a POST and returns the status code it was answered with
+fn post(
+    url: &str,
+    headers: &[(&str, String)],
+    body: &str,
+    config: &WebhookConfig,
+) -> Result<u16, String> {
+    let target = Target::parse(url)?;
+    let address = target.resolve(&config.allowed_hosts)?;
+    let timeout = config.timeout();
+    let mut stream = TcpStream::connect_timeout(&address, timeout)
+        .map_err(|e| format!("Failed to connect to {}: {}", address, e))?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|()| stream.set_write_timeout(Some(timeout)))
+        .map_err(|e| e.to_string())?;
+
+    let extra_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    let request = format!(
+        "POST {} HTTP/1.1\r\n\
+        Host: {}\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: {}\r\n\
+        {}\
+        Connection: close\r\n\
+        \r\n\
+        {}",
+        target.path,
+        target.authority(),
+        body.len(),
+        extra_headers,
+        body
+    );
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|e| format!("Failed to send to {}: {}", address, e))?;
+
+    let mut status_line = String::new();
+    BufReader::new((&stream).take(STATUS_LINE_LIMIT))
+        .read_line(&mut status_line)
+        .map_err(|e| format!("No answer from {}: {}", address, e))?;
+    status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("Malformed answer from {}.", address))
+}
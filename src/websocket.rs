@@ -0,0 +1,627 @@
+// GET /ws: a WebSocket (RFC 6455) connection for collaborative clients. Each
+// text message is a JSON object with a "type": clients subscribe to their todo
+// changes and send the same requests the HTTP API takes, and the server
+// answers each one and pushes changes as they are committed. Text messages
+// may carry a "ref", which is echoed in the reply.
+use crate::auth::Principal;
+use crate::cors;
+use crate::crypto::{base64_decode, base64_encode, sha1};
+use crate::feed::{self, ChangeFeed, Missed, TodoEvent};
+use crate::http::Headers;
+use crate::policy::Operation;
+use crate::{
+    AppState, Connection, Response, Takeover, forbidden, log_error, rate_limit_key, route,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use std::io::{ErrorKind, Write};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const PATH: &str = "/ws";
+
+// Appended to the client's key to prove the server speaks WebSocket
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Idle connections are pinged, and closed if nothing comes back
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+// Close codes
+const GOING_AWAY: u16 = 1001;
+const PROTOCOL_ERROR: u16 = 1002;
+const UNSUPPORTED_DATA: u16 = 1003;
+const INVALID_DATA: u16 = 1007;
+const TOO_BIG: u16 = 1009;
+
+pub struct Session {
+    principal: Principal,
+    peer: Option<IpAddr>,
+    feed: Arc<ChangeFeed>,
+    // The last change sent, while subscribed
+    subscribed: Option<u64>,
+    missed: Option<Missed>,
+}
+
+// Why the connection is being closed, sent to the client in a close frame
+struct Closing {
+    code: u16,
+    reason: &'static str,
+}
+
+impl Closing {
+    fn new(code: u16, reason: &'static str) -> Closing {
+        log_error(&format!("Closing WebSocket: {}", reason));
+        Closing { code, reason }
+    }
+}
+
+// What a waiting session is woken for
+enum Wake {
+    Input,
+    Published,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Command {
+    Subscribe {
+        since: Option<u64>,
+    },
+    Unsubscribe {},
+    List {
+        #[serde(default)]
+        deleted: bool,
+    },
+    Get {
+        id: usize,
+    },
+    Create {
+        title: String,
+    },
+    Update {
+        id: usize,
+        title: Option<String>,
+        completed: Option<bool>,
+    },
+    Delete {
+        id: usize,
+    },
+    Restore {
+        id: usize,
+    },
+}
+
+pub fn open(
+    headers: &Headers,
+    principal: &Principal,
+    peer: Option<IpAddr>,
+    state: &AppState,
+) -> Response {
+    let has_token = |name: &str, token: &str| {
+        headers
+            .get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "Upgrade") {
+        let error = "This endpoint needs a WebSocket upgrade.";
+        log_error(error);
+        return Response::from(("426 Upgrade Required", error.to_string()))
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade");
+    }
+    if headers.get("Sec-WebSocket-Version") != Some("13") {
+        let error = "Unsupported WebSocket version.";
+        log_error(error);
+        return Response::from(("426 Upgrade Required", error.to_string()))
+            .with_header("Sec-WebSocket-Version", "13");
+    }
+    if !cors::websocket_allowed(headers, state.config.cors.as_ref()) {
+        let error = "WebSocket connections from this origin are not allowed.";
+        log_error(error);
+        return ("403 Forbidden", error.to_string()).into();
+    }
+    let key = headers.get("Sec-WebSocket-Key").unwrap_or("").trim();
+    if base64_decode(key).is_none_or(|nonce| nonce.len() != 16) {
+        let error = "Invalid Sec-WebSocket-Key.";
+        log_error(error);
+        return ("400 Bad Request", error.to_string()).into();
+    }
+
+    let accept = base64_encode(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()));
+    let session = Session {
+        principal: principal.clone(),
+        peer,
+        feed: Arc::clone(state.db.lock().unwrap().feed()),
+        subscribed: None,
+        missed: None,
+    };
+    Response {
+        status: "101 Switching Protocols",
+        headers: Vec::new(),
+        content_type: "",
+        body: Vec::new(),
+        takeover: Some(Takeover::WebSocket(Box::new(session))),
+    }
+    .with_header("Upgrade", "websocket")
+    .with_header("Connection", "Upgrade")
+    .with_header("Sec-WebSocket-Accept", &accept)
+}
+
+impl Session {
+    // Serves the connection until either side closes it. A second thread
+    // waits for input on the socket, so the session sleeps until the client
+    // sends something, one of the user's todos changes, or a ping is due.
+    pub fn run<S: Connection>(self, stream: &mut S, state: &AppState) {
+        let watched = match stream.socket().map(TcpStream::try_clone) {
+            Some(Ok(watched)) => watched,
+            Some(Err(e)) => {
+                log_error(&format!("Failed to watch WebSocket: {}", e));
+                return;
+            }
+            None => {
+                log_error("Failed to watch WebSocket: the connection has no socket.");
+                return;
+            }
+        };
+        // Bounds reads of input that has only partly arrived
+        let read_timeout = state.config.read_timeout().max(Duration::from_millis(1));
+        if let Err(e) = stream.set_read_timeout(Some(read_timeout)) {
+            log_error(&format!("Failed to set read timeout: {}", e));
+            return;
+        }
+
+        let (wake, woken) = mpsc::channel();
+        let (resume, resumed) = mpsc::channel();
+        let input_wake = wake.clone();
+        let watcher = thread::spawn(move || watch_input(&watched, &input_wake, &resumed));
+        let watch = self.feed.watch(&self.principal.user, move || {
+            let _ = wake.send(Wake::Published);
+        });
+
+        // The channels close when it returns, so the watcher can't wait on it
+        self.serve(stream, woken, resume, state);
+
+        drop(watch);
+        // Wakes the watcher from its wait, and it finds the session gone
+        if let Some(socket) = stream.socket() {
+            let _ = socket.shutdown(Shutdown::Read);
+        }
+        let _ = watcher.join();
+    }
+
+    fn serve<S: Connection>(
+        mut self,
+        stream: &mut S,
+        woken: Receiver<Wake>,
+        resume: Sender<()>,
+        state: &AppState,
+    ) {
+        let max_message = state.config.limits.max_body;
+        let mut input = Vec::new();
+        let mut message: Option<(u8, Vec<u8>)> = None;
+        let mut last_heard = Instant::now();
+        let mut pinged = false;
+
+        // Input that arrived along with the upgrade request
+        if stream.has_buffered_input() && read_available(stream, &mut input).is_err() {
+            return;
+        }
+        let closing = loop {
+            let handled = self.handle_input(stream, &mut input, &mut message, max_message, state);
+            let sent = match handled {
+                Ok(true) => Ok(()),
+                Ok(false) => return,
+                Err(closing) => break closing,
+            }
+            .and_then(|()| self.send_changes(stream));
+            if sent.is_err() {
+                return;
+            }
+
+            if last_heard.elapsed() >= PING_INTERVAL * 2 {
+                break Closing::new(GOING_AWAY, "No reply to ping.");
+            }
+            if !pinged && last_heard.elapsed() >= PING_INTERVAL {
+                if write_frame(stream, PING, b"").is_err() {
+                    return;
+                }
+                pinged = true;
+            }
+
+            let silence = if pinged {
+                PING_INTERVAL * 2
+            } else {
+                PING_INTERVAL
+            };
+            let timeout = (last_heard + silence).saturating_duration_since(Instant::now());
+            match woken.recv_timeout(timeout) {
+                Ok(Wake::Input) => {
+                    let read = read_available(stream, &mut input);
+                    let _ = resume.send(());
+                    match read {
+                        Ok(true) => {
+                            last_heard = Instant::now();
+                            pinged = false;
+                        }
+                        Ok(false) => {}
+                        Err(_) => return,
+                    }
+                }
+                Ok(Wake::Published) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        };
+        let mut payload = closing.code.to_be_bytes().to_vec();
+        payload.extend_from_slice(closing.reason.as_bytes());
+        let _ = write_frame(stream, CLOSE, &payload);
+    }
+
+    // Handles every complete frame received so far. Ok(false) once the
+    // client's close has been answered.
+    fn handle_input<S: Connection>(
+        &mut self,
+        stream: &mut S,
+        input: &mut Vec<u8>,
+        message: &mut Option<(u8, Vec<u8>)>,
+        max_message: usize,
+        state: &AppState,
+    ) -> Result<bool, Closing> {
+        while let Some((frame, consumed)) = parse_frame(input, max_message)? {
+            input.drain(..consumed);
+            let sent = match frame.opcode {
+                PING => write_frame(stream, PONG, &frame.payload),
+                PONG => Ok(()),
+                CLOSE => {
+                    let code = match frame.payload.len() {
+                        0 => None,
+                        1 => return Err(Closing::new(PROTOCOL_ERROR, "Malformed close frame.")),
+                        _ => Some(u16::from_be_bytes([frame.payload[0], frame.payload[1]])),
+                    };
+                    // The client's code is echoed back, as RFC 6455 asks
+                    let payload = code.map_or_else(Vec::new, |code| code.to_be_bytes().to_vec());
+                    let _ = write_frame(stream, CLOSE, &payload);
+                    return Ok(false);
+                }
+                opcode => {
+                    let (opcode, data) = match (opcode, message.take()) {
+                        (CONTINUATION, Some((opcode, mut data))) => {
+                            data.extend_from_slice(&frame.payload);
+                            (opcode, data)
+                        }
+                        (CONTINUATION, None) => {
+                            return Err(Closing::new(PROTOCOL_ERROR, "Unexpected continuation."));
+                        }
+                        (_, Some(_)) => {
+                            return Err(Closing::new(PROTOCOL_ERROR, "Unfinished message."));
+                        }
+                        (opcode, None) => (opcode, frame.payload),
+                    };
+                    if data.len() > max_message {
+                        return Err(Closing::new(TOO_BIG, "Message too large."));
+                    }
+                    if !frame.fin {
+                        *message = Some((opcode, data));
+                        continue;
+                    }
+                    if opcode != TEXT {
+                        let reason = "Only text messages are supported.";
+                        return Err(Closing::new(UNSUPPORTED_DATA, reason));
+                    }
+                    let Ok(text) = String::from_utf8(data) else {
+                        return Err(Closing::new(INVALID_DATA, "Invalid UTF-8."));
+                    };
+                    let reply = self.handle_message(&text, state);
+                    send_json(stream, &reply)
+                }
+            };
+            if sent.is_err() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn handle_message(&mut self, text: &str, state: &AppState) -> Value {
+        let message: Value = match serde_json::from_str(text) {
+            Ok(message) => message,
+            Err(e) => {
+                let error = format!("Invalid message: {}", e);
+                log_error(&error);
+                return reply(None, "error", json!({ "error": error }));
+            }
+        };
+        let reference = message.get("ref").cloned();
+        let command = match serde_json::from_value::<Command>(message) {
+            Ok(command) => command,
+            Err(e) => {
+                let error = format!("Invalid message: {}", e);
+                log_error(&error);
+                return reply(reference, "error", json!({ "error": error }));
+            }
+        };
+
+        let (method, path, query, body) = match command {
+            Command::Subscribe { since } => return self.subscribe(reference, since, state),
+            Command::Unsubscribe {} => {
+                self.subscribed = None;
+                self.missed = None;
+                return reply(reference, "unsubscribed", json!({}));
+            }
+            Command::List { deleted } => {
+                let query = if deleted { "deleted=true" } else { "" };
+                ("GET", "/todos".to_string(), query, String::new())
+            }
+            Command::Get { id } => ("GET", format!("/todos/{}", id), "", String::new()),
+            Command::Create { title } => (
+                "POST",
+                "/todos".to_string(),
+                "",
+                json!({ "title": title }).to_string(),
+            ),
+            Command::Update {
+                id,
+                title,
+                completed,
+            } => {
+                let mut fields = Map::new();
+                if let Some(title) = title {
+                    fields.insert("title".to_string(), json!(title));
+                }
+                if let Some(completed) = completed {
+                    fields.insert("completed".to_string(), json!(completed));
+                }
+                let body = Value::Object(fields).to_string();
+                ("PUT", format!("/todos/{}", id), "", body)
+            }
+            Command::Delete { id } => ("DELETE", format!("/todos/{}", id), "", String::new()),
+            Command::Restore { id } => {
+                let path = format!("/todos/{}/restore", id);
+                ("POST", path, "", String::new())
+            }
+        };
+
+        let key = rate_limit_key(&Ok(self.principal.clone()), self.peer);
+        let decision = state.rate_limiter.check(method, &path, &key);
+        let (status, body) = match decision.filter(|decision| !decision.allowed) {
+            Some(_) => {
+                log_error("Rate limit exceeded.");
+                ("429 Too Many Requests", "Too many requests.".to_string())
+            }
+            None => route(
+                method,
+                &path,
+                query,
+                &Headers::new(),
+                &body,
+                &self.principal,
+                state,
+            ),
+        };
+        let status: u16 = status
+            .split(' ')
+            .next()
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(500);
+        // Handlers answer errors in plain text
+        let body = serde_json::from_str(&body).unwrap_or(Value::String(body));
+        reply(
+            reference,
+            "response",
+            json!({ "status": status, "body": body }),
+        )
+    }
+
+    fn subscribe(
+        &mut self,
+        reference: Option<Value>,
+        since: Option<u64>,
+        state: &AppState,
+    ) -> Value {
+        if !state.permits(&self.principal, Operation::Read) {
+            let (_, body) = forbidden(&self.principal, Operation::Read, state);
+            let body: Value = serde_json::from_str(&body).unwrap_or_default();
+            return reply(reference, "error", body);
+        }
+        let seq = match feed::resume(&state.db.lock().unwrap(), since) {
+            Ok(after) => after,
+            Err(missed) => {
+                let latest = missed.latest;
+                self.missed = Some(missed);
+                latest
+            }
+        };
+        self.subscribed = Some(seq);
+        reply(reference, "subscribed", json!({ "seq": seq }))
+    }
+
+    // Pushes the user's changes published since the last call
+    fn send_changes<W: Write>(&mut self, out: &mut W) -> std::io::Result<()> {
+        let Some(after) = self.subscribed else {
+            return Ok(());
+        };
+        if let Some(missed) = self.missed.take() {
+            let error = "Changes since the last event are no longer available; reload the todos.";
+            let message = json!({ "type": "reset", "seq": missed.latest, "error": error });
+            self.subscribed = Some(missed.latest);
+            return send_json(out, &message);
+        }
+        match self.feed.wait_after(after, Duration::ZERO) {
+            Ok(events) => {
+                self.subscribed = Some(events.last().map_or(after, TodoEvent::seq));
+                for event in events
+                    .iter()
+                    .filter(|event| event.change.owner == self.principal.user)
+                {
+                    send_json(out, &json!({ "type": "event", "event": event }))?;
+                }
+                Ok(())
+            }
+            Err(missed) => {
+                self.missed = Some(missed);
+                self.send_changes(out)
+            }
+        }
+    }
+}
+
+// Reads what has arrived, along with whatever the connection had already
+// received beyond it. Ok(false) if nothing could be read in time, and an error
+// once the client has gone.
+fn read_available<S: Connection>(stream: &mut S, input: &mut Vec<u8>) -> std::io::Result<bool> {
+    let mut buffer = [0; 8192];
+    let mut received = false;
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                input.extend_from_slice(&buffer[..n]);
+                received = true;
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(received);
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+        if !stream.has_buffered_input() {
+            return Ok(received);
+        }
+    }
+}
+
+// Wakes the session whenever input is waiting on the socket, and looks again
+// once the session has read it. Ends when the socket is shut down or the
+// session is gone.
+fn watch_input(socket: &TcpStream, wake: &Sender<Wake>, resumed: &Receiver<()>) {
+    let mut byte = [0; 1];
+    loop {
+        match socket.peek(&mut byte) {
+            // The session's read timeout applies to this copy of the socket too
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) =>
+            {
+                continue;
+            }
+            // Input, the end of it, or an error: the session's read finds out which
+            _ => {}
+        }
+        if wake.send(Wake::Input).is_err() || resumed.recv().is_err() {
+            return;
+        }
+    }
+}
+
+fn reply(reference: Option<Value>, kind: &str, fields: Value) -> Value {
+    let mut message = Map::new();
+    message.insert("type".to_string(), json!(kind));
+    if let Some(reference) = reference {
+        message.insert("ref".to_string(), reference);
+    }
+    if let Value::Object(fields) = fields {
+        message.extend(fields);
+    }
+    Value::Object(message)
+}
+
+// The first complete frame in `input` and its length in bytes, or None until
+// more has arrived
+fn parse_frame(input: &[u8], max_message: usize) -> Result<Option<(Frame, usize)>, Closing> {
+    let [first, second, ..] = *input else {
+        return Ok(None);
+    };
+    let fin = first & 0x80 != 0;
+    let opcode = first & 0x0F;
+    if first & 0x70 != 0 {
+        return Err(Closing::new(
+            PROTOCOL_ERROR,
+            "No extensions were negotiated.",
+        ));
+    }
+    if !matches!(opcode, CONTINUATION | TEXT | BINARY | CLOSE | PING | PONG) {
+        return Err(Closing::new(PROTOCOL_ERROR, "Unknown opcode."));
+    }
+    // Clients must mask what they send
+    if second & 0x80 == 0 {
+        return Err(Closing::new(PROTOCOL_ERROR, "Frame is not masked."));
+    }
+    let (length, mut offset) = match second & 0x7F {
+        126 if input.len() >= 4 => (u16::from_be_bytes([input[2], input[3]]) as u64, 4),
+        127 if input.len() >= 10 => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&input[2..10]);
+            (u64::from_be_bytes(bytes), 10)
+        }
+        126 | 127 => return Ok(None),
+        length => (length as u64, 2),
+    };
+    if opcode >= CLOSE && (!fin || length > 125) {
+        return Err(Closing::new(PROTOCOL_ERROR, "Invalid control frame."));
+    }
+    if length > max_message as u64 {
+        return Err(Closing::new(TOO_BIG, "Message too large."));
+    }
+    let length = length as usize;
+    if input.len() < offset + 4 + length {
+        return Ok(None);
+    }
+    let mask = [
+        input[offset],
+        input[offset + 1],
+        input[offset + 2],
+        input[offset + 3],
+    ];
+    offset += 4;
+    let payload = input[offset..offset + length]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+    let frame = Frame {
+        fin,
+        opcode,
+        payload,
+    };
+    Ok(Some((frame, offset + length)))
+}
+
+// Server frames are sent whole and unmasked
+fn write_frame<W: Write>(out: &mut W, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    out.write_all(&frame)?;
+    out.flush()
+}
+
+fn send_json<W: Write>(out: &mut W, message: &Value) -> std::io::Result<()> {
+    write_frame(out, TEXT, message.to_string().as_bytes())
+}
@@ -0,0 +1,260 @@
+mod common;
+
+use chrono::Utc;
+use common::{send_as, spawn_server};
+use naked_rust_api::audit::{self, Action, AuditEvent};
+use naked_rust_api::{Db, Store, Todo, journal};
+use serde_json::{Value, json};
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+fn events(response: &str) -> Vec<Value> {
+    let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+    serde_json::from_str(body).unwrap()
+}
+
+#[test]
+fn test_todo_history() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8280, Arc::clone(&db));
+
+    send_as(8280, "alice", "POST", "/todos", r#"{"title":"Draft"}"#);
+    send_as(
+        8280,
+        "alice",
+        "PUT",
+        "/todos/1",
+        r#"{"title":"Final","completed":false}"#,
+    );
+    send_as(8280, "alice", "DELETE", "/todos/1", "");
+    send_as(8280, "alice", "POST", "/todos/1/restore", "");
+
+    let response = send_as(8280, "alice", "GET", "/todos/1/history", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    let history = events(&response);
+    let actions: Vec<&str> = history
+        .iter()
+        .map(|event| event["action"].as_str().unwrap())
+        .collect();
+    assert_eq!(actions, ["created", "updated", "deleted", "restored"]);
+    assert!(history.iter().all(|event| event["actor"] == "alice"));
+    let seqs: Vec<u64> = history
+        .iter()
+        .map(|event| event["seq"].as_u64().unwrap())
+        .collect();
+    assert_eq!(seqs, [1, 2, 3, 4]);
+
+    // A new todo shows every field; later events only what changed
+    assert_eq!(
+        history[0]["changes"]["title"],
+        json!({ "before": null, "after": "Draft" })
+    );
+    assert_eq!(history[0]["changes"]["owner"]["after"], "alice");
+    assert_eq!(
+        history[1]["changes"],
+        json!({ "title": { "before": "Draft", "after": "Final" } })
+    );
+    assert!(history[2]["changes"]["deleted_at"]["before"].is_null());
+    assert!(history[2]["changes"]["deleted_at"]["after"].is_string());
+    assert!(history[3]["changes"]["deleted_at"]["after"].is_null());
+
+    // Another user's todo with the same id is a different todo
+    let response = send_as(8280, "bob", "GET", "/todos/1/history", "");
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    let response = send_as(8280, "alice", "GET", "/todos/x/history", "");
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+
+    // History outlives a purge
+    send_as(8280, "alice", "DELETE", "/todos/1", "");
+    send_as(8280, "alice", "POST", "/admin/purge", "");
+    let response = send_as(8280, "alice", "GET", "/todos/1/history", "");
+    assert_eq!(events(&response).len(), 5);
+}
+
+#[test]
+fn test_audit_log_filters() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8281, Arc::clone(&db));
+
+    send_as(8281, "alice", "POST", "/todos", r#"{"title":"Early"}"#);
+    thread::sleep(Duration::from_millis(20));
+    let middle = Utc::now().to_rfc3339();
+    thread::sleep(Duration::from_millis(20));
+    send_as(8281, "bob", "POST", "/todos", r#"{"title":"Late"}"#);
+    send_as(8281, "bob", "DELETE", "/todos/1", "");
+    // A rolled back bulk request leaves nothing in the log
+    send_as(
+        8281,
+        "bob",
+        "POST",
+        "/todos/bulk",
+        r#"{"atomic":true,"operations":[{"op":"create","title":"Ghost"},{"op":"delete","id":99}]}"#,
+    );
+
+    let all = events(&send_as(8281, "alice", "GET", "/audit", ""));
+    assert_eq!(all.len(), 3);
+
+    let since = events(&send_as(
+        8281,
+        "alice",
+        "GET",
+        &format!("/audit?since={}", middle.replace('+', "%2B")),
+        "",
+    ));
+    assert_eq!(since.len(), 2);
+    assert!(since.iter().all(|event| event["actor"] == "bob"));
+
+    let until = events(&send_as(
+        8281,
+        "alice",
+        "GET",
+        &format!("/audit?until={}", middle.replace('+', "%2B")),
+        "",
+    ));
+    assert_eq!(until.len(), 1);
+    assert_eq!(until[0]["changes"]["title"]["after"], "Early");
+
+    let actions: Vec<Value> = events(&send_as(8281, "alice", "GET", "/audit?actor=bob", ""))
+        .into_iter()
+        .map(|event| event["action"].clone())
+        .collect();
+    assert_eq!(actions, [json!("created"), json!("deleted")]);
+
+    let response = send_as(8281, "alice", "GET", "/audit?since=yesterday", "");
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    assert!(response.contains("Invalid since"));
+}
+
+#[test]
+fn test_audit_log_is_persisted() {
+    let dir = std::env::temp_dir().join(format!("audit-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let data_dir = dir.to_str().unwrap();
+    let db: Db = Arc::new(Mutex::new(journal::open_store(data_dir).unwrap()));
+    spawn_server(8282, Arc::clone(&db));
+
+    send_as(8282, "alice", "POST", "/todos", r#"{"title":"Kept"}"#);
+    naked_rust_api::snapshot::take(&db.lock().unwrap(), data_dir, 5).unwrap();
+    send_as(
+        8282,
+        "alice",
+        "PUT",
+        "/todos/1",
+        r#"{"title":"Kept","completed":true}"#,
+    );
+
+    // One event comes from the snapshot, the other from the journal
+    let reopened = journal::open_store(data_dir).unwrap();
+    let history = reopened.audit_log().for_todo("alice", 1);
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[1].seq, 2);
+    assert_eq!(history[1].changes["completed"].after, Value::Bool(true));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_audit_log_records_the_actor_and_pages() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8341, Arc::clone(&db));
+
+    send_as(8341, "alice", "POST", "/todos", r#"{"title":"Mine"}"#);
+    // An admin changing someone else's todos is recorded as the actor
+    let response = send_as(
+        8341,
+        "root",
+        "POST",
+        "/admin/import?on_conflict=overwrite",
+        concat!(
+            r#"{"id":1,"owner":"alice","title":"Fixed","completed":true}"#,
+            "\n",
+            r#"{"id":2,"owner":"alice","title":"Added","completed":false}"#,
+        ),
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    let history = events(&send_as(8341, "alice", "GET", "/todos/1/history", ""));
+    let actors: Vec<(&str, &str)> = history
+        .iter()
+        .map(|event| {
+            (
+                event["actor"].as_str().unwrap(),
+                event["action"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(actors, [("alice", "created"), ("root", "updated")]);
+    assert_eq!(history[1]["owner"], "alice");
+    let by_root = events(&send_as(8341, "root", "GET", "/audit?actor=root", ""));
+    assert_eq!(by_root.len(), 2);
+
+    for i in 0..5 {
+        send_as(
+            8341,
+            "bob",
+            "POST",
+            "/todos",
+            &format!(r#"{{"title":"Page {}"}}"#, i),
+        );
+    }
+    // Pages follow the cursor without gaps or repeats
+    let mut seqs = Vec::new();
+    let mut after = 0;
+    loop {
+        let page = events(&send_as(
+            8341,
+            "root",
+            "GET",
+            &format!("/audit?limit=3&after={}", after),
+            "",
+        ));
+        assert!(page.len() <= 3);
+        let Some(last) = page.last() else { break };
+        after = last["seq"].as_u64().unwrap();
+        seqs.extend(page.iter().map(|event| event["seq"].as_u64().unwrap()));
+    }
+    assert_eq!(seqs, (1..=8).collect::<Vec<u64>>());
+    // Filters apply within the page
+    let page = events(&send_as(
+        8341,
+        "root",
+        "GET",
+        "/audit?actor=bob&limit=2&after=4",
+        "",
+    ));
+    let titles: Vec<&Value> = page
+        .iter()
+        .map(|event| &event["changes"]["title"]["after"])
+        .collect();
+    assert_eq!(titles, [&json!("Page 1"), &json!("Page 2")]);
+
+    let response = send_as(8341, "root", "GET", "/audit?limit=many", "");
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    assert!(response.contains("Invalid limit"));
+}
+
+#[test]
+fn test_audit_log_keeps_the_latest_events() {
+    let mut store = Store::new();
+    let todo = Todo {
+        id: 1,
+        owner: "alice".to_string(),
+        title: "Busy".to_string(),
+        completed: false,
+        deleted_at: None,
+    };
+    for _ in 0..audit::CAPACITY + 10 {
+        store.audit(AuditEvent::new("alice", Action::Updated, None, &todo));
+    }
+    let events = store.audit_log().events();
+    assert_eq!(events.len(), audit::CAPACITY);
+    assert_eq!(events.front().unwrap().seq, 11);
+    assert_eq!(store.change_seq(), audit::CAPACITY as u64 + 10);
+
+    // Undoing past every kept event still numbers the next one right
+    let mut log = store.audit_log().clone();
+    log.truncate_after(5);
+    assert!(log.events().is_empty());
+    assert_eq!(log.next_seq(), 6);
+}
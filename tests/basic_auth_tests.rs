@@ -0,0 +1,154 @@
+mod common;
+
+use common::{send, spawn_server_with};
+use naked_rust_api::auth::hash_password;
+use naked_rust_api::config::AuthConfig;
+use naked_rust_api::{AppState, Config, Db, Store, spawn_sighup_reloader};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// base64 of "alice:wonderland", "alice:wrong", "bob:builder" and
+// "mallory:wrong"
+const ALICE: &str = "YWxpY2U6d29uZGVybGFuZA==";
+const ALICE_WRONG: &str = "YWxpY2U6d3Jvbmc=";
+const BOB: &str = "Ym9iOmJ1aWxkZXI=";
+const UNKNOWN: &str = "bWFsbG9yeTp3cm9uZw==";
+
+fn write_htpasswd(name: &str, entries: &[(&str, &str)]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("{}-{}.htpasswd", name, std::process::id()));
+    let contents: String = entries
+        .iter()
+        .map(|(user, password)| format!("{}:{}\n", user, hash_password(password)))
+        .collect();
+    fs::write(&path, format!("# test credentials\n{}", contents)).unwrap();
+    path
+}
+
+fn spawn_server(port: u16, htpasswd: &Path) -> Arc<AppState> {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    let config = Config {
+        auth: AuthConfig {
+            htpasswd_file: Some(htpasswd.to_string_lossy().into_owned()),
+            ..AuthConfig::default()
+        },
+        ..Config::default()
+    };
+    spawn_server_with(port, db, config)
+}
+
+fn get_todos(port: u16, credentials: &str) -> String {
+    let request = format!(
+        "GET /todos HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic {}\r\n\r\n",
+        credentials
+    );
+    send(port, &request)
+}
+
+#[test]
+fn test_basic_credentials() {
+    let htpasswd = write_htpasswd("basic", &[("alice", "wonderland")]);
+    spawn_server(8160, &htpasswd);
+
+    let response = get_todos(8160, ALICE);
+    assert!(response.contains("200 OK"));
+
+    let response = get_todos(8160, ALICE_WRONG);
+    assert!(response.contains("401 Unauthorized"));
+    assert!(response.contains("WWW-Authenticate: Basic realm=\"todos\""));
+    assert!(response.contains("Invalid username or password."));
+
+    let response = get_todos(8160, "not base64!");
+    assert!(response.contains("Malformed Basic credentials."));
+}
+
+#[test]
+fn test_credentials_reload() {
+    let htpasswd = write_htpasswd("reload", &[("alice", "wonderland")]);
+    let state = spawn_server(8161, &htpasswd);
+
+    assert!(get_todos(8161, BOB).contains("401 Unauthorized"));
+
+    write_htpasswd("reload", &[("alice", "wonderland"), ("bob", "builder")]);
+    state.auth.reload().unwrap();
+    assert!(get_todos(8161, BOB).contains("200 OK"));
+
+    // A broken file keeps the previous credentials
+    fs::write(&htpasswd, "bob:plaintext\n").unwrap();
+    assert!(state.auth.reload().is_err());
+    assert!(get_todos(8161, BOB).contains("200 OK"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sighup_reloads_credentials() {
+    let htpasswd = write_htpasswd("sighup", &[("alice", "wonderland")]);
+    let state = spawn_server(8162, &htpasswd);
+    spawn_sighup_reloader(Arc::clone(&state));
+
+    write_htpasswd("sighup", &[("bob", "builder")]);
+    let status = Command::new("kill")
+        .arg("-HUP")
+        .arg(std::process::id().to_string())
+        .status()
+        .expect("Failed to run kill");
+    assert!(status.success());
+
+    let mut reloaded = false;
+    for _ in 0..50 {
+        if get_todos(8162, BOB).contains("200 OK") {
+            reloaded = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(reloaded);
+    assert!(get_todos(8162, ALICE).contains("401 Unauthorized"));
+}
+
+// The quickest of a few runs, to keep other tests' load out of the timing
+fn fastest(port: u16, credentials: &str, expected: &str) -> Duration {
+    (0..3)
+        .map(|_| {
+            let started = Instant::now();
+            assert!(get_todos(port, credentials).contains(expected));
+            started.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+#[test]
+fn test_password_checks_take_the_same_time_and_are_remembered() {
+    let htpasswd = write_htpasswd("timing", &[("alice", "wonderland")]);
+    let state = spawn_server(8163, &htpasswd);
+
+    // An unknown user costs a password hash too, so it can't be told apart
+    // from a wrong password
+    let wrong_password = fastest(8163, ALICE_WRONG, "401 Unauthorized");
+    let unknown_user = fastest(8163, UNKNOWN, "401 Unauthorized");
+    assert!(
+        unknown_user * 2 > wrong_password,
+        "{:?} {:?}",
+        unknown_user,
+        wrong_password
+    );
+
+    // Once verified, the same header isn't hashed again
+    assert!(get_todos(8163, ALICE).contains("200 OK"));
+    let remembered = fastest(8163, ALICE, "200 OK");
+    assert!(
+        remembered * 2 < wrong_password,
+        "{:?} {:?}",
+        remembered,
+        wrong_password
+    );
+
+    // A reload forgets them, since the password may have changed
+    write_htpasswd("timing", &[("alice", "changed")]);
+    state.auth.reload().unwrap();
+    assert!(get_todos(8163, ALICE).contains("401 Unauthorized"));
+}
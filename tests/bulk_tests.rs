@@ -0,0 +1,102 @@
+mod common;
+
+use common::{backends, send, spawn_server, with_body};
+use naked_rust_api::{DEFAULT_USER, Todo};
+use std::sync::Arc;
+
+fn send_bulk(port: u16, request_body: &str) -> String {
+    send(port, &with_body("POST", "/todos/bulk", request_body))
+}
+
+#[test]
+fn test_bulk_mixed_operations() {
+    for (port, db) in backends(8100) {
+        let todo = Todo {
+            id: 1,
+            owner: DEFAULT_USER.to_string(),
+            title: "Existing".to_string(),
+            completed: false,
+            deleted_at: None,
+        };
+        db.lock().unwrap().insert(todo);
+        spawn_server(port, Arc::clone(&db));
+
+        let request_body = r#"{"operations":[
+            {"op":"create","title":"Bulk One"},
+            {"op":"update","id":1,"completed":true},
+            {"op":"delete","id":42}
+        ]}"#;
+        let response = send_bulk(port, request_body);
+
+        assert!(response.contains("200 OK"));
+        assert!(response.contains("\"committed\":true"));
+        assert!(response.contains("\"status\":201"));
+        assert!(response.contains("\"error\":\"Todo not found.\""));
+
+        let db = db.lock().unwrap();
+        assert_eq!(db.len(), 2);
+        assert!(db.get(DEFAULT_USER, 1).unwrap().completed);
+        assert_eq!(db.get(DEFAULT_USER, 2).unwrap().title, "Bulk One");
+    }
+}
+
+#[test]
+fn test_bulk_atomic_rolls_back() {
+    for (port, db) in backends(8101) {
+        let todo = Todo {
+            id: 1,
+            owner: DEFAULT_USER.to_string(),
+            title: "Keep me".to_string(),
+            completed: false,
+            deleted_at: None,
+        };
+        db.lock().unwrap().insert(todo);
+        spawn_server(port, Arc::clone(&db));
+
+        let request_body = r#"{"atomic":true,"operations":[
+            {"op":"create","title":"Should vanish"},
+            {"op":"delete","id":1},
+            {"op":"create","title":"   "}
+        ]}"#;
+        let response = send_bulk(port, request_body);
+
+        assert!(response.contains("400 Bad Request"));
+        assert!(response.contains("\"committed\":false"));
+        assert!(response.contains("Title cannot be empty."));
+
+        {
+            let db = db.lock().unwrap();
+            assert_eq!(db.len(), 1);
+            assert_eq!(db.get(DEFAULT_USER, 1).unwrap().title, "Keep me");
+            // The search index, id sequence and audit log are rolled back too
+            assert!(db.search(DEFAULT_USER, "vanish").is_empty());
+            assert_eq!(db.search(DEFAULT_USER, "keep").len(), 1);
+            assert_eq!(db.id_sequences()[DEFAULT_USER], 2);
+            assert_eq!(db.change_seq(), 0);
+        }
+
+        let request_body = r#"{"atomic":true,"operations":[{"op":"create","title":"Next"}]}"#;
+        let response = send_bulk(port, request_body);
+        assert!(response.contains("\"committed\":true"));
+        assert_eq!(
+            db.lock().unwrap().get(DEFAULT_USER, 2).unwrap().title,
+            "Next"
+        );
+    }
+}
+
+#[test]
+fn test_bulk_large_batch() {
+    for (port, db) in backends(8102) {
+        spawn_server(port, Arc::clone(&db));
+
+        let operations: Vec<String> = (0..500)
+            .map(|i| format!(r#"{{"op":"create","title":"Imported {}"}}"#, i))
+            .collect();
+        let request_body = format!(r#"{{"operations":[{}]}}"#, operations.join(","));
+        let response = send_bulk(port, &request_body);
+
+        assert!(response.contains("200 OK"));
+        assert_eq!(db.lock().unwrap().len(), 500);
+    }
+}
@@ -0,0 +1,172 @@
+mod common;
+
+use common::{json, send_as};
+use naked_rust_api::{AppState, Config, Db, Store, ThreadPool, handle_connection_with_state};
+use serde_json::{Value, json};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Serves connections on a pool of `threads` workers, as the server does
+fn spawn_server(port: u16, db: Db, threads: usize) {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .unwrap_or_else(|_| panic!("Failed to bind to port {}", port));
+    let state = Arc::new(AppState::new(db, Config::default()));
+
+    thread::spawn(move || {
+        let pool = ThreadPool::new(threads);
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let state = Arc::clone(&state);
+                    pool.execute(move || handle_connection_with_state(stream, &state));
+                }
+                Err(e) => {
+                    eprintln!("Connection failed: {}", e);
+                }
+            }
+        }
+    });
+}
+
+fn poll(port: u16, user: &'static str, query: &str) -> thread::JoinHandle<(String, Duration)> {
+    let path = format!("/todos/changes?{}", query);
+    thread::spawn(move || {
+        let started = Instant::now();
+        let response = send_as(port, user, "GET", &path, "");
+        (response, started.elapsed())
+    })
+}
+
+#[test]
+fn test_poll_waits_for_own_changes() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8320, Arc::clone(&db), 4);
+    send_as(8320, "alice", "POST", "/todos", r#"{"title":"First"}"#);
+    send_as(8320, "bob", "POST", "/todos", r#"{"title":"Bob's"}"#);
+
+    // Changes already made are returned at once
+    let response = send_as(8320, "alice", "GET", "/todos/changes?since=0", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    let changes = json(&response);
+    assert_eq!(changes["seq"], 2);
+    assert_eq!(changes["changes"].as_array().unwrap().len(), 1);
+    assert_eq!(changes["changes"][0]["action"], "created");
+    assert_eq!(changes["changes"][0]["todo"]["title"], "First");
+
+    // Otherwise the request waits, through other users' changes, for one of
+    // the caller's own
+    let waiting = poll(8320, "alice", "since=2&wait=10");
+    thread::sleep(Duration::from_millis(200));
+    send_as(8320, "bob", "POST", "/todos", r#"{"title":"Ignored"}"#);
+    thread::sleep(Duration::from_millis(200));
+    send_as(
+        8320,
+        "alice",
+        "PUT",
+        "/todos/1",
+        r#"{"title":"First","completed":true}"#,
+    );
+    let (response, elapsed) = waiting.join().unwrap();
+    assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+    assert!(response.contains("Content-Length"));
+    let changes = json(&response);
+    assert_eq!(changes["seq"], 4);
+    assert_eq!(changes["changes"][0]["seq"], 4);
+    assert_eq!(changes["changes"][0]["action"], "updated");
+    assert_eq!(changes["changes"][0]["todo"]["completed"], true);
+
+    // Nothing happened in time
+    let (response, elapsed) = poll(8320, "alice", "since=4&wait=1").join().unwrap();
+    assert!(elapsed >= Duration::from_secs(1));
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(json(&response), json!({ "changes": [], "seq": 4 }));
+
+    let response = send_as(8320, "alice", "GET", "/todos/changes?since=4&wait=0", "");
+    assert_eq!(json(&response)["changes"], json!([]));
+    let response = send_as(8320, "alice", "GET", "/todos/changes?since=soon", "");
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    let response = send_as(8320, "alice", "GET", "/todos/changes?wait=-1", "");
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+}
+
+#[test]
+fn test_waiting_polls_do_not_hold_workers() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8321, Arc::clone(&db), 1);
+
+    // More waiting requests than there are workers
+    let waiting: Vec<_> = (0..3).map(|_| poll(8321, "alice", "wait=10")).collect();
+    thread::sleep(Duration::from_millis(300));
+
+    // The single worker is still free to serve other requests
+    let started = Instant::now();
+    let response = send_as(8321, "alice", "POST", "/todos", r#"{"title":"Seen"}"#);
+    assert!(response.starts_with("HTTP/1.1 201 Created"));
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // And every waiting request hears about the change
+    for waiting in waiting {
+        let (response, elapsed) = waiting.join().unwrap();
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+        let changes = json(&response);
+        assert_eq!(changes["seq"], 1);
+        assert_eq!(changes["changes"][0]["todo"]["title"], "Seen");
+    }
+}
+
+#[test]
+fn test_gone_when_changes_are_no_longer_kept() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8322, Arc::clone(&db), 2);
+
+    let operations: Vec<Value> = (0..1100)
+        .map(|n| json!({ "op": "create", "title": format!("Todo {}", n) }))
+        .collect();
+    let body_json = json!({ "operations": operations }).to_string();
+    send_as(8322, "alice", "POST", "/todos/bulk", &body_json);
+
+    let response = send_as(8322, "alice", "GET", "/todos/changes?since=5", "");
+    assert!(response.starts_with("HTTP/1.1 410 Gone"));
+    let gone = json(&response);
+    assert_eq!(gone["seq"], 1100);
+    assert!(gone["error"].as_str().unwrap().contains("reload"));
+
+    // Carrying on from the given sequence number works again
+    let response = send_as(8322, "alice", "GET", "/todos/changes?since=1099", "");
+    let changes = json(&response);
+    assert_eq!(changes["seq"], 1100);
+    assert_eq!(changes["changes"][0]["todo"]["title"], "Todo 1099");
+}
+
+#[test]
+fn test_waiters_hear_only_about_their_own_changes() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8323, Arc::clone(&db), 2);
+
+    let users: Vec<&'static str> = (0..20).map(|i| &*format!("user{}", i).leak()).collect();
+    let waiting: Vec<_> = users
+        .iter()
+        .map(|user| poll(8323, user, "since=0&wait=2"))
+        .collect();
+    thread::sleep(Duration::from_millis(300));
+
+    // Busy other users don't end anyone's wait but their own
+    for _ in 0..50 {
+        send_as(8323, "user7", "POST", "/todos", r#"{"title":"Busy"}"#);
+    }
+    for (i, waiting) in waiting.into_iter().enumerate() {
+        let (response, elapsed) = waiting.join().unwrap();
+        let changes = json(&response);
+        if i == 7 {
+            assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+            assert_eq!(changes["changes"][0]["todo"]["title"], "Busy");
+        } else {
+            assert!(elapsed >= Duration::from_secs(2), "{:?}", elapsed);
+            assert_eq!(changes["changes"], json!([]));
+            // The cursor still moves past the others' changes
+            assert_eq!(changes["seq"], 50);
+        }
+    }
+}
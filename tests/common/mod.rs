@@ -0,0 +1,186 @@
+// Helpers shared by the integration tests. Each test file uses only some.
+#![allow(dead_code)]
+
+use naked_rust_api::{AppState, Config, Db, Store, handle_connection_with_state};
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Where a test's run against SQLite is served, relative to its own port
+const SQLITE_PORT_OFFSET: u16 = 1000;
+
+// Serves connections on `port` one after another, with the default config
+pub fn spawn_server(port: u16, db: Db) -> Arc<AppState> {
+    spawn_server_with(port, db, Config::default())
+}
+
+pub fn spawn_server_with(port: u16, db: Db, config: Config) -> Arc<AppState> {
+    serve(port, db, config, false)
+}
+
+// Serves every connection on a thread of its own, so slow or long-lived
+// clients don't hold up the ones a test sends next
+pub fn spawn_concurrent_server(port: u16, db: Db, config: Config) -> Arc<AppState> {
+    serve(port, db, config, true)
+}
+
+fn serve(port: u16, db: Db, config: Config, concurrent: bool) -> Arc<AppState> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .unwrap_or_else(|_| panic!("Failed to bind to port {}", port));
+    let state = Arc::new(AppState::new(db, config));
+
+    let serving = Arc::clone(&state);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) if concurrent => {
+                    let state = Arc::clone(&serving);
+                    thread::spawn(move || handle_connection_with_state(stream, &state));
+                }
+                Ok(stream) => handle_connection_with_state(stream, &serving),
+                Err(e) => {
+                    eprintln!("Connection failed: {}", e);
+                }
+            }
+        }
+    });
+    state
+}
+
+pub fn empty_db() -> Db {
+    Arc::new(Mutex::new(Store::new()))
+}
+
+// Sends a raw request and reads until the server closes the connection
+pub fn send(port: u16, request: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("Failed to connect to server");
+    stream
+        .write_all(request.as_bytes())
+        .expect("Failed to write to stream");
+
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .expect("Failed to read from stream");
+    response
+}
+
+// Sends `request_body` as `user`
+pub fn send_as(port: u16, user: &str, method: &str, path: &str, request_body: &str) -> String {
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nX-User-Id: {}\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        user,
+        request_body.len(),
+        request_body
+    );
+    send(port, &request)
+}
+
+// For bodies that aren't text either way. `extra_headers` end in CRLF.
+// Returns the response head and the raw body.
+pub fn send_bytes(
+    port: u16,
+    method: &str,
+    path: &str,
+    extra_headers: &str,
+    body: &[u8],
+) -> (String, Vec<u8>) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("Failed to connect to server");
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n{}\r\n",
+        method,
+        path,
+        body.len(),
+        extra_headers
+    );
+    stream
+        .write_all(request.as_bytes())
+        .and_then(|()| stream.write_all(body))
+        .expect("Failed to write to stream");
+
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .expect("Failed to read from stream");
+    let split = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .expect("Response has no header section");
+    let head = String::from_utf8(response[..split].to_vec()).unwrap();
+    (head, response[split + 4..].to_vec())
+}
+
+pub fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    header_values(head, name).into_iter().next()
+}
+
+// A field may be sent several times
+pub fn header_values<'a>(head: &'a str, name: &str) -> Vec<&'a str> {
+    head.lines()
+        .filter_map(|line| {
+            let (field, value) = line.split_once(':')?;
+            field.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+        .collect()
+}
+
+// A request with `body` and its Content-Length
+pub fn with_body(method: &str, path: &str, body: &str) -> String {
+    format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    )
+}
+
+pub fn body(response: &str) -> &str {
+    response.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+}
+
+// An empty store for every backend the tests run against: one held in memory,
+// and with the `sqlite` feature one written through to an SQLite database
+pub fn stores() -> Vec<Db> {
+    let memory: Db = Arc::new(Mutex::new(Store::new()));
+    #[allow(unused_mut)]
+    let mut stores = vec![memory];
+    #[cfg(feature = "sqlite")]
+    {
+        let store = naked_rust_api::sqlite::Database::in_memory()
+            .and_then(|database| database.open_store())
+            .unwrap();
+        stores.push(Arc::new(Mutex::new(store)));
+    }
+    stores
+}
+
+// The stores, each with the port to serve it on: `port` for the one in memory
+pub fn backends(port: u16) -> Vec<(u16, Db)> {
+    [port, port + SQLITE_PORT_OFFSET]
+        .into_iter()
+        .zip(stores())
+        .collect()
+}
+
+// The response body as JSON
+pub fn json(response: &str) -> Value {
+    serde_json::from_str(body(response)).unwrap()
+}
+
+// `extra_headers` end in CRLF
+pub fn send_with(port: u16, method: &str, path: &str, extra_headers: &str, body: &str) -> String {
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n{}\r\n{}",
+        method,
+        path,
+        body.len(),
+        extra_headers,
+        body
+    );
+    send(port, &request)
+}
@@ -0,0 +1,148 @@
+mod common;
+
+use common::{header, header_values, send_bytes, spawn_server_with};
+use flate2::Compression;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::GzEncoder;
+use naked_rust_api::config::CompressionConfig;
+use naked_rust_api::{Config, DEFAULT_USER, Db, Store, Todo};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+fn populated_db(count: usize) -> Db {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    for id in 1..=count {
+        db.lock().unwrap().insert(Todo {
+            id,
+            owner: DEFAULT_USER.to_string(),
+            title: format!("Compressible todo number {}", id),
+            completed: false,
+            deleted_at: None,
+        });
+    }
+    db
+}
+
+#[test]
+fn test_response_compression() {
+    spawn_server_with(8240, populated_db(200), Config::default());
+
+    let (plain_head, plain) = send_bytes(8240, "GET", "/todos", "", b"");
+    assert!(plain_head.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(header(&plain_head, "Content-Encoding"), None);
+    assert!(header_values(&plain_head, "Vary").contains(&"Accept-Encoding"));
+
+    let (head, body) = send_bytes(8240, "GET", "/todos", "Accept-Encoding: gzip\r\n", b"");
+    assert_eq!(header(&head, "Content-Encoding"), Some("gzip"));
+    assert!(header_values(&head, "Vary").contains(&"Accept-Encoding"));
+    assert_eq!(
+        header(&head, "Content-Length"),
+        Some(body.len().to_string().as_str())
+    );
+    assert!(body.len() < plain.len());
+    let mut decoded = Vec::new();
+    GzDecoder::new(&body[..]).read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, plain);
+
+    // The highest q-value wins
+    let (head, body) = send_bytes(
+        8240,
+        "GET",
+        "/todos",
+        "Accept-Encoding: gzip;q=0.5, br\r\n",
+        b"",
+    );
+    assert_eq!(header(&head, "Content-Encoding"), Some("br"));
+    let mut decoded = Vec::new();
+    brotli::Decompressor::new(&body[..], 4096)
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, plain);
+
+    let (head, body) = send_bytes(8240, "GET", "/todos", "Accept-Encoding: deflate\r\n", b"");
+    assert_eq!(header(&head, "Content-Encoding"), Some("deflate"));
+    let mut decoded = Vec::new();
+    ZlibDecoder::new(&body[..])
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, plain);
+
+    // Unknown codings and q=0 are never chosen
+    let (head, body) = send_bytes(
+        8240,
+        "GET",
+        "/todos",
+        "Accept-Encoding: zstd, gzip;q=0\r\n",
+        b"",
+    );
+    assert_eq!(header(&head, "Content-Encoding"), None);
+    assert_eq!(body, plain);
+
+    // Small responses aren't worth compressing
+    let (head, _) = send_bytes(8240, "GET", "/todos/1", "Accept-Encoding: gzip\r\n", b"");
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(header(&head, "Content-Encoding"), None);
+    assert!(!header_values(&head, "Vary").contains(&"Accept-Encoding"));
+}
+
+#[test]
+fn test_compression_disabled() {
+    let config = Config {
+        compression: CompressionConfig {
+            enabled: false,
+            ..CompressionConfig::default()
+        },
+        ..Config::default()
+    };
+    spawn_server_with(8241, populated_db(200), config);
+
+    let (head, _) = send_bytes(8241, "GET", "/todos", "Accept-Encoding: gzip, br\r\n", b"");
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(header(&head, "Content-Encoding"), None);
+    assert!(!header_values(&head, "Vary").contains(&"Accept-Encoding"));
+}
+
+#[test]
+fn test_gzip_request_body() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server_with(8242, Arc::clone(&db), Config::default());
+
+    let operations: Vec<String> = (0..100)
+        .map(|i| format!(r#"{{"op":"create","title":"Imported {}"}}"#, i))
+        .collect();
+    let bulk = format!(r#"{{"operations":[{}]}}"#, operations.join(","));
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bulk.as_bytes()).unwrap();
+    let gzipped = encoder.finish().unwrap();
+
+    let (head, _) = send_bytes(
+        8242,
+        "POST",
+        "/todos/bulk",
+        "Content-Encoding: gzip\r\n",
+        &gzipped,
+    );
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(db.lock().unwrap().len(), 100);
+
+    let (head, body) = send_bytes(
+        8242,
+        "POST",
+        "/todos/bulk",
+        "Content-Encoding: gzip\r\n",
+        b"not gzip at all",
+    );
+    assert!(head.starts_with("HTTP/1.1 400 Bad Request"));
+    assert!(String::from_utf8_lossy(&body).contains("could not be decompressed"));
+
+    let (head, _) = send_bytes(
+        8242,
+        "POST",
+        "/todos/bulk",
+        "Content-Encoding: compress\r\n",
+        bulk.as_bytes(),
+    );
+    assert!(head.starts_with("HTTP/1.1 415 Unsupported Media Type"));
+    assert_eq!(header(&head, "Accept-Encoding"), Some("br, gzip, deflate"));
+    assert_eq!(db.lock().unwrap().len(), 100);
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use common::{empty_db, send_with, spawn_server_with};
+use naked_rust_api::Config;
+use naked_rust_api::config::CorsConfig;
+use std::collections::HashMap;
+use std::fs;
+
+#[test]
+fn test_cors_preflight() {
+    let mut config = Config {
+        cors: Some(CorsConfig {
+            allowed_origins: vec!["https://app.example.com".to_string()],
+            allow_credentials: true,
+            max_age_secs: Some(600),
+            ..CorsConfig::default()
+        }),
+        ..Config::default()
+    };
+    // Preflights must get through even when every other request needs a key
+    config.auth.api_keys = HashMap::from([("secret".to_string(), "alice".to_string())]);
+    spawn_server_with(8200, empty_db(), config);
+
+    let granted = send_with(
+        8200,
+        "OPTIONS",
+        "/todos/1",
+        "Origin: https://app.example.com\r\n\
+        Access-Control-Request-Method: PUT\r\n\
+        Access-Control-Request-Headers: content-type, x-api-key\r\n",
+        "",
+    );
+    assert!(granted.contains("204 No Content"));
+    assert!(granted.contains("Access-Control-Allow-Origin: https://app.example.com"));
+    assert!(granted.contains("Access-Control-Allow-Methods: GET, POST, PUT, DELETE"));
+    assert!(granted.contains("Access-Control-Allow-Headers: Content-Type, Authorization"));
+    assert!(granted.contains("Access-Control-Allow-Credentials: true"));
+    assert!(granted.contains("Access-Control-Max-Age: 600"));
+    assert!(granted.contains("Vary: Origin"));
+
+    let wrong_origin = send_with(
+        8200,
+        "OPTIONS",
+        "/todos",
+        "Origin: https://evil.example.com\r\nAccess-Control-Request-Method: GET\r\n",
+        "",
+    );
+    assert!(wrong_origin.contains("204 No Content"));
+    assert!(!wrong_origin.contains("Access-Control-Allow-Origin"));
+
+    let wrong_header = send_with(
+        8200,
+        "OPTIONS",
+        "/todos",
+        "Origin: https://app.example.com\r\n\
+        Access-Control-Request-Method: POST\r\n\
+        Access-Control-Request-Headers: X-Custom\r\n",
+        "",
+    );
+    assert!(!wrong_header.contains("Access-Control-Allow-Origin"));
+
+    // The actual request still needs credentials, and carries CORS headers
+    // either way so the front-end can read the error
+    let actual = send_with(
+        8200,
+        "GET",
+        "/todos",
+        "Origin: https://app.example.com\r\n",
+        "",
+    );
+    assert!(actual.contains("401 Unauthorized"));
+    assert!(actual.contains("Access-Control-Allow-Origin: https://app.example.com"));
+}
+
+#[test]
+fn test_cors_wildcard_origin() {
+    let config = Config {
+        cors: Some(CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            exposed_headers: vec!["RateLimit-Remaining".to_string()],
+            ..CorsConfig::default()
+        }),
+        ..Config::default()
+    };
+    spawn_server_with(8201, empty_db(), config);
+
+    let response = send_with(
+        8201,
+        "GET",
+        "/todos",
+        "Origin: https://anywhere.example\r\n",
+        "",
+    );
+    assert!(response.contains("200 OK"));
+    assert!(response.contains("Access-Control-Allow-Origin: *"));
+    assert!(response.contains("Access-Control-Expose-Headers: RateLimit-Remaining"));
+    assert!(!response.contains("Access-Control-Allow-Credentials"));
+
+    let same_origin = send_with(8201, "GET", "/todos", "", "");
+    assert!(!same_origin.contains("Access-Control-Allow-Origin"));
+}
+
+#[test]
+fn test_wildcard_origin_with_credentials_is_refused() {
+    let path = std::env::temp_dir().join(format!("cors-{}.json", std::process::id()));
+    let cors = |origin: &str| {
+        format!(
+            r#"{{"cors": {{"allowed_origins": ["{}"], "allow_credentials": true}}}}"#,
+            origin
+        )
+    };
+    fs::write(&path, cors("*")).unwrap();
+    let error = Config::load(path.to_str().unwrap()).err().unwrap();
+    assert!(error.contains("allow_credentials"), "{}", error);
+
+    fs::write(&path, cors("https://app.example.com")).unwrap();
+    assert!(Config::load(path.to_str().unwrap()).is_ok());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_options_without_cors() {
+    spawn_server_with(8202, empty_db(), Config::default());
+
+    let response = send_with(8202, "OPTIONS", "/todos", "", "");
+    assert!(response.contains("204 No Content"));
+    assert!(response.contains("Allow: GET, POST, PUT, DELETE, OPTIONS"));
+
+    let preflight = send_with(
+        8202,
+        "OPTIONS",
+        "/todos",
+        "Origin: https://app.example.com\r\nAccess-Control-Request-Method: GET\r\n",
+        "",
+    );
+    assert!(!preflight.contains("Access-Control-Allow-Origin"));
+}
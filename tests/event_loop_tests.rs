@@ -0,0 +1,212 @@
+#![cfg(feature = "event-loop")]
+
+mod common;
+
+use common::{json, send_as};
+use naked_rust_api::http::Limits;
+use naked_rust_api::{AppState, Config, Db, Store, ThreadPool, event_loop};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn spawn_server(port: u16, config: Config) {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .unwrap_or_else(|_| panic!("Failed to bind to port {}", port));
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    let pool = ThreadPool::new(config.threads);
+    let state = Arc::new(AppState::new(db, config));
+
+    thread::spawn(move || {
+        event_loop::serve(listener, &pool, &state).unwrap();
+    });
+}
+
+fn single_worker() -> Config {
+    Config {
+        threads: 1,
+        ..Config::default()
+    }
+}
+
+fn read_response(stream: &mut TcpStream) -> String {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .expect("Failed to read from stream");
+    response
+}
+
+#[test]
+fn test_idle_connections_do_not_hold_workers() {
+    spawn_server(8330, single_worker());
+
+    // Far more unfinished requests than there are workers
+    let mut waiting: Vec<TcpStream> = (0..200)
+        .map(|_| {
+            let mut stream = TcpStream::connect(("127.0.0.1", 8330)).unwrap();
+            stream
+                .write_all(b"GET /todos HTTP/1.1\r\nHost: localhost\r\nX-User-Id: alice\r\n")
+                .unwrap();
+            stream
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(200));
+
+    let started = Instant::now();
+    let response = send_as(8330, "alice", "POST", "/todos", r#"{"title":"Served"}"#);
+    assert!(response.starts_with("HTTP/1.1 201 Created"), "{}", response);
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // Each is answered once it is complete
+    for stream in &mut waiting {
+        stream.write_all(b"\r\n").unwrap();
+    }
+    for stream in &mut waiting {
+        let response = read_response(stream);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert_eq!(json(&response)[0]["title"], "Served");
+    }
+
+    // A request trickling in, body and all, is put back together
+    let mut stream = TcpStream::connect(("127.0.0.1", 8330)).unwrap();
+    let request = "POST /todos HTTP/1.1\r\nHost: localhost\r\nX-User-Id: alice\r\nContent-Length: 18\r\n\r\n{\"title\":\"Slowly\"}";
+    for piece in request.as_bytes().chunks(7) {
+        stream.write_all(piece).unwrap();
+        thread::sleep(Duration::from_millis(10));
+    }
+    let response = read_response(&mut stream);
+    assert!(response.starts_with("HTTP/1.1 201 Created"), "{}", response);
+    assert_eq!(json(&response)["title"], "Slowly");
+}
+
+#[test]
+fn test_deadlines_and_limits() {
+    spawn_server(
+        8331,
+        Config {
+            header_timeout_secs: 1,
+            max_connections: 2,
+            ..single_worker()
+        },
+    );
+
+    // Headers that never finish are answered with 408 once the deadline is up
+    let started = Instant::now();
+    let mut slow = TcpStream::connect(("127.0.0.1", 8331)).unwrap();
+    slow.write_all(b"GET /todos HTTP/1.1\r\nHost: localhost\r\n")
+        .unwrap();
+    let mut other = TcpStream::connect(("127.0.0.1", 8331)).unwrap();
+    other
+        .write_all(b"GET /todos HTTP/1.1\r\nHost: localhost\r\n")
+        .unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    // Past the limit, connections are turned away
+    let mut refused = TcpStream::connect(("127.0.0.1", 8331)).unwrap();
+    refused
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    assert!(matches!(refused.read(&mut [0; 16]), Ok(0) | Err(_)));
+
+    let response = read_response(&mut slow);
+    assert!(
+        response.starts_with("HTTP/1.1 408 Request Timeout"),
+        "{}",
+        response
+    );
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    assert!(read_response(&mut other).starts_with("HTTP/1.1 408"));
+
+    // Malformed requests are answered without waiting for more
+    let mut stream = TcpStream::connect(("127.0.0.1", 8331)).unwrap();
+    stream.write_all(b"NOT A REQUEST\r\n\r\n").unwrap();
+    let response = read_response(&mut stream);
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request"),
+        "{}",
+        response
+    );
+
+    // Clients that give up halfway don't upset the server
+    let mut stream = TcpStream::connect(("127.0.0.1", 8331)).unwrap();
+    stream
+        .write_all(b"GET /todos HTTP/1.1\r\nHost: localhost\r\n")
+        .unwrap();
+    drop(stream);
+    let response = send_as(8331, "alice", "GET", "/todos", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+}
+
+#[test]
+fn test_long_polls_through_event_loop() {
+    spawn_server(8332, single_worker());
+
+    let waiting = thread::spawn(|| send_as(8332, "alice", "GET", "/todos/changes?wait=10", ""));
+    thread::sleep(Duration::from_millis(300));
+    let response = send_as(8332, "alice", "POST", "/todos", r#"{"title":"Awaited"}"#);
+    assert!(response.starts_with("HTTP/1.1 201 Created"));
+
+    let response = waiting.join().unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    let changes = json(&response);
+    assert_eq!(changes["seq"], 1);
+    assert_eq!(changes["changes"][0]["todo"]["title"], "Awaited");
+}
+
+#[test]
+fn test_limits_and_long_lived_connections() {
+    spawn_server(
+        8333,
+        Config {
+            threads: 2,
+            limits: Limits {
+                max_body: 16,
+                ..Limits::default()
+            },
+            ..Config::default()
+        },
+    );
+
+    // The loop applies the workers' limits, so a body too large for them is
+    // refused before it has arrived
+    let started = Instant::now();
+    let mut stream = TcpStream::connect(("127.0.0.1", 8333)).unwrap();
+    stream
+        .write_all(b"POST /todos HTTP/1.1\r\nHost: localhost\r\nX-User-Id: alice\r\nContent-Length: 100\r\n\r\n{")
+        .unwrap();
+    let response = read_response(&mut stream);
+    assert!(
+        response.starts_with("HTTP/1.1 413 Content Too Large"),
+        "{}",
+        response
+    );
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // HTTP/2 may hold only one of the two workers; the other keeps serving
+    let mut held = TcpStream::connect(("127.0.0.1", 8333)).unwrap();
+    held.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00\x00\x04\x00\x00\x00\x00\x00")
+        .unwrap();
+    let mut refused = TcpStream::connect(("127.0.0.1", 8333)).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    refused
+        .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00\x00\x04\x00\x00\x00\x00\x00")
+        .unwrap();
+    let mut frames = Vec::new();
+    refused
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    refused.read_to_end(&mut frames).unwrap();
+    // SETTINGS, then GOAWAY with REFUSED_STREAM
+    assert!(frames.ends_with(&[0x7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x7]));
+
+    let started = Instant::now();
+    let response = send_as(8333, "alice", "GET", "/todos", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(started.elapsed() < Duration::from_secs(2));
+    drop(held);
+}
@@ -0,0 +1,259 @@
+mod common;
+
+use common::{header, send_bytes, spawn_server};
+use naked_rust_api::{DEFAULT_USER, Db, Store, Todo};
+use serde_json::{Value, json};
+use std::sync::{Arc, Mutex};
+
+fn seeded_db() -> Db {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    for (id, title) in [(1, "Write report"), (2, "Say \"hi\", then leave")] {
+        db.lock().unwrap().insert(Todo {
+            id,
+            owner: DEFAULT_USER.to_string(),
+            title: title.to_string(),
+            completed: id == 1,
+            deleted_at: None,
+        });
+    }
+    db
+}
+
+#[test]
+fn test_accept_negotiation() {
+    spawn_server(8250, seeded_db());
+
+    let (head, json_body) = send_bytes(8250, "GET", "/todos", "", b"");
+    assert_eq!(
+        header(&head, "Content-Type"),
+        Some("application/json; charset=UTF-8")
+    );
+    assert_eq!(header(&head, "Vary"), Some("Accept"));
+    let expected: Value = serde_json::from_slice(&json_body).unwrap();
+
+    let (head, body) = send_bytes(8250, "GET", "/todos", "Accept: text/csv\r\n", b"");
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(
+        header(&head, "Content-Type"),
+        Some("text/csv; charset=UTF-8")
+    );
+    let csv = String::from_utf8(body).unwrap();
+    let mut lines: Vec<&str> = csv.split_terminator("\r\n").collect();
+    assert_eq!(lines.remove(0), "completed,id,owner,title");
+    lines.sort();
+    assert_eq!(
+        lines,
+        [
+            "false,2,default,\"Say \"\"hi\"\", then leave\"",
+            "true,1,default,Write report",
+        ]
+    );
+
+    let (head, body) = send_bytes(8250, "GET", "/todos", "Accept: application/yaml\r\n", b"");
+    assert_eq!(header(&head, "Content-Type"), Some("application/yaml"));
+    assert_eq!(serde_yaml::from_slice::<Value>(&body).unwrap(), expected);
+
+    let (head, body) = send_bytes(
+        8250,
+        "GET",
+        "/todos",
+        "Accept: application/x-msgpack\r\n",
+        b"",
+    );
+    assert_eq!(header(&head, "Content-Type"), Some("application/msgpack"));
+    assert_eq!(rmp_serde::from_slice::<Value>(&body).unwrap(), expected);
+
+    // The most specific range decides, then the q-value
+    let (head, _) = send_bytes(
+        8250,
+        "GET",
+        "/todos/1",
+        "Accept: application/*;q=0.5, text/csv;q=0.9\r\n",
+        b"",
+    );
+    assert_eq!(
+        header(&head, "Content-Type"),
+        Some("text/csv; charset=UTF-8")
+    );
+    let (head, _) = send_bytes(
+        8250,
+        "GET",
+        "/todos/1",
+        "Accept: text/html, */*;q=0.1\r\n",
+        b"",
+    );
+    assert_eq!(
+        header(&head, "Content-Type"),
+        Some("application/json; charset=UTF-8")
+    );
+    let (head, _) = send_bytes(
+        8250,
+        "GET",
+        "/todos/1",
+        "Accept: */*, application/json;q=0\r\n",
+        b"",
+    );
+    assert_eq!(
+        header(&head, "Content-Type"),
+        Some("text/csv; charset=UTF-8")
+    );
+
+    // Nothing is created when the response couldn't be read anyway
+    let (head, body) = send_bytes(
+        8250,
+        "POST",
+        "/todos",
+        "Accept: text/html\r\n",
+        br#"{"title":"Unreadable"}"#,
+    );
+    assert!(head.starts_with("HTTP/1.1 406 Not Acceptable"));
+    assert!(String::from_utf8_lossy(&body).contains("text/csv"));
+    let (_, body) = send_bytes(8250, "GET", "/todos", "", b"");
+    assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), expected);
+
+    // Error messages are not converted
+    let (head, body) = send_bytes(8250, "GET", "/todos/99", "Accept: text/csv\r\n", b"");
+    assert!(head.starts_with("HTTP/1.1 404 Not Found"));
+    assert_eq!(body, b"Todo not found.");
+}
+
+#[test]
+fn test_request_content_types() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8251, Arc::clone(&db));
+
+    let (head, _) = send_bytes(
+        8251,
+        "POST",
+        "/todos",
+        "Content-Type: application/yaml\r\n",
+        b"title: Learn YAML\n",
+    );
+    assert!(head.starts_with("HTTP/1.1 201 Created"));
+
+    let msgpack = rmp_serde::to_vec(&json!({"title": "Learn MessagePack"})).unwrap();
+    let (head, _) = send_bytes(
+        8251,
+        "POST",
+        "/todos",
+        "Content-Type: application/msgpack\r\n",
+        &msgpack,
+    );
+    assert!(head.starts_with("HTTP/1.1 201 Created"));
+
+    let (head, _) = send_bytes(
+        8251,
+        "POST",
+        "/todos",
+        "Content-Type: text/csv; charset=UTF-8\r\n",
+        b"title\r\n\"Learn CSV, quickly\"\r\n",
+    );
+    assert!(head.starts_with("HTTP/1.1 201 Created"));
+
+    let (head, _) = send_bytes(
+        8251,
+        "PUT",
+        "/todos/3",
+        "Content-Type: text/csv\r\n",
+        b"title,completed\nLearned CSV,true\n",
+    );
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+
+    let todo = |id: usize| -> Value {
+        let (_, body) = send_bytes(8251, "GET", &format!("/todos/{}", id), "", b"");
+        serde_json::from_slice(&body).unwrap()
+    };
+    assert_eq!(todo(1)["title"], "Learn YAML");
+    assert_eq!(todo(2)["title"], "Learn MessagePack");
+    assert_eq!(todo(3)["title"], "Learned CSV");
+    assert_eq!(todo(3)["completed"], true);
+
+    let (head, _) = send_bytes(
+        8251,
+        "POST",
+        "/todos",
+        "Content-Type: text/plain\r\n",
+        b"Learn nothing",
+    );
+    assert!(head.starts_with("HTTP/1.1 415 Unsupported Media Type"));
+
+    let (head, body) = send_bytes(
+        8251,
+        "POST",
+        "/todos",
+        "Content-Type: application/yaml\r\n",
+        b"title: [unclosed\n",
+    );
+    assert!(head.starts_with("HTTP/1.1 400 Bad Request"));
+    assert!(String::from_utf8_lossy(&body).contains("not valid YAML"));
+
+    let (head, _) = send_bytes(
+        8251,
+        "POST",
+        "/todos",
+        "Content-Type: text/csv\r\n",
+        b"title,completed\nToo,many,fields\n",
+    );
+    assert!(head.starts_with("HTTP/1.1 400 Bad Request"));
+    assert_eq!(db.lock().unwrap().len(), 3);
+}
+
+#[test]
+fn test_csv_round_trip_keeps_types() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    for (id, title) in [(1, "42"), (2, "true"), (3, "Pay 3, then 4")] {
+        db.lock().unwrap().insert(Todo {
+            id,
+            owner: "alice".to_string(),
+            title: title.to_string(),
+            completed: id == 2,
+            deleted_at: (id == 3).then(chrono::Utc::now),
+        });
+    }
+    spawn_server(8343, Arc::clone(&db));
+    let target: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8344, Arc::clone(&target));
+
+    let (head, csv) = send_bytes(8343, "GET", "/admin/export", "Accept: text/csv\r\n", b"");
+    assert_eq!(
+        header(&head, "Content-Type"),
+        Some("text/csv; charset=UTF-8")
+    );
+    let (head, _) = send_bytes(
+        8344,
+        "POST",
+        "/admin/import",
+        "Content-Type: text/csv\r\n",
+        &csv,
+    );
+    assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+
+    let export = |port: u16| -> Value {
+        let (_, body) = send_bytes(
+            port,
+            "GET",
+            "/admin/export",
+            "Accept: application/json\r\n",
+            b"",
+        );
+        serde_json::from_slice(&body).unwrap()
+    };
+    let imported = export(8344);
+    assert_eq!(imported, export(8343));
+    // Titles that look like numbers or booleans are still titles
+    assert_eq!(imported[0]["id"], 1);
+    assert_eq!(imported[0]["title"], "42");
+    assert_eq!(imported[1]["title"], "true");
+    assert_eq!(imported[1]["completed"], true);
+
+    // Ids in CSV request bodies are numbers too, and titles strings
+    let (head, _) = send_bytes(
+        8344,
+        "PUT",
+        "/todos/2",
+        "Content-Type: text/csv\r\nX-User-Id: alice\r\n",
+        b"id,title,completed\r\n2,7,false\r\n",
+    );
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(target.lock().unwrap().get("alice", 2).unwrap().title, "7");
+}
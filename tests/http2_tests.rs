@@ -0,0 +1,424 @@
+mod common;
+
+use bytes::Bytes;
+use common::spawn_concurrent_server;
+use h2::client::SendRequest;
+use naked_rust_api::http::Limits;
+use naked_rust_api::{Config, Db, Store};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Opens an HTTP/2 connection with prior knowledge using the h2 crate
+async fn connect(port: u16) -> SendRequest<Bytes> {
+    let tcp = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .expect("Failed to connect to server");
+    let (client, connection) = h2::client::handshake(tcp)
+        .await
+        .expect("HTTP/2 handshake failed");
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+    client
+}
+
+async fn send(client: SendRequest<Bytes>, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut client = client.ready().await.expect("Connection is not ready");
+    let request = http::Request::builder()
+        .method(method)
+        .uri(format!("http://localhost{}", path))
+        .body(())
+        .unwrap();
+    let (response, mut stream) = client
+        .send_request(request, body.is_empty())
+        .expect("Failed to send request");
+    if !body.is_empty() {
+        stream
+            .send_data(Bytes::copy_from_slice(body.as_bytes()), true)
+            .expect("Failed to send body");
+    }
+
+    let response = response.await.expect("Failed to read response");
+    let status = response.status().as_u16();
+    let mut body = response.into_body();
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.expect("Failed to read body");
+        body.flow_control().release_capacity(chunk.len()).unwrap();
+        data.extend_from_slice(&chunk);
+    }
+    (status, String::from_utf8(data).unwrap())
+}
+
+#[tokio::test]
+async fn test_h2_prior_knowledge_multiplexing() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_concurrent_server(8230, Arc::clone(&db), Config::default());
+    let client = connect(8230).await;
+
+    // All requests are in flight on one connection at once
+    let requests: Vec<_> = (0..20)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let body = format!(r#"{{"title":"Stream {}"}}"#, i);
+                send(client, "POST", "/todos", &body).await
+            })
+        })
+        .collect();
+    for request in requests {
+        let (status, body) = request.await.unwrap();
+        assert_eq!(status, 201);
+        assert!(body.contains("\"title\":\"Stream "));
+    }
+    assert_eq!(db.lock().unwrap().len(), 20);
+
+    let (status, body) = send(client.clone(), "GET", "/todos/1", "").await;
+    assert_eq!(status, 200);
+    assert!(body.contains("\"id\":1"));
+
+    let (status, _) = send(client, "GET", "/todos/999", "").await;
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn test_h2_flow_control() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_concurrent_server(8231, Arc::clone(&db), Config::default());
+    let client = connect(8231).await;
+
+    // Both the upload and the listing are larger than the initial window
+    let operations: Vec<String> = (0..2000)
+        .map(|i| format!(r#"{{"op":"create","title":"Flow controlled {}"}}"#, i))
+        .collect();
+    let upload = format!(r#"{{"operations":[{}]}}"#, operations.join(","));
+    assert!(upload.len() > 65_535);
+    let (status, _) = send(client.clone(), "POST", "/todos/bulk", &upload).await;
+    assert_eq!(status, 200);
+    assert_eq!(db.lock().unwrap().len(), 2000);
+
+    let (status, listing) = send(client, "GET", "/todos", "").await;
+    assert_eq!(status, 200);
+    assert!(listing.len() > 65_535);
+    assert!(listing.contains("Flow controlled 1999"));
+}
+
+#[tokio::test]
+async fn test_h2_responses_drop_connection_specific_fields() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_concurrent_server(8350, db, Config::default());
+    let client = connect(8350).await;
+
+    // The 426 sets Upgrade and Connection over HTTP/1.1. The h2 client treats
+    // a response carrying them as malformed.
+    let (status, body) = send(client, "GET", "/ws", "").await;
+    assert_eq!(status, 426);
+    assert!(body.contains("WebSocket upgrade"));
+}
+
+// Minimal frame reader for tests that talk HTTP/2 by hand
+fn read_frame(stream: &mut TcpStream) -> (u8, u8, u32, Vec<u8>) {
+    let mut header = [0; 9];
+    stream
+        .read_exact(&mut header)
+        .expect("Failed to read frame");
+    let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    let mut payload = vec![0; length];
+    stream
+        .read_exact(&mut payload)
+        .expect("Failed to read frame");
+    let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+    (header[3], header[4], stream_id, payload)
+}
+
+#[test]
+fn test_h2c_upgrade() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    db.lock().unwrap().insert(naked_rust_api::Todo {
+        id: 1,
+        owner: naked_rust_api::DEFAULT_USER.to_string(),
+        title: "Upgraded".to_string(),
+        completed: false,
+        deleted_at: None,
+    });
+    spawn_concurrent_server(8232, db, Config::default());
+
+    let mut stream = TcpStream::connect(("127.0.0.1", 8232)).expect("Failed to connect to server");
+    stream
+        .write_all(
+            b"GET /todos/1 HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Connection: Upgrade, HTTP2-Settings\r\n\
+            Upgrade: h2c\r\n\
+            HTTP2-Settings: AAMAAABkAAQAAP__\r\n\
+            \r\n",
+        )
+        .expect("Failed to write to stream");
+
+    let mut switching = [0; 71];
+    stream.read_exact(&mut switching).unwrap();
+    let switching = String::from_utf8_lossy(&switching);
+    assert!(switching.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(switching.ends_with("Upgrade: h2c\r\n\r\n"));
+
+    // Client preface: the magic string and an empty SETTINGS frame
+    stream
+        .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00\x00\x04\x00\x00\x00\x00\x00")
+        .unwrap();
+
+    let mut status = None;
+    let mut body = Vec::new();
+    loop {
+        let (kind, flags, stream_id, payload) = read_frame(&mut stream);
+        match kind {
+            // HEADERS on stream 1; ":status: 200" is static table entry 8
+            0x1 => {
+                assert_eq!(stream_id, 1);
+                status = payload.first().copied();
+            }
+            0x0 => {
+                assert_eq!(stream_id, 1);
+                body.extend_from_slice(&payload);
+                if flags & 0x1 != 0 {
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    assert_eq!(status, Some(0x88));
+    assert!(String::from_utf8_lossy(&body).contains("\"title\":\"Upgraded\""));
+}
+
+#[test]
+fn test_h2_compression_error() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_concurrent_server(8233, db, Config::default());
+
+    let mut stream = TcpStream::connect(("127.0.0.1", 8233)).expect("Failed to connect to server");
+    stream
+        .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00\x00\x04\x00\x00\x00\x00\x00")
+        .unwrap();
+    // HEADERS on stream 1 referencing a table index that doesn't exist
+    stream
+        .write_all(b"\x00\x00\x01\x01\x05\x00\x00\x00\x01\xff")
+        .unwrap();
+
+    loop {
+        let (kind, _, _, payload) = read_frame(&mut stream);
+        if kind == 0x7 {
+            // GOAWAY: last stream id, then the error code
+            assert_eq!(&payload[4..8], &[0, 0, 0, 0x9]);
+            break;
+        }
+    }
+}
+
+// Connection preface and an empty SETTINGS frame
+const CLIENT_PREFACE: &[u8] =
+    b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00\x00\x04\x00\x00\x00\x00\x00";
+
+fn write_frame(stream: &mut TcpStream, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&stream_id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame).unwrap();
+}
+
+// The response HEADERS frame for `stream_id`
+fn read_headers(stream: &mut TcpStream, stream_id: u32) -> Vec<u8> {
+    loop {
+        let (kind, _, id, payload) = read_frame(stream);
+        if kind == 0x1 && id == stream_id {
+            return payload;
+        }
+        assert_ne!(kind, 0x7, "GOAWAY");
+    }
+}
+
+#[test]
+fn test_h2_header_amplification() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_concurrent_server(8234, db, Config::default());
+    let mut stream = TcpStream::connect(("127.0.0.1", 8234)).expect("Failed to connect to server");
+    stream.write_all(CLIENT_PREFACE).unwrap();
+
+    // GET / with a 4000 byte header that goes into the dynamic table...
+    let mut block = vec![0x82, 0x86, 0x84, 0x40, 5];
+    block.extend_from_slice(b"x-big");
+    block.extend_from_slice(&[0x7f, 0xa1, 0x1e]);
+    block.extend_from_slice(&[b'a'; 4000]);
+    // ...and is then referenced 200 times, a byte each
+    block.extend_from_slice(&[0xbe; 200]);
+    assert!(block.len() < 16_384);
+    write_frame(&mut stream, 0x1, 0x5, 1, &block);
+    let headers = read_headers(&mut stream, 1);
+    assert!(
+        headers.windows(3).any(|status| status == b"431"),
+        "{:?}",
+        headers
+    );
+
+    // The connection carries on, with the table still in step: one reference
+    // to the header is fine
+    write_frame(&mut stream, 0x1, 0x5, 3, &[0x82, 0x86, 0x84, 0xbe]);
+    let headers = read_headers(&mut stream, 3);
+    // :status 404, from the static table
+    assert_eq!(headers[0], 0x8d);
+}
+
+#[test]
+fn test_h2_pings_do_not_keep_connection_open() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    let config = Config {
+        header_timeout_secs: 1,
+        ..Config::default()
+    };
+    spawn_concurrent_server(8235, db, config);
+    let mut stream = TcpStream::connect(("127.0.0.1", 8235)).expect("Failed to connect to server");
+    stream.write_all(CLIENT_PREFACE).unwrap();
+    let started = Instant::now();
+
+    let mut pinger = stream.try_clone().unwrap();
+    thread::spawn(move || {
+        for _ in 0..50 {
+            write_frame(&mut pinger, 0x6, 0, 0, &[0; 8]);
+            thread::sleep(Duration::from_millis(100));
+        }
+    });
+
+    // Only the header timeout counts, however many PINGs arrive
+    loop {
+        let (kind, _, _, payload) = read_frame(&mut stream);
+        if kind == 0x7 {
+            assert_eq!(&payload[4..8], &[0, 0, 0, 0]);
+            break;
+        }
+    }
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+}
+
+#[test]
+fn test_h2_connections_are_capped() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    let config = Config {
+        http2_max_connections: Some(1),
+        ..Config::default()
+    };
+    spawn_concurrent_server(8236, db, config);
+
+    // The one connection allowed stays open
+    let mut held = TcpStream::connect(("127.0.0.1", 8236)).expect("Failed to connect to server");
+    held.write_all(CLIENT_PREFACE).unwrap();
+    assert_eq!(read_frame(&mut held).0, 0x4);
+
+    // The next is refused before any stream is processed
+    let mut refused = TcpStream::connect(("127.0.0.1", 8236)).expect("Failed to connect to server");
+    refused.write_all(CLIENT_PREFACE).unwrap();
+    write_frame(&mut refused, 0x1, 0x5, 1, &[0x82, 0x86, 0x84]);
+    loop {
+        let (kind, _, _, payload) = read_frame(&mut refused);
+        assert_ne!(kind, 0x1, "a stream was answered");
+        if kind == 0x7 {
+            // Last stream id 0, REFUSED_STREAM
+            assert_eq!(payload, [0, 0, 0, 0, 0, 0, 0, 0x7]);
+            break;
+        }
+    }
+
+    // An h2c upgrade is answered over HTTP/1.1 instead
+    let mut upgrade = TcpStream::connect(("127.0.0.1", 8236)).expect("Failed to connect to server");
+    upgrade
+        .write_all(
+            b"GET /todos HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Connection: Upgrade, HTTP2-Settings\r\n\
+            Upgrade: h2c\r\n\
+            HTTP2-Settings: AAMAAABkAAQAAP__\r\n\
+            \r\n",
+        )
+        .unwrap();
+    let mut response = String::new();
+    upgrade.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+    // Once the first connection ends, there is room again
+    drop(held);
+    thread::sleep(Duration::from_millis(200));
+    let mut stream = TcpStream::connect(("127.0.0.1", 8236)).expect("Failed to connect to server");
+    stream.write_all(CLIENT_PREFACE).unwrap();
+    write_frame(&mut stream, 0x1, 0x5, 1, &[0x82, 0x86, 0x84]);
+    // :status 404 for GET /, from the static table
+    assert_eq!(read_headers(&mut stream, 1)[0], 0x8d);
+}
+
+// WINDOW_UPDATE increments sent for each stream until the server goes quiet
+fn read_credits(stream: &mut TcpStream) -> (HashMap<u32, u32>, bool) {
+    stream
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let mut credits = HashMap::new();
+    let mut answered = false;
+    let mut header = [0; 9];
+    while stream.read_exact(&mut header).is_ok() {
+        let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let mut payload = vec![0; length];
+        stream.read_exact(&mut payload).unwrap();
+        let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+        match header[3] {
+            0x1 => answered = true,
+            0x8 => {
+                let increment = u32::from_be_bytes(payload.try_into().unwrap());
+                *credits.entry(stream_id).or_default() += increment;
+            }
+            _ => {}
+        }
+    }
+    (credits, answered)
+}
+
+#[test]
+fn test_h2_request_bodies_are_flow_controlled() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    let config = Config {
+        limits: Limits {
+            max_body: 100_000,
+            ..Limits::default()
+        },
+        ..Config::default()
+    };
+    spawn_concurrent_server(8351, db, config);
+    let mut stream = TcpStream::connect(("127.0.0.1", 8351)).expect("Failed to connect to server");
+    stream.write_all(CLIENT_PREFACE).unwrap();
+
+    // The connection window grows to fit one body past the limit
+    let (credits, _) = read_credits(&mut stream);
+    assert_eq!(credits, HashMap::from([(0, 100_001 - 65_535)]));
+
+    // POST /todos, body to follow
+    let mut block = vec![0x83, 0x86, 0x04, 6];
+    block.extend_from_slice(b"/todos");
+    write_frame(&mut stream, 0x1, 0x4, 1, &block);
+    for _ in 0..4 {
+        write_frame(&mut stream, 0x0, 0, 1, &[b'a'; 16_000]);
+    }
+    // The stream is credited up to the body limit, the connection not at all
+    // while the body is still buffered
+    let (credits, answered) = read_credits(&mut stream);
+    assert!(!answered);
+    assert_eq!(credits, HashMap::from([(1, 100_001 - 65_535)]));
+
+    // Once the body has been dealt with, the connection gets its room back
+    write_frame(&mut stream, 0x0, 0x1, 1, &[]);
+    let (credits, answered) = read_credits(&mut stream);
+    assert!(answered);
+    assert_eq!(credits, HashMap::from([(0, 64_000)]));
+}
@@ -0,0 +1,339 @@
+use naked_rust_api::http::{Limits, ParseError, Progress, Request, Version, parse};
+use naked_rust_api::{Db, Store, handle_connection};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+fn parse_complete(raw: &[u8]) -> Request {
+    match parse(raw, &Limits::default()) {
+        Ok(Some((request, consumed))) => {
+            assert_eq!(consumed, raw.len());
+            request
+        }
+        Ok(None) => panic!("Request was reported incomplete"),
+        Err(e) => panic!("Request was rejected: {}", e),
+    }
+}
+
+fn parse_error(raw: &[u8]) -> ParseError {
+    match parse(raw, &Limits::default()) {
+        Err(e) => e,
+        Ok(_) => panic!("Request was accepted"),
+    }
+}
+
+#[test]
+fn test_parse_tolerant_whitespace_and_multi_values() {
+    let request = parse_complete(
+        b"\r\nGET /todos?deleted=true HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        x-user-id:alice\r\n\
+        Accept:   application/json  \r\n\
+        Accept:\ttext/plain\r\n\
+        \r\n",
+    );
+
+    assert_eq!(request.method, "GET");
+    assert_eq!(request.path(), "/todos");
+    assert_eq!(request.query(), "deleted=true");
+    assert_eq!(request.version, Version::Http11);
+    assert_eq!(request.headers.get("X-User-Id"), Some("alice"));
+    let accept: Vec<&str> = request.headers.get_all("ACCEPT").collect();
+    assert_eq!(accept, ["application/json", "text/plain"]);
+}
+
+#[test]
+fn test_parse_bare_lf_and_binary_body() {
+    let mut raw = b"POST /todos HTTP/1.0\nContent-Length: 4\n\n".to_vec();
+    raw.extend_from_slice(&[0xff, 0x00, b'\r', b'\n']);
+    let request = parse_complete(&raw);
+
+    assert_eq!(request.version, Version::Http10);
+    assert_eq!(request.body, [0xff, 0x00, b'\r', b'\n']);
+}
+
+#[test]
+fn test_parse_incomplete() {
+    let limits = Limits::default();
+    assert!(
+        parse(b"GET /todos HTTP/1.1\r\nHost: x", &limits)
+            .unwrap()
+            .is_none()
+    );
+    assert!(
+        parse(
+            b"POST /todos HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\nabc",
+            &limits
+        )
+        .unwrap()
+        .is_none()
+    );
+    assert!(
+        parse(
+            b"POST /todos HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc",
+            &limits
+        )
+        .unwrap()
+        .is_none()
+    );
+}
+
+#[test]
+fn test_parse_chunked_body() {
+    let request = parse_complete(
+        b"POST /todos HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        Transfer-Encoding: chunked\r\n\
+        \r\n\
+        5;name=value\r\nhello\r\n\
+        6\r\n world\r\n\
+        0\r\n\
+        Trailer-Field: ignored\r\n\
+        \r\n",
+    );
+    assert_eq!(request.body, b"hello world");
+}
+
+#[test]
+fn test_parse_rejections() {
+    assert_eq!(
+        parse_error(b"GET /todos HTTP/2.0\r\n\r\n"),
+        ParseError::UnsupportedVersion
+    );
+    assert_eq!(
+        parse_error(b"GET /todos HTTX/1.1\r\n\r\n"),
+        ParseError::BadRequestLine
+    );
+    assert_eq!(
+        parse_error(b"GET /todos\r\n\r\n"),
+        ParseError::BadRequestLine
+    );
+    assert_eq!(
+        parse_error(b"GET /todos HTTP/1.1\r\nHost: localhost\r\nX-User-Id : alice\r\n\r\n"),
+        ParseError::BadHeader
+    );
+    assert_eq!(
+        parse_error(
+            b"GET /todos HTTP/1.1\r\nHost: localhost\r\nX-User-Id: alice\r\n folded\r\n\r\n"
+        ),
+        ParseError::BadHeader
+    );
+    assert_eq!(
+        parse_error(b"GET /todos HTTP/1.1\r\nHost: localhost\r\nNo colon here\r\n\r\n"),
+        ParseError::BadHeader
+    );
+    assert_eq!(
+        parse_error(b"POST /todos HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabc"),
+        ParseError::DuplicateContentLength
+    );
+    assert_eq!(
+        parse_error(b"POST /todos HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3, 4\r\n\r\nabc"),
+        ParseError::DuplicateContentLength
+    );
+    assert_eq!(
+        parse_error(b"POST /todos HTTP/1.1\r\nHost: localhost\r\nContent-Length: +3\r\n\r\nabc"),
+        ParseError::BadContentLength
+    );
+    assert_eq!(
+        parse_error(
+            b"POST /todos HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n"
+        ),
+        ParseError::BadTransferEncoding
+    );
+    assert_eq!(
+        parse_error(
+            b"POST /todos HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"
+        ),
+        ParseError::UnsupportedTransferEncoding
+    );
+    assert_eq!(
+        parse_error(
+            b"POST /todos HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"
+        ),
+        ParseError::BadChunk
+    );
+}
+
+#[test]
+fn test_parse_host_rules() {
+    assert_eq!(
+        parse_error(b"GET /todos HTTP/1.1\r\n\r\n"),
+        ParseError::MissingHost
+    );
+    assert_eq!(
+        parse_error(b"GET /todos HTTP/1.1\r\nHost: a.example\r\nHost: b.example\r\n\r\n"),
+        ParseError::DuplicateHost
+    );
+    assert_eq!(
+        parse_error(b"GET /todos HTTP/1.0\r\nHost: a.example\r\nhost: a.example\r\n\r\n"),
+        ParseError::DuplicateHost
+    );
+    // HTTP/1.0 predates Host, so it may be left out
+    let request = parse_complete(b"GET /todos HTTP/1.0\r\n\r\n");
+    assert_eq!(request.headers.get("Host"), None);
+}
+
+#[test]
+fn test_parse_limits() {
+    let limits = Limits {
+        max_request_line: 32,
+        max_head: 64,
+        max_headers: 2,
+        max_body: 8,
+    };
+    let long_target = format!(
+        "GET /{} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        "a".repeat(64)
+    );
+    assert_eq!(
+        parse(long_target.as_bytes(), &limits).err(),
+        Some(ParseError::RequestLineTooLong)
+    );
+    // Limits apply before the line or section is complete
+    assert_eq!(
+        parse(&long_target.as_bytes()[..40], &limits).err(),
+        Some(ParseError::RequestLineTooLong)
+    );
+    assert_eq!(
+        parse(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n",
+            &limits
+        )
+        .err(),
+        Some(ParseError::HeadersTooLarge)
+    );
+    let big_header = format!(
+        "GET / HTTP/1.1\r\nHost: localhost\r\nA: {}\r\n\r\n",
+        "b".repeat(64)
+    );
+    assert_eq!(
+        parse(big_header.as_bytes(), &limits).err(),
+        Some(ParseError::HeadersTooLarge)
+    );
+    assert_eq!(
+        parse(
+            b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 9\r\n\r\n",
+            &limits
+        )
+        .err(),
+        Some(ParseError::BodyTooLarge)
+    );
+}
+
+#[test]
+fn test_progress_agrees_with_parse() {
+    let limits = Limits {
+        max_request_line: 32,
+        max_head: 96,
+        max_headers: 2,
+        max_body: 16,
+    };
+    let requests: [&[u8]; 14] = [
+        b"\r\nGET /todos HTTP/1.1\r\nHost: localhost\r\nA: 1\r\n\r\n",
+        b"POST /todos HTTP/1.1\nHost: localhost\nContent-Length: 5\n\nhello",
+        b"POST /todos HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n3;x=y\r\nabc\r\n2\r\nde\r\n0\r\nT: 1\r\n\r\n",
+        b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabcX",
+        b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+        b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n9\r\n123456789\r\n9\r\n",
+        b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 17\r\n\r\n",
+        b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n",
+        b"GET /aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nA: bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\r\n",
+        b"GET / HTTP/9.9\r\n",
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nBad Header: 1\r\n",
+        b"NOT A REQUEST\r\n\r\n",
+    ];
+    for request in requests {
+        // Fed a byte at a time, progress reports done exactly when parse
+        // stops asking for more
+        let mut progress = Progress::default();
+        for end in 0..=request.len() {
+            let partial = &request[..end];
+            let parsed = parse(partial, &limits);
+            assert_eq!(
+                progress.advance(partial, &limits),
+                !matches!(parsed, Ok(None)),
+                "{:?} after {} bytes",
+                String::from_utf8_lossy(request),
+                end
+            );
+        }
+    }
+}
+
+fn send_raw(port: u16, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("Failed to connect to server");
+    stream
+        .write_all(request)
+        .expect("Failed to write to stream");
+
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .expect("Failed to read from stream");
+    response
+}
+
+#[test]
+fn test_server_uses_strict_parser() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    let listener = TcpListener::bind("127.0.0.1:8220").expect("Failed to bind to port 8220");
+    let db_clone = Arc::clone(&db);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    handle_connection(stream, Arc::clone(&db_clone));
+                }
+                Err(e) => {
+                    eprintln!("Connection failed: {}", e);
+                }
+            }
+        }
+    });
+
+    let created = send_raw(
+        8220,
+        b"POST /todos HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        X-User-Id:alice\r\n\
+        Transfer-Encoding: chunked\r\n\
+        \r\n\
+        8\r\n{\"title\"\r\n\
+        c\r\n:\"Chunked\"}\r\n\
+        0\r\n\r\n",
+    );
+    assert!(created.contains("201 Created"));
+    assert!(created.contains("\"owner\":\"alice\""));
+    assert!(created.contains("\"title\":\"Chunked\""));
+
+    let smuggled = send_raw(
+        8220,
+        b"POST /todos HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\nContent-Length: 20\r\n\r\n{}",
+    );
+    assert!(smuggled.contains("400 Bad Request"));
+    assert!(smuggled.contains("Duplicate Content-Length."));
+
+    let no_host = send_raw(8220, b"GET /todos HTTP/1.1\r\nX-User-Id: alice\r\n\r\n");
+    assert!(no_host.contains("400 Bad Request"));
+    assert!(no_host.contains("Missing Host header."));
+
+    let two_hosts = send_raw(
+        8220,
+        b"GET /todos HTTP/1.1\r\nHost: a.example\r\nHost: b.example\r\n\r\n",
+    );
+    assert!(two_hosts.contains("400 Bad Request"));
+    assert!(two_hosts.contains("Duplicate Host header."));
+
+    let http2 = send_raw(8220, b"GET /todos HTTP/2.0\r\n\r\n");
+    assert!(http2.contains("505 HTTP Version Not Supported"));
+
+    let binary = send_raw(
+        8220,
+        b"POST /todos HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\n\xff\xfe",
+    );
+    assert!(binary.contains("400 Bad Request"));
+    assert!(binary.contains("Request body is not valid UTF-8."));
+}
@@ -0,0 +1,108 @@
+mod common;
+
+use common::{send, spawn_concurrent_server, spawn_server_with};
+use naked_rust_api::{Config, Db, Store};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+fn post_todo(port: u16, key: &str, request_body: &str) -> String {
+    let request = format!(
+        "POST /todos HTTP/1.1\r\nHost: localhost\r\nIdempotency-Key: {}\r\nContent-Length: {}\r\n\r\n{}",
+        key,
+        request_body.len(),
+        request_body
+    );
+    send(port, &request)
+}
+
+#[test]
+fn test_retry_replays_original_response() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server_with(8110, Arc::clone(&db), Config::default());
+
+    let first = post_todo(8110, "retry-1", r#"{"title":"Pay rent"}"#);
+    let second = post_todo(8110, "retry-1", r#"{"title":"Pay rent"}"#);
+
+    assert!(first.contains("201 Created"));
+    assert!(second.contains("201 Created"));
+    assert!(second.contains("\"id\":1"));
+    assert_eq!(db.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn test_reused_key_with_different_body() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server_with(8111, Arc::clone(&db), Config::default());
+
+    post_todo(8111, "reuse-1", r#"{"title":"First"}"#);
+    let response = post_todo(8111, "reuse-1", r#"{"title":"Second"}"#);
+
+    assert!(response.contains("422 Unprocessable Entity"));
+    assert_eq!(db.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn test_key_expires_after_window() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    let config = Config {
+        idempotency_ttl_secs: 1,
+        ..Config::default()
+    };
+    spawn_server_with(8112, Arc::clone(&db), config);
+
+    post_todo(8112, "expire-1", r#"{"title":"Once"}"#);
+    thread::sleep(Duration::from_millis(1100));
+    let response = post_todo(8112, "expire-1", r#"{"title":"Once"}"#);
+
+    assert!(response.contains("\"id\":2"));
+    assert_eq!(db.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn test_oldest_keys_make_way() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    let config = Config {
+        idempotency_max_keys: 2,
+        ..Config::default()
+    };
+    spawn_server_with(8354, Arc::clone(&db), config);
+
+    for key in ["cap-1", "cap-2", "cap-3"] {
+        post_todo(8354, key, r#"{"title":"Capped"}"#);
+    }
+    // The newest keys are still replayed
+    assert!(post_todo(8354, "cap-3", r#"{"title":"Capped"}"#).contains("\"id\":3"));
+    assert!(post_todo(8354, "cap-2", r#"{"title":"Capped"}"#).contains("\"id\":2"));
+    assert_eq!(db.lock().unwrap().len(), 3);
+
+    // The oldest was forgotten, so its retry runs again
+    let response = post_todo(8354, "cap-1", r#"{"title":"Capped"}"#);
+    assert!(response.contains("\"id\":4"));
+    assert_eq!(db.lock().unwrap().len(), 4);
+}
+
+#[test]
+fn test_concurrent_retries_run_once() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    // Every connection is served at once, so retries really race
+    spawn_concurrent_server(8345, Arc::clone(&db), Config::default());
+
+    let retries: Vec<_> = (0..16)
+        .map(|_| thread::spawn(|| post_todo(8345, "race-1", r#"{"title":"Only once"}"#)))
+        .collect();
+    let others: Vec<_> = (0..16)
+        .map(|i| {
+            thread::spawn(move || post_todo(8345, &format!("other-{}", i), r#"{"title":"Each"}"#))
+        })
+        .collect();
+
+    let responses: Vec<String> = retries.into_iter().map(|t| t.join().unwrap()).collect();
+    let body = |response: &str| response.split_once("\r\n\r\n").unwrap().1.to_string();
+    assert!(responses[0].contains("201 Created"));
+    assert!(responses.iter().all(|r| body(r) == body(&responses[0])));
+    for other in others {
+        assert!(other.join().unwrap().contains("201 Created"));
+    }
+    assert_eq!(db.lock().unwrap().len(), 17);
+}
@@ -0,0 +1,148 @@
+mod common;
+
+use common::{send_with, spawn_server_with};
+use naked_rust_api::config::AuthConfig;
+use naked_rust_api::{Config, Db, Store};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+const POLICY: &str = r#"{
+    "users": { "rita": "reader", "will": "writer", "ada": "admin", "eve": "auditor" },
+    "roles": { "auditor": ["read", "admin"] }
+}"#;
+
+// Sends `request_body` with `user`'s API key
+fn send_as(port: u16, user: &str, method: &str, path: &str, request_body: &str) -> String {
+    let extra_headers = format!("X-API-Key: {}-key\r\n", user);
+    send_with(port, method, path, &extra_headers, request_body)
+}
+
+fn spawn_server(port: u16, db: Db, policy: &str) {
+    let path = std::env::temp_dir().join(format!("policy-{}-{}.json", port, std::process::id()));
+    fs::write(&path, policy).unwrap();
+    // Every user gets the API key `<user>-key`
+    let keys = ["rita", "will", "ada", "eve", "nobody"]
+        .map(|user| (format!("{}-key", user), user.to_string()));
+    let config = Config {
+        policy_file: Some(path.to_string_lossy().into_owned()),
+        auth: AuthConfig {
+            api_keys: HashMap::from(keys),
+            ..AuthConfig::default()
+        },
+        ..Config::default()
+    };
+
+    spawn_server_with(port, db, config);
+}
+
+#[test]
+fn test_roles_limit_operations() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8170, Arc::clone(&db), POLICY);
+
+    let response = send_as(8170, "rita", "GET", "/todos", "");
+    assert!(response.contains("200 OK"));
+
+    let response = send_as(8170, "rita", "POST", "/todos", r#"{"title":"Nope"}"#);
+    assert!(response.contains("403 Forbidden"));
+    assert!(response.contains("\"role\":\"reader\""));
+    assert!(response.contains("\"operation\":\"create\""));
+
+    let response = send_as(8170, "will", "POST", "/todos", r#"{"title":"Yes"}"#);
+    assert!(response.contains("201 Created"));
+    let response = send_as(8170, "will", "DELETE", "/todos/1", "");
+    assert!(response.contains("200 OK"));
+
+    let response = send_as(8170, "will", "POST", "/admin/purge", "");
+    assert!(response.contains("403 Forbidden"));
+
+    let response = send_as(8170, "ada", "POST", "/admin/purge", "");
+    assert!(response.contains("200 OK"));
+    assert!(response.contains("\"purged\":1"));
+    assert!(db.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_custom_roles_and_unassigned_users() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8171, db, POLICY);
+
+    let response = send_as(8171, "eve", "POST", "/admin/purge", "");
+    assert!(response.contains("200 OK"));
+    let response = send_as(8171, "eve", "PUT", "/todos/1", r#"{"completed":true}"#);
+    assert!(response.contains("403 Forbidden"));
+
+    let response = send_as(8171, "nobody", "GET", "/todos", "");
+    assert!(response.contains("403 Forbidden"));
+    assert!(response.contains("\"role\":null"));
+}
+
+#[test]
+fn test_bulk_items_are_authorized_individually() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8172, Arc::clone(&db), POLICY);
+
+    let request_body = r#"{"operations":[{"op":"create","title":"Sneaky"}]}"#;
+    let response = send_as(8172, "rita", "POST", "/todos/bulk", request_body);
+    assert!(response.contains("\"status\":403"));
+    assert!(db.lock().unwrap().is_empty());
+
+    let response = send_as(8172, "will", "POST", "/todos/bulk", request_body);
+    assert!(response.contains("\"status\":201"));
+}
+
+#[test]
+fn test_no_route_around_a_role() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8347, Arc::clone(&db), POLICY);
+    send_as(8347, "will", "POST", "/todos", r#"{"title":"Kept"}"#);
+    send_as(8347, "will", "DELETE", "/todos/1", "");
+    let changes = db.lock().unwrap().change_seq();
+
+    // A reader can't change anything by any other way in either
+    for (method, path, request_body) in [
+        ("PUT", "/todos/1", r#"{"title":"Changed","completed":true}"#),
+        ("POST", "/todos/1/restore", ""),
+        (
+            "POST",
+            "/admin/import",
+            r#"{"id":9,"owner":"will","title":"In","completed":false}"#,
+        ),
+        ("GET", "/admin/export", ""),
+        ("GET", "/audit", ""),
+        ("POST", "/admin/purge", ""),
+    ] {
+        let response = send_as(8347, "rita", method, path, request_body);
+        assert!(response.contains("403 Forbidden"), "{} {}", method, path);
+    }
+    // Admin rights don't include restoring, which needs the delete permission
+    let response = send_as(8347, "eve", "POST", "/todos/1/restore", "");
+    assert!(response.contains("403 Forbidden"));
+    assert_eq!(db.lock().unwrap().change_seq(), changes);
+    assert_eq!(db.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn test_claimed_users_get_no_role() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8348, Arc::clone(&db), POLICY);
+
+    // With authentication configured, X-User-Id isn't accepted at all
+    let response = send_with(8348, "POST", "/admin/purge", "X-User-Id: ada\r\n", "");
+    assert!(response.contains("401 Unauthorized"));
+
+    // And a server set up without it gives claimed users no role
+    let path = std::env::temp_dir().join(format!("policy-8349-{}.json", std::process::id()));
+    fs::write(&path, POLICY).unwrap();
+    let config = Config {
+        policy_file: Some(path.to_string_lossy().into_owned()),
+        ..Config::default()
+    };
+    spawn_server_with(8349, Arc::clone(&db), config);
+    for extra_headers in ["X-User-Id: ada\r\n", ""] {
+        let response = send_with(8349, "GET", "/audit", extra_headers, "");
+        assert!(response.contains("403 Forbidden"));
+        assert!(response.contains("\"role\":null"));
+    }
+}
@@ -0,0 +1,162 @@
+mod common;
+
+use common::{empty_db, send_with, spawn_concurrent_server, spawn_server_with};
+use naked_rust_api::Config;
+use naked_rust_api::config::{RateLimitConfig, RateLimitRule};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+
+fn rule(method: Option<&str>, path: Option<&str>, capacity: u32) -> RateLimitRule {
+    RateLimitRule {
+        method: method.map(str::to_string),
+        path: path.map(str::to_string),
+        capacity,
+        // Slow enough that no token comes back while a test runs
+        refill_per_sec: 0.001,
+    }
+}
+
+fn send(port: u16, method: &str, path: &str, extra_headers: &str) -> String {
+    send_with(port, method, path, extra_headers, r#"{"title":"Limited"}"#)
+}
+
+#[test]
+fn test_rate_limit_per_address() {
+    let config = Config {
+        rate_limit: RateLimitConfig {
+            default: Some(rule(None, None, 2)),
+            ..RateLimitConfig::default()
+        },
+        ..Config::default()
+    };
+    spawn_server_with(8180, empty_db(), config);
+
+    let first = send(8180, "GET", "/todos", "");
+    assert!(first.contains("200 OK"));
+    assert!(first.contains("RateLimit-Limit: 2"));
+    assert!(first.contains("RateLimit-Remaining: 1"));
+
+    let second = send(8180, "GET", "/todos", "");
+    assert!(second.contains("200 OK"));
+    assert!(second.contains("RateLimit-Remaining: 0"));
+
+    // Claiming another user doesn't buy a fresh bucket
+    let third = send(8180, "GET", "/todos", "X-User-Id: someone-else\r\n");
+    assert!(third.contains("429 Too Many Requests"));
+    assert!(third.contains("Retry-After: "));
+    assert!(third.contains("RateLimit-Remaining: 0"));
+    assert!(third.contains("Too many requests."));
+}
+
+#[test]
+fn test_rate_limit_per_route() {
+    let config = Config {
+        rate_limit: RateLimitConfig {
+            routes: vec![
+                rule(Some("POST"), Some("/todos"), 1),
+                rule(None, Some("/todos/*"), 3),
+            ],
+            ..RateLimitConfig::default()
+        },
+        ..Config::default()
+    };
+    spawn_server_with(8181, empty_db(), config);
+
+    assert!(send(8181, "POST", "/todos", "").contains("201 Created"));
+    assert!(send(8181, "POST", "/todos", "").contains("429 Too Many Requests"));
+
+    // Listing matches no rule and is never limited
+    let list = send(8181, "GET", "/todos", "");
+    assert!(list.contains("200 OK"));
+    assert!(!list.contains("RateLimit-Limit"));
+
+    // Each rule has its own budget
+    let single = send(8181, "GET", "/todos/1", "");
+    assert!(single.contains("200 OK"));
+    assert!(single.contains("RateLimit-Limit: 3"));
+}
+
+#[test]
+fn test_rate_limit_per_api_key() {
+    let mut config = Config {
+        rate_limit: RateLimitConfig {
+            default: Some(rule(None, None, 1)),
+            ..RateLimitConfig::default()
+        },
+        ..Config::default()
+    };
+    config.auth.api_keys = HashMap::from([
+        ("key-alice".to_string(), "alice".to_string()),
+        ("key-bob".to_string(), "bob".to_string()),
+    ]);
+    spawn_server_with(8182, empty_db(), config);
+
+    let alice = "X-API-Key: key-alice\r\n";
+    assert!(send(8182, "GET", "/todos", alice).contains("200 OK"));
+    assert!(send(8182, "GET", "/todos", alice).contains("429 Too Many Requests"));
+
+    // Same address, different key: a separate bucket
+    assert!(send(8182, "GET", "/todos", "X-API-Key: key-bob\r\n").contains("200 OK"));
+
+    // Failed logins are limited by address
+    assert!(send(8182, "GET", "/todos", "X-API-Key: wrong\r\n").contains("401 Unauthorized"));
+    assert!(send(8182, "GET", "/todos", "X-API-Key: wrong\r\n").contains("429 Too Many Requests"));
+}
+
+#[test]
+fn test_rate_limit_keeps_at_most_max_clients() {
+    let mut config = Config {
+        rate_limit: RateLimitConfig {
+            default: Some(rule(None, None, 1)),
+            max_clients: 2,
+            ..RateLimitConfig::default()
+        },
+        ..Config::default()
+    };
+    config.auth.api_keys = ["ann", "ben", "cal"]
+        .into_iter()
+        .map(|user| (format!("key-{}", user), user.to_string()))
+        .collect();
+    spawn_server_with(8355, empty_db(), config);
+
+    for user in ["ann", "ben"] {
+        let key = format!("X-API-Key: key-{}\r\n", user);
+        assert!(send(8355, "GET", "/todos", &key).contains("200 OK"));
+    }
+    assert!(send(8355, "GET", "/todos", "X-API-Key: key-ben\r\n").contains("429"));
+
+    // A third client pushes out the least recently used bucket, not ben's
+    assert!(send(8355, "GET", "/todos", "X-API-Key: key-cal\r\n").contains("200 OK"));
+    assert!(send(8355, "GET", "/todos", "X-API-Key: key-ben\r\n").contains("429"));
+    assert!(send(8355, "GET", "/todos", "X-API-Key: key-ann\r\n").contains("200 OK"));
+}
+
+#[test]
+fn test_rate_limit_under_concurrent_burst() {
+    let config = Config {
+        rate_limit: RateLimitConfig {
+            default: Some(rule(None, None, 5)),
+            ..RateLimitConfig::default()
+        },
+        ..Config::default()
+    };
+    let db = empty_db();
+    spawn_concurrent_server(8346, Arc::clone(&db), config);
+
+    let burst: Vec<_> = (0..30)
+        .map(|_| thread::spawn(|| send(8346, "POST", "/todos", "")))
+        .collect();
+    let statuses: Vec<String> = burst
+        .into_iter()
+        .map(|request| request.join().unwrap().lines().next().unwrap().to_string())
+        .collect();
+    let created = statuses
+        .iter()
+        .filter(|s| s.contains("201 Created"))
+        .count();
+    let limited = statuses.iter().filter(|s| s.contains("429")).count();
+    assert_eq!((created, limited), (5, 25));
+    // Limited requests changed nothing
+    assert_eq!(db.lock().unwrap().len(), 5);
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use common::{backends, body, send, spawn_server, with_body};
+use naked_rust_api::{DEFAULT_USER, Db, Todo};
+
+fn body_ids(response: &str) -> Vec<u64> {
+    let hits: serde_json::Value = serde_json::from_str(body(response)).unwrap();
+    hits.as_array()
+        .unwrap()
+        .iter()
+        .map(|hit| hit["id"].as_u64().unwrap())
+        .collect()
+}
+
+fn seed(db: &Db, titles: &[&str]) {
+    for (i, title) in titles.iter().enumerate() {
+        db.lock().unwrap().insert(Todo {
+            id: i + 1,
+            owner: DEFAULT_USER.to_string(),
+            title: title.to_string(),
+            completed: false,
+            deleted_at: None,
+        });
+    }
+}
+
+#[test]
+fn test_search_ranks_and_matches_prefixes() {
+    for (port, db) in backends(8130) {
+        seed(
+            &db,
+            &[
+                "Buy milk",
+                "Learn Rust: rust book, RUST exercises",
+                "Read about rustls",
+                "Learn Go",
+            ],
+        );
+        spawn_server(port, db);
+
+        let response = send(
+            port,
+            "GET /todos/search?q=Rust HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert!(response.contains("200 OK"));
+        assert_eq!(body_ids(&response), vec![2, 3]);
+
+        let response = send(
+            port,
+            "GET /todos/search?q=learn+ru HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert_eq!(body_ids(&response), vec![2]);
+
+        let response = send(
+            port,
+            "GET /todos/search?q=%20 HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert!(response.contains("400 Bad Request"));
+    }
+}
+
+#[test]
+fn test_search_index_follows_changes() {
+    for (port, db) in backends(8131) {
+        seed(&db, &["Walk the dog", "Feed the cat"]);
+        spawn_server(port, db);
+
+        let request_body = r#"{"title":"Walk the cat","completed":false}"#;
+        send(port, &with_body("PUT", "/todos/1", request_body));
+        let response = send(
+            port,
+            "GET /todos/search?q=dog HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert!(body_ids(&response).is_empty());
+
+        let response = send(
+            port,
+            "GET /todos/search?q=cat HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert_eq!(body_ids(&response).len(), 2);
+
+        send(port, "DELETE /todos/2 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let response = send(
+            port,
+            "GET /todos/search?q=cat HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert_eq!(body_ids(&response), vec![1]);
+
+        send(
+            port,
+            "POST /todos/2/restore HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        let response = send(
+            port,
+            "GET /todos/search?q=feed HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert_eq!(body_ids(&response), vec![2]);
+    }
+}
+
+#[test]
+fn test_search_scores_count_only_the_owners_live_todos() {
+    for (port, db) in backends(8342) {
+        seed(&db, &["Walk the dog", "Feed the cat"]);
+        for i in 1..=20 {
+            db.lock().unwrap().insert(Todo {
+                id: i,
+                owner: "zoe".to_string(),
+                title: format!("Zoe's dog {}", i),
+                completed: false,
+                deleted_at: None,
+            });
+        }
+        db.lock().unwrap().insert(Todo {
+            id: 1,
+            owner: "bob".to_string(),
+            title: "Walk the dog".to_string(),
+            completed: false,
+            deleted_at: None,
+        });
+        spawn_server(port, db);
+
+        // A trashed todo weighs no more than one never created
+        send(port, "DELETE /todos/2 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let score = |user: &str| {
+            let request = format!(
+                "GET /todos/search?q=dog HTTP/1.1\r\nHost: localhost\r\nX-User-Id: {}\r\n\r\n",
+                user
+            );
+            let hits: serde_json::Value =
+                serde_json::from_str(body(&send(port, &request))).unwrap();
+            assert_eq!(hits.as_array().unwrap().len(), 1);
+            hits[0]["score"].as_f64().unwrap()
+        };
+        assert_eq!(score(DEFAULT_USER), score("bob"));
+
+        // Listing reaches only the owner's todos, in id order
+        let response = send(
+            port,
+            "GET /todos HTTP/1.1\r\nHost: localhost\r\nX-User-Id: zoe\r\n\r\n",
+        );
+        let ids: Vec<u64> = serde_json::from_str::<serde_json::Value>(body(&response))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|todo| todo["id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(ids, (1..=20).collect::<Vec<u64>>());
+    }
+}
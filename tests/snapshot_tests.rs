@@ -0,0 +1,225 @@
+use chrono::Utc;
+use naked_rust_api::{Store, Todo, journal, snapshot};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+fn todo(id: usize, title: &str) -> Todo {
+    Todo {
+        id,
+        owner: "alice".to_string(),
+        title: title.to_string(),
+        completed: false,
+        deleted_at: None,
+    }
+}
+
+fn data_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+// Keeps journal entries and snapshots on either side of a point in time apart
+fn tick() {
+    thread::sleep(Duration::from_millis(5));
+}
+
+fn titles(store: &naked_rust_api::Store) -> Vec<String> {
+    let mut titles: Vec<String> = store.values().map(|todo| todo.title.clone()).collect();
+    titles.sort();
+    titles
+}
+
+#[test]
+fn test_snapshot_rotates_journal_and_reopens() {
+    let dir = data_dir("snapshot-reopen");
+    let data_dir = dir.to_str().unwrap();
+    let mut store = journal::open_store(data_dir).unwrap();
+    let id = store.next_id("alice");
+    store.insert(todo(id, "Before"));
+    let taken = snapshot::take(&store, data_dir, 5).unwrap();
+    assert!(taken.path.exists());
+    // The journal now only holds what came after the snapshot
+    assert_eq!(
+        fs::read_to_string(journal::journal_path(data_dir)).unwrap(),
+        ""
+    );
+    let id = store.next_id("alice");
+    store.insert(todo(id, "After"));
+    store.remove("alice", 1);
+    assert_eq!(
+        journal::read_entries(&journal::journal_path(data_dir))
+            .unwrap()
+            .len(),
+        2
+    );
+
+    let mut reopened = journal::open_store(data_dir).unwrap();
+    assert_eq!(titles(&reopened), ["After"]);
+    assert_eq!(reopened.next_id("alice"), 3);
+    // No temporary files are left behind
+    let names: Vec<String> = fs::read_dir(dir.join("snapshots"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(names.len(), 1);
+    assert!(names[0].starts_with("snapshot-") && names[0].ends_with(".jsonl"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_retention_prunes_snapshots_and_journals() {
+    let dir = data_dir("snapshot-retention");
+    let data_dir = dir.to_str().unwrap();
+    let mut store = journal::open_store(data_dir).unwrap();
+    let mut times = Vec::new();
+    for id in 1..=4 {
+        store.insert(todo(id, &format!("Todo {}", id)));
+        tick();
+        times.push(Utc::now());
+        tick();
+        snapshot::take(&store, data_dir, 2).unwrap();
+        tick();
+    }
+
+    let kept = snapshot::list(data_dir).unwrap();
+    assert_eq!(kept.len(), 2);
+    assert!(kept[0].at < kept[1].at);
+    let archived = fs::read_dir(&dir)
+        .unwrap()
+        .filter(|entry| {
+            let name = entry.as_ref().unwrap().file_name();
+            name.to_str().unwrap().starts_with("journal-")
+        })
+        .count();
+    assert_eq!(archived, 1);
+
+    // History before the oldest kept snapshot is gone
+    let error = snapshot::rebuild(data_dir, times[1]).err().unwrap();
+    assert!(error.contains("No snapshot at or before"));
+    assert_eq!(
+        titles(&snapshot::rebuild(data_dir, times[3]).unwrap()).len(),
+        4
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_rebuild_at_a_point_in_time() {
+    let dir = data_dir("snapshot-rebuild");
+    let data_dir = dir.to_str().unwrap();
+    let mut store = journal::open_store(data_dir).unwrap();
+    store.insert(todo(1, "First"));
+    tick();
+    let before_snapshot = Utc::now();
+    tick();
+    snapshot::take(&store, data_dir, 5).unwrap();
+    store.insert(todo(2, "Second"));
+    tick();
+    let after_second = Utc::now();
+    tick();
+    snapshot::take(&store, data_dir, 5).unwrap();
+    store.update("alice", 1, |todo| todo.title = "First, renamed".to_string());
+    store.remove("alice", 2);
+    tick();
+    let latest = Utc::now();
+
+    // Before any snapshot, the first journal is replayed from scratch
+    assert_eq!(
+        titles(&snapshot::rebuild(data_dir, before_snapshot).unwrap()),
+        ["First"]
+    );
+    // Between snapshots, the archived journal fills the gap
+    assert_eq!(
+        titles(&snapshot::rebuild(data_dir, after_second).unwrap()),
+        ["First", "Second"]
+    );
+    assert_eq!(
+        titles(&snapshot::rebuild(data_dir, latest).unwrap()),
+        ["First, renamed"]
+    );
+
+    // Restoring makes the old state current and keeps it across a restart
+    snapshot::restore(data_dir, after_second, 5).unwrap();
+    assert_eq!(
+        titles(&journal::open_store(data_dir).unwrap()),
+        ["First", "Second"]
+    );
+    // and the state it replaced can still be restored
+    assert_eq!(
+        titles(&snapshot::rebuild(data_dir, latest).unwrap()),
+        ["First, renamed"]
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_restore_command() {
+    let dir = data_dir("snapshot-cli");
+    fs::create_dir_all(&dir).unwrap();
+    let store_dir = dir.join("store");
+    let data_dir = store_dir.to_str().unwrap();
+    let config = dir.join("config.json");
+    fs::write(
+        &config,
+        serde_json::json!({ "data_dir": data_dir }).to_string(),
+    )
+    .unwrap();
+
+    let mut store = journal::open_store(data_dir).unwrap();
+    store.insert(todo(1, "Keep me"));
+    tick();
+    let at = Utc::now().to_rfc3339();
+    tick();
+    store.insert(todo(2, "Undo me"));
+    drop(store);
+
+    let binary = env!("CARGO_BIN_EXE_naked-rust-api");
+    let config = config.to_str().unwrap();
+    let restore = Command::new(binary)
+        .args(["restore", "--config", config, "--at", &at])
+        .output()
+        .unwrap();
+    assert!(restore.status.success());
+    assert!(String::from_utf8_lossy(&restore.stdout).contains("Restored 1 todos"));
+    assert_eq!(titles(&journal::open_store(data_dir).unwrap()), ["Keep me"]);
+
+    let invalid = Command::new(binary)
+        .args(["restore", "--config", config, "--at", "yesterday"])
+        .output()
+        .unwrap();
+    assert!(!invalid.status.success());
+    assert!(String::from_utf8_lossy(&invalid.stderr).contains("Invalid time"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_failed_journal_write_is_undone() {
+    let mut store = Store::new();
+    store.insert(todo(1, "Kept"));
+    // Every write to this journal fails for want of space
+    let journal = journal::Journal::open(Path::new("/dev/full")).unwrap();
+    let mut store = store.with_journal(Arc::new(journal));
+    let audited = store.audit_log().events().len();
+
+    let failed = store.transaction(|store| store.remove("alice", 1).is_some());
+    assert!(failed.unwrap_err().contains("Failed to write to journal"));
+    assert_eq!(titles(&store), ["Kept"]);
+
+    // A batch is undone as a whole
+    store.begin_batch();
+    store.update("alice", 1, |todo| todo.title = "Renamed".to_string());
+    store.insert(todo(2, "Lost"));
+    assert!(store.commit_batch().is_err());
+    assert_eq!(titles(&store), ["Kept"]);
+    assert_eq!(store.audit_log().events().len(), audited);
+}
@@ -0,0 +1,191 @@
+#![cfg(feature = "sqlite")]
+
+mod common;
+
+use common::{body, send, spawn_server, with_body};
+use naked_rust_api::journal::Change;
+use naked_rust_api::sqlite::Database;
+use naked_rust_api::{DEFAULT_USER, Db, Store, Todo, transfer};
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+fn database_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("{}-{}.db", name, std::process::id()));
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+    path
+}
+
+fn todo(id: usize, title: &str) -> Todo {
+    Todo {
+        id,
+        owner: "alice".to_string(),
+        title: title.to_string(),
+        completed: false,
+        deleted_at: None,
+    }
+}
+
+// Every todo, trash included, as the export sees them
+fn contents(store: &Store) -> Value {
+    serde_json::to_value(transfer::export(store)).unwrap()
+}
+
+// Drives one store through the API and returns what every response said
+fn exercise(port: u16, db: Db) -> Vec<String> {
+    spawn_server(port, db);
+    let requests = [
+        ("POST", "/todos", r#"{"title":"Write the report"}"#),
+        ("POST", "/todos", r#"{"title":"Review the report"}"#),
+        ("POST", "/todos", r#"{"title":"Send the report"}"#),
+        (
+            "PUT",
+            "/todos/1",
+            r#"{"title":"Write the report","completed":true}"#,
+        ),
+        ("DELETE", "/todos/2", ""),
+        ("DELETE", "/todos/3", ""),
+        ("POST", "/todos/3/restore", ""),
+        ("POST", "/admin/purge", ""),
+        // Rolled back, so neither backend may keep any of it
+        (
+            "POST",
+            "/todos/bulk",
+            r#"{"atomic":true,"operations":[{"op":"create","title":"Ghost"},{"op":"delete","id":99}]}"#,
+        ),
+        (
+            "POST",
+            "/todos/bulk",
+            r#"{"operations":[{"op":"create","title":"Archive the report"},{"op":"update","id":3,"completed":true}]}"#,
+        ),
+        ("POST", "/todos", r#"{"title":"Plan the next one"}"#),
+        // Listings are left out: their order is not defined
+        ("GET", "/todos/3", ""),
+        ("GET", "/todos/2", ""),
+    ];
+    requests
+        .iter()
+        .map(|(method, path, request_body)| {
+            let response = send(port, &with_body(method, path, request_body));
+            let status = response.lines().next().unwrap_or("").to_string();
+            format!("{} {} -> {} {}", method, path, status, body(&response))
+        })
+        .collect()
+}
+
+#[test]
+fn test_both_backends_behave_the_same() {
+    let memory: Db = Arc::new(Mutex::new(Store::new()));
+    let path = database_path("sqlite-same");
+    let path = path.to_str().unwrap();
+    let store = Database::open(path).and_then(Database::open_store).unwrap();
+    let sqlite: Db = Arc::new(Mutex::new(store));
+
+    let expected = exercise(8270, Arc::clone(&memory));
+    let actual = exercise(8271, Arc::clone(&sqlite));
+    assert_eq!(actual, expected);
+    assert_eq!(
+        contents(&sqlite.lock().unwrap()),
+        contents(&memory.lock().unwrap())
+    );
+
+    // What was written reloads to the same state, ids included
+    let mut reloaded = Database::open(path).unwrap().load().unwrap();
+    assert_eq!(contents(&reloaded), contents(&memory.lock().unwrap()));
+    assert_eq!(reloaded.next_id("default"), 6);
+    assert_eq!(
+        reloaded.audit_log().events(),
+        sqlite.lock().unwrap().audit_log().events()
+    );
+}
+
+#[test]
+fn test_migrations_run_once() {
+    let path = database_path("sqlite-migrations");
+    let path = path.to_str().unwrap();
+    let database = Database::open(path).unwrap();
+    assert_eq!(database.schema_version().unwrap(), 2);
+    database
+        .write(&[Change::Put {
+            todo: todo(1, "Survives reopening"),
+        }])
+        .unwrap();
+    drop(database);
+
+    // Opening again leaves the schema and the data alone
+    let database = Database::open(path).unwrap();
+    assert_eq!(database.schema_version().unwrap(), 2);
+    let store = database.load().unwrap();
+    assert_eq!(store.get("alice", 1).unwrap().title, "Survives reopening");
+    drop(database);
+
+    // A database from a newer build is refused rather than misread
+    let connection = rusqlite::Connection::open(path).unwrap();
+    connection.pragma_update(None, "user_version", 99).unwrap();
+    drop(connection);
+    let error = Database::open(path).err().unwrap();
+    assert!(error.contains("schema version 99 is newer"));
+}
+
+#[test]
+fn test_failed_write_fails_the_request() {
+    let path = database_path("sqlite-failure");
+    let path = path.to_str().unwrap();
+    let store = Database::open(path).and_then(Database::open_store).unwrap();
+    let db: Db = Arc::new(Mutex::new(store));
+    spawn_server(8272, Arc::clone(&db));
+    let response = send(8272, &with_body("POST", "/todos", r#"{"title":"Stored"}"#));
+    assert!(response.contains("201 Created"));
+    let audited = db.lock().unwrap().audit_log().events().len();
+
+    // Every write fails from here on
+    let connection = rusqlite::Connection::open(path).unwrap();
+    connection.execute_batch("DROP TABLE todos").unwrap();
+
+    let response = send(8272, &with_body("POST", "/todos", r#"{"title":"Lost"}"#));
+    assert!(
+        response.contains("500 Internal Server Error"),
+        "{}",
+        response
+    );
+    let response = send(
+        8272,
+        &with_body("PUT", "/todos/1", r#"{"title":"Renamed","completed":true}"#),
+    );
+    assert!(
+        response.contains("500 Internal Server Error"),
+        "{}",
+        response
+    );
+    let response = send(8272, "DELETE /todos/1 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(
+        response.contains("500 Internal Server Error"),
+        "{}",
+        response
+    );
+    // Each operation of a bulk request fails on its own, unless it is atomic
+    let operations = r#"[{"op":"create","title":"Lost too"}]"#;
+    let request = with_body(
+        "POST",
+        "/todos/bulk",
+        &format!(r#"{{"operations":{}}}"#, operations),
+    );
+    let response = send(8272, &request);
+    assert!(response.contains("\"status\":500"), "{}", response);
+    let request = with_body(
+        "POST",
+        "/todos/bulk",
+        &format!(r#"{{"atomic":true,"operations":{}}}"#, operations),
+    );
+    assert!(send(8272, &request).contains("500 Internal Server Error"));
+
+    // Nothing that failed to be stored is left in memory, nor in the audit log
+    let db = db.lock().unwrap();
+    assert_eq!(db.len(), 1);
+    assert_eq!(db.get(DEFAULT_USER, 1).unwrap().title, "Stored");
+    assert!(db.get(DEFAULT_USER, 1).unwrap().deleted_at.is_none());
+    assert_eq!(db.audit_log().events().len(), audited);
+}
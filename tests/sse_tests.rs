@@ -0,0 +1,178 @@
+mod common;
+
+use common::{send_as, spawn_server};
+use naked_rust_api::{Db, Store};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+struct Subscription {
+    reader: BufReader<TcpStream>,
+    head: String,
+}
+
+fn subscribe(port: u16, user: &str, last_event_id: Option<&str>) -> Subscription {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("Failed to connect to server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let last_event_id = last_event_id
+        .map(|id| format!("Last-Event-ID: {}\r\n", id))
+        .unwrap_or_default();
+    let request = format!(
+        "GET /todos/events HTTP/1.1\r\nHost: localhost\r\nX-User-Id: {}\r\nAccept: text/event-stream\r\n{}\r\n",
+        user, last_event_id
+    );
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut reader = BufReader::new(stream);
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        head.push_str(&line);
+        if line == "\r\n" || line.is_empty() {
+            break;
+        }
+    }
+    Subscription { reader, head }
+}
+
+impl Subscription {
+    // The fields of the next event, skipping comments and the retry hint
+    fn next_event(&mut self) -> HashMap<String, String> {
+        loop {
+            let mut fields = HashMap::new();
+            loop {
+                let mut line = String::new();
+                self.reader
+                    .read_line(&mut line)
+                    .expect("No event arrived in time");
+                let line = line.trim_end_matches('\n');
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(": ") {
+                    fields.insert(name.to_string(), value.to_string());
+                }
+            }
+            if fields.contains_key("event") {
+                return fields;
+            }
+        }
+    }
+}
+
+fn data(event: &HashMap<String, String>) -> Value {
+    serde_json::from_str(&event["data"]).unwrap()
+}
+
+#[test]
+fn test_stream_delivers_own_changes() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8290, Arc::clone(&db));
+
+    let mut alice = subscribe(8290, "alice", None);
+    assert!(alice.head.starts_with("HTTP/1.1 200 OK"));
+    assert!(alice.head.contains("Content-Type: text/event-stream"));
+    assert!(alice.head.contains("Cache-Control: no-cache"));
+    assert!(!alice.head.contains("Content-Length"));
+
+    send_as(8290, "alice", "POST", "/todos", r#"{"title":"Watched"}"#);
+    send_as(8290, "bob", "POST", "/todos", r#"{"title":"Not alice's"}"#);
+    // Rolled back, so never published
+    send_as(
+        8290,
+        "alice",
+        "POST",
+        "/todos/bulk",
+        r#"{"atomic":true,"operations":[{"op":"create","title":"Ghost"},{"op":"delete","id":99}]}"#,
+    );
+    send_as(
+        8290,
+        "alice",
+        "PUT",
+        "/todos/1",
+        r#"{"title":"Watched","completed":true}"#,
+    );
+    send_as(8290, "alice", "DELETE", "/todos/1", "");
+
+    let created = alice.next_event();
+    assert_eq!(created["event"], "created");
+    assert_eq!(created["id"], "1");
+    let body = data(&created);
+    assert_eq!(body["todo"]["title"], "Watched");
+    assert_eq!(body["actor"], "alice");
+
+    // Bob's change takes sequence number 2 but isn't shown to alice
+    let updated = alice.next_event();
+    assert_eq!(updated["event"], "updated");
+    assert_eq!(updated["id"], "3");
+    assert_eq!(
+        data(&updated)["changes"],
+        json!({ "completed": { "before": false, "after": true } })
+    );
+    assert_eq!(data(&updated)["todo"]["completed"], true);
+
+    let deleted = alice.next_event();
+    assert_eq!(deleted["event"], "deleted");
+    assert!(data(&deleted)["todo"]["deleted_at"].is_string());
+}
+
+#[test]
+fn test_reconnect_replays_missed_events() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8291, Arc::clone(&db));
+
+    for title in ["One", "Two", "Three"] {
+        let body = format!(r#"{{"title":"{}"}}"#, title);
+        send_as(8291, "alice", "POST", "/todos", &body);
+    }
+
+    let mut alice = subscribe(8291, "alice", Some("1"));
+    assert_eq!(alice.next_event()["id"], "2");
+    let third = alice.next_event();
+    assert_eq!(third["id"], "3");
+    assert_eq!(data(&third)["todo"]["title"], "Three");
+
+    // Then it carries on live
+    send_as(8291, "alice", "POST", "/todos", r#"{"title":"Four"}"#);
+    assert_eq!(alice.next_event()["id"], "4");
+
+    let mut stream = TcpStream::connect(("127.0.0.1", 8291)).unwrap();
+    stream
+        .write_all(b"GET /todos/events HTTP/1.1\r\nHost: localhost\r\nLast-Event-ID: soon\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+}
+
+#[test]
+fn test_reset_when_replay_buffer_is_exceeded() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8292, Arc::clone(&db));
+
+    let operations: Vec<Value> = (0..1100)
+        .map(|n| json!({ "op": "create", "title": format!("Todo {}", n) }))
+        .collect();
+    let body = json!({ "operations": operations }).to_string();
+    send_as(8292, "alice", "POST", "/todos/bulk", &body);
+
+    // The first events have been dropped from the buffer
+    let mut alice = subscribe(8292, "alice", Some("5"));
+    let reset = alice.next_event();
+    assert_eq!(reset["event"], "reset");
+    assert_eq!(reset["id"], "1100");
+    assert!(data(&reset)["error"].as_str().unwrap().contains("reload"));
+
+    send_as(8292, "alice", "POST", "/todos", r#"{"title":"After"}"#);
+    assert_eq!(alice.next_event()["id"], "1101");
+
+    // Within the buffer, nothing is lost
+    let mut alice = subscribe(8292, "alice", Some("1099"));
+    assert_eq!(alice.next_event()["id"], "1100");
+}
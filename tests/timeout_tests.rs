@@ -0,0 +1,174 @@
+mod common;
+
+use common::{empty_db, spawn_concurrent_server};
+use naked_rust_api::Config;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn read_response(stream: &mut TcpStream) -> String {
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .expect("Failed to read from stream");
+    response
+}
+
+#[test]
+fn test_header_timeout() {
+    let config = Config {
+        header_timeout_secs: 1,
+        ..Config::default()
+    };
+    spawn_concurrent_server(8210, empty_db(), config);
+
+    let started = Instant::now();
+    let mut stream = TcpStream::connect(("127.0.0.1", 8210)).expect("Failed to connect to server");
+    stream
+        .write_all(b"GET /todos HTTP/1.1\r\nHost: localhost\r\nX-User-Id: slow")
+        .expect("Failed to write to stream");
+    let response = read_response(&mut stream);
+
+    assert!(response.contains("408 Request Timeout"));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_read_timeout() {
+    let config = Config {
+        read_timeout_secs: 1,
+        ..Config::default()
+    };
+    spawn_concurrent_server(8211, empty_db(), config);
+
+    let started = Instant::now();
+    let mut stream = TcpStream::connect(("127.0.0.1", 8211)).expect("Failed to connect to server");
+    let response = read_response(&mut stream);
+
+    assert!(response.contains("408 Request Timeout"));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_request_deadline() {
+    let config = Config {
+        read_timeout_secs: 5,
+        request_deadline_secs: 1,
+        ..Config::default()
+    };
+    spawn_concurrent_server(8212, empty_db(), config);
+
+    let started = Instant::now();
+    let mut stream = TcpStream::connect(("127.0.0.1", 8212)).expect("Failed to connect to server");
+    stream
+        .write_all(b"POST /todos HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100\r\n\r\n")
+        .expect("Failed to write to stream");
+
+    // Each byte arrives well within the read timeout, but the whole body never
+    // does within the deadline
+    let mut writer = stream.try_clone().unwrap();
+    thread::spawn(move || {
+        for _ in 0..20 {
+            thread::sleep(Duration::from_millis(200));
+            if writer.write_all(b"x").is_err() {
+                break;
+            }
+        }
+    });
+    let response = read_response(&mut stream);
+
+    assert!(response.contains("408 Request Timeout"));
+    assert!(started.elapsed() < Duration::from_secs(4));
+}
+
+#[test]
+fn test_max_connections_per_ip() {
+    let config = Config {
+        read_timeout_secs: 2,
+        max_connections_per_ip: Some(1),
+        ..Config::default()
+    };
+    spawn_concurrent_server(8213, empty_db(), config);
+
+    // An idle connection holds the only slot until it times out
+    let mut idle = TcpStream::connect(("127.0.0.1", 8213)).expect("Failed to connect to server");
+    thread::sleep(Duration::from_millis(300));
+
+    let mut rejected =
+        TcpStream::connect(("127.0.0.1", 8213)).expect("Failed to connect to server");
+    rejected
+        .write_all(b"GET /todos HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .expect("Failed to write to stream");
+    let response = read_response(&mut rejected);
+    assert!(response.contains("503 Service Unavailable"));
+    assert!(response.contains("Too many connections."));
+
+    assert!(read_response(&mut idle).contains("408 Request Timeout"));
+
+    let mut admitted =
+        TcpStream::connect(("127.0.0.1", 8213)).expect("Failed to connect to server");
+    admitted
+        .write_all(b"GET /todos HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .expect("Failed to write to stream");
+    assert!(read_response(&mut admitted).contains("200 OK"));
+}
+
+#[test]
+fn test_max_takeovers() {
+    let config = Config {
+        max_takeovers: 2,
+        ..Config::default()
+    };
+    spawn_concurrent_server(8340, empty_db(), config);
+
+    // Two waiting long polls take both threads
+    let mut waiting: Vec<TcpStream> = (0..2)
+        .map(|_| {
+            let mut stream =
+                TcpStream::connect(("127.0.0.1", 8340)).expect("Failed to connect to server");
+            stream
+                .write_all(b"GET /todos/changes?wait=1 HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .expect("Failed to write to stream");
+            stream
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(300));
+
+    // Whatever kind of takeover comes next is turned away
+    for request in [
+        "GET /todos/changes?wait=1 HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        "GET /todos/events HTTP/1.1\r\nHost: localhost\r\nAccept: text/event-stream\r\n\r\n",
+    ] {
+        let mut rejected =
+            TcpStream::connect(("127.0.0.1", 8340)).expect("Failed to connect to server");
+        rejected
+            .write_all(request.as_bytes())
+            .expect("Failed to write to stream");
+        let response = read_response(&mut rejected);
+        assert!(response.contains("503 Service Unavailable"), "{}", response);
+        assert!(response.contains("Retry-After: 1"));
+        assert!(response.contains("Too many long-lived connections."));
+    }
+
+    // Requests answered right away are still served
+    let mut stream = TcpStream::connect(("127.0.0.1", 8340)).expect("Failed to connect to server");
+    stream
+        .write_all(b"GET /todos HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .expect("Failed to write to stream");
+    assert!(read_response(&mut stream).contains("200 OK"));
+
+    // Slots are given back once the polls are answered
+    for stream in &mut waiting {
+        assert!(read_response(stream).contains("200 OK"));
+    }
+    thread::sleep(Duration::from_millis(100));
+    let mut admitted =
+        TcpStream::connect(("127.0.0.1", 8340)).expect("Failed to connect to server");
+    admitted
+        .write_all(b"GET /todos/changes?wait=1 HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .expect("Failed to write to stream");
+    let response = read_response(&mut admitted);
+    assert!(response.contains("200 OK"), "{}", response);
+    assert!(response.contains("\"changes\":[]"));
+}
@@ -0,0 +1,166 @@
+#![cfg(feature = "tls")]
+
+use naked_rust_api::{AppState, Config, Db, Store, handle_connection_with_state, tls};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Writes a fresh self-signed certificate for localhost and returns the PEM
+// paths along with the DER certificate clients should trust
+fn self_signed(name: &str) -> (PathBuf, PathBuf, Vec<u8>) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = std::env::temp_dir();
+    let cert_path = dir.join(format!("{}-{}-cert.pem", name, std::process::id()));
+    let key_path = dir.join(format!("{}-{}-key.pem", name, std::process::id()));
+    fs::write(&cert_path, certified.cert.pem()).unwrap();
+    fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+    (cert_path, key_path, certified.cert.der().to_vec())
+}
+
+fn spawn_server(port: u16, cert_path: &Path, key_path: &Path) {
+    let config =
+        tls::load_server_config(&cert_path.to_string_lossy(), &key_path.to_string_lossy()).unwrap();
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .unwrap_or_else(|_| panic!("Failed to bind to port {}", port));
+    let state = Arc::new(AppState::new(db, Config::default()));
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let stream = tls::accept(stream, &config).unwrap();
+                    handle_connection_with_state(stream, &state);
+                }
+                Err(e) => {
+                    eprintln!("Connection failed: {}", e);
+                }
+            }
+        }
+    });
+}
+
+fn connect_tls(port: u16, trusted: &[u8]) -> StreamOwned<ClientConnection, TcpStream> {
+    let mut roots = RootCertStore::empty();
+    roots.add(trusted.to_vec().into()).unwrap();
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    let connection =
+        ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap())
+            .unwrap();
+    let socket = TcpStream::connect(("127.0.0.1", port)).expect("Failed to connect to server");
+    StreamOwned::new(connection, socket)
+}
+
+fn send_tls(port: u16, trusted: &[u8], request: &str) -> String {
+    let mut stream = connect_tls(port, trusted);
+    stream
+        .write_all(request.as_bytes())
+        .expect("Failed to write to stream");
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .expect("Failed to read from stream");
+    response
+}
+
+#[test]
+fn test_tls_round_trip() {
+    let (cert_path, key_path, cert_der) = self_signed("tls-round-trip");
+    spawn_server(8190, &cert_path, &key_path);
+
+    let request_body = r#"{"title":"Encrypted"}"#;
+    let created = send_tls(
+        8190,
+        &cert_der,
+        &format!(
+            "POST /todos HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+            request_body.len(),
+            request_body
+        ),
+    );
+    assert!(created.contains("201 Created"));
+
+    let fetched = send_tls(
+        8190,
+        &cert_der,
+        "GET /todos/1 HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    );
+    assert!(fetched.contains("200 OK"));
+    assert!(fetched.contains("\"title\":\"Encrypted\""));
+}
+
+#[test]
+fn test_tls_rejects_plaintext() {
+    let (cert_path, key_path, _) = self_signed("tls-plaintext");
+    spawn_server(8191, &cert_path, &key_path);
+
+    let mut stream = TcpStream::connect(("127.0.0.1", 8191)).expect("Failed to connect to server");
+    stream
+        .write_all(b"GET /todos HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .expect("Failed to write to stream");
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+
+    assert!(!String::from_utf8_lossy(&response).contains("200 OK"));
+}
+
+#[test]
+fn test_tls_invalid_key_file() {
+    let (cert_path, _, _) = self_signed("tls-invalid-key");
+    let key_path = std::env::temp_dir().join(format!("tls-bad-key-{}.pem", std::process::id()));
+    fs::write(&key_path, "not a key").unwrap();
+
+    let result = tls::load_server_config(&cert_path.to_string_lossy(), &key_path.to_string_lossy());
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_tls_websocket() {
+    let (cert_path, key_path, cert_der) = self_signed("tls-websocket");
+    spawn_server(8192, &cert_path, &key_path);
+
+    let mut stream = connect_tls(8192, &cert_der);
+    stream
+        .sock
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let request = "GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    assert!(head.starts_with(b"HTTP/1.1 101 Switching Protocols"));
+
+    // Two messages in one write arrive in the same TLS record, and the second
+    // is answered without waiting for more input
+    let mut frames = Vec::new();
+    for id in [1, 2] {
+        let message = format!(r#"{{"type":"get","id":{}}}"#, id);
+        let mask = [1, 2, 3, 4];
+        frames.extend_from_slice(&[0x81, 0x80 | message.len() as u8]);
+        frames.extend_from_slice(&mask);
+        frames.extend(message.bytes().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    }
+    stream.write_all(&frames).unwrap();
+    for _ in 0..2 {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).unwrap();
+        let mut payload = vec![0; (head[1] & 0x7F) as usize];
+        stream.read_exact(&mut payload).unwrap();
+        assert!(String::from_utf8_lossy(&payload).contains(r#""status":404"#));
+    }
+}
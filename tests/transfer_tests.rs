@@ -0,0 +1,297 @@
+mod common;
+
+use common::{body, send_with, spawn_server};
+use naked_rust_api::{Db, Store, Todo, journal};
+use serde_json::Value;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+fn todo(owner: &str, id: usize, title: &str, deleted: bool) -> Todo {
+    Todo {
+        id,
+        owner: owner.to_string(),
+        title: title.to_string(),
+        completed: false,
+        deleted_at: deleted.then(chrono::Utc::now),
+    }
+}
+
+fn data_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn test_export_and_import() {
+    let source: Db = Arc::new(Mutex::new(Store::new()));
+    for todo in [
+        todo("bob", 1, "Bob's todo", false),
+        todo("alice", 2, "Trashed", true),
+        todo("alice", 1, "Alice's todo", false),
+    ] {
+        source.lock().unwrap().insert(todo);
+    }
+    spawn_server(8260, source);
+
+    let export = send_with(8260, "GET", "/admin/export", "", "");
+    assert!(export.starts_with("HTTP/1.1 200 OK"));
+    assert!(export.contains("Content-Type: application/x-ndjson\r\n"));
+    let lines: Vec<Value> = body(&export)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let keys: Vec<(&str, u64)> = lines
+        .iter()
+        .map(|todo| {
+            (
+                todo["owner"].as_str().unwrap(),
+                todo["id"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(keys, [("alice", 1), ("alice", 2), ("bob", 1)]);
+    assert!(lines[1]["deleted_at"].is_string());
+
+    // The export can still be negotiated as a plain JSON array
+    let array = send_with(
+        8260,
+        "GET",
+        "/admin/export",
+        "Accept: application/json\r\n",
+        "",
+    );
+    assert_eq!(
+        serde_json::from_str::<Value>(body(&array)).unwrap(),
+        Value::Array(lines)
+    );
+
+    let target: Db = Arc::new(Mutex::new(Store::new()));
+    target
+        .lock()
+        .unwrap()
+        .insert(todo("alice", 1, "Already here", false));
+    spawn_server(8261, Arc::clone(&target));
+    let lines = body(&export);
+
+    // By default a clash rejects the whole import
+    let response = send_with(8261, "POST", "/admin/import", "", lines);
+    assert!(response.starts_with("HTTP/1.1 409 Conflict"));
+    assert!(body(&response).contains("todo 1 of user 'alice' already exists"));
+    assert_eq!(target.lock().unwrap().len(), 1);
+
+    let response = send_with(8261, "POST", "/admin/import?on_conflict=skip", "", lines);
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    let summary: Value = serde_json::from_str(body(&response)).unwrap();
+    assert_eq!(summary["imported"], 2);
+    assert_eq!(summary["skipped"], 1);
+    assert_eq!(
+        target.lock().unwrap().get("alice", 1).unwrap().title,
+        "Already here"
+    );
+
+    let response = send_with(
+        8261,
+        "POST",
+        "/admin/import?on_conflict=renumber",
+        "",
+        lines,
+    );
+    let summary: Value = serde_json::from_str(body(&response)).unwrap();
+    assert_eq!(summary["renumbered"], 3);
+    assert_eq!(target.lock().unwrap().len(), 6);
+    assert_eq!(
+        target.lock().unwrap().get("alice", 3).unwrap().title,
+        "Alice's todo"
+    );
+
+    let response = send_with(8261, "POST", "/admin/import?mode=replace", "", lines);
+    let summary: Value = serde_json::from_str(body(&response)).unwrap();
+    assert_eq!(summary["removed"], 6);
+    assert_eq!(summary["imported"], 3);
+    let db = target.lock().unwrap();
+    assert_eq!(db.len(), 3);
+    assert_eq!(db.get("alice", 1).unwrap().title, "Alice's todo");
+    assert!(db.get("alice", 2).unwrap().is_deleted());
+    drop(db);
+
+    // Other formats work too, named by Content-Type
+    let response = send_with(
+        8261,
+        "POST",
+        "/admin/import?on_conflict=overwrite",
+        "Content-Type: application/json\r\n",
+        r#"[{"id":1,"owner":"bob","title":"Overwritten","completed":true}]"#,
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(
+        target.lock().unwrap().get("bob", 1).unwrap().title,
+        "Overwritten"
+    );
+
+    let response = send_with(
+        8261,
+        "POST",
+        "/admin/import",
+        "",
+        "{\"id\":9,\"owner\":\"carol\",\"title\":\"Fine\",\"completed\":false}\n{\"id\":9}\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    assert!(body(&response).contains("Record 2"));
+    let response = send_with(8261, "POST", "/admin/import?mode=append", "", lines);
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    assert!(target.lock().unwrap().get("carol", 9).is_none());
+}
+
+#[test]
+fn test_large_exports_are_streamed() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8353, Arc::clone(&db));
+
+    // Nothing to export is still a valid document
+    let empty = send_with(
+        8353,
+        "GET",
+        "/admin/export",
+        "Accept: application/json\r\n",
+        "",
+    );
+    assert_eq!(body(&empty), "[]");
+    assert_eq!(body(&send_with(8353, "GET", "/admin/export", "", "")), "");
+
+    for (owner, count) in [("alice", 700), ("bob", 534)] {
+        for id in 1..=count {
+            let title = format!("{} {}", owner, id);
+            db.lock().unwrap().insert(todo(owner, id, &title, false));
+        }
+    }
+
+    // Written as it is read, so the length isn't known up front
+    let export = send_with(8353, "GET", "/admin/export", "", "");
+    assert!(export.starts_with("HTTP/1.1 200 OK"));
+    assert!(!export.contains("Content-Length"));
+    let lines: Vec<Value> = body(&export)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 1234);
+    assert_eq!(lines[699]["title"], "alice 700");
+    assert_eq!(lines[700]["title"], "bob 1");
+
+    let array = send_with(
+        8353,
+        "GET",
+        "/admin/export",
+        "Accept: application/json\r\n",
+        "",
+    );
+    assert_eq!(
+        serde_json::from_str::<Value>(body(&array)).unwrap(),
+        Value::Array(lines)
+    );
+}
+#[test]
+fn test_journal_persistence() {
+    let dir = data_dir("journal");
+    let data_dir = dir.to_str().unwrap();
+    let db: Db = Arc::new(Mutex::new(journal::open_store(data_dir).unwrap()));
+    spawn_server(8262, Arc::clone(&db));
+
+    send_with(8262, "POST", "/todos", "", r#"{"title":"Kept"}"#);
+    send_with(8262, "POST", "/todos", "", r#"{"title":"Renamed"}"#);
+    send_with(8262, "POST", "/todos", "", r#"{"title":"Deleted"}"#);
+    send_with(
+        8262,
+        "PUT",
+        "/todos/2",
+        "",
+        r#"{"title":"Renamed twice","completed":true}"#,
+    );
+    send_with(8262, "DELETE", "/todos/3", "", "");
+    // A rolled back batch leaves no trace in the journal
+    let bulk =
+        r#"{"atomic":true,"operations":[{"op":"create","title":"Ghost"},{"op":"delete","id":99}]}"#;
+    let response = send_with(8262, "POST", "/todos/bulk", "", bulk);
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    send_with(8262, "POST", "/admin/purge", "", "");
+
+    let reopened = journal::open_store(data_dir).unwrap();
+    let mut titles: Vec<&str> = reopened.values().map(|todo| todo.title.as_str()).collect();
+    titles.sort();
+    assert_eq!(titles, ["Kept", "Renamed twice"]);
+    // Ids are not reused after a restart
+    let mut reopened = reopened;
+    assert_eq!(reopened.next_id("default"), 4);
+
+    // A line torn by a crash is dropped and later writes still land
+    let path = journal::journal_path(data_dir);
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"{\"at\":\"2026-01-01T00:00:00Z\",\"op\":\"pu")
+        .unwrap();
+    let mut store = journal::open_store(data_dir).unwrap();
+    store.insert(todo("default", 7, "After the crash", false));
+    let store = journal::open_store(data_dir).unwrap();
+    assert_eq!(store.len(), 3);
+    assert_eq!(store.get("default", 7).unwrap().title, "After the crash");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_export_and_import_commands() {
+    let dir = data_dir("transfer-cli");
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.json");
+    let store = dir.join("store");
+    fs::write(
+        &config,
+        serde_json::json!({ "data_dir": store.to_str().unwrap() }).to_string(),
+    )
+    .unwrap();
+    let input = dir.join("backup.jsonl");
+    fs::write(
+        &input,
+        "{\"id\":1,\"owner\":\"alice\",\"title\":\"From a backup\",\"completed\":false}\n\
+        {\"id\":4,\"owner\":\"bob\",\"title\":\"Also restored\",\"completed\":true}\n",
+    )
+    .unwrap();
+
+    let binary = env!("CARGO_BIN_EXE_naked-rust-api");
+    let config = config.to_str().unwrap();
+    let import = Command::new(binary)
+        .args(["import", "--config", config, "--input"])
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert!(import.status.success());
+    let summary: Value = serde_json::from_slice(&import.stdout).unwrap();
+    assert_eq!(summary["imported"], 2);
+
+    // Importing the same file again clashes unless told what to do
+    let again = Command::new(binary)
+        .args(["import", "--config", config, "--input"])
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert!(!again.status.success());
+    assert!(String::from_utf8_lossy(&again.stderr).contains("already exists"));
+
+    let export = Command::new(binary)
+        .args(["export", "--config", config])
+        .output()
+        .unwrap();
+    assert!(export.status.success());
+    assert_eq!(
+        String::from_utf8(export.stdout).unwrap(),
+        fs::read_to_string(&input).unwrap()
+    );
+
+    let missing = Command::new(binary).arg("export").output().unwrap();
+    assert!(!missing.status.success());
+    assert!(String::from_utf8_lossy(&missing.stderr).contains("data_dir"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
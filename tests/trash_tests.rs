@@ -0,0 +1,107 @@
+mod common;
+
+use chrono::{Duration as ChronoDuration, Utc};
+use common::{backends, send, spawn_server, stores};
+use naked_rust_api::{DEFAULT_USER, Db, Todo, purge_deleted_todos};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn seed(db: &Db) {
+    let todo = Todo {
+        id: 1,
+        owner: DEFAULT_USER.to_string(),
+        title: "Take out trash".to_string(),
+        completed: false,
+        deleted_at: None,
+    };
+    db.lock().unwrap().insert(todo);
+}
+
+#[test]
+fn test_deleted_todo_moves_to_trash() {
+    for (port, db) in backends(8120) {
+        seed(&db);
+        spawn_server(port, Arc::clone(&db));
+
+        let response = send(port, "DELETE /todos/1 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.contains("Todo has been deleted."));
+
+        let response = send(port, "GET /todos HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(!response.contains("Take out trash"));
+
+        let response = send(port, "GET /todos/1 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.contains("404 Not Found"));
+
+        let response = send(
+            port,
+            "GET /todos?deleted=true HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert!(response.contains("Take out trash"));
+        assert!(response.contains("\"deleted_at\""));
+
+        let response = send(port, "DELETE /todos/1 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.contains("404 Not Found"));
+    }
+}
+
+#[test]
+fn test_restore_deleted_todo() {
+    for (port, db) in backends(8121) {
+        seed(&db);
+        spawn_server(port, Arc::clone(&db));
+
+        let response = send(
+            port,
+            "POST /todos/1/restore HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert!(response.contains("409 Conflict"));
+
+        send(port, "DELETE /todos/1 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let response = send(
+            port,
+            "POST /todos/1/restore HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert!(response.contains("200 OK"));
+        assert!(!response.contains("deleted_at"));
+
+        let response = send(port, "GET /todos/1 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.contains("200 OK"));
+
+        let response = send(
+            port,
+            "POST /todos/7/restore HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert!(response.contains("404 Not Found"));
+    }
+}
+
+#[test]
+fn test_purge_respects_retention() {
+    for db in stores() {
+        seed(&db);
+        let old = Todo {
+            id: 2,
+            owner: DEFAULT_USER.to_string(),
+            title: "Long gone".to_string(),
+            completed: false,
+            deleted_at: Some(Utc::now() - ChronoDuration::days(40)),
+        };
+        let recent = Todo {
+            id: 3,
+            owner: DEFAULT_USER.to_string(),
+            title: "Just deleted".to_string(),
+            completed: false,
+            deleted_at: Some(Utc::now()),
+        };
+        db.lock().unwrap().insert(old);
+        db.lock().unwrap().insert(recent);
+
+        let purged = purge_deleted_todos(&db, Duration::from_secs(30 * 24 * 60 * 60));
+
+        assert_eq!(purged, 1);
+        let db = db.lock().unwrap();
+        assert!(db.get(DEFAULT_USER, 1).is_some());
+        assert!(db.get(DEFAULT_USER, 2).is_none());
+        assert!(db.get(DEFAULT_USER, 3).is_some());
+    }
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use common::{send_as, spawn_server_with};
+use naked_rust_api::{Config, Db, Store};
+use std::sync::{Arc, Mutex};
+
+#[test]
+fn test_todos_are_scoped_to_their_owner() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server_with(8140, Arc::clone(&db), Config::default());
+
+    let response = send_as(
+        8140,
+        "alice",
+        "POST",
+        "/todos",
+        r#"{"title":"Alice's todo"}"#,
+    );
+    assert!(response.contains("\"id\":1"));
+    assert!(response.contains("\"owner\":\"alice\""));
+
+    let response = send_as(8140, "bob", "GET", "/todos/1", "");
+    assert!(response.contains("404 Not Found"));
+
+    let response = send_as(8140, "bob", "PUT", "/todos/1", r#"{"completed":true}"#);
+    assert!(response.contains("404 Not Found"));
+
+    let response = send_as(8140, "bob", "DELETE", "/todos/1", "");
+    assert!(response.contains("404 Not Found"));
+
+    let response = send_as(8140, "bob", "POST", "/todos", r#"{"title":"Bob's todo"}"#);
+    assert!(response.contains("\"id\":1"));
+
+    let response = send_as(8140, "bob", "GET", "/todos", "");
+    assert!(response.contains("Bob's todo"));
+    assert!(!response.contains("Alice's todo"));
+
+    let response = send_as(8140, "alice", "GET", "/todos/search?q=todo", "");
+    assert!(response.contains("Alice's todo"));
+    assert!(!response.contains("Bob's todo"));
+
+    assert_eq!(db.lock().unwrap().len(), 2);
+    assert!(!db.lock().unwrap().get("alice", 1).unwrap().completed);
+}
+
+#[test]
+fn test_unknown_user_is_rejected() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    let config = Config {
+        users: vec!["alice".to_string()],
+        ..Config::default()
+    };
+    spawn_server_with(8141, db, config);
+
+    let response = send_as(8141, "mallory", "GET", "/todos", "");
+    assert!(response.contains("401 Unauthorized"));
+    assert!(response.contains("Unknown user."));
+
+    let response = send_as(8141, "alice", "GET", "/todos", "");
+    assert!(response.contains("200 OK"));
+}
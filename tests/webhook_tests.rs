@@ -0,0 +1,409 @@
+mod common;
+
+use common::{json, send_as, spawn_server_with};
+use naked_rust_api::audit::{Action, AuditEvent};
+use naked_rust_api::config::WebhookConfig;
+use naked_rust_api::feed::TodoEvent;
+use naked_rust_api::webhooks::{self, NewWebhook, Outcome, Webhooks};
+use naked_rust_api::{Config, Db, Store, Todo};
+use serde_json::{Value, json};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SECRET: &str = "correct horse battery staple";
+
+// The receivers in these tests listen on loopback, which webhooks may only
+// point at when it is allowed
+fn webhook_config() -> WebhookConfig {
+    WebhookConfig {
+        allowed_hosts: vec!["127.0.0.1".to_string()],
+        ..WebhookConfig::default()
+    }
+}
+
+fn config() -> Config {
+    Config {
+        webhooks: webhook_config(),
+        ..Config::default()
+    }
+}
+
+fn spawn_server(port: u16, db: Db, config: Config) {
+    let state = spawn_server_with(port, db, config);
+    naked_rust_api::spawn_webhook_dispatcher(state);
+}
+
+struct Delivered {
+    head: String,
+    body: String,
+}
+
+impl Delivered {
+    fn header(&self, name: &str) -> &str {
+        self.head
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{}: ", name)))
+            .unwrap_or_else(|| panic!("No {} header", name))
+    }
+}
+
+// A webhook receiver answering each request with the next of `statuses`, and
+// 200 once they run out
+fn spawn_receiver(statuses: Vec<u16>) -> (String, Receiver<Delivered>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut statuses = statuses.into_iter();
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                head.push_str(&line);
+                if line == "\r\n" || line.is_empty() {
+                    break;
+                }
+            }
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .map_or(0, |length| length.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            let status = statuses.next().unwrap_or(200);
+            let response = format!("HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).unwrap();
+            let body = String::from_utf8(body).unwrap();
+            let _ = sender.send(Delivered { head, body });
+        }
+    });
+    (url, receiver)
+}
+
+// A URL nothing listens on
+fn dead_url() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("http://{}/hook", listener.local_addr().unwrap())
+}
+
+fn register(port: u16, user: &str, url: &str, events: Option<Value>) -> Value {
+    let mut webhook = json!({ "url": url, "secret": SECRET });
+    if let Some(events) = events {
+        webhook["events"] = events;
+    }
+    let response = send_as(port, user, "POST", "/webhooks", &webhook.to_string());
+    assert!(response.starts_with("HTTP/1.1 201 Created"), "{}", response);
+    json(&response)
+}
+
+// Polls the delivery log until it has `count` entries
+fn wait_for_log(port: u16, user: &str, id: u64, count: usize) -> Vec<Value> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let path = format!("/webhooks/{}/deliveries", id);
+        let log = json(&send_as(port, user, "GET", &path, ""));
+        let log = log.as_array().unwrap().clone();
+        if log.len() >= count || Instant::now() > deadline {
+            return log;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn outcomes(log: &[Value]) -> Vec<&str> {
+    log.iter()
+        .map(|record| record["outcome"].as_str().unwrap())
+        .collect()
+}
+
+#[test]
+fn test_deliveries_are_signed() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8310, Arc::clone(&db), config());
+    let (url, received) = spawn_receiver(Vec::new());
+
+    let webhook = register(8310, "alice", &url, None);
+    assert_eq!(webhook["id"], 1);
+    assert_eq!(
+        webhook["events"],
+        json!(["created", "updated", "deleted", "restored"])
+    );
+    assert!(webhook.get("secret").is_none());
+
+    send_as(8310, "bob", "POST", "/todos", r#"{"title":"Not alice's"}"#);
+    send_as(8310, "alice", "POST", "/todos", r#"{"title":"Announced"}"#);
+    let delivery = received.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(delivery.head.starts_with("POST /hook HTTP/1.1"));
+    assert_eq!(delivery.header("X-Webhook-Event"), "created");
+    assert_eq!(delivery.header("X-Webhook-Id"), "1");
+    let expected = webhooks::signature(
+        SECRET,
+        delivery.header("X-Webhook-Timestamp"),
+        &delivery.body,
+    );
+    assert_eq!(delivery.header("X-Webhook-Signature"), expected);
+    assert!(expected.starts_with("sha256=") && expected.len() == 7 + 64);
+    let event: Value = serde_json::from_str(&delivery.body).unwrap();
+    assert_eq!(event["todo"]["title"], "Announced");
+    assert_eq!(event["seq"], 2);
+
+    let log = wait_for_log(8310, "alice", 1, 1);
+    assert_eq!(outcomes(&log), ["delivered"]);
+    assert_eq!(log[0]["status"], 200);
+    assert_eq!(log[0]["seq"], 2);
+
+    // Webhooks belong to their owner
+    let listed = json(&send_as(8310, "alice", "GET", "/webhooks", ""));
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    assert!(listed[0].get("secret").is_none());
+    assert_eq!(
+        json(&send_as(8310, "bob", "GET", "/webhooks", "")),
+        json!([])
+    );
+    let response = send_as(8310, "bob", "GET", "/webhooks/1/deliveries", "");
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    let response = send_as(8310, "bob", "DELETE", "/webhooks/1", "");
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+
+    let invalid = [
+        json!({ "url": "https://example.com/hook", "secret": SECRET }),
+        json!({ "url": "http://:80/hook", "secret": SECRET }),
+        json!({ "url": url, "secret": "short" }),
+        json!({ "url": url, "secret": SECRET, "events": [] }),
+        json!({ "url": url, "secret": SECRET, "events": ["exploded"] }),
+        json!({ "url": url }),
+    ];
+    for webhook in invalid {
+        let response = send_as(8310, "alice", "POST", "/webhooks", &webhook.to_string());
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request"),
+            "{}",
+            webhook
+        );
+    }
+
+    // Once removed, nothing more is sent
+    let response = send_as(8310, "alice", "DELETE", "/webhooks/1", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    send_as(
+        8310,
+        "alice",
+        "POST",
+        "/todos",
+        r#"{"title":"Unannounced"}"#,
+    );
+    assert!(received.recv_timeout(Duration::from_millis(300)).is_err());
+}
+
+#[test]
+fn test_failed_deliveries_are_retried() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    let mut config = config();
+    config.webhooks.initial_backoff_ms = 20;
+    config.webhooks.max_attempts = 3;
+    spawn_server(8311, Arc::clone(&db), config);
+    let (url, received) = spawn_receiver(vec![500, 503]);
+
+    let flaky = register(8311, "alice", &url, Some(json!(["deleted"])));
+    let dead = register(8311, "alice", &dead_url(), None);
+
+    send_as(8311, "alice", "POST", "/todos", r#"{"title":"Doomed"}"#);
+    send_as(8311, "alice", "DELETE", "/todos/1", "");
+
+    // The creation is filtered out; the deletion gets through on the third try
+    let log = wait_for_log(8311, "alice", flaky["id"].as_u64().unwrap(), 3);
+    assert_eq!(outcomes(&log), ["retrying", "retrying", "delivered"]);
+    let statuses: Vec<&Value> = log.iter().map(|record| &record["status"]).collect();
+    assert_eq!(statuses, [&json!(500), &json!(503), &json!(200)]);
+    assert!(log.iter().all(|record| record["action"] == "deleted"));
+    let attempts: Vec<Delivered> = received.try_iter().collect();
+    assert_eq!(attempts.len(), 3);
+    // Every attempt is the same delivery
+    assert!(
+        attempts
+            .iter()
+            .all(|attempt| attempt.header("X-Webhook-Delivery")
+                == attempts[0].header("X-Webhook-Delivery"))
+    );
+
+    // A receiver that never answers is given up on after max_attempts
+    let log = wait_for_log(8311, "alice", dead["id"].as_u64().unwrap(), 6);
+    let first: Vec<&Value> = log.iter().filter(|record| record["seq"] == 1).collect();
+    let outcomes: Vec<&str> = first
+        .iter()
+        .map(|record| record["outcome"].as_str().unwrap())
+        .collect();
+    assert_eq!(outcomes, ["retrying", "retrying", "failed"]);
+    assert!(first[0]["status"].is_null());
+    assert!(first[0]["error"].as_str().unwrap().contains("connect"));
+}
+
+fn event(title: &str) -> TodoEvent {
+    let todo = Todo {
+        id: 1,
+        owner: "alice".to_string(),
+        title: title.to_string(),
+        completed: false,
+        deleted_at: None,
+    };
+    let mut change = AuditEvent::new("alice", Action::Created, None, &todo);
+    change.seq = 1;
+    TodoEvent { change, todo }
+}
+
+#[test]
+fn test_queue_survives_restart() {
+    let path = std::env::temp_dir().join(format!("webhooks-{}.json", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let config = webhook_config();
+    let (url, received) = spawn_receiver(Vec::new());
+
+    let webhooks = Webhooks::open(config.clone(), Some(path.clone())).unwrap();
+    let new = NewWebhook {
+        url,
+        events: None,
+        secret: SECRET.to_string(),
+    };
+    let webhook = webhooks.create("alice", new).unwrap();
+    webhooks.enqueue(&[event("Queued before the crash")]);
+    assert_eq!(webhooks.pending(), 1);
+    drop(webhooks);
+
+    // The queued delivery is picked up again and sent
+    let webhooks = Arc::new(Webhooks::open(config.clone(), Some(path.clone())).unwrap());
+    assert_eq!(webhooks.pending(), 1);
+    assert_eq!(webhooks.until_next_due(), Some(Duration::ZERO));
+    webhooks.deliver_due();
+    let delivery = received.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(delivery.body.contains("Queued before the crash"));
+    // The secret was kept, so the signature still checks out
+    let expected = webhooks::signature(
+        SECRET,
+        delivery.header("X-Webhook-Timestamp"),
+        &delivery.body,
+    );
+    assert_eq!(delivery.header("X-Webhook-Signature"), expected);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while webhooks.pending() > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(webhooks.pending(), 0);
+    drop(webhooks);
+
+    let webhooks = Webhooks::open(config.clone(), Some(path.clone())).unwrap();
+    assert_eq!(webhooks.pending(), 0);
+    let log = webhooks.deliveries("alice", webhook.id).unwrap();
+    assert_eq!(log.len(), 1);
+    assert_eq!(log[0].outcome, Outcome::Delivered);
+    assert_eq!(webhooks.list("alice")[0].url, webhook.url);
+
+    // A long journal is merged into the state file, which still ends up in
+    // the same state
+    let events: Vec<TodoEvent> = (0..1500).map(|n| event(&format!("Todo {}", n))).collect();
+    webhooks.enqueue(&events);
+    drop(webhooks);
+    let journal = path.with_extension("jsonl");
+    assert!(fs::read_to_string(&journal).unwrap().lines().count() < 1000);
+    let webhooks = Webhooks::open(config, Some(path.clone())).unwrap();
+    assert_eq!(webhooks.pending(), 1500);
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&journal).unwrap();
+}
+
+#[test]
+fn test_internal_addresses_are_refused() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8312, Arc::clone(&db), Config::default());
+
+    let internal = [
+        "http://127.0.0.1:8312/todos",
+        "http://localhost/hook",
+        "http://169.254.169.254/latest/meta-data",
+        "http://10.0.0.7/hook",
+        "http://192.168.1.1:8080/hook",
+        "http://0.0.0.0/hook",
+        "http://[::1]/hook",
+        "http://[::ffff:127.0.0.1]/hook",
+        "http://[2002:7f00:1::1]/hook",
+        "http://[64:ff9b::a9fe:a9fe]/hook",
+        "http://[fec0::1]:8080/hook",
+    ];
+    for url in internal {
+        let webhook = json!({ "url": url, "secret": SECRET });
+        let response = send_as(8312, "alice", "POST", "/webhooks", &webhook.to_string());
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request"),
+            "{}: {}",
+            url,
+            response
+        );
+    }
+    let webhook = json!({ "url": "http://169.254.169.254/", "secret": SECRET });
+    let response = send_as(8312, "alice", "POST", "/webhooks", &webhook.to_string());
+    assert!(response.contains("not a public address"), "{}", response);
+    let webhook = json!({ "url": "http://[2002:a9fe:a9fe::1]/", "secret": SECRET });
+    let response = send_as(8312, "alice", "POST", "/webhooks", &webhook.to_string());
+    assert!(response.contains("not a public address"), "{}", response);
+
+    // Nothing can be smuggled into the request line or Host header
+    let smuggled = [
+        "http://example.com/hook HTTP/1.1\r\nHost: localhost\r\nX-Injected: 1\r\n\r\n",
+        "http://example.com/hook\nGET /admin",
+        "http://example.com/a b",
+        "http://exa\tmple.com/hook",
+        "http://[::1\r\n]/hook",
+    ];
+    for url in smuggled {
+        let webhook = json!({ "url": url, "secret": SECRET });
+        let response = send_as(8312, "alice", "POST", "/webhooks", &webhook.to_string());
+        assert!(
+            response.contains("Invalid webhook URL"),
+            "{:?}: {}",
+            url,
+            response
+        );
+    }
+    assert_eq!(
+        json(&send_as(8312, "alice", "GET", "/webhooks", "")),
+        json!([])
+    );
+}
+
+#[test]
+fn test_slow_receivers_hold_up_only_their_own_webhook() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    let mut config = config();
+    config.webhooks.timeout_secs = 5;
+    spawn_server(8313, Arc::clone(&db), config);
+
+    // Accepts connections and never answers them
+    let hanging = TcpListener::bind("127.0.0.1:0").unwrap();
+    let hanging_url = format!("http://{}/hook", hanging.local_addr().unwrap());
+    thread::spawn(move || {
+        let held: Vec<TcpStream> = hanging.incoming().map_while(Result::ok).collect();
+        drop(held);
+    });
+    let (url, received) = spawn_receiver(Vec::new());
+    register(8313, "bob", &hanging_url, None);
+    register(8313, "alice", &url, None);
+
+    for n in 0..3 {
+        let title = json!({ "title": format!("Bob's {}", n) }).to_string();
+        send_as(8313, "bob", "POST", "/todos", &title);
+    }
+    thread::sleep(Duration::from_millis(100));
+    let started = Instant::now();
+    send_as(8313, "alice", "POST", "/todos", r#"{"title":"On time"}"#);
+    let delivery = received.recv_timeout(Duration::from_secs(4)).unwrap();
+    assert!(delivery.body.contains("On time"));
+    assert!(started.elapsed() < Duration::from_secs(3));
+}
@@ -0,0 +1,345 @@
+mod common;
+
+use common::{empty_db, send_as, spawn_server, spawn_server_with};
+use naked_rust_api::config::CorsConfig;
+use naked_rust_api::{Config, Db, Store};
+use serde_json::{Value, json};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The sample key and answer from RFC 6455
+const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+const ACCEPT: &str = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+// Sends a handshake with `extra` headers and returns the response head, and
+// the socket if the upgrade went through
+fn handshake(port: u16, user: &str, extra: &str) -> (String, TcpStream) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("Failed to connect to server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let request = format!(
+        "GET /ws HTTP/1.1\r\nHost: localhost\r\nX-User-Id: {}\r\n{}\r\n",
+        user, extra
+    );
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).unwrap() == 0 {
+            break;
+        }
+        head.push(byte[0]);
+    }
+    (String::from_utf8(head).unwrap(), stream)
+}
+
+fn connect(port: u16, user: &str) -> TcpStream {
+    let upgrade = format!(
+        "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+        KEY
+    );
+    let (head, stream) = handshake(port, user, &upgrade);
+    assert!(
+        head.starts_with("HTTP/1.1 101 Switching Protocols"),
+        "{}",
+        head
+    );
+    assert!(head.contains(&format!("Sec-WebSocket-Accept: {}", ACCEPT)));
+    stream
+}
+
+// Clients mask every frame they send
+fn send_frame(stream: &mut TcpStream, first: u8, payload: &[u8]) {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![first];
+    match payload.len() {
+        length if length < 126 => frame.push(0x80 | length as u8),
+        length => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    stream.write_all(&frame).unwrap();
+}
+
+fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0; 2];
+    stream
+        .read_exact(&mut head)
+        .expect("No frame arrived in time");
+    assert_eq!(head[1] & 0x80, 0, "Server frames are not masked");
+    let length = match head[1] & 0x7F {
+        126 => {
+            let mut bytes = [0; 2];
+            stream.read_exact(&mut bytes).unwrap();
+            u16::from_be_bytes(bytes) as usize
+        }
+        127 => {
+            let mut bytes = [0; 8];
+            stream.read_exact(&mut bytes).unwrap();
+            u64::from_be_bytes(bytes) as usize
+        }
+        length => length as usize,
+    };
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload).unwrap();
+    (head[0] & 0x0F, payload)
+}
+
+fn send_json(stream: &mut TcpStream, message: Value) {
+    send_frame(stream, 0x81, message.to_string().as_bytes());
+}
+
+fn read_json(stream: &mut TcpStream) -> Value {
+    let (opcode, payload) = read_frame(stream);
+    assert_eq!(opcode, 0x1);
+    serde_json::from_slice(&payload).unwrap()
+}
+
+fn close_code(payload: &[u8]) -> u16 {
+    u16::from_be_bytes([payload[0], payload[1]])
+}
+
+#[test]
+fn test_commands_over_socket() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8300, Arc::clone(&db));
+    let mut socket = connect(8300, "alice");
+
+    send_json(
+        &mut socket,
+        json!({ "type": "create", "title": "Live", "ref": 1 }),
+    );
+    let reply = read_json(&mut socket);
+    assert_eq!(reply["type"], "response");
+    assert_eq!(reply["ref"], 1);
+    assert_eq!(reply["status"], 201);
+    assert_eq!(reply["body"]["title"], "Live");
+    assert_eq!(reply["body"]["owner"], "alice");
+
+    send_json(
+        &mut socket,
+        json!({ "type": "update", "id": 1, "completed": true, "ref": "b" }),
+    );
+    let reply = read_json(&mut socket);
+    assert_eq!(reply["ref"], "b");
+    assert_eq!(reply["body"]["completed"], true);
+    assert_eq!(reply["body"]["title"], "Live");
+
+    send_json(&mut socket, json!({ "type": "get", "id": 1 }));
+    assert_eq!(read_json(&mut socket)["body"]["completed"], true);
+    send_json(&mut socket, json!({ "type": "delete", "id": 1 }));
+    assert_eq!(read_json(&mut socket)["status"], 200);
+    send_json(&mut socket, json!({ "type": "list", "deleted": true }));
+    assert_eq!(read_json(&mut socket)["body"].as_array().unwrap().len(), 1);
+    send_json(&mut socket, json!({ "type": "restore", "id": 1 }));
+    assert_eq!(read_json(&mut socket)["status"], 200);
+
+    // Errors come back with the handler's status and message
+    send_json(&mut socket, json!({ "type": "get", "id": 7 }));
+    let reply = read_json(&mut socket);
+    assert_eq!(reply["status"], 404);
+    assert!(reply["body"].is_string());
+    send_json(&mut socket, json!({ "type": "shout", "ref": 9 }));
+    let reply = read_json(&mut socket);
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["ref"], 9);
+    send_frame(&mut socket, 0x81, b"not json");
+    assert_eq!(read_json(&mut socket)["type"], "error");
+
+    // The changes went through the same store as the HTTP API
+    let response = send_as(8300, "alice", "GET", "/todos/1", "");
+    assert!(response.contains(r#""completed":true"#));
+}
+
+#[test]
+fn test_subscription_pushes_changes() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8301, Arc::clone(&db));
+    send_as(8301, "alice", "POST", "/todos", r#"{"title":"Before"}"#);
+
+    let mut socket = connect(8301, "alice");
+    send_json(&mut socket, json!({ "type": "subscribe", "ref": "s" }));
+    let reply = read_json(&mut socket);
+    assert_eq!(reply["type"], "subscribed");
+    assert_eq!(reply["ref"], "s");
+    assert_eq!(reply["seq"], 1);
+
+    send_as(8301, "bob", "POST", "/todos", r#"{"title":"Bob's"}"#);
+    send_as(8301, "alice", "POST", "/todos", r#"{"title":"From HTTP"}"#);
+    let pushed = read_json(&mut socket);
+    assert_eq!(pushed["type"], "event");
+    assert_eq!(pushed["event"]["seq"], 3);
+    assert_eq!(pushed["event"]["action"], "created");
+    assert_eq!(pushed["event"]["todo"]["title"], "From HTTP");
+
+    // A client's own commands are pushed too, after their reply
+    send_json(&mut socket, json!({ "type": "delete", "id": 2 }));
+    assert_eq!(read_json(&mut socket)["type"], "response");
+    let pushed = read_json(&mut socket);
+    assert_eq!(pushed["event"]["action"], "deleted");
+
+    // Resuming replays what came after `since`
+    let mut other = connect(8301, "alice");
+    send_json(&mut other, json!({ "type": "subscribe", "since": 1 }));
+    assert_eq!(read_json(&mut other)["seq"], 1);
+    assert_eq!(read_json(&mut other)["event"]["seq"], 3);
+    assert_eq!(read_json(&mut other)["event"]["seq"], 4);
+
+    send_json(&mut other, json!({ "type": "unsubscribe" }));
+    assert_eq!(read_json(&mut other)["type"], "unsubscribed");
+    send_as(8301, "alice", "POST", "/todos", r#"{"title":"Unseen"}"#);
+    send_json(&mut other, json!({ "type": "get", "id": 3 }));
+    assert_eq!(read_json(&mut other)["type"], "response");
+}
+
+#[test]
+fn test_control_frames_and_errors() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8302, Arc::clone(&db));
+
+    // Ping is answered with the same payload
+    let mut socket = connect(8302, "alice");
+    send_frame(&mut socket, 0x89, b"are you there");
+    assert_eq!(read_frame(&mut socket), (0xA, b"are you there".to_vec()));
+
+    // A fragmented message is put back together, with a ping in between
+    let message = json!({ "type": "create", "title": "In pieces" }).to_string();
+    let (first, second) = message.as_bytes().split_at(10);
+    send_frame(&mut socket, 0x01, first);
+    send_frame(&mut socket, 0x89, b"");
+    send_frame(&mut socket, 0x80, second);
+    assert_eq!(read_frame(&mut socket).0, 0xA);
+    assert_eq!(read_json(&mut socket)["body"]["title"], "In pieces");
+
+    // The client's close code is echoed, then the connection ends
+    send_frame(&mut socket, 0x88, &1000u16.to_be_bytes());
+    let (opcode, payload) = read_frame(&mut socket);
+    assert_eq!(opcode, 0x8);
+    assert_eq!(close_code(&payload), 1000);
+    assert_eq!(socket.read(&mut [0; 16]).unwrap(), 0);
+
+    // Binary messages aren't understood
+    let mut socket = connect(8302, "alice");
+    send_frame(&mut socket, 0x82, b"\x00\x01");
+    let (opcode, payload) = read_frame(&mut socket);
+    assert_eq!(opcode, 0x8);
+    assert_eq!(close_code(&payload), 1003);
+
+    // Unmasked frames break the protocol
+    let mut socket = connect(8302, "alice");
+    socket.write_all(&[0x81, 0x02, b'{', b'}']).unwrap();
+    let (opcode, payload) = read_frame(&mut socket);
+    assert_eq!(opcode, 0x8);
+    assert_eq!(close_code(&payload), 1002);
+
+    // Requests that aren't proper upgrades are refused
+    let (head, _) = handshake(8302, "alice", "");
+    assert!(head.starts_with("HTTP/1.1 426 Upgrade Required"));
+    let (head, _) = handshake(
+        8302,
+        "alice",
+        "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n",
+    );
+    assert!(head.starts_with("HTTP/1.1 426 Upgrade Required"));
+    assert!(head.contains("Sec-WebSocket-Version: 13"));
+    let (head, _) = handshake(
+        8302,
+        "alice",
+        "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: short\r\nSec-WebSocket-Version: 13\r\n",
+    );
+    assert!(head.starts_with("HTTP/1.1 400 Bad Request"));
+}
+
+#[test]
+fn test_cross_site_upgrades_are_refused() {
+    let config = Config {
+        cors: Some(CorsConfig {
+            allowed_origins: vec!["https://app.example.com".to_string(), "*".to_string()],
+            ..CorsConfig::default()
+        }),
+        ..Config::default()
+    };
+    spawn_server_with(8352, empty_db(), config);
+    let upgrade = format!(
+        "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+        KEY
+    );
+
+    // No Origin, the server's own and one listed by name
+    for origin in [
+        "",
+        "Origin: http://localhost\r\n",
+        "Origin: https://app.example.com\r\n",
+    ] {
+        let (head, _) = handshake(8352, "alice", &format!("{}{}", upgrade, origin));
+        assert!(head.starts_with("HTTP/1.1 101"), "{}: {}", origin, head);
+    }
+
+    // Any other page, even with a wildcard configured
+    let origin = "Origin: https://evil.example\r\n";
+    let (head, _) = handshake(8352, "alice", &format!("{}{}", upgrade, origin));
+    assert!(head.starts_with("HTTP/1.1 403 Forbidden"), "{}", head);
+}
+
+#[test]
+fn test_idle_sockets_wake_only_for_their_changes() {
+    let db: Db = Arc::new(Mutex::new(Store::new()));
+    spawn_server(8303, Arc::clone(&db));
+
+    // Sessions sleep on the feed, woken only by their own user's changes
+    let feed = Arc::clone(db.lock().unwrap().feed());
+    let woken: Vec<Arc<AtomicUsize>> = (0..2).map(|_| Arc::new(AtomicUsize::new(0))).collect();
+    let watches: Vec<_> = ["alice", "bob"]
+        .into_iter()
+        .zip(&woken)
+        .map(|(user, count)| {
+            let count = Arc::clone(count);
+            feed.watch(user, move || {
+                count.fetch_add(1, Ordering::SeqCst);
+            })
+        })
+        .collect();
+    send_as(8303, "alice", "POST", "/todos", r#"{"title":"First"}"#);
+    send_as(8303, "alice", "POST", "/todos", r#"{"title":"Second"}"#);
+    assert_eq!(woken[0].load(Ordering::SeqCst), 2);
+    assert_eq!(woken[1].load(Ordering::SeqCst), 0);
+    drop(watches);
+    send_as(8303, "alice", "POST", "/todos", r#"{"title":"Unwatched"}"#);
+    assert_eq!(woken[0].load(Ordering::SeqCst), 2);
+
+    // Many idle subscribers don't hold up a push to another one
+    let idle: Vec<TcpStream> = (0..50)
+        .map(|i| {
+            let mut socket = connect(8303, &format!("user{}", i));
+            send_json(&mut socket, json!({ "type": "subscribe" }));
+            assert_eq!(read_json(&mut socket)["type"], "subscribed");
+            socket
+        })
+        .collect();
+    let mut socket = connect(8303, "alice");
+    send_json(&mut socket, json!({ "type": "subscribe" }));
+    assert_eq!(read_json(&mut socket)["seq"], 3);
+    let started = Instant::now();
+    send_as(8303, "alice", "POST", "/todos", r#"{"title":"Pushed"}"#);
+    assert_eq!(read_json(&mut socket)["event"]["todo"]["title"], "Pushed");
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    // Input and a closing client are still noticed while asleep
+    thread::sleep(Duration::from_millis(300));
+    send_json(&mut socket, json!({ "type": "get", "id": 4 }));
+    assert_eq!(read_json(&mut socket)["status"], 200);
+    for mut socket in idle {
+        send_frame(&mut socket, 0x88, &1000u16.to_be_bytes());
+        assert_eq!(read_frame(&mut socket).0, 0x8);
+        assert_eq!(socket.read(&mut [0; 16]).unwrap(), 0);
+    }
+}